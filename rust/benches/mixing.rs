@@ -0,0 +1,290 @@
+use std::sync::Mutex;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use itertools::iproduct;
+use petgraph::graph::{Graph, NodeIndex};
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::{current_num_threads, prelude::*};
+use rust::{
+    blah,
+    circuit::{BaseGate, Circuit},
+    circuit_to_collision_sets, find_convex_fast, find_replacement_circuit, graph_level,
+    permutation_table, prepare_circuit, sample_circuit_with_base_gate, ConvexSearchOptions,
+    HashSet, ReplacementSearchOptions,
+};
+
+/// (gates, n) of circuits the skeleton graph benches run on
+const GRAPH_PARAMS: [(usize, u8); 4] = [(1_000, 64), (1_000, 128), (10_000, 64), (10_000, 128)];
+
+fn sample_circuit(gates: usize, n: u8) -> Circuit<BaseGate<2, u8>> {
+    let mut rng = ChaCha8Rng::seed_from_u64(gates as u64 * 256 + n as u64);
+    sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng).0
+}
+
+fn bench_graph_level(c: &mut Criterion) {
+    let mut group = c.benchmark_group("graph_level");
+    for (gates, n) in GRAPH_PARAMS {
+        let (_, _, skeleton_graph, _, _, graph_neighbours, _, _) =
+            prepare_circuit(&sample_circuit(gates, n));
+        group.bench_function(BenchmarkId::from_parameter(format!("{gates}/{n}")), |b| {
+            b.iter(|| graph_level(&skeleton_graph, &graph_neighbours, &HashSet::default()))
+        });
+    }
+    group.finish();
+}
+
+fn bench_find_convex_fast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_convex_fast");
+    for (gates, n) in GRAPH_PARAMS {
+        let (_, _, skeleton_graph, _, _, graph_neighbours, _, _) =
+            prepare_circuit(&sample_circuit(gates, n));
+        let level = graph_level(&skeleton_graph, &graph_neighbours, &HashSet::default());
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for (ell_out, level_bounded) in iproduct!([2, 4], [false, true]) {
+            let suffix = if level_bounded { "/level_bounded" } else { "" };
+            group.bench_function(
+                BenchmarkId::new(format!("ell_out={ell_out}{suffix}"), format!("{gates}/{n}")),
+                |b| {
+                    b.iter(|| {
+                        find_convex_fast(
+                            &skeleton_graph,
+                            &level,
+                            ell_out,
+                            10_000,
+                            &ConvexSearchOptions {
+                                level_bounded,
+                                ..Default::default()
+                            },
+                            &mut rng,
+                            &HashSet::default(),
+                            &HashSet::default(),
+                        )
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+/// Convex search with start nodes in a shuffled `Vec` shared by all threads behind a `Mutex`.
+/// Reference for the shards of [`find_convex_fast`].
+fn find_convex_shared_queue(
+    graph: &Graph<usize, usize>,
+    level: &[usize],
+    ell_out: usize,
+    max_iterations: usize,
+    rng: &mut ChaCha8Rng,
+) -> Option<(NodeIndex, HashSet<NodeIndex>)> {
+    let mut start_nodes = graph.node_indices().collect::<Vec<_>>();
+    let (start_nodes, _) = start_nodes.partial_shuffle(rng, max_iterations);
+    let queue = Mutex::new(start_nodes.to_vec());
+    (0..current_num_threads())
+        .into_par_iter()
+        .find_map_any(|_| loop {
+            let start_node = queue.lock().unwrap().pop()?;
+            let mut convex_set = HashSet::from_iter([start_node]);
+            if blah(
+                ell_out,
+                &mut convex_set,
+                graph,
+                level,
+                &HashSet::default(),
+                &HashSet::default(),
+                &ConvexSearchOptions::default(),
+            ) {
+                return Some((start_node, convex_set));
+            }
+        })
+}
+
+/// Convex search on a circuit of the size mixing runs on, with and without prioritizing start
+/// nodes that have enough successors, and with start nodes in a shared queue instead of shards
+fn bench_find_convex_fast_50k(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_convex_fast_50k");
+    group.sample_size(10);
+    for n in [64, 128] {
+        let (_, _, skeleton_graph, _, _, graph_neighbours, _, _) =
+            prepare_circuit(&sample_circuit(50_000, n));
+        let level = graph_level(&skeleton_graph, &graph_neighbours, &HashSet::default());
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for (ell_out, prioritize_start_nodes) in iproduct!([2, 4], [false, true]) {
+            let suffix = if prioritize_start_nodes {
+                "/prioritize_start_nodes"
+            } else {
+                ""
+            };
+            group.bench_function(
+                BenchmarkId::new(format!("ell_out={ell_out}{suffix}"), n),
+                |b| {
+                    b.iter(|| {
+                        find_convex_fast(
+                            &skeleton_graph,
+                            &level,
+                            ell_out,
+                            10_000,
+                            &ConvexSearchOptions {
+                                prioritize_start_nodes,
+                                ..Default::default()
+                            },
+                            &mut rng,
+                            &HashSet::default(),
+                            &HashSet::default(),
+                        )
+                    })
+                },
+            );
+        }
+        for ell_out in [2, 4] {
+            group.bench_function(
+                BenchmarkId::new(format!("ell_out={ell_out}/shared_queue"), n),
+                |b| {
+                    b.iter(|| {
+                        find_convex_shared_queue(&skeleton_graph, &level, ell_out, 10_000, &mut rng)
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn bench_blah(c: &mut Criterion) {
+    let mut group = c.benchmark_group("blah");
+    for (gates, n) in GRAPH_PARAMS {
+        let (_, _, skeleton_graph, _, _, graph_neighbours, _, _) =
+            prepare_circuit(&sample_circuit(gates, n));
+        let level = graph_level(&skeleton_graph, &graph_neighbours, &HashSet::default());
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        group.bench_function(BenchmarkId::from_parameter(format!("{gates}/{n}")), |b| {
+            b.iter_batched(
+                || {
+                    let start_node = NodeIndex::new(rng.gen_range(0..gates));
+                    HashSet::from_iter([start_node])
+                },
+                |mut convex_set| {
+                    blah(
+                        4,
+                        &mut convex_set,
+                        &skeleton_graph,
+                        &level,
+                        &HashSet::default(),
+                        &HashSet::default(),
+                        &ConvexSearchOptions::default(),
+                    )
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_circuit_to_collision_sets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("circuit_to_collision_sets");
+    for (gates, n) in [(100, 64), (100, 128), (1_000, 64), (1_000, 128)] {
+        let circuit = sample_circuit(gates, n);
+        group.bench_function(BenchmarkId::from_parameter(format!("{gates}/{n}")), |b| {
+            b.iter(|| circuit_to_collision_sets(&circuit))
+        });
+    }
+    group.finish();
+}
+
+fn bench_circuit_run(c: &mut Criterion) {
+    let mut group = c.benchmark_group("circuit_run");
+    // Replacement search evaluates small candidates on every input, so copying inputs dominates
+    for (gates, n) in [(4, 12), (4, 16)] {
+        let circuit = sample_circuit(gates, n);
+        let inputs = (0..1usize << n)
+            .map(|value| (0..n).map(|j| (value >> j) & 1 == 1).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        group.bench_function(BenchmarkId::new("clone", format!("{gates}/{n}")), |b| {
+            b.iter(|| {
+                for input in &inputs {
+                    let mut outputs = input.to_vec();
+                    circuit.run(&mut outputs);
+                    black_box(&outputs);
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("run_into", format!("{gates}/{n}")), |b| {
+            let mut outputs = Vec::with_capacity(n as usize);
+            b.iter(|| {
+                for input in &inputs {
+                    circuit.run_into(input, &mut outputs);
+                    black_box(&outputs);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_permutation_table(c: &mut Criterion) {
+    let mut group = c.benchmark_group("permutation_table");
+    // Table of C^out with 4 gates, built once per replacement search
+    fn bench<const N: usize, const N2: usize>(
+        group: &mut criterion::BenchmarkGroup<criterion::measurement::WallTime>,
+    ) {
+        let circuit = sample_circuit(4, N as u8);
+        group.bench_function(BenchmarkId::from_parameter(N), |b| {
+            b.iter(|| permutation_table::<N, N2>(&circuit))
+        });
+    }
+    bench::<5, { 1 << 5 }>(&mut group);
+    bench::<9, { 1 << 9 }>(&mut group);
+    bench::<11, { 1 << 11 }>(&mut group);
+    group.finish();
+}
+
+fn bench_find_replacement_circuit(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_replacement_circuit");
+    group.sample_size(10);
+    // C^out has \ell^out gates on \omega^out wires. Replacement is rarely found, so all iterations
+    // are usually run.
+    for (gates, n) in [(2, 5), (4, 5), (4, 7), (4, 9), (4, 12)] {
+        let circuit = sample_circuit(gates, n);
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        group.bench_function(BenchmarkId::from_parameter(format!("{gates}/{n}")), |b| {
+            b.iter(|| {
+                find_replacement_circuit(
+                    &circuit,
+                    4,
+                    n as usize,
+                    100_000,
+                    &ReplacementSearchOptions::default(),
+                    &mut rng,
+                )
+            })
+        });
+    }
+    // No. of inputs of the bit-sliced prefilter, on C^out with 4 gates on 9 wires
+    let circuit = sample_circuit(4, 9);
+    for prefilter_inputs in [0, 16, 64] {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let options = ReplacementSearchOptions {
+            prefilter_inputs,
+            ..Default::default()
+        };
+        group.bench_function(
+            BenchmarkId::new("prefilter_inputs", prefilter_inputs),
+            |b| b.iter(|| find_replacement_circuit(&circuit, 4, 9, 100_000, &options, &mut rng)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_graph_level,
+    bench_find_convex_fast,
+    bench_find_convex_fast_50k,
+    bench_blah,
+    bench_circuit_to_collision_sets,
+    bench_circuit_run,
+    bench_permutation_table,
+    bench_find_replacement_circuit
+);
+criterion_main!(benches);
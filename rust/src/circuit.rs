@@ -1,10 +1,16 @@
+use crate::HashMap;
 use bitvec::{array::BitArray, vec::BitVec};
-use hashbrown::HashMap;
 use itertools::{chain, izip, Itertools};
 use petgraph::{graph::NodeIndex, Graph};
 use rand::{seq::SliceRandom, RngCore};
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, iter::repeat_with};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fmt::{Debug, Display},
+    iter::repeat_with,
+    sync::OnceLock,
+};
 
 pub trait Gate {
     type Input: ?Sized;
@@ -124,10 +130,49 @@ where
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// Max. no. of wires for which [`Circuit::is_bijection`] runs the circuit on all inputs
+pub const MAX_BIJECTION_CHECK_N: usize = 20;
+
+/// Max. no. of wires for which [`Circuit::to_matrix`] builds the `2^n x 2^n` matrix
+pub const MAX_MATRIX_N: usize = 12;
+
+/// Circuit has more wires than an exhaustive computation over all inputs allows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TooManyWires {
+    pub n: usize,
+    pub max_n: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Circuit<G> {
     gates: Vec<G>,
     n: usize,
+    /// Map from gate id to index of the gate in `gates`. Built on first call to
+    /// [`Circuit::gate_by_id`].
+    #[serde(skip)]
+    id_index: OnceLock<HashMap<usize, usize>>,
+    /// Annotations of gates keyed by gate id. Not serialized with the circuit, so that serialized
+    /// circuits and their digests don't depend on annotations. Jobs and the JSON format
+    /// [`crate::PrettyCircuit`] store them separately.
+    #[serde(skip)]
+    annotations: BTreeMap<usize, String>,
+}
+
+impl<G: PartialEq> PartialEq for Circuit<G> {
+    fn eq(&self, other: &Self) -> bool {
+        self.gates == other.gates && self.n == other.n
+    }
+}
+
+impl<G: Eq> Eq for Circuit<G> {}
+
+impl<G: Debug> Debug for Circuit<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Circuit")
+            .field("gates", &self.gates)
+            .field("n", &self.n)
+            .finish()
+    }
 }
 
 impl<G> Circuit<G>
@@ -139,6 +184,140 @@ where
             g.run(inputs);
         });
     }
+
+    /// Copies `src` into `dst` and runs the circuit on `dst`. Unlike cloning inputs before every
+    /// [`Self::run`], `dst` can be reused across calls to avoid allocating per evaluation.
+    pub fn run_into(&self, src: &[bool], dst: &mut Vec<bool>) {
+        dst.clear();
+        dst.extend_from_slice(src);
+        self.run(dst);
+    }
+
+    /// Same as [`Self::run`] but also returns wire values after each gate. `i`-th entry is the state
+    /// of wires after gate `i` is applied. Much slower than [`Self::run`]. Only meant for debugging.
+    pub fn run_with_trace(&self, inputs: &mut [bool]) -> Vec<Vec<bool>> {
+        self.gates
+            .iter()
+            .map(|g| {
+                g.run(inputs);
+                inputs.to_vec()
+            })
+            .collect()
+    }
+
+    /// Returns true if the circuit computes a bijection on `0..2^n`, checked by running it on all
+    /// `2^n` inputs. Every circuit of valid [`BaseGate`]s does, so this is meant as a sanity check
+    /// of imported or transformed circuits.
+    ///
+    /// Returns error if `n > MAX_BIJECTION_CHECK_N` instead of doing `2^n` work.
+    pub fn is_bijection(&self) -> Result<bool, TooManyWires> {
+        if self.n > MAX_BIJECTION_CHECK_N {
+            return Err(TooManyWires {
+                n: self.n,
+                max_n: MAX_BIJECTION_CHECK_N,
+            });
+        }
+
+        let mut is_output = vec![false; 1 << self.n];
+        for value in 0..1usize << self.n {
+            if std::mem::replace(&mut is_output[self.run_on_value(value)], true) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns the `2^n x 2^n` permutation matrix of the circuit. Row `i` has a single `true`, in
+    /// the column of the output of input `i`, where bit `j` is the value of wire `j`.
+    ///
+    /// Inputs are row vectors, hence matrix of circuit `a` followed by circuit `b` is the product
+    /// of matrix of `a` and matrix of `b`, in that order.
+    ///
+    /// Returns error if `n > MAX_MATRIX_N`.
+    ///
+    /// ```
+    /// use rust::circuit::{BaseGate, Circuit};
+    ///
+    /// // wire 0 ^= wire 1 & wire 2
+    /// let circuit = Circuit::new(vec![BaseGate::<2, u8>::new(0, 0, [1, 2], 1)], 3);
+    /// let matrix = circuit.to_matrix().unwrap();
+    /// assert!(matrix[1][1] && matrix[6][7] && matrix[7][6]);
+    /// ```
+    pub fn to_matrix(&self) -> Result<Vec<Vec<bool>>, TooManyWires> {
+        if self.n > MAX_MATRIX_N {
+            return Err(TooManyWires {
+                n: self.n,
+                max_n: MAX_MATRIX_N,
+            });
+        }
+
+        Ok((0..1usize << self.n)
+            .map(|value| {
+                let mut row = vec![false; 1 << self.n];
+                row[self.run_on_value(value)] = true;
+                row
+            })
+            .collect())
+    }
+
+    /// Runs the circuit on input `value`, where bit `j` is the value of wire `j`
+    fn run_on_value(&self, value: usize) -> usize {
+        let mut inputs = (0..self.n).map(|j| (value >> j) & 1 == 1).collect_vec();
+        self.run(&mut inputs);
+        inputs
+            .iter()
+            .rev()
+            .fold(0, |output, bit| (output << 1) | *bit as usize)
+    }
+
+    /// Returns truth table of the circuit restricted to wires `keep` when every other wire `w`
+    /// is pinned to input `fixed[w]`
+    ///
+    /// Bit `j` of index `i` and of entry `i` is the value of wire `keep[j]` at input and at output
+    /// respectively. Outputs of pinned wires are ignored, so the table need not be a permutation.
+    ///
+    /// Panics if `keep` has duplicates or 20 or more wires, or if a wire is in both `keep` and
+    /// `fixed` or in neither.
+    ///
+    /// ```
+    /// use rust::{circuit::{BaseGate, Circuit}, HashMap};
+    ///
+    /// // wire 0 ^= wire 1 & wire 2
+    /// let circuit = Circuit::new(vec![BaseGate::<2, u8>::new(0, 0, [1, 2], 1)], 3);
+    /// let fixed: HashMap<_, _> = [(2, true)].into_iter().collect();
+    /// // wire 0 ^= wire 1
+    /// assert_eq!(circuit.project(&[0, 1], &fixed), vec![0, 1, 3, 2]);
+    /// ```
+    pub fn project(&self, keep: &[usize], fixed: &HashMap<usize, bool>) -> Vec<usize> {
+        assert!(keep.len() < 20, "{} >= 20; Too big!", keep.len());
+        assert!(
+            keep.iter().all_unique(),
+            "Kept wires {keep:?} are not unique"
+        );
+        for wire in 0..self.n {
+            assert!(
+                keep.contains(&wire) != fixed.contains_key(&wire),
+                "Wire {wire} must be either kept or fixed"
+            );
+        }
+
+        let mut inputs = vec![false; self.n];
+        fixed
+            .iter()
+            .for_each(|(wire, value)| inputs[*wire] = *value);
+        (0..1usize << keep.len())
+            .map(|value| {
+                let mut inputs = inputs.clone();
+                for (j, wire) in keep.iter().enumerate() {
+                    inputs[*wire] = (value >> j) & 1 == 1;
+                }
+                self.run(&mut inputs);
+                keep.iter().enumerate().fold(0, |output, (j, wire)| {
+                    output | (inputs[*wire] as usize) << j
+                })
+            })
+            .collect()
+    }
 }
 
 impl<G> Circuit<G>
@@ -150,17 +329,27 @@ where
         let mut first_gates = self.gates.clone();
         let second_gates = first_gates.split_off(at_gate);
         (
-            Circuit {
-                gates: first_gates,
-                n: self.n,
-            },
-            Circuit {
-                gates: second_gates,
-                n: self.n,
-            },
+            Circuit::new(first_gates, self.n),
+            Circuit::new(second_gates, self.n),
         )
     }
 
+    /// Returns the same circuit on `new_n` wires. Wires `self.n()..new_n` are not touched by any
+    /// gate, hence pass through unchanged.
+    ///
+    /// Panics if `new_n < self.n()`.
+    pub fn pad_to(&self, new_n: usize) -> Circuit<G> {
+        assert!(
+            new_n >= self.n,
+            "Can't pad circuit on {} wires to {new_n} wires",
+            self.n
+        );
+        Circuit {
+            annotations: self.annotations.clone(),
+            ..Circuit::new(self.gates.clone(), new_n)
+        }
+    }
+
     pub fn from_top_sorted_nodes(
         top_sorted_nodes: &[NodeIndex],
         skeleton_graph: &Graph<usize, usize>,
@@ -184,7 +373,12 @@ where
 
 impl<G> Circuit<G> {
     pub fn new(gates: Vec<G>, n: usize) -> Self {
-        Circuit { gates, n }
+        Circuit {
+            gates,
+            n,
+            id_index: OnceLock::new(),
+            annotations: BTreeMap::new(),
+        }
     }
 
     pub fn n(&self) -> usize {
@@ -195,47 +389,373 @@ impl<G> Circuit<G> {
         self.gates.as_ref()
     }
 
+    /// No. of gates in the circuit
+    ///
+    /// ```
+    /// use rust::circuit::{BaseGate, Circuit};
+    ///
+    /// let empty = Circuit::<BaseGate<2, u8>>::new(vec![], 3);
+    /// assert!(empty.is_empty());
+    ///
+    /// let circuit = Circuit::new(vec![BaseGate::<2, u8>::new(0, 0, [1, 2], 1)], 3);
+    /// assert_eq!(circuit.num_gates(), 1);
+    /// assert!(!circuit.is_empty());
+    /// ```
+    pub fn num_gates(&self) -> usize {
+        self.gates.len()
+    }
+
+    /// Returns true if the circuit has no gates
+    pub fn is_empty(&self) -> bool {
+        self.gates.is_empty()
+    }
+
+    /// Annotation of gate with id `id`, if any
+    pub fn annotation(&self, id: usize) -> Option<&str> {
+        self.annotations.get(&id).map(String::as_str)
+    }
+
+    /// Annotations of gates keyed by gate id
+    pub fn annotations(&self) -> &BTreeMap<usize, String> {
+        &self.annotations
+    }
+
     pub fn gates_mut(&mut self) -> &mut [G] {
+        // Gate ids may change
+        self.id_index.take();
         self.gates.as_mut()
     }
+
+    /// Iterates over gates in the order they are applied
+    ///
+    /// ```
+    /// use rust::circuit::{BaseGate, Circuit, Gate};
+    ///
+    /// let circuit = Circuit::new(
+    ///     vec![
+    ///         BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+    ///         BaseGate::<2, u8>::new(1, 1, [0, 2], 6),
+    ///     ],
+    ///     3,
+    /// );
+    /// let ids: Vec<_> = circuit.iter_gates().map(|g| g.id()).collect();
+    /// assert_eq!(ids, vec![0, 1]);
+    /// ```
+    pub fn iter_gates(&self) -> std::slice::Iter<'_, G> {
+        self.gates.iter()
+    }
 }
 
+impl<G: Gate> Circuit<G> {
+    /// Returns gate with id `id`. Index from gate ids to gates is built on the first call.
+    ///
+    /// ```
+    /// use rust::circuit::{BaseGate, Circuit, Gate};
+    ///
+    /// let circuit = Circuit::new(
+    ///     vec![
+    ///         BaseGate::<2, u8>::new(7, 0, [1, 2], 1),
+    ///         BaseGate::<2, u8>::new(3, 1, [0, 2], 6),
+    ///     ],
+    ///     3,
+    /// );
+    /// assert_eq!(circuit.gate_by_id(3).unwrap().target(), 1);
+    /// assert!(circuit.gate_by_id(0).is_none());
+    /// ```
+    pub fn gate_by_id(&self, id: usize) -> Option<&G> {
+        let id_index = self.id_index.get_or_init(|| {
+            self.gates
+                .iter()
+                .enumerate()
+                .map(|(index, g)| (g.id(), index))
+                .collect()
+        });
+        id_index.get(&id).map(|index| &self.gates[*index])
+    }
+
+    /// Annotates gate with id `id`, replacing its previous annotation. Annotations tag gates to
+    /// trace them through obfuscation and don't affect how the circuit runs.
+    ///
+    /// Panics if the circuit has no gate with id `id`.
+    pub fn annotate(&mut self, id: usize, annotation: impl Into<String>) {
+        assert!(
+            self.gate_by_id(id).is_some(),
+            "Gate {id} is not in the circuit"
+        );
+        self.annotations.insert(id, annotation.into());
+    }
+
+    /// Returns the circuit with annotations of `other`'s gates that are also in the circuit, that is
+    /// gates with the same id. Carries annotations over to a circuit rebuilt from `other`, for
+    /// example with [`Circuit::from_top_sorted_nodes`] after local mixing. Annotations of gates
+    /// that were removed are dropped.
+    ///
+    /// ```
+    /// use rust::circuit::{BaseGate, Circuit};
+    ///
+    /// let mut circuit = Circuit::new(
+    ///     vec![
+    ///         BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+    ///         BaseGate::<2, u8>::new(1, 1, [0, 2], 6),
+    ///     ],
+    ///     3,
+    /// );
+    /// circuit.annotate(0, "first");
+    /// circuit.annotate(1, "second");
+    ///
+    /// let rebuilt = Circuit::new(vec![BaseGate::<2, u8>::new(1, 1, [0, 2], 6)], 3)
+    ///     .with_annotations_from(&circuit);
+    /// assert_eq!(rebuilt.annotation(0), None);
+    /// assert_eq!(rebuilt.annotation(1), Some("second"));
+    /// ```
+    pub fn with_annotations_from(mut self, other: &Circuit<G>) -> Self {
+        for (id, annotation) in &other.annotations {
+            if self.gate_by_id(*id).is_some() {
+                self.annotations.insert(*id, annotation.clone());
+            }
+        }
+        self
+    }
+}
+
+impl<G: Serialize> Circuit<G> {
+    /// SHA-256 hash of the bincode serialized circuit. Unlike [`Circuit::canonical_hash`], gate ids
+    /// are hashed, hence it identifies the exact circuit, for example a job's circuit across
+    /// stores, whereas `canonical_hash` compares circuits by their gates only.
+    pub fn digest(&self) -> [u8; 32] {
+        Sha256::digest(bincode::serialize(self).unwrap()).into()
+    }
+
+    /// Hex encoded [`Self::digest`]
+    pub fn digest_hex(&self) -> String {
+        hex::encode(self.digest())
+    }
+}
+
+impl<const N: usize, D: PartialEq + Copy> Circuit<BaseGate<N, D>> {
+    /// Iterates over gates that have `wire` as target or as one of the controls, in the order they
+    /// are applied
+    ///
+    /// ```
+    /// use rust::circuit::{BaseGate, Circuit, Gate};
+    ///
+    /// let circuit = Circuit::new(
+    ///     vec![
+    ///         BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+    ///         BaseGate::<2, u8>::new(1, 3, [1, 2], 6),
+    ///         BaseGate::<2, u8>::new(2, 2, [0, 3], 6),
+    ///     ],
+    ///     4,
+    /// );
+    /// let ids: Vec<_> = circuit.gates_on_wire(0).map(|g| g.id()).collect();
+    /// assert_eq!(ids, vec![0, 2]);
+    /// ```
+    pub fn gates_on_wire(&self, wire: D) -> impl Iterator<Item = &BaseGate<N, D>> + '_ {
+        self.gates
+            .iter()
+            .filter(move |g| g.target == wire || g.controls.contains(&wire))
+    }
+
+    /// Returns no. of gates per control function code
+    pub fn control_func_histogram(&self) -> HashMap<u8, usize> {
+        let mut histogram = HashMap::default();
+        self.gates.iter().for_each(|g| {
+            *histogram.entry(g.control_func).or_default() += 1;
+        });
+        histogram
+    }
+
+    /// Returns no. of times each wire is used as target and as control, indexed by wire. A gate
+    /// with the same control twice counts once per control.
+    pub fn wire_usage(&self) -> Vec<(usize, usize)>
+    where
+        D: Into<usize>,
+    {
+        let mut usage = vec![(0, 0); self.n];
+        self.gates.iter().for_each(|g| {
+            usage[g.target.into()].0 += 1;
+            g.controls.iter().for_each(|c| usage[(*c).into()].1 += 1);
+        });
+        usage
+    }
+
+    /// Returns true if both circuits have the same no. of wires and the same gates in the same
+    /// order. Unlike `==`, gate ids are ignored.
+    pub fn eq_ignoring_ids(&self, other: &Self) -> bool {
+        self.n == other.n
+            && self.gates.len() == other.gates.len()
+            && izip!(&self.gates, &other.gates).all(|(a, b)| {
+                a.target == b.target && a.controls == b.controls && a.control_func == b.control_func
+            })
+    }
+
+    /// Returns inverse of the circuit. Every gate flips its target with a function of wires other
+    /// than the target, so is its own inverse, and the inverse is the gates in reverse order. Gate
+    /// ids and annotations are kept.
+    pub fn inverse(&self) -> Self {
+        Circuit {
+            annotations: self.annotations.clone(),
+            ..Circuit::new(self.gates.iter().rev().cloned().collect(), self.n)
+        }
+    }
+
+    /// Returns circuit that runs `self` and then `other`. Gates are given ids `0..` in order so
+    /// that ids stay unique.
+    ///
+    /// ```
+    /// use rust::circuit::{BaseGate, Circuit};
+    ///
+    /// let circuit = Circuit::new(vec![BaseGate::<2, u8>::new(7, 0, [1, 2], 1)], 3);
+    /// let composed = circuit.compose(&circuit.inverse());
+    /// assert_eq!(composed.gates(), [BaseGate::new(0, 0, [1, 2], 1), BaseGate::new(1, 0, [1, 2], 1)]);
+    /// ```
+    pub fn compose(&self, other: &Self) -> Self {
+        assert_eq!(self.n, other.n, "Circuits have different no. of wires");
+        let gates = chain![&self.gates, &other.gates]
+            .enumerate()
+            .map(|(id, g)| BaseGate {
+                id,
+                target: g.target,
+                controls: g.controls,
+                control_func: g.control_func,
+            })
+            .collect();
+        Circuit::new(gates, self.n)
+    }
+
+    /// Returns circuit on `n` wires with every wire `w` of every gate relabelled to `map[w]`. Gate
+    /// ids and annotations are kept.
+    ///
+    /// `map` must be injective over wires used by the gates and must map them to wires less than
+    /// `n`. Wires not used by any gate may be missing from `map`.
+    ///
+    /// ```
+    /// use rust::{circuit::{BaseGate, Circuit}, HashMap};
+    ///
+    /// let circuit = Circuit::new(vec![BaseGate::<2, u8>::new(0, 5, [2, 7], 1)], 8);
+    /// let map: HashMap<_, _> = [(2, 0), (5, 1), (7, 2)].into_iter().collect();
+    /// let remapped = circuit.remap_wires(&map, 3).unwrap();
+    /// assert_eq!(remapped.gates(), [BaseGate::new(0, 1, [0, 2], 1)]);
+    /// ```
+    pub fn remap_wires(
+        &self,
+        map: &HashMap<usize, usize>,
+        n: usize,
+    ) -> Result<Self, RemapWiresError>
+    where
+        D: Into<usize> + TryFrom<usize>,
+    {
+        // Wire each new wire is mapped from
+        let mut mapped_from = HashMap::default();
+        let mut remap = |wire: D| {
+            let wire = wire.into();
+            let new_wire = *map.get(&wire).ok_or(RemapWiresError::Unmapped { wire })?;
+            if *mapped_from.entry(new_wire).or_insert(wire) != wire {
+                return Err(RemapWiresError::NotInjective { new_wire });
+            }
+            if new_wire >= n {
+                return Err(RemapWiresError::OutOfBounds { wire, new_wire });
+            }
+            D::try_from(new_wire).map_err(|_| RemapWiresError::OutOfBounds { wire, new_wire })
+        };
+
+        let gates = self
+            .gates
+            .iter()
+            .map(|g| {
+                let mut controls = g.controls;
+                for control in controls.iter_mut() {
+                    *control = remap(*control)?;
+                }
+                Ok(BaseGate {
+                    id: g.id,
+                    target: remap(g.target)?,
+                    controls,
+                    control_func: g.control_func,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Circuit {
+            annotations: self.annotations.clone(),
+            ..Circuit::new(gates, n)
+        })
+    }
+}
+
+/// Reason why [`Circuit::remap_wires`] failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemapWiresError {
+    /// Wire used by a gate is not in the map
+    Unmapped { wire: usize },
+    /// More than one wire used by gates is mapped to `new_wire`
+    NotInjective { new_wire: usize },
+    /// Wire used by a gate is mapped to `new_wire` that is not less than the no. of wires
+    OutOfBounds { wire: usize, new_wire: usize },
+}
+
+/// Compact mode (`{}`) lists one gate per line as `id: target ^= FUNC(controls)`. Verbose mode
+/// (`{:#}`) additionally draws wires, with `O` marking the target and `I` marking the controls of
+/// each gate.
 impl<const N: usize, D> Display for Circuit<BaseGate<N, D>>
 where
     D: Into<usize> + Copy + PartialEq,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let id_width = self
+            .gates
+            .iter()
+            .map(|g| g.id().to_string().len())
+            .max()
+            .unwrap_or(1);
+
         writeln!(f)?;
-        writeln!(f, "{:-<15}", "")?;
-        for i in 0..self.n {
-            write!(f, "{:2}", i)?;
-        }
-        writeln!(f)?;
+        writeln!(f, "n = {}, gates = {}", self.n, self.num_gates())?;
 
-        writeln!(f, "{:-<15}", "")?;
+        if f.alternate() {
+            writeln!(f, "{:-<1$}", "", 2 * self.n + 1)?;
+            write!(f, "{:1}", "")?;
+            for i in 0..self.n {
+                write!(f, "{:2}", i % 100)?;
+            }
+            writeln!(f)?;
+            writeln!(f, "{:-<1$}", "", 2 * self.n + 1)?;
+        }
 
-        // Print 20 rows of values from 0 to 10
         for g in self.gates.iter() {
-            write!(f, "{:1}", "")?;
-            for j in 0..self.n {
-                let controls = g
-                    .controls()
-                    .iter()
-                    .map(|v| (*v).into())
-                    .collect::<Vec<usize>>();
-                if g.target().into() == j {
-                    write!(f, "{:2}", "O")?;
-                } else if controls.contains(&j) {
-                    write!(f, "{:2}", "I")?;
-                } else {
-                    write!(f, "{:2}", "x")?;
+            let controls = g
+                .controls()
+                .iter()
+                .map(|v| (*v).into())
+                .collect::<Vec<usize>>();
+
+            if f.alternate() {
+                write!(f, "{:1}", "")?;
+                for j in 0..self.n {
+                    if g.target().into() == j {
+                        write!(f, "{:>2}", "O")?;
+                    } else if controls.contains(&j) {
+                        write!(f, "{:>2}", "I")?;
+                    } else {
+                        write!(f, "{:>2}", "x")?;
+                    }
                 }
+                write!(f, "  ")?;
             }
-            writeln!(f)?;
+
+            write!(f, "{:>2$}: {} ^= ", g.id(), g.target().into(), id_width)?;
+            // Control functions are only decoded for gates with 2 controls
+            if N == 2 && g.control_func() < 16 {
+                write!(f, "{:?}", Base2GateControlFunc::from_u8(g.control_func()))?;
+            } else {
+                write!(f, "f{}", g.control_func())?;
+            }
+            writeln!(f, "({})", controls.iter().join(", "))?;
         }
 
-        writeln!(f, "{:-<15}", "")?;
-        writeln!(f)?;
+        if f.alternate() {
+            writeln!(f, "{:-<1$}", "", 2 * self.n + 1)?;
+        }
         Ok(())
     }
 }
@@ -262,7 +782,7 @@ pub enum Base2GateControlFunc {
 }
 
 impl Base2GateControlFunc {
-    const fn from_u8(v: u8) -> Self {
+    pub const fn from_u8(v: u8) -> Self {
         match v {
             0 => Self::F,
             1 => Self::AND,
@@ -284,7 +804,7 @@ impl Base2GateControlFunc {
         }
     }
 
-    const fn evaluate(&self, a: bool, b: bool) -> bool {
+    pub const fn evaluate(&self, a: bool, b: bool) -> bool {
         match self {
             Self::F => false,
             Self::AND => a & b,
@@ -304,6 +824,28 @@ impl Base2GateControlFunc {
             Self::T => true,
         }
     }
+
+    /// Same as [`Self::evaluate`] but evaluates 64 bit-sliced inputs at once
+    pub const fn evaluate_bitsliced(&self, a: u64, b: u64) -> u64 {
+        match self {
+            Self::F => 0,
+            Self::AND => a & b,
+            Self::AND_NB => a & (!b),
+            Self::A => a,
+            Self::AND_NA => (!a) & b,
+            Self::B => b,
+            Self::XOR => a ^ b,
+            Self::OR => a | b,
+            Self::NOR => !(a | b),
+            Self::EQUIV => (a & b) | ((!a) & (!b)),
+            Self::NB => !b,
+            Self::OR_NB => (!b) | a,
+            Self::NA => !a,
+            Self::OR_NA => (!a) | b,
+            Self::NAND => !(a & b),
+            Self::T => !0,
+        }
+    }
 }
 
 impl Circuit<BaseGate<2, u8>> {
@@ -352,19 +894,40 @@ impl Circuit<BaseGate<2, u8>> {
         t
     };
 
-    pub fn sample_multi_stage_cipher(n: usize, mut rng: impl RngCore) -> Self {
+    /// Default no. of stages of [`Self::sample_multi_stage_cipher`]
+    pub const DEFAULT_CIPHER_STAGES: usize = 3;
+
+    /// Default no. of layers per stage of [`Self::sample_multi_stage_cipher`] for `n` wires
+    pub fn default_cipher_layers_per_stage(n: usize) -> usize {
+        n.next_power_of_two().ilog2() as usize
+    }
+
+    /// Samples multi-stage cipher on `n` wires with `stages` stages. Stages alternate between
+    /// inflationary and non-inflationary, starting with inflationary. Each stage has
+    /// `layers_per_stage` layers of `(n + 2) / 3` triplets.
+    ///
+    /// Panics if `n < 3` as triplets need 3 distinct wires.
+    pub fn sample_multi_stage_cipher(
+        n: usize,
+        stages: usize,
+        layers_per_stage: usize,
+        mut rng: impl RngCore,
+    ) -> Self {
+        assert!(n >= 3, "n = {n} < 3; Too few wires for multi-stage cipher");
         let log_n = n.next_power_of_two().ilog2() as usize;
 
-        let stages = [true, false, true].map(|is_inflationary| {
+        let stages = (0..stages).map(|stage| {
+            let is_inflationary = stage % 2 == 0;
+
             let pi = {
                 let mut indices = (0..n as u8).collect_vec();
                 indices.shuffle(&mut rng);
                 indices
             };
 
-            let layers = (0..log_n)
+            let layers = (0..layers_per_stage)
                 .map(|l| {
-                    let step = 3usize.pow(l as u32);
+                    let step = 3usize.pow((l % log_n) as u32);
                     let chunk_step = 3 * step;
                     (0..n)
                         .step_by(chunk_step)
@@ -389,22 +952,32 @@ impl Circuit<BaseGate<2, u8>> {
                 .collect_vec();
 
             if is_inflationary {
-                izip!(
-                    pi_layers.into_iter().flatten(),
-                    Self::INFLATIONARY_GATES.choose_multiple(&mut rng, log_n * ((n + 2) / 3) * 3)
-                )
-                .flat_map(|(triplet, &(m, gates))| {
-                    gates.into_iter().take(m).map(
-                        move |(target, [control0, control1], control_func)| {
-                            (
-                                triplet[target as usize],
-                                [triplet[control0 as usize], triplet[control1 as usize]],
-                                control_func as u8,
-                            )
-                        },
-                    )
-                })
-                .collect_vec()
+                // `choose_multiple` returns at most `INFLATIONARY_GATES.len()` gates
+                let triplet_count = pi_layers
+                    .iter()
+                    .map(|triplets| triplets.len())
+                    .sum::<usize>();
+                let mut inflationary_gates = Vec::with_capacity(triplet_count);
+                while inflationary_gates.len() < triplet_count {
+                    inflationary_gates.extend(
+                        Self::INFLATIONARY_GATES
+                            .choose_multiple(&mut rng, triplet_count - inflationary_gates.len()),
+                    );
+                }
+
+                izip!(pi_layers.into_iter().flatten(), inflationary_gates)
+                    .flat_map(|(triplet, &(m, gates))| {
+                        gates.into_iter().take(m).map(
+                            move |(target, [control0, control1], control_func)| {
+                                (
+                                    triplet[target as usize],
+                                    [triplet[control0 as usize], triplet[control1 as usize]],
+                                    control_func as u8,
+                                )
+                            },
+                        )
+                    })
+                    .collect_vec()
             } else {
                 izip!(
                     pi_layers.into_iter().flatten(),
@@ -426,14 +999,481 @@ impl Circuit<BaseGate<2, u8>> {
             n as _,
         )
     }
+
+    /// Runs the circuit on 64 inputs at once. `wires[j]` holds `j`-th bit of each of the 64
+    /// inputs.
+    pub fn run_bitsliced(&self, wires: &mut [u64]) {
+        self.gates.iter().for_each(|g| {
+            let [c0, c1] = g.controls;
+            wires[g.target as usize] ^= Base2GateControlFunc::from_u8(g.control_func)
+                .evaluate_bitsliced(wires[c0 as usize], wires[c1 as usize]);
+        });
+    }
+
+    /// Returns structural dependency matrix of the circuit. Entry `[output][input]` is true iff
+    /// there is a chain of gates from wire `input` at input to wire `output` at output, where a gate
+    /// makes its target depend on the controls its control function reads.
+    ///
+    /// Entries can be true even if the output doesn't depend on the input, for example when gates
+    /// cancel each other, but never the other way around.
+    pub fn dependency_matrix(&self) -> Vec<Vec<bool>> {
+        let mut dependencies = (0..self.n)
+            .map(|wire| {
+                let mut dependency = BitVec::<usize>::repeat(false, self.n);
+                dependency.set(wire, true);
+                dependency
+            })
+            .collect_vec();
+        for gate in &self.gates {
+            let control_func = Base2GateControlFunc::from_u8(gate.control_func());
+            let reads_a = [false, true]
+                .into_iter()
+                .any(|b| control_func.evaluate(false, b) != control_func.evaluate(true, b));
+            let reads_b = [false, true]
+                .into_iter()
+                .any(|a| control_func.evaluate(a, false) != control_func.evaluate(a, true));
+            let [a, b] = gate.controls();
+            for (control, reads) in [(a, reads_a), (b, reads_b)] {
+                if reads {
+                    let control_dependency = dependencies[control as usize].clone();
+                    dependencies[gate.target() as usize] |= control_dependency;
+                }
+            }
+        }
+        dependencies
+            .into_iter()
+            .map(|dependency| dependency.into_iter().collect())
+            .collect()
+    }
+
+    /// SHA-256 hash of no. of wires and the gates in order. Gate ids are not hashed, hence circuits
+    /// with the same gates in the same order have the same hash. Refer to [`Circuit::digest`] for
+    /// a hash that includes gate ids.
+    pub fn canonical_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update((self.n as u64).to_le_bytes());
+        self.gates.iter().for_each(|g| {
+            hasher.update([g.target, g.controls[0], g.controls[1], g.control_func]);
+        });
+        hasher.finalize().into()
+    }
+
+    /// Synthesizes a circuit that computes permutation `perm` on `0..2^n`. Wire `j` holds the
+    /// `j`-th bit of the value.
+    ///
+    /// Permutation is decomposed into transpositions. Pairs of transpositions are conjugated to
+    /// `(0 1)(2 3)`, which flips wire 0 when wires `2..n` are all unset, and multi-controlled gates
+    /// are decomposed into gates with 2 controls using a borrowed wire.
+    ///
+    /// Gates with 2 controls only compute even permutations for `n >= 4`. Hence, returns `None` if
+    /// `perm` is odd and `n >= 4`. Panics if `n < 3` or `perm` is not a bijection on `0..2^n`.
+    pub fn synthesize_from_permutation(perm: &[usize], n: usize) -> Option<Self> {
+        assert!(
+            (3..=16).contains(&n),
+            "Can only synthesize for 3 <= n <= 16, not {n}"
+        );
+        assert_eq!(perm.len(), 1 << n, "Expected permutation on 2^{n} values");
+        assert!(
+            perm.iter().all(|v| *v < 1 << n) && perm.iter().all_unique(),
+            "Permutation is not a bijection"
+        );
+
+        // Transpositions in order of application
+        let mut transpositions = vec![];
+        let mut visited = vec![false; perm.len()];
+        for start in 0..perm.len() {
+            if visited[start] {
+                continue;
+            }
+            let mut cycle = vec![start];
+            visited[start] = true;
+            let mut next = perm[start];
+            while next != start {
+                visited[next] = true;
+                cycle.push(next);
+                next = perm[next];
+            }
+            // Cycle x_1 -> x_2 -> ... -> x_k equals (x_1 x_2)(x_2 x_3)...(x_{k-1} x_k) where the
+            // right most transposition is applied first.
+            transpositions.extend(
+                cycle
+                    .into_iter()
+                    .tuple_windows::<(_, _)>()
+                    .collect_vec()
+                    .into_iter()
+                    .rev(),
+            );
+        }
+
+        let gates = if n == 3 {
+            transpositions
+                .into_iter()
+                .flat_map(|(a, b)| transposition_gates(&[a, b], n))
+                .collect_vec()
+        } else {
+            if transpositions.len() % 2 == 1 {
+                return None;
+            }
+            transpositions
+                .into_iter()
+                .tuples()
+                .flat_map(|((a, b), (c, d))| {
+                    if [a, b, c, d].iter().all_unique() {
+                        transposition_gates(&[a, b, c, d], n)
+                    } else {
+                        // (a b) then (c d) equals (a b)(e f) then (e f)(c d) for any (e f) disjoint
+                        // with both
+                        let mut others = (0..).filter(|v| ![a, b, c, d].contains(v));
+                        let (e, f) = (others.next().unwrap(), others.next().unwrap());
+                        chain![
+                            transposition_gates(&[a, b, e, f], n),
+                            transposition_gates(&[e, f, c, d], n)
+                        ]
+                        .collect_vec()
+                    }
+                })
+                .collect_vec()
+        };
+
+        Some(Self::new(
+            izip!(0.., gates)
+                .map(|(id, (target, controls, control_func))| {
+                    BaseGate::new(id, target, controls, control_func)
+                })
+                .collect(),
+            n,
+        ))
+    }
+
+    /// Parses a circuit from an OpenQASM 2.0 program with gates `x`, `cx`, and `ccx` on a single
+    /// `qreg`, for example a circuit transpiled with Qiskit. Gate ids are set to gate indices.
+    ///
+    /// `ccx` maps to a gate with control function `AND`, `cx` to a gate with control function `A`
+    /// and one unused control, and `x` to a gate with control function `T` and two unused
+    /// controls. Hence the register must have at least 3 qubits. `OPENQASM`, `include`, `creg`,
+    /// and `barrier` statements are ignored.
+    ///
+    /// Unused controls don't change what a gate computes but still make it collide with gates that
+    /// target them, which adds edges to the skeleton graph and shrinks convex subcircuits. They are
+    /// therefore taken from wires that no gate of the program targets and only then from the
+    /// smallest other wires.
+    pub fn from_qasm(s: &str) -> Result<Self, QasmParseError> {
+        // Name and size of the register
+        let mut qreg: Option<(&str, usize)> = None;
+        // Target and controls of each gate
+        let mut gates: Vec<(u8, Vec<u8>)> = vec![];
+        for (line, statement) in s.lines().enumerate().flat_map(|(index, line)| {
+            let line = line.split("//").next().unwrap();
+            line.split(';')
+                .map(str::trim)
+                .filter(|statement| !statement.is_empty())
+                .map(move |statement| (index + 1, statement))
+        }) {
+            let err = |kind| QasmParseError { line, kind };
+            let (op, operands) = statement
+                .split_once(char::is_whitespace)
+                .map_or((statement, ""), |(op, operands)| (op, operands.trim()));
+            match op {
+                "OPENQASM" | "include" | "creg" | "barrier" => {}
+                "qreg" => {
+                    if qreg.is_some() {
+                        return Err(err(QasmParseErrorKind::MultipleRegisters));
+                    }
+                    let (name, size) = parse_qasm_qubit(operands)
+                        .ok_or_else(|| err(QasmParseErrorKind::InvalidOperand(operands.into())))?;
+                    if !(3..=u8::MAX as usize + 1).contains(&size) {
+                        return Err(err(QasmParseErrorKind::InvalidRegisterSize(size)));
+                    }
+                    qreg = Some((name, size));
+                }
+                "x" | "cx" | "ccx" => {
+                    let (name, n) = qreg.ok_or(err(QasmParseErrorKind::MissingRegister))?;
+                    let qubits = operands
+                        .split(',')
+                        .map(|operand| match parse_qasm_qubit(operand.trim()) {
+                            Some((operand_name, qubit)) if operand_name == name && qubit < n => {
+                                Ok(qubit as u8)
+                            }
+                            _ => Err(err(QasmParseErrorKind::InvalidOperand(operand.into()))),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let expected = match op {
+                        "x" => 1,
+                        "cx" => 2,
+                        _ => 3,
+                    };
+                    if qubits.len() != expected {
+                        return Err(err(QasmParseErrorKind::OperandCount {
+                            expected,
+                            found: qubits.len(),
+                        }));
+                    }
+                    if !qubits.iter().all_unique() {
+                        return Err(err(QasmParseErrorKind::RepeatedQubit));
+                    }
+
+                    let (target, controls) = qubits.split_last().unwrap();
+                    gates.push((*target, controls.to_vec()));
+                }
+                "c3x" | "c4x" | "mcx" | "mcx_gray" | "mcx_recursive" | "mcx_vchain" => {
+                    return Err(err(QasmParseErrorKind::TooManyControls));
+                }
+                "measure" | "reset" => {
+                    return Err(err(QasmParseErrorKind::Measurement));
+                }
+                _ => {
+                    return Err(err(QasmParseErrorKind::UnsupportedStatement(
+                        statement.into(),
+                    )))
+                }
+            }
+        }
+
+        let (_, n) = qreg.ok_or(QasmParseError {
+            line: s.lines().count(),
+            kind: QasmParseErrorKind::MissingRegister,
+        })?;
+
+        let (untargeted, targeted): (Vec<_>, Vec<_>) =
+            (0..n as u8).partition(|wire| gates.iter().all(|(target, _)| target != wire));
+        let gates = izip!(0.., gates)
+            .map(|(id, (target, controls))| {
+                let mut unused = chain![&untargeted, &targeted]
+                    .copied()
+                    .filter(|wire| *wire != target && !controls.contains(wire));
+                let (controls, control_func) = match *controls {
+                    [] => (
+                        [unused.next().unwrap(), unused.next().unwrap()],
+                        Base2GateControlFunc::T,
+                    ),
+                    [control] => ([control, unused.next().unwrap()], Base2GateControlFunc::A),
+                    [control0, control1] => ([control0, control1], Base2GateControlFunc::AND),
+                    _ => unreachable!(),
+                };
+                BaseGate::new(id, target, controls, control_func as u8)
+            })
+            .collect();
+        Ok(Self::new(gates, n))
+    }
+}
+
+/// Parses QASM operand `name[index]`
+fn parse_qasm_qubit(operand: &str) -> Option<(&str, usize)> {
+    let (name, index) = operand.strip_suffix(']')?.split_once('[')?;
+    Some((name.trim(), index.trim().parse().ok()?))
+}
+
+/// Reason why [`Circuit::from_qasm`] rejected a program at line `line`, counted from 1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QasmParseError {
+    pub line: usize,
+    pub kind: QasmParseErrorKind,
+}
+
+impl Display for QasmParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            QasmParseErrorKind::UnsupportedStatement(statement) => {
+                write!(f, "unsupported statement `{statement}`")
+            }
+            QasmParseErrorKind::TooManyControls => write!(f, "gate has more than 2 controls"),
+            QasmParseErrorKind::Measurement => {
+                write!(f, "measurements and resets are not supported")
+            }
+            QasmParseErrorKind::MultipleRegisters => write!(f, "more than one `qreg` declared"),
+            QasmParseErrorKind::MissingRegister => write!(
+                f,
+                "gate used before `qreg` declaration or no `qreg` declared"
+            ),
+            QasmParseErrorKind::InvalidRegisterSize(size) => write!(
+                f,
+                "`qreg` has {size} qubits, expected between 3 and {}",
+                u8::MAX as usize + 1
+            ),
+            QasmParseErrorKind::InvalidOperand(operand) => {
+                write!(f, "`{operand}` is not a qubit of the register")
+            }
+            QasmParseErrorKind::OperandCount { expected, found } => {
+                write!(f, "gate has {found} operands, expected {expected}")
+            }
+            QasmParseErrorKind::RepeatedQubit => write!(f, "gate uses a qubit more than once"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QasmParseErrorKind {
+    /// Statement is not supported
+    UnsupportedStatement(String),
+    /// Gate has more than 2 controls
+    TooManyControls,
+    /// Program measures or resets qubits
+    Measurement,
+    /// Program declares more than one `qreg`
+    MultipleRegisters,
+    /// Gate is used before the `qreg` declaration or program has no `qreg`
+    MissingRegister,
+    /// `qreg` has fewer than 3 or more than 256 qubits
+    InvalidRegisterSize(usize),
+    /// Operand is not a qubit of the register
+    InvalidOperand(String),
+    /// Gate has a wrong no. of operands
+    OperandCount { expected: usize, found: usize },
+    /// Gate uses a qubit more than once
+    RepeatedQubit,
+}
+
+type GateDescriptor = (u8, [u8; 2], u8);
+
+/// Applies gate `(target, controls, control_func)` to value `v`
+fn apply_gate((target, [c0, c1], control_func): GateDescriptor, v: usize) -> usize {
+    let a = (v >> c0) & 1 == 1;
+    let b = (v >> c1) & 1 == 1;
+    v ^ ((Base2GateControlFunc::from_u8(control_func).evaluate(a, b) as usize) << target)
+}
+
+/// Returns wires in `0..n` other than `wires`
+fn other_wires(wires: &[u8], n: usize) -> impl Iterator<Item = u8> + '_ {
+    (0..n as u8).filter(|w| !wires.contains(w))
+}
+
+/// Gates that flip `target` iff every control wire `w` equals its polarity `p` for `(w, p)` in
+/// `controls`.
+///
+/// With more than 2 controls, the gate is split into two halves `C_1` and `C_2` and a borrowed
+/// wire `a` not in controls and target: `t ^= C_2 & a; a ^= C_1; t ^= C_2 & a; a ^= C_1`, which
+/// leaves `a` unchanged and sets `t ^= C_1 & C_2`.
+fn multi_controlled_gates(controls: &[(u8, bool)], target: u8, n: usize) -> Vec<GateDescriptor> {
+    use Base2GateControlFunc::*;
+
+    match controls {
+        [] => {
+            let (c0, c1) = other_wires(&[target], n).next_tuple().unwrap();
+            vec![(target, [c0, c1], T as u8)]
+        }
+        [(c, p)] => {
+            let other = other_wires(&[target, *c], n).next().unwrap();
+            vec![(target, [*c, other], if *p { A } else { NA } as u8)]
+        }
+        [(c0, p0), (c1, p1)] => {
+            let control_func = match (p0, p1) {
+                (true, true) => AND,
+                (true, false) => AND_NB,
+                (false, true) => AND_NA,
+                (false, false) => NOR,
+            };
+            vec![(target, [*c0, *c1], control_func as u8)]
+        }
+        _ => {
+            let wires = chain![controls.iter().map(|(w, _)| *w), [target]].collect_vec();
+            let borrowed = other_wires(&wires, n).next().unwrap();
+            let (c1, c2) = controls.split_at(controls.len().div_ceil(2));
+            let g1 = multi_controlled_gates(
+                &chain![c2.iter().copied(), [(borrowed, true)]].collect_vec(),
+                target,
+                n,
+            );
+            let g2 = multi_controlled_gates(c1, borrowed, n);
+            chain![g1.clone(), g2.clone(), g1, g2].collect()
+        }
+    }
+}
+
+/// Gates that compute transposition `(points[0] points[1])` if `points` has 2 values or the
+/// product of disjoint transpositions `(points[0] points[1])(points[2] points[3])` if `points` has
+/// 4 values.
+///
+/// Finds gates `P` that map `points` to `0, 1, 2, 3` respectively, applies `(0 1)` or `(0 1)(2 3)`
+/// and then `P^{-1}`. Every gate is an involution, hence `P^{-1}` is `P` in reverse order.
+fn transposition_gates(points: &[usize], n: usize) -> Vec<GateDescriptor> {
+    assert!(points.len() == 2 || points.len() == 4);
+
+    let not = |t: u8| {
+        let (c0, c1) = other_wires(&[t], n).next_tuple().unwrap();
+        (t, [c0, c1], Base2GateControlFunc::T as u8)
+    };
+    let cnot = |c: u8, t: u8| {
+        let other = other_wires(&[c, t], n).next().unwrap();
+        (t, [c, other], Base2GateControlFunc::A as u8)
+    };
+    let swap = |i: u8, j: u8| [cnot(i, j), cnot(j, i), cnot(i, j)];
+
+    let mut conjugation = vec![];
+    let mut points = points.to_vec();
+    let mut push = |gates: &[GateDescriptor], points: &mut Vec<usize>| {
+        for gate in gates {
+            points.iter_mut().for_each(|v| *v = apply_gate(*gate, *v));
+            conjugation.push(*gate);
+        }
+    };
+    let set_bits = |v: usize| (0..n as u8).filter(move |j| (v >> j) & 1 == 1);
+
+    // Map points[0] to 0
+    for j in set_bits(points[0]).collect_vec() {
+        push(&[not(j)], &mut points);
+    }
+
+    // Map points[1] to 1 while fixing 0. CNOTs fix 0.
+    let i = set_bits(points[1]).next().unwrap();
+    for j in set_bits(points[1]).filter(|j| *j != i).collect_vec() {
+        push(&[cnot(i, j)], &mut points);
+    }
+    if i != 0 {
+        push(&swap(i, 0), &mut points);
+    }
+
+    if points.len() == 4 {
+        // Map points[2] to 2 while fixing 0 and 1. CNOTs controlled on wire k > 0 fix both.
+        let k = set_bits(points[2]).find(|k| *k > 0).unwrap();
+        for j in set_bits(points[2]).filter(|j| *j != k).collect_vec() {
+            push(&[cnot(k, j)], &mut points);
+        }
+        if k != 1 {
+            push(&swap(k, 1), &mut points);
+        }
+
+        // Map points[3] to 3 while fixing 0, 1 and 2. If points[3] is not 3, it must have some
+        // wire k > 1 set.
+        if points[3] != 3 {
+            let k = set_bits(points[3]).find(|k| *k > 1).unwrap();
+            for j in set_bits(points[3]).filter(|j| *j != k).collect_vec() {
+                push(&[cnot(k, j)], &mut points);
+            }
+            push(&[cnot(k, 0), cnot(k, 1)], &mut points);
+            push(&[(k, [0, 1], Base2GateControlFunc::AND as u8)], &mut points);
+        }
+    }
+
+    assert_eq!(points, (0..points.len()).collect_vec());
+
+    // (0 1) flips wire 0 iff wires 1..n are unset. (0 1)(2 3) flips wire 0 iff wires 2..n are
+    // unset.
+    let free_wires = if points.len() == 2 { 1 } else { 2 };
+    let transposition = multi_controlled_gates(
+        &(free_wires..n as u8).map(|w| (w, false)).collect_vec(),
+        0,
+        n,
+    );
+
+    chain![
+        conjugation.iter().copied(),
+        transposition,
+        conjugation.iter().rev().copied()
+    ]
+    .collect()
 }
 
 #[cfg(test)]
 mod test {
-    use crate::circuit::{Base2GateControlFunc, BaseGate, Circuit};
+    use crate::{
+        check_exact_equivalence,
+        circuit::{Base2GateControlFunc, BaseGate, Circuit, Gate, RemapWiresError, TooManyWires},
+        sample_circuit_with_base_gate, HashMap,
+    };
     use core::array::from_fn;
     use itertools::{chain, izip, Itertools};
-    use rand::SeedableRng;
+    use rand::{Rng, SeedableRng};
     use rand_chacha::ChaCha8Rng;
     use std::collections::HashSet;
 
@@ -582,12 +1622,644 @@ mod test {
         assert!(table.is_subset(&HashSet::from(TABLE)));
     }
 
+    #[test]
+    fn run_bitsliced() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 8;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, n as u8, 1.0, &mut rng);
+
+        // Lane `k` holds input value `k`
+        let mut wires: [u64; 8] =
+            from_fn(|j| (0..64).fold(0, |lanes, k| lanes | ((k >> j) & 1) << k));
+        circuit.run_bitsliced(&mut wires);
+
+        for k in 0..64 {
+            let mut inputs = (0..n).map(|j| (k >> j) & 1 == 1).collect_vec();
+            circuit.run(&mut inputs);
+            let outputs = (0..n).map(|j| (wires[j] >> k) & 1 == 1).collect_vec();
+            assert_eq!(inputs, outputs);
+        }
+    }
+
+    #[test]
+    fn gate_accessors() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (mut circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, 8, 1.0, &mut rng);
+
+        for g in circuit.iter_gates() {
+            assert_eq!(circuit.gate_by_id(g.id()), Some(g));
+        }
+        assert!(circuit.gate_by_id(100).is_none());
+
+        for wire in 0..8 {
+            let gates_on_wire = circuit.gates_on_wire(wire).collect_vec();
+            let expected = circuit
+                .iter_gates()
+                .filter(|g| g.target() == wire || g.controls().contains(&wire))
+                .collect_vec();
+            assert_eq!(gates_on_wire, expected);
+        }
+
+        // Index is rebuilt after ids change
+        circuit.gates_mut().iter_mut().for_each(|g| g.id += 1000);
+        assert!(circuit.gate_by_id(0).is_none());
+        assert_eq!(circuit.gate_by_id(1000), circuit.gates().first());
+    }
+
+    #[test]
+    fn run_with_trace() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 8;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(50, n as u8, 1.0, &mut rng);
+
+        let inputs = (0..n).map(|_| rng.gen_bool(0.5)).collect_vec();
+        let mut traced_inputs = inputs.clone();
+        let trace = circuit.run_with_trace(&mut traced_inputs);
+        assert_eq!(trace.len(), circuit.gates().len());
+
+        // State after gate `i` is output of the first `i + 1` gates
+        let mut outputs = inputs.clone();
+        for (g, state) in izip!(circuit.gates(), trace.iter()) {
+            g.run(&mut outputs);
+            assert_eq!(&outputs, state);
+        }
+        assert_eq!(traced_inputs, outputs);
+    }
+
+    #[test]
+    fn run_into() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 8;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(50, n as u8, 1.0, &mut rng);
+
+        // Buffer is reused, and stale contents of different length must not leak into outputs
+        let mut dst = vec![true; 2 * n];
+        for _ in 0..10 {
+            let inputs = (0..n).map(|_| rng.gen_bool(0.5)).collect_vec();
+            circuit.run_into(&inputs, &mut dst);
+
+            let mut outputs = inputs.clone();
+            circuit.run(&mut outputs);
+            assert_eq!(dst, outputs);
+        }
+    }
+
+    #[test]
+    fn eq_ignoring_ids() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, 8, 1.0, &mut rng);
+
+        let relabelled = Circuit::new(
+            circuit
+                .gates()
+                .iter()
+                .map(|g| BaseGate::new(g.id() + 1000, g.target, g.controls, g.control_func))
+                .collect(),
+            circuit.n(),
+        );
+        assert_ne!(circuit, relabelled);
+        assert!(circuit.eq_ignoring_ids(&relabelled));
+
+        let mut gates = circuit.gates().to_vec();
+        gates[0].control_func ^= 1;
+        assert!(!circuit.eq_ignoring_ids(&Circuit::new(gates, circuit.n())));
+    }
+
+    #[test]
+    fn inverse_and_compose() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, 8, 1.0, &mut rng);
+
+        let identity = Circuit::<BaseGate<2, u8>>::new(vec![], 8)
+            .to_matrix()
+            .unwrap();
+        let composed = circuit.compose(&circuit.inverse());
+        assert_eq!(composed.num_gates(), 200);
+        assert!(composed.gates().iter().map(|g| g.id()).eq(0..200));
+        assert_eq!(composed.to_matrix().unwrap(), identity);
+        assert_eq!(
+            circuit.inverse().compose(&circuit).to_matrix().unwrap(),
+            identity
+        );
+
+        // Composition with inverse of another circuit is the identity only if both circuits
+        // compute the same permutation
+        let (other, _) = sample_circuit_with_base_gate::<2, u8, _>(100, 8, 1.0, &mut rng);
+        assert_eq!(
+            circuit.compose(&other.inverse()).to_matrix().unwrap() == identity,
+            circuit.to_matrix().unwrap() == other.to_matrix().unwrap()
+        );
+    }
+
+    #[test]
+    fn display() {
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(9, 0, [1, 2], 1),
+                BaseGate::<2, u8>::new(10, 2, [0, 1], 6),
+            ],
+            3,
+        );
+
+        assert_eq!(
+            format!("{}", circuit),
+            "\nn = 3, gates = 2\n 9: 0 ^= AND(1, 2)\n10: 2 ^= XOR(0, 1)\n"
+        );
+        assert_eq!(
+            format!("{:#}", circuit),
+            "\nn = 3, gates = 2\n-------\n  0 1 2\n-------\n  O I I   9: 0 ^= AND(1, 2)\n  I I O  10: 2 ^= XOR(0, 1)\n-------\n"
+        );
+    }
+
+    #[test]
+    fn is_bijection() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(50, 8, 1.0, &mut rng);
+        assert_eq!(circuit.is_bijection(), Ok(true));
+        assert_eq!(
+            Circuit::<BaseGate<2, u8>>::new(vec![], 4).is_bijection(),
+            Ok(true)
+        );
+
+        // Gate with target as one of the controls is not reversible
+        let circuit = Circuit::new(vec![BaseGate::<2, u8>::new(0, 0, [0, 1], 1)], 2);
+        assert_eq!(circuit.is_bijection(), Ok(false));
+
+        let circuit = Circuit::<BaseGate<2, u8>>::new(vec![], 21);
+        assert_eq!(
+            circuit.is_bijection(),
+            Err(TooManyWires { n: 21, max_n: 20 })
+        );
+    }
+
+    #[test]
+    fn to_matrix() {
+        // Product of boolean matrices
+        fn mul(a: &[Vec<bool>], b: &[Vec<bool>]) -> Vec<Vec<bool>> {
+            a.iter()
+                .map(|row| {
+                    (0..b[0].len())
+                        .map(|j| izip!(row, b).any(|(a_ik, b_k)| *a_ik && b_k[j]))
+                        .collect()
+                })
+                .collect()
+        }
+
+        let mut rng = ChaCha8Rng::from_entropy();
+        for n in [3, 6] {
+            let (a, _) = sample_circuit_with_base_gate::<2, u8, _>(20, n, 1.0, &mut rng);
+            let (b, _) = sample_circuit_with_base_gate::<2, u8, _>(20, n, 1.0, &mut rng);
+            let a_matrix = a.to_matrix().unwrap();
+            for row in a_matrix.iter() {
+                assert_eq!(row.iter().filter(|entry| **entry).count(), 1);
+            }
+
+            let composed =
+                Circuit::new(chain![a.gates(), b.gates()].cloned().collect(), n as usize);
+            assert_eq!(
+                composed.to_matrix().unwrap(),
+                mul(&a_matrix, &b.to_matrix().unwrap())
+            );
+        }
+
+        let circuit = Circuit::<BaseGate<2, u8>>::new(vec![], 13);
+        assert_eq!(circuit.to_matrix(), Err(TooManyWires { n: 13, max_n: 12 }));
+    }
+
+    #[test]
+    fn dependency_matrix() {
+        // wire 0 ^= wire 1 & wire 2, wire 2 ^= wire 0, wire 1 ^= false
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], Base2GateControlFunc::AND as u8),
+                BaseGate::<2, u8>::new(1, 2, [0, 1], Base2GateControlFunc::A as u8),
+                BaseGate::<2, u8>::new(2, 1, [0, 2], Base2GateControlFunc::F as u8),
+            ],
+            3,
+        );
+        assert_eq!(
+            circuit.dependency_matrix(),
+            vec![
+                vec![true, true, true],
+                vec![false, true, false],
+                vec![true, true, true]
+            ]
+        );
+
+        // Flipping an input wire only changes output wires that depend on it
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(10, 8, 1.0, &mut rng);
+        let matrix = circuit.dependency_matrix();
+        for _ in 0..100 {
+            let inputs = (0..8).map(|_| rng.gen_bool(0.5)).collect_vec();
+            let mut outputs = inputs.clone();
+            circuit.run(&mut outputs);
+            for input in 0..8 {
+                let mut flipped_outputs = inputs.clone();
+                flipped_outputs[input] = !flipped_outputs[input];
+                circuit.run(&mut flipped_outputs);
+                for output in 0..8 {
+                    if outputs[output] != flipped_outputs[output] {
+                        assert!(matrix[output][input]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn project() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(20, 5, 1.0, &mut rng);
+
+        // Keeping all wires gives the permutation of the circuit
+        let permutation = (0..1usize << 5)
+            .map(|value| {
+                let mut inputs = (0..5).map(|j| (value >> j) & 1 == 1).collect_vec();
+                circuit.run(&mut inputs);
+                inputs
+                    .iter()
+                    .rev()
+                    .fold(0, |output, bit| (output << 1) | *bit as usize)
+            })
+            .collect_vec();
+        assert_eq!(
+            circuit.project(&[0, 1, 2, 3, 4], &HashMap::default()),
+            permutation
+        );
+
+        // Projected table is the permutation restricted to inputs with pinned wires 1 and 3
+        let keep = [4, 0, 2];
+        let fixed: HashMap<_, _> = [(1, true), (3, false)].into_iter().collect();
+        let table = circuit.project(&keep, &fixed);
+        assert_eq!(table.len(), 8);
+        for (value, output) in table.into_iter().enumerate() {
+            let mut input = 1 << 1;
+            for (j, wire) in keep.iter().enumerate() {
+                input |= ((value >> j) & 1) << wire;
+            }
+            let expected_output = keep.iter().enumerate().fold(0, |o, (j, wire)| {
+                o | ((permutation[input] >> wire) & 1) << j
+            });
+            assert_eq!(output, expected_output);
+        }
+    }
+
+    #[test]
+    fn pad_to() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(20, 4, 1.0, &mut rng);
+        let padded = circuit.pad_to(6);
+        assert_eq!(padded.n(), 6);
+        assert_eq!(padded.gates(), circuit.gates());
+
+        for _ in 0..100 {
+            let inputs = (0..6).map(|_| rng.gen_bool(0.5)).collect_vec();
+            let mut outputs = inputs.clone();
+            padded.run(&mut outputs);
+            let mut expected_outputs = inputs[..4].to_vec();
+            circuit.run(&mut expected_outputs);
+            assert_eq!(outputs[..4], expected_outputs);
+            assert_eq!(outputs[4..], inputs[4..]);
+        }
+
+        assert_eq!(circuit.pad_to(4), circuit);
+    }
+
+    #[test]
+    fn remap_wires() {
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(3, 4, [1, 6], 1),
+                BaseGate::<2, u8>::new(7, 1, [6, 2], 6),
+            ],
+            8,
+        );
+        let map: HashMap<_, _> = [(1, 0), (2, 3), (4, 1), (6, 2)].into_iter().collect();
+
+        let remapped = circuit.remap_wires(&map, 4).unwrap();
+        assert_eq!(remapped.n(), 4);
+        assert_eq!(
+            remapped.gates(),
+            [
+                BaseGate::new(3, 1, [0, 2], 1),
+                BaseGate::new(7, 0, [2, 3], 6)
+            ]
+        );
+
+        // Remapping back with the inverse map gives the original circuit
+        let inverse = map.iter().map(|(old, new)| (*new, *old)).collect();
+        assert_eq!(remapped.remap_wires(&inverse, 8).unwrap(), circuit);
+
+        let mut map = map;
+        map.remove(&2);
+        assert_eq!(
+            circuit.remap_wires(&map, 4),
+            Err(RemapWiresError::Unmapped { wire: 2 })
+        );
+        map.insert(2, 0);
+        assert_eq!(
+            circuit.remap_wires(&map, 4),
+            Err(RemapWiresError::NotInjective { new_wire: 0 })
+        );
+        map.insert(2, 3);
+        assert_eq!(
+            circuit.remap_wires(&map, 3),
+            Err(RemapWiresError::OutOfBounds {
+                wire: 2,
+                new_wire: 3
+            })
+        );
+        map.insert(2, 256);
+        assert_eq!(
+            circuit.remap_wires(&map, 300),
+            Err(RemapWiresError::OutOfBounds {
+                wire: 2,
+                new_wire: 256
+            })
+        );
+    }
+
+    #[test]
+    fn control_func_histogram() {
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], Base2GateControlFunc::AND as u8),
+                BaseGate::<2, u8>::new(1, 1, [0, 2], Base2GateControlFunc::XOR as u8),
+                BaseGate::<2, u8>::new(2, 2, [0, 1], Base2GateControlFunc::AND as u8),
+                BaseGate::<2, u8>::new(3, 0, [1, 2], Base2GateControlFunc::T as u8),
+                BaseGate::<2, u8>::new(4, 1, [0, 2], Base2GateControlFunc::AND as u8),
+            ],
+            3,
+        );
+
+        let histogram = circuit.control_func_histogram();
+        assert_eq!(histogram.len(), 3);
+        assert_eq!(histogram[&(Base2GateControlFunc::AND as u8)], 3);
+        assert_eq!(histogram[&(Base2GateControlFunc::XOR as u8)], 1);
+        assert_eq!(histogram[&(Base2GateControlFunc::T as u8)], 1);
+    }
+
+    #[test]
+    fn wire_usage() {
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], Base2GateControlFunc::AND as u8),
+                BaseGate::<2, u8>::new(1, 1, [0, 2], Base2GateControlFunc::XOR as u8),
+                BaseGate::<2, u8>::new(2, 0, [1, 2], Base2GateControlFunc::T as u8),
+                BaseGate::<2, u8>::new(3, 2, [0, 1], Base2GateControlFunc::AND as u8),
+            ],
+            4,
+        );
+
+        // Wire 3 is unused
+        assert_eq!(circuit.wire_usage(), vec![(2, 2), (1, 3), (1, 3), (0, 0)]);
+        assert_eq!(
+            Circuit::<BaseGate<2, u8>>::new(vec![], 2).wire_usage(),
+            vec![(0, 0); 2]
+        );
+    }
+
+    #[test]
+    fn canonical_hash() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, 8, 1.0, &mut rng);
+
+        let relabelled = Circuit::new(
+            circuit
+                .gates()
+                .iter()
+                .map(|g| BaseGate::new(g.id() + 1000, g.target, g.controls, g.control_func))
+                .collect(),
+            circuit.n(),
+        );
+        assert_eq!(circuit.canonical_hash(), relabelled.canonical_hash());
+
+        let mut gates = circuit.gates().to_vec();
+        gates.pop();
+        let truncated = Circuit::new(gates, circuit.n());
+        assert_ne!(circuit.canonical_hash(), truncated.canonical_hash());
+    }
+
+    #[test]
+    fn digest() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, 8, 1.0, &mut rng);
+        assert_eq!(circuit.digest(), circuit.clone().digest());
+        assert_eq!(circuit.digest_hex(), hex::encode(circuit.digest()));
+
+        // Gate ids are hashed, unlike with canonical hash
+        let relabelled = Circuit::new(
+            circuit
+                .gates()
+                .iter()
+                .map(|g| BaseGate::new(g.id() + 1000, g.target, g.controls, g.control_func))
+                .collect(),
+            circuit.n(),
+        );
+        assert_eq!(circuit.canonical_hash(), relabelled.canonical_hash());
+        assert_ne!(circuit.digest(), relabelled.digest());
+    }
+
+    #[test]
+    fn synthesize_from_permutation() {
+        let mut rng = ChaCha8Rng::from_entropy();
+
+        for n in 3..=8 {
+            let (circuit, _) =
+                sample_circuit_with_base_gate::<2, u8, _>(100, n as u8, 1.0, &mut rng);
+            let perm = (0..1 << n)
+                .map(|value| {
+                    let mut inputs = (0..n).map(|i| (value >> i) & 1 == 1).collect_vec();
+                    circuit.run(&mut inputs);
+                    inputs.iter().rfold(0, |v, bit| (v << 1) ^ *bit as usize)
+                })
+                .collect_vec();
+
+            let synthesized = Circuit::synthesize_from_permutation(&perm, n).unwrap();
+            assert_eq!(synthesized.n(), n);
+            assert!(check_exact_equivalence(&circuit, &synthesized).0);
+        }
+
+        // Odd permutations are only synthesizable for n = 3
+        let mut perm = (0..1 << 3).collect_vec();
+        perm.swap(2, 5);
+        let synthesized = Circuit::synthesize_from_permutation(&perm, 3).unwrap();
+        for (value, expected) in perm.iter().enumerate() {
+            let mut inputs = (0..3).map(|i| (value >> i) & 1 == 1).collect_vec();
+            synthesized.run(&mut inputs);
+            assert_eq!(
+                inputs.iter().rfold(0, |v, bit| (v << 1) ^ *bit as usize),
+                *expected
+            );
+        }
+
+        let mut perm = (0..1 << 4).collect_vec();
+        perm.swap(2, 5);
+        assert!(Circuit::synthesize_from_permutation(&perm, 4).is_none());
+    }
+
     #[test]
     fn sample_mutli_stage_cipher() {
         let mut rng = ChaCha8Rng::from_entropy();
-        let circuit = Circuit::sample_multi_stage_cipher(64, &mut rng);
+        let circuit = Circuit::sample_multi_stage_cipher(
+            64,
+            Circuit::DEFAULT_CIPHER_STAGES,
+            Circuit::default_cipher_layers_per_stage(64),
+            &mut rng,
+        );
 
         dbg!(circuit.n());
         dbg!(circuit.gates().len());
+
+        // Single non-inflationary layer has one gate per triplet
+        let circuit = Circuit::sample_multi_stage_cipher(64, 2, 1, &mut rng);
+        let triplet_count = 64usize.div_ceil(3);
+        let inflationary_gates = circuit.gates().len() - triplet_count;
+        assert!(triplet_count <= inflationary_gates && inflationary_gates <= 4 * triplet_count);
+
+        // More layers than log(n) must still produce a valid circuit
+        let circuit = Circuit::sample_multi_stage_cipher(64, 1, 20, &mut rng);
+        for gate in circuit.gates() {
+            let [c0, c1] = gate.controls();
+            assert!(gate.target() != c0 && gate.target() != c1 && c0 != c1);
+        }
+    }
+
+    #[test]
+    fn from_qasm() {
+        use super::{QasmParseError, QasmParseErrorKind};
+
+        // Exports gates with control functions that map to `x`, `cx`, and `ccx`
+        fn to_qasm(circuit: &Circuit<BaseGate<2, u8>>) -> String {
+            let mut qasm = format!(
+                "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[{}];\n",
+                circuit.n()
+            );
+            for gate in circuit.gates() {
+                let [c0, c1] = gate.controls();
+                let t = gate.target();
+                qasm += &match Base2GateControlFunc::from_u8(gate.control_func()) {
+                    Base2GateControlFunc::T => format!("x q[{t}];\n"),
+                    Base2GateControlFunc::A => format!("cx q[{c0}],q[{t}];\n"),
+                    Base2GateControlFunc::B => format!("cx q[{c1}],q[{t}];\n"),
+                    Base2GateControlFunc::AND => format!("ccx q[{c0}],q[{c1}],q[{t}];\n"),
+                    _ => unreachable!(),
+                };
+            }
+            qasm
+        }
+
+        let mut rng = ChaCha8Rng::from_entropy();
+        let control_funcs = [
+            Base2GateControlFunc::T,
+            Base2GateControlFunc::A,
+            Base2GateControlFunc::B,
+            Base2GateControlFunc::AND,
+        ];
+        for _ in 0..20 {
+            let n = rng.gen_range(3..8);
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(30, n, 1.0, &mut rng);
+            let circuit = Circuit::new(
+                circuit
+                    .gates()
+                    .iter()
+                    .map(|gate| {
+                        let control_func = control_funcs[rng.gen_range(0..4)];
+                        BaseGate::new(
+                            gate.id(),
+                            gate.target(),
+                            gate.controls(),
+                            control_func as u8,
+                        )
+                    })
+                    .collect(),
+                n as usize,
+            );
+
+            let parsed = Circuit::from_qasm(&to_qasm(&circuit)).unwrap();
+            assert_eq!(parsed.n(), circuit.n());
+            assert_eq!(parsed.num_gates(), circuit.num_gates());
+            assert!(check_exact_equivalence(&circuit, &parsed).0);
+        }
+
+        // Qiskit output with comments and barriers
+        let qasm = "OPENQASM 2.0;
+include \"qelib1.inc\";
+// transpiled
+qreg q[4];
+creg c[4];
+x q[0];
+barrier q[0],q[1],q[2],q[3];
+cx q[0], q[1]; ccx q[0],q[1],q[3];
+";
+        let circuit = Circuit::from_qasm(qasm).unwrap();
+        assert_eq!(circuit.n(), 4);
+        let mut inputs = vec![false; 4];
+        circuit.run(&mut inputs);
+        assert_eq!(inputs, vec![true, true, false, true]);
+
+        // Unused controls are taken from untargeted wires 1 and 3, hence the gates don't collide
+        let circuit = Circuit::from_qasm("qreg q[4];\nx q[0];\ncx q[1],q[2];").unwrap();
+        let [x, cx] = circuit.gates() else {
+            unreachable!()
+        };
+        assert_eq!((x.target(), x.controls()), (0, [1, 3]));
+        assert_eq!((cx.target(), cx.controls()), (2, [1, 3]));
+        assert!(!x.check_collision(cx));
+
+        let err = |line, kind| Err(QasmParseError { line, kind });
+        assert_eq!(
+            Circuit::from_qasm("qreg q[4];\nx q[0];\nc3x q[0],q[1],q[2],q[3];"),
+            err(3, QasmParseErrorKind::TooManyControls)
+        );
+        assert_eq!(
+            Circuit::from_qasm("qreg q[4];\ncreg c[4];\nmeasure q[0] -> c[0];"),
+            err(3, QasmParseErrorKind::Measurement)
+        );
+        assert_eq!(
+            Circuit::from_qasm("qreg q[4];\nqreg r[4];"),
+            err(2, QasmParseErrorKind::MultipleRegisters)
+        );
+        assert_eq!(
+            Circuit::from_qasm("qreg q[4];\ncx q[0],r[1];"),
+            err(2, QasmParseErrorKind::InvalidOperand("r[1]".into()))
+        );
+        assert_eq!(
+            Circuit::from_qasm("qreg q[4];\ncx q[0],q[4];"),
+            err(2, QasmParseErrorKind::InvalidOperand("q[4]".into()))
+        );
+        assert_eq!(
+            Circuit::from_qasm("qreg q[4];\nccx q[0],q[1];"),
+            err(
+                2,
+                QasmParseErrorKind::OperandCount {
+                    expected: 3,
+                    found: 2
+                }
+            )
+        );
+        assert_eq!(
+            Circuit::from_qasm("qreg q[4];\ncx q[1],q[1];"),
+            err(2, QasmParseErrorKind::RepeatedQubit)
+        );
+        assert_eq!(
+            Circuit::from_qasm("qreg q[2];"),
+            err(1, QasmParseErrorKind::InvalidRegisterSize(2))
+        );
+        assert_eq!(
+            Circuit::from_qasm("x q[0];"),
+            err(1, QasmParseErrorKind::MissingRegister)
+        );
+        assert_eq!(
+            Circuit::from_qasm("qreg q[4];\nh q[0];"),
+            err(2, QasmParseErrorKind::UnsupportedStatement("h q[0]".into()))
+        );
+        assert_eq!(
+            Circuit::from_qasm("qreg q[4];\nh q[0];")
+                .unwrap_err()
+                .to_string(),
+            "line 2: unsupported statement `h q[0]`"
+        );
     }
 }
@@ -1,5 +1,5 @@
 use bitvec::{array::BitArray, bitarr, order::Lsb0, vec::BitVec};
-use circuit::{BaseGate, Circuit, Gate};
+use circuit::{BaseGate, Circuit, Gate, TooManyWires};
 use either::Either::{Left, Right};
 use itertools::{chain, izip, Itertools};
 use num_traits::Zero;
@@ -15,6 +15,7 @@ use rand::{
     seq::SliceRandom,
     Rng, RngCore, SeedableRng,
 };
+use rand_chacha::ChaCha8Rng;
 use rayon::{
     current_num_threads,
     iter::{
@@ -23,20 +24,32 @@ use rayon::{
     },
     slice::{ParallelSlice, ParallelSliceMut},
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     array::from_fn,
-    collections:: VecDeque,
+    collections::{BTreeMap, VecDeque},
     fmt::{Debug, Display},
     hash::Hash,
+    io::Write,
     iter::{self, repeat_with},
     ops::Deref,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
         Arc, Mutex,
     },
     time::Duration,
 };
-use hashbrown::{HashMap, HashSet};
+/// Hasher of [`HashMap`] and [`HashSet`]. With feature `no-rayon` hasher keys are fixed so that
+/// iteration order is the same on every run.
+#[cfg(not(feature = "no-rayon"))]
+pub type HashBuilder = hashbrown::DefaultHashBuilder;
+#[cfg(feature = "no-rayon")]
+pub type HashBuilder = std::hash::BuildHasherDefault<std::hash::DefaultHasher>;
+
+pub type HashMap<K, V> = hashbrown::HashMap<K, V, HashBuilder>;
+pub type HashSet<T> = hashbrown::HashSet<T, HashBuilder>;
 
 pub mod circuit;
 
@@ -79,17 +92,40 @@ where
         .collect_vec()
 }
 
-fn input_value_to_bitstring_map(n: usize) -> HashMap<usize, Vec<bool>> {
-    assert!(n < 20, "{n} >= 20; Too big!");
-    let mut map = HashMap::new();
-    for i in 0..1usize << n {
-        let mut bitstring = vec![false; n];
-        for j in 0..n {
-            bitstring[j] = ((i >> j) & 1) == 1
-        }
-        map.insert(i, bitstring);
+/// No. of threads to split searches across. Always 1 with feature `single-thread`.
+fn search_threads() -> usize {
+    if cfg!(feature = "single-thread") {
+        1
+    } else {
+        current_num_threads()
+    }
+}
+
+/// Returns any `Some` output of `f` over `items`. Items are processed in parallel unless feature
+/// `single-thread` is enabled, in which case the output for the first item is returned.
+fn find_map_any<T: Send, U: Send>(
+    items: impl Iterator<Item = T> + Send,
+    f: impl Fn(T) -> Option<U> + Sync + Send,
+) -> Option<U> {
+    #[cfg(feature = "single-thread")]
+    {
+        let mut items = items;
+        return items.find_map(f);
     }
-    return map;
+    #[cfg(not(feature = "single-thread"))]
+    return items.par_bridge().find_map_any(f);
+}
+
+/// Returns `n` bit bitstring of `value`. `j`-th bit of `value` is at index `j`.
+fn value_to_bitstring(value: usize, n: usize) -> Vec<bool> {
+    (0..n).map(|j| ((value >> j) & 1) == 1).collect()
+}
+
+/// Inverse of [`value_to_bitstring`]
+fn bitstring_to_value(bitstring: &[bool]) -> usize {
+    bitstring
+        .iter()
+        .rfold(0, |value, bit| (value << 1) | *bit as usize)
 }
 
 fn sample_m_unique_values<const M: usize, D, R: RngCore>(
@@ -100,7 +136,7 @@ where
     D: SampleUniform + Eq + Hash + Copy + Zero,
 {
     // Note(Jay): I removed the hash set way because iterator over hashset is in random order which is not good if we want to debug with a seeded rng.
-    let mut values = HashSet::new();
+    let mut values = HashSet::default();
     // let mut i = 0;
     // while i < M {
     //     let sample = rng.sample(distribution);
@@ -116,21 +152,63 @@ where
     return values;
 }
 
-fn permutation_map<G>(
-    circuit: &Circuit<G>,
-    input_value_to_bitstring_map: &HashMap<usize, Vec<bool>>,
-) -> HashMap<usize, Vec<bool>>
+/// Returns permutation computed by the circuit. Output of input `value` is at index `value`.
+fn permutation_map<G>(circuit: &Circuit<G>) -> Vec<usize>
 where
-    G: Gate<Input = [bool]>,
+    G: Gate<Input = [bool]> + Sync,
+{
+    let n = circuit.n();
+    assert!(n < 20, "{n} >= 20; Too big!");
+    (0..1usize << n)
+        .into_par_iter()
+        .map(|value| {
+            let mut inputs = value_to_bitstring(value, n);
+            circuit.run(&mut inputs);
+            bitstring_to_value(&inputs)
+        })
+        .collect()
+}
+
+/// Returns `(inputs, outputs)` of the circuit for every input on `N` wires, where `N2 = 2^N`.
+/// Entry `i` is for input `i`. Inputs are independent, so the table is filled in parallel.
+// Public for benches only
+#[doc(hidden)]
+pub fn permutation_table<const N: usize, const N2: usize>(
+    circuit: &Circuit<BaseGate<2, u8>>,
+) -> [([bool; N], [bool; N]); N2] {
+    let mut table = [([false; N], [false; N]); N2];
+    table
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(i, (inputs, outputs))| {
+            *inputs = from_fn(|j| (i >> j) & 1 == 1);
+            *outputs = *inputs;
+            circuit.run(outputs);
+        });
+    table
+}
+
+/// Returns true if the circuit computes the identity permutation
+///
+/// Most circuits don't, so the circuit is first run on a few fixed inputs and the permutation of
+/// all 2^n inputs is only built if they are all fixed points.
+fn is_identity<G>(circuit: &Circuit<G>) -> bool
+where
+    G: Gate<Input = [bool]> + Sync,
 {
-    let mut permutation_map = HashMap::new();
-    for (value, bitstring) in input_value_to_bitstring_map.iter() {
-        let mut inputs = bitstring.clone();
+    let n = circuit.n();
+    let fixes_prefilter = (0..64u64).all(|k| {
+        // Multiplying by an odd constant spreads the bits of `k` over all wires
+        let value = k.wrapping_mul(0x9E37_79B9_7F4A_7C15) as usize & ((1 << n) - 1);
+        let mut inputs = value_to_bitstring(value, n);
         circuit.run(&mut inputs);
-        // assert_ne!(bitstring_map.get(&i).unwrap(), &inputs);
-        permutation_map.insert(*value, inputs);
-    }
-    return permutation_map;
+        bitstring_to_value(&inputs) == value
+    });
+    fixes_prefilter
+        && permutation_map(circuit)
+            .into_iter()
+            .enumerate()
+            .all(|(value, output)| value == output)
 }
 
 pub fn sample_circuit_with_base_gate<const MAX_K: usize, D, R: RngCore>(
@@ -142,6 +220,13 @@ pub fn sample_circuit_with_base_gate<const MAX_K: usize, D, R: RngCore>(
 where
     D: Zero + SampleUniform + Copy + Eq + Hash + Debug + Display + Into<usize>,
 {
+    // Sampling unique wires of a gate never terminates with fewer wires
+    let min_n = if MAX_K == 2 || two_prob == 1.0 { 3 } else { 4 };
+    assert!(
+        gate_count == 0 || n.into() >= min_n,
+        "n = {n} < {min_n}; Too few wires to sample a gate"
+    );
+
     let three_replacement_cost = 4; // 3-way gates can be decomposed into 4 2-way gates
     let two_replacement_cost = 1;
 
@@ -250,6 +335,11 @@ pub fn sample_circuit_with_base_gate_fast<R: Rng>(
     n: u8,
     rng: &mut R,
 ) {
+    // Sampling unique wires of a gate never terminates with fewer wires
+    assert!(
+        circuit.gates().is_empty() || n >= 3,
+        "n = {n} < 3; Too few wires to sample a gate"
+    );
     let set: [bool; 16] = from_fn(|i| (i as u8) >= n);
     let mut rng = repeat_with(|| rng.next_u32()).flat_map(|v| v.to_le_bytes());
 
@@ -272,6 +362,113 @@ pub fn sample_circuit_with_base_gate_fast<R: Rng>(
     });
 }
 
+/// Samples a random circuit of `gates` gates on `total_n` wires that touches exactly `active`
+/// randomly chosen wires
+///
+/// Useful to construct C^out with a given \omega^out directly instead of searching for convex
+/// subsets of a large circuit. Requires `3 <= active <= total_n` and `3 * gates >= active` so
+/// that every active wire can be touched.
+pub fn sample_circuit_with_active_wires<R: RngCore>(
+    active: usize,
+    gates: usize,
+    total_n: usize,
+    rng: &mut R,
+) -> Circuit<BaseGate<2, u8>> {
+    assert!(
+        3 <= active && active <= total_n && total_n <= u8::MAX as usize + 1,
+        "Invalid no. of active wires {active} for n = {total_n}"
+    );
+    assert!(
+        3 * gates >= active,
+        "{gates} gates cannot touch {active} active wires"
+    );
+
+    // Active wires in random order
+    let wires = rand::seq::index::sample(rng, total_n, active).into_vec();
+
+    // First gates cover all active wires, remaining gates use any 3 active wires
+    let mut gate_wires = wires
+        .chunks(3)
+        .map(|chunk| {
+            let mut gate_wires = [0; 3];
+            gate_wires[..chunk.len()].copy_from_slice(chunk);
+            for i in chunk.len()..3 {
+                gate_wires[i] = loop {
+                    let wire = wires[rng.next_u64() as usize % active];
+                    if !gate_wires[..i].contains(&wire) {
+                        break wire;
+                    }
+                };
+            }
+            gate_wires
+        })
+        .collect_vec();
+    while gate_wires.len() < gates {
+        let indices = rand::seq::index::sample(rng, active, 3);
+        gate_wires.push(from_fn(|i| wires[indices.index(i)]));
+    }
+    gate_wires.shuffle(rng);
+
+    let gates = izip!(0.., gate_wires)
+        .map(|(id, [target, control0, control1])| {
+            let control_func = rng.next_u64() as u8 % BaseGate::<2, u8>::N_CONTROL_FUNC;
+            BaseGate::<2, u8>::new(
+                id,
+                target as u8,
+                [control0 as u8, control1 as u8],
+                control_func,
+            )
+        })
+        .collect();
+
+    Circuit::new(gates, total_n)
+}
+
+/// Samples a random circuit of `gate_count` gates on `n` wires whose skeleton graph is weakly
+/// connected
+///
+/// Every gate after the first is sampled uniformly from the gates that collide with at least one
+/// earlier gate, that is, its target is a control of an earlier gate or one of its controls is the
+/// target of an earlier gate. Hence every gate is connected to the first gate.
+pub fn sample_connected_circuit<R: RngCore>(
+    gate_count: usize,
+    n: u8,
+    rng: &mut R,
+) -> Circuit<BaseGate<2, u8>> {
+    assert!(
+        gate_count == 0 || n >= 3,
+        "n = {n} < 3; Too few wires to sample a gate"
+    );
+
+    // Whether the wire is the target and one of the controls of any gate sampled so far
+    let mut is_target = vec![false; n as usize];
+    let mut is_control = vec![false; n as usize];
+    let gates = (0..gate_count)
+        .map(|id| {
+            let [target, control0, control1] = loop {
+                let wires = rand::seq::index::sample(rng, n as usize, 3);
+                let [target, control0, control1] = from_fn(|i| wires.index(i));
+                if id == 0 || is_control[target] || is_target[control0] || is_target[control1] {
+                    break [target, control0, control1];
+                }
+            };
+            is_target[target] = true;
+            is_control[control0] = true;
+            is_control[control1] = true;
+
+            let control_func = rng.next_u64() as u8 % BaseGate::<2, u8>::N_CONTROL_FUNC;
+            BaseGate::<2, u8>::new(
+                id,
+                target as u8,
+                [control0 as u8, control1 as u8],
+                control_func,
+            )
+        })
+        .collect();
+
+    Circuit::new(gates, n as usize)
+}
+
 /// Checks whether collisions set of any circuit is weakly connected.
 ///
 /// Any directed graph is weakly connected if the underlying undirected graph is fully connected.
@@ -291,7 +488,7 @@ fn is_collisions_set_weakly_connected(collisions_set: &[HashSet<usize>]) -> bool
     }
 
     let mut all_nodes: HashSet<usize> = HashSet::from_iter(0..gate_count);
-    let mut nodes_visited: HashSet<usize> = HashSet::new();
+    let mut nodes_visited: HashSet<usize> = HashSet::default();
     let mut stack = vec![0];
     let mut is_weakly_connected = true;
     while nodes_visited.len() < gate_count {
@@ -318,19 +515,92 @@ fn is_collisions_set_weakly_connected(collisions_set: &[HashSet<usize>]) -> bool
     is_weakly_connected
 }
 
-fn find_replacement_circuit<
-    const MAX_K: usize,
-    const WC: bool,
-    D,
-    R: Send + Sync + RngCore + SeedableRng,
->(
+/// Default of [`ReplacementSearchOptions::prefilter_inputs`]
+pub const REPLACEMENT_PREFILTER_INPUTS: usize = 64;
+
+/// Settings of replacement circuit search
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplacementSearchOptions {
+    /// Whether collisions set of replacements must be weakly connected. Enabled by default.
+    ///
+    /// Disabling it rejects fewer candidates, so replacements are found in fewer iterations. But a
+    /// replacement may then be made of independent groups of gates, which fragments the
+    /// dependency graph of the mixed circuit. Meant for studying whether the check is necessary.
+    pub weak_connectivity_check: bool,
+    /// No. of inputs, at most 64, on which candidates are checked with bit-sliced evaluation before
+    /// checking them on all inputs. 0 disables the prefilter. Defaults to
+    /// [`REPLACEMENT_PREFILTER_INPUTS`].
+    ///
+    /// Only used by search specialized for `n <= 11`. Fewer inputs make the prefilter cheaper, but
+    /// let more candidates through to the slower check on every input.
+    pub prefilter_inputs: usize,
+}
+
+impl Default for ReplacementSearchOptions {
+    fn default() -> Self {
+        ReplacementSearchOptions {
+            weak_connectivity_check: true,
+            prefilter_inputs: REPLACEMENT_PREFILTER_INPUTS,
+        }
+    }
+}
+
+/// Settings of convex subcircuit search
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConvexSearchOptions {
+    /// Whether convex sets are grown by the candidate of lowest level. Disabled by default.
+    ///
+    /// [`blah`] grows a convex set by a node outside the set that a node of the set has an edge
+    /// to, and pulls in every node on a path from the set to it. Such paths only run through nodes
+    /// of lower level than the candidate. Enabling it picks the candidate of lowest level instead
+    /// of the first one found, so fewer nodes are pulled in and fewer sets grow past the desired
+    /// size, at the cost of visiting all outgoing edges of the set.
+    pub level_bounded: bool,
+    /// Whether start nodes that can grow a convex set of the desired size are tried first.
+    /// Disabled by default.
+    ///
+    /// Convex sets only grow by successors of the start node, so start nodes with fewer
+    /// successors than the desired size, like the deepest nodes of the graph, always fail. Enabling
+    /// it has [`find_convex_fast`] count successors of the next start node of a shard, up to the
+    /// desired size, and defer it to the end of the shard if it doesn't have enough.
+    pub prioritize_start_nodes: bool,
+}
+
+/// Returns true if functionally equivalent `candidate` is an acceptable replacement of `circuit`.
+///
+/// Candidate is acceptable if it is not the same circuit as `circuit`, ignoring gate ids, and its
+/// collisions set is weakly connected, unless the check is disabled in `options`.
+fn is_acceptable_replacement<const MAX_K: usize, D>(
+    candidate: &Circuit<BaseGate<MAX_K, D>>,
+    circuit: &Circuit<BaseGate<MAX_K, D>>,
+    options: &ReplacementSearchOptions,
+) -> bool
+where
+    D: Into<usize> + Copy + PartialEq,
+{
+    !candidate.eq_ignoring_ids(circuit)
+        && (!options.weak_connectivity_check
+            || is_collisions_set_weakly_connected(&circuit_to_collision_sets(candidate)))
+}
+
+/// Max. no. of wires of a circuit to find a replacement for. Replacement search tabulates the
+/// permutation computed by the circuit, which has `2^n` entries.
+pub const MAX_REPLACEMENT_N: usize = 19;
+
+/// Generic replacement circuit search. Use [`find_replacement_circuit`] instead unless
+/// `MAX_K != 2`.
+///
+/// Also returns total no. of candidate circuits sampled. Panics if `circuit` has more than
+/// [`MAX_REPLACEMENT_N`] wires.
+fn find_replacement_circuit_generic<const MAX_K: usize, D, R: Send + Sync + RngCore + SeedableRng>(
     circuit: &Circuit<BaseGate<MAX_K, D>>,
     ell_in: usize,
     n: D,
     two_prob: f64,
     max_iterations: usize,
+    options: &ReplacementSearchOptions,
     rng: &mut R,
-) -> Option<Circuit<BaseGate<MAX_K, D>>>
+) -> (Option<Circuit<BaseGate<MAX_K, D>>>, usize)
 where
     D: Send
         + Sync
@@ -345,49 +615,42 @@ where
         + PartialEq
         + Eq,
 {
-    let input_value_to_bitstring_map = input_value_to_bitstring_map(circuit.n());
-    let permutation_map = permutation_map(circuit, &input_value_to_bitstring_map);
+    assert_replacement_n(circuit.n());
+    let permutation_map = permutation_map(circuit);
 
-    // let mut visited_circuits = HashMap::new();
-    let max_iterations = max_iterations / current_num_threads();
+    // let mut visited_circuits = HashMap::default();
+    let max_iterations = max_iterations / search_threads();
+    let iterations = AtomicUsize::new(0);
 
-    (0..current_num_threads())
-        .map(|_| R::from_rng(&mut *rng).unwrap())
-        .collect_vec()
-        .into_par_iter()
-        .find_map_any(|mut rng| {
+    let replacement_circuit = find_map_any(
+        (0..search_threads()).map(|_| R::from_rng(&mut *rng).unwrap()),
+        |mut rng| {
             let mut curr_iter = 0;
             let mut replacement_circuit = None;
+            // Reused for every input, instead of allocating a bitstring per input
+            let mut inputs = vec![false; circuit.n()];
 
             while curr_iter < max_iterations {
                 let (random_circuit, _) =
                     sample_circuit_with_base_gate::<MAX_K, D, _>(ell_in, n, two_prob, &mut rng);
 
                 let mut funtionally_equivalent = true;
-                for (value, bitstring) in input_value_to_bitstring_map.iter() {
-                    let mut inputs = bitstring.to_vec();
+                for (value, output) in permutation_map.iter().enumerate() {
+                    inputs
+                        .iter_mut()
+                        .enumerate()
+                        .for_each(|(j, bit)| *bit = (value >> j) & 1 == 1);
                     random_circuit.run(&mut inputs);
 
-                    if &inputs != permutation_map.get(value).unwrap() {
+                    if bitstring_to_value(&inputs) != *output {
                         funtionally_equivalent = false;
                         break;
                     }
                 }
 
                 if funtionally_equivalent {
-                    funtionally_equivalent = &random_circuit != circuit
-                }
-
-                if funtionally_equivalent && WC {
-                    let collisions_set = circuit_to_collision_sets(&random_circuit);
-                    let is_weakly_connected = is_collisions_set_weakly_connected(&collisions_set);
-                    #[cfg(feature = "trace")]
-                    {
-                        if funtionally_equivalent && !is_weakly_connected {
-                            log::trace!("[find_replacement_circuit] wft");
-                        }
-                    }
-                    funtionally_equivalent = is_weakly_connected;
+                    funtionally_equivalent =
+                        is_acceptable_replacement(&random_circuit, circuit, options);
                 }
 
                 if funtionally_equivalent {
@@ -425,53 +688,123 @@ where
             );
             // println!("Visited frequency: {:?}", visited_freq);
 
+            iterations.fetch_add(curr_iter + replacement_circuit.is_some() as usize, Relaxed);
             replacement_circuit
-        })
+        },
+    );
+
+    (replacement_circuit, iterations.into_inner())
+}
+
+/// Panics with an explanation, instead of failing to allocate `2^n` entry tables, if replacement
+/// search does not support circuits with `n` wires
+fn assert_replacement_n(n: usize) {
+    assert!(
+        n <= MAX_REPLACEMENT_N,
+        "Cannot search replacement of a circuit with {n} wires: search tabulates all 2^{n} inputs \
+        and supports at most {MAX_REPLACEMENT_N} wires, and its const generic fast path at most \
+        11. Choose smaller convex subcircuits, eg. with a smaller ell^out."
+    );
 }
 
-fn find_replacement_circuit_fast<R: Send + Sync + RngCore + SeedableRng>(
+/// Finds a circuit with `ell_in` gates that is functionally equivalent to `circuit` on `n` wires
+///
+/// Returned replacement circuit is never the same as `circuit` and its collisions set is weakly
+/// connected, unless the check is disabled in `options`. Returns `None` if no replacement is found
+/// in `max_iterations` iterations. Also returns total no. of candidate circuits sampled.
+///
+/// For `3 <= n <= 11`, uses search specialized with `n` as const generic. For
+/// `12 <= n <= MAX_REPLACEMENT_N`, falls back to [`find_replacement_circuit_generic`]. Panics for
+/// any other `n`.
+///
+/// With feature `deterministic`, always uses [`find_replacement_circuit_deterministic`].
+///
+/// If `circuit` computes the identity, which happens once gates cancel out, random search almost
+/// never finds a replacement. Uses [`find_identity_replacement_circuit`] instead.
+pub fn find_replacement_circuit<R: Send + Sync + RngCore + SeedableRng>(
     circuit: &Circuit<BaseGate<2, u8>>,
     ell_in: usize,
     n: usize,
     max_iterations: usize,
+    options: &ReplacementSearchOptions,
     rng: &mut R,
-) -> Option<Circuit<BaseGate<2, u8>>> {
+) -> (Option<Circuit<BaseGate<2, u8>>>, usize) {
+    assert!(n >= 3, "{n} < 3; Too small!");
+    assert_replacement_n(n);
+    if is_identity(circuit) {
+        return find_identity_replacement_circuit(circuit, ell_in, n, max_iterations, options, rng);
+    }
+    if cfg!(feature = "deterministic") {
+        return find_replacement_circuit_deterministic(
+            circuit,
+            ell_in,
+            n,
+            max_iterations,
+            options,
+            rng,
+        );
+    }
     return match n {
-        3 => inner::<_, 3, { 1 << 3 }>(circuit, ell_in, max_iterations, rng),
-        4 => inner::<_, 4, { 1 << 4 }>(circuit, ell_in, max_iterations, rng),
-        5 => inner::<_, 5, { 1 << 5 }>(circuit, ell_in, max_iterations, rng),
-        6 => inner::<_, 6, { 1 << 6 }>(circuit, ell_in, max_iterations, rng),
-        7 => inner::<_, 7, { 1 << 7 }>(circuit, ell_in, max_iterations, rng),
-        8 => inner::<_, 8, { 1 << 8 }>(circuit, ell_in, max_iterations, rng),
-        9 => inner::<_, 9, { 1 << 9 }>(circuit, ell_in, max_iterations, rng),
-        10 => inner::<_, 10, { 1 << 10 }>(circuit, ell_in, max_iterations, rng),
-        11 => inner::<_, 11, { 1 << 11 }>(circuit, ell_in, max_iterations, rng),
-        _ => unimplemented!(),
+        3 => inner::<_, 3, { 1 << 3 }>(circuit, ell_in, max_iterations, options, rng),
+        4 => inner::<_, 4, { 1 << 4 }>(circuit, ell_in, max_iterations, options, rng),
+        5 => inner::<_, 5, { 1 << 5 }>(circuit, ell_in, max_iterations, options, rng),
+        6 => inner::<_, 6, { 1 << 6 }>(circuit, ell_in, max_iterations, options, rng),
+        7 => inner::<_, 7, { 1 << 7 }>(circuit, ell_in, max_iterations, options, rng),
+        8 => inner::<_, 8, { 1 << 8 }>(circuit, ell_in, max_iterations, options, rng),
+        9 => inner::<_, 9, { 1 << 9 }>(circuit, ell_in, max_iterations, options, rng),
+        10 => inner::<_, 10, { 1 << 10 }>(circuit, ell_in, max_iterations, options, rng),
+        11 => inner::<_, 11, { 1 << 11 }>(circuit, ell_in, max_iterations, options, rng),
+        _ => find_replacement_circuit_generic::<2, u8, _>(
+            circuit,
+            ell_in,
+            n as u8,
+            1.0,
+            max_iterations,
+            options,
+            rng,
+        ),
     };
 
     fn inner<R: Send + Sync + RngCore + SeedableRng, const N: usize, const N2: usize>(
         circuit: &Circuit<BaseGate<2, u8>>,
         ell_in: usize,
         max_iterations: usize,
+        options: &ReplacementSearchOptions,
         rng: &mut R,
-    ) -> Option<Circuit<BaseGate<2, u8>>> {
-        let mut permutations: [_; N2] = from_fn(|i| {
-            let inputs = from_fn::<_, N, _>(|j| (i >> j) & 1 == 1);
-            let mut outputs = inputs;
-            circuit.run(&mut outputs);
-            (inputs, outputs)
-        });
+    ) -> (Option<Circuit<BaseGate<2, u8>>>, usize) {
+        let mut permutations = permutation_table::<N, N2>(circuit);
 
         permutations.shuffle(rng);
 
-        // let mut visited_circuits = HashMap::new();
-        let max_iterations = max_iterations / current_num_threads();
+        // Since permutations are shuffled, first `prefilter_len` permutations are a random subset
+        assert!(
+            options.prefilter_inputs <= 64,
+            "Can prefilter at most 64 inputs, not {}",
+            options.prefilter_inputs
+        );
+        let prefilter_len = options.prefilter_inputs.min(N2);
+        let (prefilter, permutations) = permutations.split_at(prefilter_len);
+        // Lanes `prefilter_len..64` are unused and must be ignored
+        let prefilter_mask = u64::MAX
+            .checked_shr((64 - prefilter_len) as u32)
+            .unwrap_or(0);
+        let mut prefilter_inputs = [0u64; N];
+        let mut prefilter_outputs = [0u64; N];
+        for (k, (inputs, outputs)) in prefilter.iter().enumerate() {
+            for j in 0..N {
+                prefilter_inputs[j] |= (inputs[j] as u64) << k;
+                prefilter_outputs[j] |= (outputs[j] as u64) << k;
+            }
+        }
+
+        // let mut visited_circuits = HashMap::default();
+        let max_iterations = max_iterations / search_threads();
         let found = AtomicBool::new(false);
+        let iterations = AtomicUsize::new(0);
 
-        (0..current_num_threads())
-            .map(|_| R::from_rng(&mut *rng).unwrap())
-            .par_bridge()
-            .find_map_any(|mut rng| {
+        let replacement_circuit = find_map_any(
+            (0..search_threads()).map(|_| R::from_rng(&mut *rng).unwrap()),
+            |mut rng| {
                 let epoch_size = rng.gen_range(10..20);
                 let mut curr_iter = 0;
                 let mut replacement_circuit = None;
@@ -481,13 +814,23 @@ fn find_replacement_circuit_fast<R: Send + Sync + RngCore + SeedableRng>(
 
                 while curr_iter < max_iterations {
                     if curr_iter % epoch_size == 0 && found.load(Relaxed) {
+                        iterations.fetch_add(curr_iter, Relaxed);
                         return None;
                     }
 
                     sample_circuit_with_base_gate_fast(&mut random_circuit, N as u8, &mut rng);
 
+                    // Phase 1: fast reject with bit-sliced check on the random subset
+                    let mut wires = prefilter_inputs;
+                    random_circuit.run_bitsliced(&mut wires);
+                    if izip!(wires, prefilter_outputs).any(|(w, o)| (w ^ o) & prefilter_mask != 0) {
+                        curr_iter += 1;
+                        continue;
+                    }
+
+                    // Phase 2: check rest of the permutations
                     let mut funtionally_equivalent = true;
-                    for (inputs, map) in &permutations {
+                    for (inputs, map) in permutations {
                         let mut inputs = *inputs;
                         random_circuit.run(&mut inputs);
 
@@ -498,14 +841,8 @@ fn find_replacement_circuit_fast<R: Send + Sync + RngCore + SeedableRng>(
                     }
 
                     if funtionally_equivalent {
-                        funtionally_equivalent = &random_circuit != circuit
-                    }
-
-                    if funtionally_equivalent {
-                        let collisions_set = circuit_to_collision_sets(&random_circuit);
-                        let is_weakly_connected =
-                            is_collisions_set_weakly_connected(&collisions_set);
-                        funtionally_equivalent = is_weakly_connected;
+                        funtionally_equivalent =
+                            is_acceptable_replacement(&random_circuit, circuit, options);
                     }
 
                     if funtionally_equivalent {
@@ -527,9 +864,123 @@ fn find_replacement_circuit_fast<R: Send + Sync + RngCore + SeedableRng>(
                 #[cfg(feature = "trace")]
                 log::trace!("Finding replacement total iterations: {}", curr_iter,);
 
+                iterations.fetch_add(curr_iter + replacement_circuit.is_some() as usize, Relaxed);
                 replacement_circuit
-            })
+            },
+        );
+
+        (replacement_circuit, iterations.into_inner())
+    }
+}
+
+/// Finds a replacement for `circuit` that computes the identity. Returned circuit is a random
+/// circuit C with `ell_in / 2` gates followed by C in reverse, which is C^-1 since every gate is an
+/// involution. For odd `ell_in` the last gate of C^-1 is split into 2 gates with the same wires,
+/// whose control functions XOR to its control function, so that it has exactly `ell_in` gates.
+///
+/// C is resampled until the returned circuit is acceptable as in [`find_replacement_circuit`].
+/// With at least 2 gates in C this only requires collisions set of C to be weakly connected.
+/// Gates of a smaller C all have the same wires and don't collide, so returns `None` without
+/// sampling if `ell_in` is less than 4, or less than 2 if the weak connectivity check is disabled.
+fn find_identity_replacement_circuit<R: RngCore>(
+    circuit: &Circuit<BaseGate<2, u8>>,
+    ell_in: usize,
+    n: usize,
+    max_iterations: usize,
+    options: &ReplacementSearchOptions,
+    rng: &mut R,
+) -> (Option<Circuit<BaseGate<2, u8>>>, usize) {
+    let min_ell_in = if options.weak_connectivity_check {
+        4
+    } else {
+        2
+    };
+    if ell_in < min_ell_in {
+        return (None, 0);
+    }
+    for curr_iter in 0..max_iterations {
+        let (half_circuit, _) =
+            sample_circuit_with_base_gate::<2, u8, _>(ell_in / 2, n as u8, 1.0, rng);
+        let mut gates = chain(half_circuit.gates(), half_circuit.gates().iter().rev())
+            .cloned()
+            .collect_vec();
+        if ell_in % 2 == 1 {
+            // Control function is the truth table of the control bits, so the target is flipped by
+            // the XOR of control functions of consecutive gates with the same wires
+            let last = gates.pop().unwrap();
+            let split_func = loop {
+                let control_func = rng.next_u32() as u8 % BaseGate::<2, u8>::N_CONTROL_FUNC;
+                if control_func != 0 && control_func != last.control_func() {
+                    break control_func;
+                }
+            };
+            for control_func in [split_func, split_func ^ last.control_func()] {
+                gates.push(BaseGate::new(
+                    0,
+                    last.target(),
+                    last.controls(),
+                    control_func,
+                ));
+            }
+        }
+        let candidate = Circuit::new(
+            gates
+                .into_iter()
+                .enumerate()
+                .map(|(id, g)| BaseGate::new(id, g.target(), g.controls(), g.control_func()))
+                .collect(),
+            n,
+        );
+
+        if is_acceptable_replacement(&candidate, circuit, options) {
+            return (Some(candidate), curr_iter + 1);
+        }
+    }
+
+    (None, max_iterations)
+}
+
+/// Single threaded replacement circuit search for debugging.
+///
+/// Unlike [`find_replacement_circuit`], does not shuffle inputs and samples candidates one after
+/// another from `rng`. Candidates are checked on inputs in increasing order, so the returned
+/// replacement only depends on state of `rng`. Acceptance criteria and returned values are the
+/// same as [`find_replacement_circuit`].
+fn find_replacement_circuit_deterministic<R: RngCore>(
+    circuit: &Circuit<BaseGate<2, u8>>,
+    ell_in: usize,
+    n: usize,
+    max_iterations: usize,
+    options: &ReplacementSearchOptions,
+    rng: &mut R,
+) -> (Option<Circuit<BaseGate<2, u8>>>, usize) {
+    assert!((3..20).contains(&n), "n = {n} is not in [3, 20)");
+
+    let permutations = (0..1usize << n)
+        .map(|value| {
+            let inputs = (0..n).map(|j| (value >> j) & 1 == 1).collect_vec();
+            let mut outputs = inputs.clone();
+            circuit.run(&mut outputs);
+            (inputs, outputs)
+        })
+        .collect_vec();
+
+    let mut random_circuit = Circuit::new(vec![BaseGate::new(0, 0, [0, 0], 0); ell_in], n);
+    let mut random_outputs = Vec::with_capacity(n);
+    for curr_iter in 0..max_iterations {
+        sample_circuit_with_base_gate_fast(&mut random_circuit, n as u8, rng);
+
+        let funtionally_equivalent = permutations.iter().all(|(inputs, outputs)| {
+            random_circuit.run_into(inputs, &mut random_outputs);
+            &random_outputs == outputs
+        });
+
+        if funtionally_equivalent && is_acceptable_replacement(&random_circuit, circuit, options) {
+            return (Some(random_circuit), curr_iter + 1);
+        }
     }
+
+    (None, max_iterations)
 }
 
 fn dfs_fast(
@@ -671,19 +1122,41 @@ fn dfs2(
     return return_bool;
 }
 
-fn blah(
+/// Grows `convex_set` by random neighbours, backtracking on sets that are not convex, until it has
+/// `desire_set_size` nodes. Returns whether such convex set is found. Sets that would have to
+/// contain any of `frozen_nodes` to be convex are rejected. Refer to
+/// [`ConvexSearchOptions::level_bounded`] for how the next node is picked.
+// Public for benches only
+#[doc(hidden)]
+pub fn blah(
     desire_set_size: usize,
     convex_set: &mut HashSet<NodeIndex>,
     graph: &Graph<usize, usize>,
     level: &[usize],
     removed_nodes: &HashSet<NodeIndex>,
+    frozen_nodes: &HashSet<NodeIndex>,
+    options: &ConvexSearchOptions,
 ) -> bool {
     if convex_set.len() == desire_set_size {
         return true;
     }
     // pick one edge randomly
     // check whether the graph still remains convex. If it does check whether max length has been reached. If yes, then return true with else pop the element out and return false.
-    let candidate_node = {
+    let candidate_node = if options.level_bounded {
+        let candidate_node = convex_set
+            .iter()
+            .flat_map(|source_node| graph.neighbors_directed(*source_node, Direction::Outgoing))
+            .filter(|node| {
+                !removed_nodes.contains(node)
+                    && !frozen_nodes.contains(node)
+                    && !convex_set.contains(node)
+            })
+            .min_by_key(|node| level[node.index()]);
+        match candidate_node {
+            Some(candidate_node) => candidate_node,
+            None => return false,
+        }
+    } else {
         let mut iter_convex_set = convex_set.iter();
         let mut candidate_node = None;
         loop {
@@ -693,7 +1166,9 @@ fn blah(
                     // We really want to iterate over all edges in big graph! So just find the first one that's not in the convex set
                     let mut edge_iter = graph
                         .neighbors_directed(*source_node, Direction::Outgoing)
-                        .filter(|node| !removed_nodes.contains(node));
+                        .filter(|node| {
+                            !removed_nodes.contains(node) && !frozen_nodes.contains(node)
+                        });
                     loop {
                         match edge_iter.next() {
                             Some(potential_candidate) => {
@@ -731,9 +1206,9 @@ fn blah(
     //     return false;
     // }
 
-    let mut union_visited_with_path = HashSet::new();
+    let mut union_visited_with_path = HashSet::default();
     union_visited_with_path.insert(candidate_node);
-    let mut union_visited = HashSet::new();
+    let mut union_visited = HashSet::default();
     let mut path = vec![];
     for source in convex_set.iter() {
         let dfs_did_not_break = dfs2(
@@ -755,13 +1230,24 @@ fn blah(
     }
 
     union_visited_with_path.retain(|n| !convex_set.contains(n));
+    if !union_visited_with_path.is_disjoint(frozen_nodes) {
+        return false;
+    }
 
     if union_visited_with_path.len() + convex_set.len() <= desire_set_size {
         for node in union_visited_with_path {
             convex_set.insert(node);
         }
         if convex_set.len() < desire_set_size {
-            return blah(desire_set_size, convex_set, graph, level, removed_nodes);
+            return blah(
+                desire_set_size,
+                convex_set,
+                graph,
+                level,
+                removed_nodes,
+                frozen_nodes,
+                options,
+            );
         } else {
             return true;
         }
@@ -806,7 +1292,7 @@ fn find_all_predecessors(
     removed_nodes: &HashSet<NodeIndex>,
 ) -> HashSet<NodeIndex> {
     // Find all predecessors and successors of subgrpah C^out
-    let mut imm_predecessors = HashSet::new();
+    let mut imm_predecessors = HashSet::default();
 
     // First find all immediate predecessors and successors
     for node in convex_set.iter() {
@@ -835,7 +1321,7 @@ fn find_all_successors(
     graph: &Graph<usize, usize>,
     removed_nodes: &HashSet<NodeIndex>,
 ) -> HashSet<NodeIndex> {
-    let mut imm_successors = HashSet::new();
+    let mut imm_successors = HashSet::default();
     // First find all immediate predecessors and successors
     for node in convex_set.iter() {
         for succ in graph
@@ -863,7 +1349,12 @@ pub fn toposort_with_cached_graph_neighbours(
     graph_neighbors: &[[HashSet<NodeIndex>; 2]],
     removed_nodes: &HashSet<NodeIndex>,
 ) -> Vec<NodeIndex> {
-    let levels = graph_level_single_threaded(&skeleton_graph, &graph_neighbors, removed_nodes);
+    let levels = graph_level_single_threaded(
+        &skeleton_graph,
+        &graph_neighbors,
+        removed_nodes,
+        Direction::Outgoing,
+    );
     let mut node_indices = skeleton_graph
         .node_indices()
         .into_iter()
@@ -881,7 +1372,7 @@ fn graph_neighbors(
         .into_par_iter()
         .map(|n| {
             if removed_nodes.contains(&NodeIndex::from(n)) {
-                [HashSet::new(), HashSet::new()]
+                [HashSet::default(), HashSet::default()]
             } else {
                 [Direction::Incoming, Direction::Outgoing].map(|dir| {
                     graph
@@ -903,9 +1394,9 @@ fn update_graph_neighbors(
 ) {
     to_remove_nodes.iter().for_each(|n| {
         let incoming = &mut in_degree[n.index()][0];
-        *incoming = HashSet::new();
+        *incoming = HashSet::default();
         let outgoing = &mut in_degree[n.index()][1];
-        *outgoing = HashSet::new();
+        *outgoing = HashSet::default();
     });
 
 
@@ -926,17 +1417,32 @@ fn update_graph_neighbors(
     });
 }
 
+/// Returns indices of edges in `graph_neighbors` entries that are followed, and of edges that are
+/// counted as in-degree, when levelling in `direction`
+fn level_neighbour_indices(direction: Direction) -> (usize, usize) {
+    match direction {
+        Direction::Outgoing => (1, 0),
+        Direction::Incoming => (0, 1),
+    }
+}
+
 /// Implements Kahn's algorithm. Instead of the original graph it uses cached incoming and outgoing edges per edge
+///
+/// Follows edges in `direction`. Levels are distances from sources with `Outgoing` and distances
+/// from sinks with `Incoming`.
 fn graph_level_single_threaded(
     graph: &Graph<usize, usize>,
     graph_neighbors: &[[HashSet<NodeIndex>; 2]],
     removed_nodes: &HashSet<NodeIndex>,
+    direction: Direction,
 ) -> Vec<usize> {
+    let (next_index, prev_index) = level_neighbour_indices(direction);
     let mut stack = Vec::new();
     let mut degree = graph_neighbors
         .iter()
         .enumerate()
-        .map(|(n, [incomings, _])| {
+        .map(|(n, neighbours)| {
+            let incomings = &neighbours[prev_index];
             let node = NodeIndex::from(n as u32);
             if incomings.is_empty() && !removed_nodes.contains(&node) {
                 stack.push(node);
@@ -948,16 +1454,18 @@ fn graph_level_single_threaded(
     let mut next = None;
     while let Some(curr) = next.take().or_else(|| stack.pop()) {
         let curr_level = level[curr.index()];
-        graph_neighbors[curr.index()][1].iter().for_each(|succ| {
-            assert!(!removed_nodes.contains(succ));
-            let (succ_in_degree, succ_level) = &mut degree[succ.index()];
-            *succ_in_degree -= 1;
-            *succ_level = std::cmp::max(curr_level + 1, *succ_level);
-            level[succ.index()] = *succ_level;
-            if *succ_in_degree == 0 {
-                stack.push(*succ);
-            }
-        });
+        graph_neighbors[curr.index()][next_index]
+            .iter()
+            .for_each(|succ| {
+                assert!(!removed_nodes.contains(succ));
+                let (succ_in_degree, succ_level) = &mut degree[succ.index()];
+                *succ_in_degree -= 1;
+                *succ_level = std::cmp::max(curr_level + 1, *succ_level);
+                level[succ.index()] = *succ_level;
+                if *succ_in_degree == 0 {
+                    stack.push(*succ);
+                }
+            });
     }
     level
 }
@@ -967,11 +1475,100 @@ pub fn graph_level(
     graph_neighbors: &[[HashSet<NodeIndex>; 2]],
     removed_nodes: &HashSet<NodeIndex>,
 ) -> Vec<usize> {
+    graph_level_in_direction(graph, graph_neighbors, removed_nodes, Direction::Outgoing)
+}
+
+/// Updates `level`, output of [`graph_level`] before the graph changed, for the current graph.
+///
+/// Only levels of `changed_nodes` and nodes reachable from them are recomputed. `changed_nodes`
+/// must include every live node that gained an incoming edge or whose incoming edges were removed,
+/// unless removed edges were transitive. Levels of removed nodes are set to 0 and nodes added to
+/// the graph are appended to `level`.
+pub fn update_graph_level(
+    level: &mut Vec<usize>,
+    graph: &Graph<usize, usize>,
+    graph_neighbors: &[[HashSet<NodeIndex>; 2]],
+    removed_nodes: &HashSet<NodeIndex>,
+    changed_nodes: impl IntoIterator<Item = NodeIndex>,
+) {
+    level.resize(graph.node_count(), 0);
+
+    // Nodes whose level may have changed
+    let mut affected = HashSet::default();
+    let mut stack = changed_nodes.into_iter().collect_vec();
+    while let Some(node) = stack.pop() {
+        if removed_nodes.contains(&node) {
+            level[node.index()] = 0;
+            continue;
+        }
+        if affected.insert(node) {
+            stack.extend(graph_neighbors[node.index()][1].iter().copied());
+        }
+    }
+
+    // Recompute levels of affected nodes in topological order. Levels of unaffected
+    // predecessors are up to date.
+    let mut in_degree: HashMap<NodeIndex, usize> = affected
+        .iter()
+        .map(|node| {
+            let degree = graph_neighbors[node.index()][0]
+                .iter()
+                .filter(|pred| affected.contains(*pred))
+                .count();
+            (*node, degree)
+        })
+        .collect();
+    let mut stack = in_degree
+        .iter()
+        .filter(|(_, degree)| **degree == 0)
+        .map(|(node, _)| *node)
+        .collect_vec();
+    while let Some(node) = stack.pop() {
+        level[node.index()] = graph_neighbors[node.index()][0]
+            .iter()
+            .map(|pred| level[pred.index()] + 1)
+            .max()
+            .unwrap_or(0);
+        for succ in graph_neighbors[node.index()][1].iter() {
+            let degree = in_degree.get_mut(succ).unwrap();
+            *degree -= 1;
+            if *degree == 0 {
+                stack.push(*succ);
+            }
+        }
+    }
+}
+
+/// Same as [`graph_level`] but follows edges in reverse. Level of a node is the length of the
+/// longest path from the node to any sink.
+///
+/// For any node, `graph_level + graph_level_reverse` is the length of the longest path through
+/// the node.
+pub fn graph_level_reverse(
+    graph: &Graph<usize, usize>,
+    graph_neighbors: &[[HashSet<NodeIndex>; 2]],
+    removed_nodes: &HashSet<NodeIndex>,
+) -> Vec<usize> {
+    graph_level_in_direction(graph, graph_neighbors, removed_nodes, Direction::Incoming)
+}
+
+fn graph_level_in_direction(
+    graph: &Graph<usize, usize>,
+    graph_neighbors: &[[HashSet<NodeIndex>; 2]],
+    removed_nodes: &HashSet<NodeIndex>,
+    direction: Direction,
+) -> Vec<usize> {
+    if cfg!(feature = "single-thread") {
+        return graph_level_single_threaded(graph, graph_neighbors, removed_nodes, direction);
+    }
+
+    let (next_index, prev_index) = level_neighbour_indices(direction);
     let stack = Arc::new(Mutex::new(Vec::new()));
     let degree = graph_neighbors
         .par_iter()
         .enumerate()
-        .filter_map(|(n, [incomings, _])| {
+        .filter_map(|(n, neighbours)| {
+            let incomings = &neighbours[prev_index];
             if incomings.is_empty() && !removed_nodes.contains(&NodeIndex::from(n as u32)) {
                 stack.lock().unwrap().push(NodeIndex::from(n as u32));
             }
@@ -995,7 +1592,7 @@ pub fn graph_level(
         while let Some(curr) = next.take().or_else(|| stack.lock().unwrap().pop()) {
             let curr_level = degree[curr.index()].1.load(Relaxed);
             unsafe { level_slice.write(curr.index(), curr_level) };
-            let mut succs = graph_neighbors[curr.index()][1]
+            let succs = graph_neighbors[curr.index()][next_index]
                 .iter()
                 // .filter(|node| !removed_nodes.contains(*node))
                 .flat_map(|succ| {
@@ -1019,33 +1616,127 @@ pub fn graph_level(
     level
 }
 
-fn find_convex_fast<R: Send + Sync + RngCore + SeedableRng>(
+/// Reason why [find_convex_fast] failed to find a convex subset
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConvexSearchFailure {
+    /// Tried `max_iterations` start nodes. Increasing `max_iterations` may help.
+    ExhaustedIterations {
+        /// No. of start nodes tried
+        attempts: usize,
+        /// No. of partial convex sets grown beyond the start node but abandoned
+        abandoned: usize,
+    },
+    /// Tried every start node of the graph. Graph does not have a convex subset of size `ell_out`
+    /// reachable from any start node.
+    ExhaustedStartNodes {
+        /// No. of start nodes tried
+        attempts: usize,
+        /// No. of partial convex sets grown beyond the start node but abandoned
+        abandoned: usize,
+    },
+    /// Graph has fewer than `ell_out` gates that are not frozen. No start node was tried.
+    TooFewGates {
+        /// No. of gates in the graph that are not frozen
+        gates: usize,
+    },
+}
+
+/// Returns whether `start_node` has at least `count` successors, that is nodes reachable from it,
+/// on paths that avoid `removed_nodes` and `frozen_nodes`. Stops searching once `count` are found.
+fn has_successors(
+    graph: &Graph<usize, usize>,
+    start_node: NodeIndex,
+    count: usize,
+    removed_nodes: &HashSet<NodeIndex>,
+    frozen_nodes: &HashSet<NodeIndex>,
+) -> bool {
+    let mut visited = HashSet::default();
+    let mut stack = vec![start_node];
+    while let Some(node) = stack.pop() {
+        for succ in graph.neighbors_directed(node, Direction::Outgoing) {
+            if removed_nodes.contains(&succ) || frozen_nodes.contains(&succ) {
+                continue;
+            }
+            if visited.insert(succ) {
+                if visited.len() >= count {
+                    return true;
+                }
+                stack.push(succ);
+            }
+        }
+    }
+    visited.len() >= count
+}
+
+/// Finds a convex subset with `ell_out` nodes that contains none of `frozen_nodes`. Returns start
+/// node, convex subset, and no. of start nodes tried. Start nodes are sampled once and split into
+/// a shard per thread. Refer to [`ConvexSearchOptions::prioritize_start_nodes`] for the order a
+/// shard is tried in. `ell_out` must be at least 1.
+// Public for benches only
+#[doc(hidden)]
+#[allow(clippy::too_many_arguments)]
+pub fn find_convex_fast<R: Send + Sync + RngCore + SeedableRng>(
     graph: &Graph<usize, usize>,
     level: &[usize],
     ell_out: usize,
     max_iterations: usize,
+    options: &ConvexSearchOptions,
     rng: &mut R,
     removed_nodes: &HashSet<NodeIndex>,
-) -> Option<(NodeIndex, HashSet<NodeIndex>)> {
-    let max_iterations = max_iterations / current_num_threads();
+    frozen_nodes: &HashSet<NodeIndex>,
+) -> Result<(NodeIndex, HashSet<NodeIndex>, usize), ConvexSearchFailure> {
+    assert!(ell_out > 0, "Convex subsets must have at least 1 node");
 
-    let found = AtomicBool::new(false);
+    // Sample start nodes once and give each thread a disjoint shard of them
+    let mut start_nodes = graph
+        .node_indices()
+        .filter(|node| !removed_nodes.contains(node) && !frozen_nodes.contains(node))
+        .collect_vec();
+    if start_nodes.len() < ell_out {
+        return Err(ConvexSearchFailure::TooFewGates {
+            gates: start_nodes.len(),
+        });
+    }
+    let exhausts_start_nodes = start_nodes.len() <= max_iterations;
+    let (start_nodes, _) = start_nodes.partial_shuffle(rng, max_iterations);
+    let shard_size = start_nodes.len().div_ceil(search_threads()).max(1);
 
-    (0..current_num_threads())
-        .map(|_| R::from_rng(&mut *rng).unwrap())
-        .par_bridge()
-        .find_map_any(|mut rng| {
+    let found = AtomicBool::new(false);
+    let attempts = AtomicUsize::new(0);
+    let abandoned = AtomicUsize::new(0);
+
+    let convex_set = find_map_any(
+        start_nodes
+            .chunks(shard_size)
+            .map(|shard| (shard, R::from_rng(&mut *rng).unwrap())),
+        |(shard, mut rng)| {
             let epoch_size = rng.gen_range(5..10);
             let mut t = Duration::default();
             let mut curr_iter = 0;
             let mut return_set = None;
-            for start_node in (0..graph.node_count())
-                .filter(|index| !removed_nodes.contains(&NodeIndex::from(*index as u32)))
-                .collect_vec()
-                .choose_multiple(&mut rng, max_iterations)
-                .into_iter()
-                .map(|v| NodeIndex::from(*v as u32))
-            {
+            let mut shard = shard.iter().copied();
+            let mut deferred = vec![];
+            loop {
+                let start_node = match shard.next() {
+                    Some(node)
+                        if options.prioritize_start_nodes
+                            && !has_successors(
+                                graph,
+                                node,
+                                ell_out - 1,
+                                removed_nodes,
+                                frozen_nodes,
+                            ) =>
+                    {
+                        deferred.push(node);
+                        continue;
+                    }
+                    Some(node) => node,
+                    None => match deferred.pop() {
+                        Some(node) => node,
+                        None => break,
+                    },
+                };
                 assert!(
                     !removed_nodes.contains(&start_node),
                     "[find_convex_fast] Start node is in removed_nodes set"
@@ -1055,36 +1746,123 @@ fn find_convex_fast<R: Send + Sync + RngCore + SeedableRng>(
                     return None;
                 }
 
-                let mut convex_set = HashSet::new();
+                let mut convex_set = HashSet::default();
                 convex_set.insert(start_node);
 
                 let sttt = std::time::Instant::now();
-                let moment_of_truth = blah(ell_out, &mut convex_set, graph, &level, removed_nodes);
+                let moment_of_truth = blah(
+                    ell_out,
+                    &mut convex_set,
+                    graph,
+                    &level,
+                    removed_nodes,
+                    frozen_nodes,
+                    options,
+                );
                 t += sttt.elapsed();
 
                 if moment_of_truth {
                     assert!(convex_set.len() == ell_out);
                     return_set = Some((start_node, convex_set));
                     found.store(true, Relaxed);
+                    curr_iter += 1;
                     break;
                 } else {
+                    if convex_set.len() > 1 {
+                        abandoned.fetch_add(1, Relaxed);
+                    }
                     curr_iter += 1;
                 }
             }
 
+            attempts.fetch_add(curr_iter, Relaxed);
+
             #[cfg(feature = "trace")]
             log::trace!("Find convex subcircuit iterations: {curr_iter}");
 
             // println!("find_convex_fast_iter: {curr_iter}, blah: {t:?}");
 
             return_set
+        },
+    );
+
+    let attempts = attempts.into_inner();
+    convex_set
+        .map(|(start_node, convex_set)| (start_node, convex_set, attempts))
+        .ok_or_else(|| {
+            let abandoned = abandoned.into_inner();
+            if exhausts_start_nodes {
+                ConvexSearchFailure::ExhaustedStartNodes {
+                    attempts,
+                    abandoned,
+                }
+            } else {
+                ConvexSearchFailure::ExhaustedIterations {
+                    attempts,
+                    abandoned,
+                }
+            }
+        })
+}
+
+/// Returns histogram of sizes of convex sets grown by [`blah`] towards `max_size` nodes from
+/// `samples` random start nodes. Sizes below `max_size` are sets at which growing failed.
+///
+/// Use it to check how often a convex set of a given size is found in a circuit. `graph` must not
+/// have removed nodes, like the skeleton graph returned by [`prepare_circuit`].
+pub fn convex_size_histogram<R: RngCore>(
+    graph: &Graph<usize, usize>,
+    max_size: usize,
+    samples: usize,
+    options: &ConvexSearchOptions,
+    rng: &mut R,
+) -> BTreeMap<usize, usize> {
+    assert!(max_size > 0);
+    let mut histogram = BTreeMap::new();
+    if graph.node_count() == 0 {
+        return histogram;
+    }
+
+    let removed_nodes = HashSet::default();
+    let level = graph_level(
+        graph,
+        &graph_neighbors(graph, &mut HashSet::default()),
+        &removed_nodes,
+    );
+    let start_nodes = (0..samples)
+        .map(|_| NodeIndex::new(rng.gen_range(0..graph.node_count())))
+        .collect_vec();
+    let sizes = start_nodes
+        .par_iter()
+        .map(|start_node| {
+            let mut convex_set = HashSet::default();
+            convex_set.insert(*start_node);
+            blah(
+                max_size,
+                &mut convex_set,
+                graph,
+                &level,
+                &removed_nodes,
+                &HashSet::default(),
+                options,
+            );
+            convex_set.len()
         })
+        .collect::<Vec<_>>();
+    for size in sizes {
+        *histogram.entry(size).or_default() += 1;
+    }
+    histogram
 }
 
-fn circuit_to_collision_sets<G: Gate>(circuit: &Circuit<G>) -> Vec<HashSet<usize>> {
+/// Returns set of collisions, as gate indices, of every gate of the circuit. Gate i collides with
+/// gate j > i if they collide and no gate k, i < k < j, collides with both.
+// Public for benches only
+#[doc(hidden)]
+pub fn circuit_to_collision_sets<G: Gate>(circuit: &Circuit<G>) -> Vec<HashSet<usize>> {
     let mut all_collision_sets = Vec::with_capacity(circuit.gates().len());
     for (i, gi) in circuit.gates().iter().enumerate() {
-        let mut collision_set_i = HashSet::new();
+        let mut collision_set_i = HashSet::default();
         for (j, gj) in (circuit.gates().iter().enumerate()).skip(i + 1) {
             if gi.check_collision(gj) {
                 collision_set_i.insert(j);
@@ -1111,9 +1889,10 @@ fn circuit_to_collision_sets<G: Gate>(circuit: &Circuit<G>) -> Vec<HashSet<usize
     return all_collision_sets;
 }
 
-pub fn prepare_circuit<G: Gate>(
-    circuit: &Circuit<G>,
-) -> (
+/// Skeleton graph and caches returned by [`prepare_circuit`]: direct connections, direct incoming
+/// connections, skeleton graph, gate id to node index map, gate map, graph neighbours, active
+/// edges with gate ids, and latest gate id
+pub type PreparedCircuit<G> = (
     HashMap<usize, HashSet<usize>>,
     HashMap<usize, HashSet<usize>>,
     Graph<usize, usize>,
@@ -1122,14 +1901,16 @@ pub fn prepare_circuit<G: Gate>(
     Vec<[HashSet<NodeIndex>; 2]>,
     HashSet<(usize, usize)>,
     usize,
-)
+);
+
+pub fn prepare_circuit<G: Gate>(circuit: &Circuit<G>) -> PreparedCircuit<G>
 where
     G: Clone,
 {
     let mut direct_connections_outgoing = Vec::with_capacity(circuit.gates().len());
 
     for (i, gi) in circuit.gates().iter().enumerate() {
-        let mut direct_collisions_i = HashSet::new();
+        let mut direct_collisions_i = HashSet::default();
         for (j, gj) in (circuit.gates().iter().enumerate()).skip(i + 1) {
             if gi.check_collision(gj) {
                 direct_collisions_i.insert(j);
@@ -1138,9 +1919,9 @@ where
         direct_connections_outgoing.push(direct_collisions_i);
     }
 
-    let mut direct_incoming_connections_map = HashMap::new();
+    let mut direct_incoming_connections_map = HashMap::default();
     for (i, gi) in circuit.gates().iter().enumerate().rev() {
-        let mut direct_incoming_conn_i = HashSet::new();
+        let mut direct_incoming_conn_i = HashSet::default();
         for (j, gj) in (circuit.gates().iter().enumerate().rev()).skip(circuit.gates().len() - i) {
             if gi.check_collision(gj) {
                 direct_incoming_conn_i.insert(gj.id());
@@ -1171,14 +1952,14 @@ where
     }
 
     // direct connections map
-    let mut direct_connections_map = HashMap::new();
+    let mut direct_connections_map = HashMap::default();
     for (index, connections) in direct_connections_outgoing.into_iter().enumerate() {
         let conn = HashSet::from_iter(connections.iter().map(|i| circuit.gates()[*i].id()));
         direct_connections_map.insert(circuit.gates()[index].id(), conn);
     }
 
     // edge collection
-    let mut active_edges_with_gateids = HashSet::new();
+    let mut active_edges_with_gateids = HashSet::default();
 
     // create skeleton graph with transitive connections
     let mut skeleton = Graph::<usize, usize>::new();
@@ -1201,7 +1982,7 @@ where
         });
 
     // create gate id to gate map
-    let mut gate_map = HashMap::new();
+    let mut gate_map = HashMap::default();
     let mut latest_id = 0;
     for gate in circuit.gates() {
         latest_id = std::cmp::max(latest_id, gate.id());
@@ -1209,12 +1990,12 @@ where
     }
 
     // create gate id to node index map
-    let mut gate_id_to_node_index_map = HashMap::new();
+    let mut gate_id_to_node_index_map = HashMap::default();
     for node in skeleton.node_indices() {
         gate_id_to_node_index_map.insert(*skeleton.node_weight(node).unwrap(), node);
     }
 
-    let graph_neighbours = graph_neighbors(&skeleton, &HashSet::new());
+    let graph_neighbours = graph_neighbors(&skeleton, &HashSet::default());
 
     (
         direct_connections_map,
@@ -1228,6 +2009,187 @@ where
     )
 }
 
+/// Checks that the skeleton graph returned by [`prepare_circuit`] is acyclic and consistent with
+/// the caches and the gates. That is, every node has a gate in `gate_map`, every gate id maps to
+/// its node, `graph_neighbours` matches the edges of the graph, and the direct connections have an
+/// entry for every gate. Every edge and every direct connection must be between gates that
+/// collide, direct connections must go from lower to higher level of the graph, and edges must be
+/// direct connections recorded in the active edges.
+///
+/// Returns description of the first inconsistency found.
+pub fn validate_prepared<G: Gate>(prepared: &PreparedCircuit<G>) -> Result<(), String> {
+    let (
+        direct_connections,
+        direct_incoming_connections,
+        skeleton_graph,
+        gate_id_to_node_index_map,
+        gate_map,
+        graph_neighbours,
+        active_edges_with_gateids,
+        latest_id,
+    ) = prepared;
+
+    if let Err(cycle) = toposort(skeleton_graph, None) {
+        return Err(format!(
+            "Skeleton graph has a cycle through node {:?}",
+            cycle.node_id()
+        ));
+    }
+
+    if graph_neighbours.len() != skeleton_graph.node_count() {
+        return Err(format!(
+            "Graph neighbours has {} entries but skeleton graph has {} nodes",
+            graph_neighbours.len(),
+            skeleton_graph.node_count()
+        ));
+    }
+
+    for node in skeleton_graph.node_indices() {
+        let id = skeleton_graph[node];
+        if !gate_map.contains_key(&id) {
+            return Err(format!(
+                "Node {node:?} has gate id {id} missing in gate map"
+            ));
+        }
+        if gate_id_to_node_index_map.get(&id) != Some(&node) {
+            return Err(format!(
+                "Gate id {id} maps to {:?} instead of node {node:?}",
+                gate_id_to_node_index_map.get(&id)
+            ));
+        }
+        for (neighbours, direction) in graph_neighbours[node.index()]
+            .iter()
+            .zip([Direction::Incoming, Direction::Outgoing])
+        {
+            let expected = skeleton_graph
+                .neighbors_directed(node, direction)
+                .collect::<HashSet<_>>();
+            if *neighbours != expected {
+                return Err(format!(
+                    "{direction:?} graph neighbours of node {node:?} do not match skeleton graph"
+                ));
+            }
+        }
+    }
+
+    for (id, gate) in gate_map.iter() {
+        if gate.id() != *id {
+            return Err(format!(
+                "Gate map has gate with id {} at key {id}",
+                gate.id()
+            ));
+        }
+        if !gate_id_to_node_index_map.contains_key(id) {
+            return Err(format!("Gate id {id} has no node in skeleton graph"));
+        }
+        if !direct_connections.contains_key(id) || !direct_incoming_connections.contains_key(id) {
+            return Err(format!("Gate id {id} has no direct connections"));
+        }
+        if id > latest_id {
+            return Err(format!("Gate id {id} exceeds latest id {latest_id}"));
+        }
+    }
+
+    // Edges only order gates that collide
+    for edge in skeleton_graph.edge_references() {
+        let (source, target) = (skeleton_graph[edge.source()], skeleton_graph[edge.target()]);
+        if !gate_map[&source].check_collision(&gate_map[&target]) {
+            return Err(format!(
+                "Edge from gate {source} to gate {target} joins gates that don't collide"
+            ));
+        }
+        if !direct_connections[&source].contains(&target) {
+            return Err(format!(
+                "Edge from gate {source} to gate {target} is not a direct connection"
+            ));
+        }
+        if !active_edges_with_gateids.contains(&(source, target)) {
+            return Err(format!(
+                "Edge from gate {source} to gate {target} is not an active edge"
+            ));
+        }
+    }
+    if active_edges_with_gateids.len() != skeleton_graph.edge_count() {
+        return Err(format!(
+            "{} active edges but skeleton graph has {} edges",
+            active_edges_with_gateids.len(),
+            skeleton_graph.edge_count()
+        ));
+    }
+
+    // Colliding gates don't commute, so a direct connection must be implied by a path in the graph
+    let level = graph_level(skeleton_graph, graph_neighbours, &HashSet::default());
+    for (id, connections) in direct_connections.iter() {
+        for connection in connections {
+            let Some(gate) = gate_map.get(connection) else {
+                return Err(format!(
+                    "Gate {id} is directly connected to gate {connection} missing in gate map"
+                ));
+            };
+            if !gate_map[id].check_collision(gate) {
+                return Err(format!(
+                    "Gate {id} is directly connected to gate {connection} that it doesn't collide with"
+                ));
+            }
+            if level[gate_id_to_node_index_map[id].index()]
+                >= level[gate_id_to_node_index_map[connection].index()]
+            {
+                return Err(format!(
+                    "Gate {id} is directly connected to gate {connection} but doesn't precede it in skeleton graph"
+                ));
+            }
+            if !direct_incoming_connections[connection].contains(id) {
+                return Err(format!(
+                    "Gate {id} is directly connected to gate {connection} but not the other way"
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Inconsistency between gate map and skeleton graph found by [`check_gate_map_consistency`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GateMapInconsistency {
+    /// Live node has gate id `id` that is not in the gate map
+    MissingGate { id: usize },
+    /// Gate map, or circuit passed to [`rebuild_maps`], has gate id `id` that is not the weight of
+    /// any live node
+    StaleGate { id: usize },
+    /// Gate id `id` is greater than the latest id. New gates could reuse the id.
+    IdAfterLatest { id: usize, latest_id: usize },
+}
+
+/// Checks that every live node of the skeleton graph has a gate in `gate_map`, every gate in
+/// `gate_map` belongs to a live node, and no gate id exceeds `latest_id`.
+///
+/// Returns the first inconsistency found.
+pub fn check_gate_map_consistency<G>(
+    skeleton_graph: &Graph<usize, usize>,
+    gate_map: &HashMap<usize, G>,
+    removed_nodes: &HashSet<NodeIndex>,
+    latest_id: usize,
+) -> Result<(), GateMapInconsistency> {
+    let live_ids = skeleton_graph
+        .node_indices()
+        .filter(|node| !removed_nodes.contains(node))
+        .map(|node| skeleton_graph[node])
+        .collect::<HashSet<_>>();
+
+    if let Some(id) = live_ids.iter().find(|id| !gate_map.contains_key(*id)) {
+        return Err(GateMapInconsistency::MissingGate { id: *id });
+    }
+    if let Some(id) = gate_map.keys().find(|id| !live_ids.contains(*id)) {
+        return Err(GateMapInconsistency::StaleGate { id: *id });
+    }
+    if let Some(id) = gate_map.keys().find(|id| **id > latest_id) {
+        return Err(GateMapInconsistency::IdAfterLatest { id: *id, latest_id });
+    }
+
+    Ok(())
+}
+
 pub fn dfs_within_convex_set(
     curr_node: NodeIndex,
     convex_set: &HashSet<NodeIndex>,
@@ -1250,493 +2212,1424 @@ pub fn dfs_within_convex_set(
     top_sorted.push_front(curr_node);
 }
 
-/// Local mixing step
-///
-/// Returns false if mixing step is not successuful which may happen if one of the following is true
-/// - Elements in convex subset < \ell^out
-/// - \omega^out <= 3
-/// - Not able to find repalcement circuit after exhausting max_replacement_iterations iterations
-pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
-    skeleton_graph: &mut Graph<usize, usize>,
-    ell_in: usize,
-    ell_out: usize,
-    n: u8,
-    direct_connections: &mut HashMap<usize, HashSet<usize>>,
-    direct_incoming_connections: &mut HashMap<usize, HashSet<usize>>,
-    gate_map: &mut HashMap<usize, BaseGate<2, u8>>,
-    gate_id_to_node_index_map: &mut HashMap<usize, NodeIndex>,
-    graph_neighbours: &mut Vec<[HashSet<NodeIndex>; 2]>,
-    removed_nodes: &mut HashSet<NodeIndex>,
-    active_edges_with_gateids: &mut HashSet<(usize, usize)>,
-    latest_id: &mut usize,
-    max_replacement_iterations: usize,
-    max_convex_iterations: usize,
-    rng: &mut R,
-) -> bool {
-    assert!(ell_out <= ell_in);
+/// Statistics of a successful local mixing step
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct LocalMixingStepStats {
+    /// No. of start nodes tried to find convex subset C^out
+    pub convex_iterations: usize,
+    /// No. of candidate circuits sampled to find replacement C^in, summed over \ell^in retries
+    pub replacement_iterations: usize,
+    /// \ell^in of replacement C^in
+    pub ell_in: usize,
+    /// \omega^out, no. of active wires of C^out
+    pub active_wires: usize,
+}
 
-    let level = graph_level(skeleton_graph, graph_neighbours, &removed_nodes);
+/// Time spent in each part of local mixing steps, summed over successful and failed steps
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MixingTimings {
+    /// Levelling the skeleton graph and searching for convex subset C^out
+    pub convex_search: Duration,
+    /// Searching for replacement circuit C^in, including retries with larger \ell^in
+    pub replacement_search: Duration,
+    /// Replacing C^out with C^in in the skeleton graph and its caches
+    pub rewiring: Duration,
+    /// Equivalence checks of the obfuscated circuit
+    pub verification: Duration,
+}
 
-    let (cout_convex_start_node, cout_convex_subset) = timed!(
-        "Find convex subcircuit",
-        match find_convex_fast(
-            &skeleton_graph,
-            &level,
-            ell_out,
-            max_convex_iterations,
-            rng,
-            removed_nodes
-        ) {
-            Some((convex_start_node, convex_subset)) => (convex_start_node, convex_subset),
-            None => {
-                log::trace!("[returned false] Find convex subscircuit");
-                return false;
-            }
-        }
-    );
+/// Histograms of replacement searches of local mixing steps, found or not. Steps that don't find
+/// a convex subset C^out don't search for a replacement, hence are not tallied.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplacementHistograms {
+    /// No. of searches keyed by \omega^out. Large \omega^out makes replacement search slow.
+    pub active_wires: BTreeMap<usize, usize>,
+    /// No. of searches that found C^in keyed by the smallest power of 2 that is at least the no.
+    /// of candidates sampled, summed over retries with larger \ell^in
+    pub found_iterations: BTreeMap<usize, usize>,
+    /// No. of searches that found no C^in keyed the same as `found_iterations`. Shows whether
+    /// `max_replacement_iterations` cuts off many replacements.
+    pub failed_iterations: BTreeMap<usize, usize>,
+}
 
-    let mut convex_subset_top_sorted = VecDeque::new();
-    dfs_within_convex_set(
-        cout_convex_start_node,
-        &cout_convex_subset,
-        &skeleton_graph,
-        &mut HashSet::new(),
-        &mut convex_subset_top_sorted,
-    );
+impl ReplacementHistograms {
+    /// Tallies a search on C^out with `active_wires` that sampled `iterations` candidates
+    fn record(&mut self, active_wires: usize, iterations: usize, found: bool) {
+        *self.active_wires.entry(active_wires).or_default() += 1;
+        let iterations_histogram = if found {
+            &mut self.found_iterations
+        } else {
+            &mut self.failed_iterations
+        };
+        *iterations_histogram
+            .entry(iterations.next_power_of_two())
+            .or_default() += 1;
+    }
+}
 
-    // Convex subset sorted in topological order
-    let convex_subgraph_top_sorted_gate_ids = convex_subset_top_sorted
-        .iter()
-        .map(|node_index| skeleton_graph.node_weight(*node_index).unwrap());
+/// Progress of a local mixing step, successful or not, passed to the progress callback of
+/// [run_local_mixing]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StepProgress {
+    /// Step no. the step was run at
+    pub step: usize,
+    /// Whether the step replaced a convex subset, at least one with a batch
+    pub success: bool,
+    /// No. of gates of the obfuscated circuit after the step
+    pub gate_count: usize,
+    /// Time spent in the local mixing step, excluding equivalence check and checkpoint
+    pub elapsed: Duration,
+}
 
-    for node in convex_subset_top_sorted.iter() {
-        assert!(!removed_nodes.contains(node));
+/// Skeleton graph of a circuit being mixed and the caches that local mixing steps keep consistent
+/// with it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MixingState {
+    pub skeleton_graph: Graph<usize, usize>,
+    pub direct_connections: HashMap<usize, HashSet<usize>>,
+    pub direct_incoming_connections: HashMap<usize, HashSet<usize>>,
+    pub gate_map: HashMap<usize, BaseGate<2, u8>>,
+    pub gate_id_to_node_index_map: HashMap<usize, NodeIndex>,
+    pub graph_neighbours: Vec<[HashSet<NodeIndex>; 2]>,
+    pub removed_nodes: HashSet<NodeIndex>,
+    /// Nodes that are never part of C^out, hence never removed
+    pub frozen_nodes: HashSet<NodeIndex>,
+    pub active_edges_with_gateids: HashSet<(usize, usize)>,
+    pub latest_id: usize,
+    /// No. of wires of the circuit
+    pub n: u8,
+    /// Output of [`graph_level`] cached across steps. Recomputed by the next step if it doesn't
+    /// have a level for every node of the graph, for example when it is empty.
+    #[serde(skip)]
+    pub level: Vec<usize>,
+}
+
+impl MixingState {
+    pub fn new(circuit: &Circuit<BaseGate<2, u8>>) -> Self {
+        Self::from_prepared(prepare_circuit(circuit), circuit.n() as u8)
     }
 
-    #[cfg(feature = "trace")]
-    log::trace!(
-        "Convex subset gate ids: {:?}",
-        &convex_subgraph_top_sorted_gate_ids.clone().collect_vec()
-    );
+    /// State of a circuit with `n` wires from the output of [`prepare_circuit`]
+    pub fn from_prepared(prepared: PreparedCircuit<BaseGate<2, u8>>, n: u8) -> Self {
+        let (
+            direct_connections,
+            direct_incoming_connections,
+            skeleton_graph,
+            gate_id_to_node_index_map,
+            gate_map,
+            graph_neighbours,
+            active_edges_with_gateids,
+            latest_id,
+        ) = prepared;
+        MixingState {
+            skeleton_graph,
+            direct_connections,
+            direct_incoming_connections,
+            gate_map,
+            gate_id_to_node_index_map,
+            graph_neighbours,
+            removed_nodes: HashSet::default(),
+            frozen_nodes: HashSet::default(),
+            active_edges_with_gateids,
+            latest_id,
+            n,
+            level: vec![],
+        }
+    }
 
-    let convex_subgraph_gates =
-        convex_subgraph_top_sorted_gate_ids.map(|node| gate_map.get(node).unwrap());
+    /// Freezes gates with ids `frozen_gates`, so that they are never removed. Returns the id of the
+    /// first gate that is not in the circuit as an error.
+    pub fn freeze_gates<'a>(
+        &mut self,
+        frozen_gates: impl IntoIterator<Item = &'a usize>,
+    ) -> Result<(), usize> {
+        for id in frozen_gates {
+            let node = self.gate_id_to_node_index_map.get(id).ok_or(*id)?;
+            self.frozen_nodes.insert(*node);
+        }
+        Ok(())
+    }
 
-    // Set of active wires in convex subgraph
-    let mut omega_out = HashSet::new();
-    convex_subgraph_gates.clone().for_each(|g| {
-        omega_out.insert(g.target());
-        for wire in g.controls().iter() {
-            omega_out.insert(*wire);
+    /// Recomputes `level` if it doesn't have a level for every node of the graph
+    pub fn refresh_level(&mut self) {
+        if self.level.len() != self.skeleton_graph.node_count() {
+            self.level = graph_level(
+                &self.skeleton_graph,
+                &self.graph_neighbours,
+                &self.removed_nodes,
+            );
         }
-    });
+    }
 
-    // return false if omega^out <= 3 because finding a replacement is apparently not possilbe.
-    // if omega_out.len() <= 3 {
-    //     log::trace!("[returned false] <= active wires");
-    //     return false;
-    // }
+    /// No. of gates of the circuit
+    pub fn gate_count(&self) -> usize {
+        self.skeleton_graph.node_count() - self.removed_nodes.len()
+    }
 
-    // Map from old wires to new wires in C^out
-    let mut old_to_new_map = HashMap::new();
-    let mut new_to_old_map = HashMap::new();
-    for (new_index, old_index) in omega_out.iter().enumerate() {
-        old_to_new_map.insert(*old_index, new_index as u8);
-        new_to_old_map.insert(new_index as u8, *old_index);
-    }
-    let c_out_gates = convex_subgraph_gates
-        .clone()
-        .enumerate()
-        .map(|(index, gate)| {
-            let old_controls = gate.controls();
-            let mut new_controls = [0u8; 2];
-            new_controls[0] = *old_to_new_map.get(&old_controls[0]).unwrap();
-            new_controls[1] = *old_to_new_map.get(&old_controls[1]).unwrap();
-            BaseGate::new(
-                index,
-                *old_to_new_map.get(&gate.target()).unwrap(),
-                new_controls,
-                gate.control_func(),
-            )
-        })
-        .collect_vec();
+    /// Circuit of the current skeleton graph
+    pub fn circuit(&self) -> Circuit<BaseGate<2, u8>> {
+        let top_sorted_nodes = toposort_with_cached_graph_neighbours(
+            &self.skeleton_graph,
+            &self.graph_neighbours,
+            &self.removed_nodes,
+        );
+        Circuit::from_top_sorted_nodes(
+            &top_sorted_nodes,
+            &self.skeleton_graph,
+            &self.gate_map,
+            self.n as usize,
+        )
+    }
 
-    let c_out = Circuit::new(c_out_gates, omega_out.len());
+    /// Circuit of the current skeleton graph like [`Self::circuit`], with gates of the same level
+    /// in random order. Skeleton graph of the circuit has the same gates and collisions, with
+    /// nodes and the edges of every node in a new order.
+    pub fn shuffled_circuit<R: RngCore>(&self, rng: &mut R) -> Circuit<BaseGate<2, u8>> {
+        let level = graph_level(
+            &self.skeleton_graph,
+            &self.graph_neighbours,
+            &self.removed_nodes,
+        );
+        let mut top_sorted_nodes = self
+            .skeleton_graph
+            .node_indices()
+            .filter(|node| !self.removed_nodes.contains(node))
+            .collect_vec();
+        top_sorted_nodes.shuffle(rng);
+        top_sorted_nodes.sort_by_key(|node| level[node.index()]);
+        Circuit::from_top_sorted_nodes(
+            &top_sorted_nodes,
+            &self.skeleton_graph,
+            &self.gate_map,
+            self.n as usize,
+        )
+    }
+}
 
-    let c_in_dash = timed!(
-        "Find replacement circuit",
-        match find_replacement_circuit_fast(
-            &c_out,
-            ell_in,
-            c_out.n(),
-            max_replacement_iterations,
-            rng,
-        ) {
-            Some(c_in_dash) => c_in_dash,
-            None => {
-                log::trace!("[returned false] Find replacement circuit");
-                return false;
-            }
-        }
-    );
+/// Sizes and search limits of a local mixing step
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MixingStepParams {
+    /// \ell^out, no. of gates of C^out
+    pub ell_out: usize,
+    /// \ell^in, no. of gates of C^in
+    pub ell_in: usize,
+    /// Replacement circuit search is retried with \ell^in + 1 up to this after a failure
+    pub max_ell_in: usize,
+    pub max_convex_iterations: usize,
+    pub max_replacement_iterations: usize,
+    pub convex_search: ConvexSearchOptions,
+    pub replacement_search: ReplacementSearchOptions,
+}
 
-    let c_in = Circuit::new(
-        c_in_dash
-            .gates()
-            .iter()
-            .map(|g| {
-                *latest_id += 1;
+/// Settings of a step of [`run_local_mixing`]
+#[derive(Clone, Copy, Debug)]
+pub struct MixingOptions {
+    /// `max_replacement_iterations` is the initial value of `replacement_iterations_schedule`
+    pub step_params: MixingStepParams,
+    pub replacement_iterations_schedule: ReplacementIterationsSchedule,
+    /// Step no. of the step and total no. of steps of the run, for the schedule and logs
+    pub step: usize,
+    pub total_steps: usize,
+    /// Whether to check equivalence and checkpoint after a successful step
+    pub to_checkpoint: bool,
+    pub probabilitic_eq_check_iterations: usize,
+    /// Besides checkpoints, mixed circuit is checked every `verify_every` steps. 0 disables the
+    /// intermediate checks, 1 checks after every step.
+    pub verify_every: usize,
+    /// No. of convex subsets tried at once. More than 1 runs [`local_mixing_batch`] instead of
+    /// [`local_mixing_step`] and checks the mixed circuit after every batch, regardless of
+    /// `verify_every`.
+    pub batch_regions: usize,
+    /// Whether every step replaces C^out with a circuit of the same no. of gates. Debug builds
+    /// assert that steps don't change the gate count.
+    pub preserve_gate_count: bool,
+}
 
-                let new_controls = g.controls();
-                // assert!(new_controls[2] == D::try_from(c_in_dash.n).unwrap());
-                let mut old_controls = [0u8; 2];
-                old_controls[0] = *new_to_old_map.get(&new_controls[0]).unwrap();
-                old_controls[1] = *new_to_old_map.get(&new_controls[1]).unwrap();
-                BaseGate::<2, _>::new(
-                    *latest_id,
-                    *new_to_old_map.get(&g.target()).unwrap(),
-                    old_controls,
-                    g.control_func(),
-                )
-            })
-            .collect(),
-        n.into(),
-    );
+/// Outputs of a step of [`run_local_mixing`] besides the mixed circuit. Every output is optional.
+#[derive(Default)]
+pub struct MixingObservers<'a> {
+    /// Skeleton graph is dumped here if the equivalence check fails
+    pub skeleton_dump_path: Option<&'a Path>,
+    /// State before the step is dumped here if the step panics or fails the equivalence check,
+    /// see [`MixingStepSnapshot`]
+    pub step_snapshot_path: Option<&'a Path>,
+    /// Metrics of every successful step are appended here, see [`StepMetrics`]
+    pub metrics_csv_path: Option<&'a Path>,
+    /// Metrics of the circuit at every checkpoint are appended here, see [`CheckpointMetrics`]
+    pub checkpoint_csv_path: Option<&'a Path>,
+    /// Every successful step is appended here, see [`MixingTraceRecord`]. Records are appended
+    /// before the step is verified, so steps after the last checkpoint of an interrupted run are
+    /// traced too.
+    pub mixing_trace_path: Option<&'a Path>,
+    /// Replacement searches of every step, successful or not, are tallied here
+    pub replacement_histograms: Option<&'a mut ReplacementHistograms>,
+    /// Time spent in the step is added here
+    pub timings: Option<&'a mut MixingTimings>,
+    /// Called with the progress of every step, successful or not
+    pub progress: Option<&'a mut dyn FnMut(StepProgress)>,
+}
 
-    #[cfg(feature = "trace")]
-    {
-        log::trace!("Old to new wires map: {:?}", &old_to_new_map);
-        log::trace!("New to old wires map: {:?}", &new_to_old_map);
-        log::trace!("@@@@ C^out @@@@ {}", &c_out);
-        log::trace!("@@@@ C^in' @@@@ {}", &c_in_dash);
-        // log::trace!("@@@@ C^in @@@@ {}", &c_in);
-        let collision_sets_c_in = circuit_to_collision_sets(&c_in_dash);
-        log::trace!("C^in collision sets: {:?}", &collision_sets_c_in);
+/// Metrics of a successful local mixing step written as a row of metrics CSV
+struct StepMetrics {
+    step: usize,
+    ell_out: usize,
+    ell_in: usize,
+    convex_iterations: usize,
+    replacement_iterations: usize,
+    step_time: Duration,
+    gate_count: usize,
+    depth: usize,
+}
+
+impl StepMetrics {
+    const CSV_HEADER: &'static str = "step,ell_out,ell_in,convex_iterations,replacement_iterations,step_time_ms,gate_count,depth";
+
+    /// Appends metrics as a row to CSV file at `path`. Writes header first if file is empty.
+    fn append_to_csv(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = open_csv_for_append(path, Self::CSV_HEADER)?;
+        writeln!(
+            file,
+            "{},{},{},{},{},{:.3},{},{}",
+            self.step,
+            self.ell_out,
+            self.ell_in,
+            self.convex_iterations,
+            self.replacement_iterations,
+            self.step_time.as_secs_f64() * 1000.0,
+            self.gate_count,
+            self.depth
+        )
     }
+}
 
-    // #### Replace C^out with C^in #### //
+/// No. of random inputs used to estimate the avalanche score written to checkpoint CSV
+const CHECKPOINT_CSV_AVALANCHE_ITERATIONS: usize = 100;
 
-    // Find all predecessors and successors of subgrpah C^out
-    let mut c_out_imm_predecessors = HashSet::new();
-    let mut c_out_imm_successors = HashSet::new();
-    // First find all immediate predecessors and successors
-    for node in cout_convex_subset.iter() {
-        for pred in skeleton_graph
-            .neighbors_directed(node.clone(), Direction::Incoming)
-            .filter(|node| !removed_nodes.contains(node))
-        {
-            if !cout_convex_subset.contains(&pred) {
-                c_out_imm_predecessors.insert(pred);
-            }
-        }
-        for succ in skeleton_graph
-            .neighbors_directed(node.clone(), Direction::Outgoing)
-            .filter(|node| !removed_nodes.contains(node))
-        {
-            if !cout_convex_subset.contains(&succ) {
-                c_out_imm_successors.insert(succ);
-            }
+/// Metrics of the obfuscated circuit at a checkpoint written as a row of checkpoint CSV. Only has
+/// the columns needed to plot convergence.
+struct CheckpointMetrics {
+    step: usize,
+    gate_count: usize,
+    depth: usize,
+    avalanche_score: f64,
+}
+
+impl CheckpointMetrics {
+    const CSV_HEADER: &'static str = "step,gate_count,depth,avalanche_score";
+
+    /// Computes metrics of `circuit` at `step`. Avalanche score is estimated with an RNG seeded
+    /// with `step` so that the mixing RNG is not affected.
+    fn new(step: usize, circuit: &Circuit<BaseGate<2, u8>>) -> Self {
+        Self {
+            step,
+            gate_count: circuit.num_gates(),
+            depth: longest_chain(circuit),
+            avalanche_score: avalanche_score(
+                circuit,
+                CHECKPOINT_CSV_AVALANCHE_ITERATIONS,
+                &mut ChaCha8Rng::seed_from_u64(step as u64),
+            ),
         }
     }
 
-    // add C^in gates to the graph
-    let cin_gates = c_in.gates();
-    let cin_nodes = cin_gates
-        .iter()
-        .map(|gate| {
-            let node_index = skeleton_graph.add_node(gate.id());
-            assert!(gate_map.insert(gate.id(), gate.clone()).is_none());
-            assert!(gate_id_to_node_index_map
-                .insert(gate.id(), node_index)
-                .is_none());
-            node_index
-        })
-        .collect_vec();
+    /// Appends metrics as a row to CSV file at `path`. Writes header first if file is empty.
+    fn append_to_csv(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = open_csv_for_append(path, Self::CSV_HEADER)?;
+        writeln!(
+            file,
+            "{},{},{},{:.4}",
+            self.step, self.gate_count, self.depth, self.avalanche_score
+        )
+    }
+}
 
-    let cout_predecessors = timed!(
-        "Find all predecessors",
-        find_all_predecessors(&cout_convex_subset, &skeleton_graph, removed_nodes)
-    );
-    let cout_successors = timed!(
-        "Find all successors",
-        find_all_successors(&cout_convex_subset, &skeleton_graph, removed_nodes)
-    );
+/// Opens CSV file at `path` for appending rows. Writes `header` first if file is empty.
+fn open_csv_for_append(path: &Path, header: &str) -> std::io::Result<std::fs::File> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", header)?;
+    }
+    Ok(file)
+}
 
-    assert!(cout_predecessors.is_disjoint(&cout_successors));
-    assert!(cout_predecessors.is_disjoint(&removed_nodes));
-    assert!(cout_successors.is_disjoint(&removed_nodes));
+/// Successful local mixing step appended as a JSON line to the mixing trace by
+/// [`run_local_mixing`]. Removing `removed_gates` from and adding `added_gates` to the circuit for
+/// every record in order reconstructs the gates of the obfuscated circuit from the original
+/// circuit.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MixingTraceRecord {
+    /// Step no. the step was run at
+    pub step: usize,
+    /// Ids of gates of C^out, in ascending order
+    pub removed_gates: Vec<usize>,
+    /// Gates of C^in, in the order they are applied
+    pub added_gates: Vec<BaseGate<2, u8>>,
+    /// \omega^out, no. of active wires of C^out
+    pub active_wires: usize,
+    /// Edges `(from gate id, to gate id)` of the skeleton graph added by the step, that is every
+    /// edge from or to a gate of C^in, in ascending order. For a step of a batch, edges are taken
+    /// after the whole batch, leaving out edges to gates added by later steps of the batch.
+    pub new_edges: Vec<(usize, usize)>,
+}
 
-    let top_sorted_predecessors = {
-        let mut top_sorted_predecessors = Vec::from_iter(cout_predecessors.iter().copied());
-        top_sorted_predecessors.par_sort_by(|a, b| level[a.index()].cmp(&level[b.index()]));
-        top_sorted_predecessors
-    };
-    let top_sorted_successors = {
-        let mut top_sorted_successorss = Vec::from_iter(cout_successors.iter().copied());
-        top_sorted_successorss.par_sort_by(|a, b| level[a.index()].cmp(&level[b.index()]));
-        top_sorted_successorss
-    };
-    let top_sorted_outsiders = {
-        let insider = HashSet::<_>::from_iter(
-            chain![
-                &cin_nodes,
-                &cout_convex_subset,
-                &cout_successors,
-                &cout_predecessors,
-                removed_nodes.deref()
-            ]
-            .copied(),
-        );
-        let mut top_sorted_outsiders = skeleton_graph
-            .node_indices()
-            .par_bridge()
-            .filter(|n| !insider.contains(n))
-            .collect::<Vec<_>>();
-        top_sorted_outsiders.par_sort_by(|a, b| level[a.index()].cmp(&level[b.index()]));
-        top_sorted_outsiders
-    };
+impl MixingTraceRecord {
+    /// Appends record as a JSON line to file at `path`
+    pub fn append_to_jsonl(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{}", serde_json::to_string(self).unwrap())
+    }
+}
 
-    log::trace!(
-        "@@@ No. of predecessors: {}@@@",
-        top_sorted_predecessors.len()
+/// Local mixing step
+///
+/// Returns None if mixing step is not successuful which may happen if one of the following is true
+/// - Elements in convex subset < \ell^out
+/// - \omega^out <= 3
+/// - Not able to find repalcement circuit after exhausting max_replacement_iterations iterations
+///   for every \ell^in in `[ell_in, max_ell_in]`
+///
+/// If replacement circuit search fails with \ell^in, search for replacement of the same C^out is
+/// retried with \ell^in + 1 until \ell^in = `max_ell_in`. `max_ell_in` caps the number of gates
+/// a single step can add to the circuit. Set `max_ell_in = ell_in` to never retry.
+///
+/// `state.level` is updated for the nodes downstream of the replaced subset. Time spent in the
+/// step is added to `timings`. Replacement search, if the step gets to it, is tallied in
+/// `histograms` if given.
+///
+/// If `cout_gate_ids` is given, ids of the gates of C^out replaced by a successful step are pushed
+/// to it in ascending order.
+pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
+    state: &mut MixingState,
+    params: &MixingStepParams,
+    rng: &mut R,
+    timings: &mut MixingTimings,
+    histograms: Option<&mut ReplacementHistograms>,
+    cout_gate_ids: Option<&mut Vec<usize>>,
+) -> Option<LocalMixingStepStats> {
+    assert!(params.ell_out <= params.ell_in);
+    assert!(params.ell_in <= params.max_ell_in);
+
+    let convex_search_start = std::time::Instant::now();
+    state.refresh_level();
+
+    let convex = timed!(
+        "Find convex subcircuit",
+        find_convex_fast(
+            &state.skeleton_graph,
+            &state.level,
+            params.ell_out,
+            params.max_convex_iterations,
+            &params.convex_search,
+            rng,
+            &state.removed_nodes,
+            &state.frozen_nodes
+        )
     );
-    log::trace!("@@@ No. of successors: {}@@@", top_sorted_successors.len());
+    timings.convex_search += convex_search_start.elapsed();
+    let (cout_convex_start_node, cout_convex_subset, convex_iterations) = match convex {
+        Ok(convex) => convex,
+        Err(_failure) => {
+            #[cfg(feature = "trace")]
+            log::trace!("Find convex subcircuit failed: {:?}", _failure);
+            log::trace!("[returned false] Find convex subscircuit");
+            return None;
+        }
+    };
 
-    #[cfg(feature = "trace")]
-    {
-        log::trace!(
-            "C^in gate ids: {:?}",
-            cin_gates.iter().map(|g| g.id()).collect_vec()
-        );
+    // Node weights are kept when nodes are removed, but ids are collected before for clarity
+    let cout_ids = cout_gate_ids.is_some().then(|| {
+        cout_convex_subset
+            .iter()
+            .map(|node| state.skeleton_graph[*node])
+            .sorted()
+            .collect_vec()
+    });
 
-        log::trace!(
-            "Top sorted predecessors: {:?}",
-            node_indices_to_gate_ids(top_sorted_predecessors.iter(), skeleton_graph)
-        );
-        log::trace!(
-            "Top sorted successors: {:?}",
-            node_indices_to_gate_ids(top_sorted_successors.iter(), skeleton_graph)
-        );
-        log::trace!(
-            "Top sorted outsiders: {:?}",
-            node_indices_to_gate_ids(top_sorted_outsiders.iter(), skeleton_graph)
-        );
+    let stats = replace_convex_set(
+        state,
+        cout_convex_start_node,
+        &cout_convex_subset,
+        params,
+        rng,
+        timings,
+        histograms,
+    )
+    .map(|stats| LocalMixingStepStats {
+        convex_iterations,
+        ..stats
+    })?;
+    if let (Some(cout_gate_ids), Some(cout_ids)) = (cout_gate_ids, cout_ids) {
+        cout_gate_ids.extend(cout_ids);
     }
 
-    let mut new_edges = HashSet::new();
-    let mut remove_edges = HashSet::new();
-
-    // Successors
-    timed!("Process successors", {
-        // create blank entries in direct incoming connections for C^in nodes
-        cin_gates.iter().for_each(|g| {
-            direct_incoming_connections.insert(g.id(), HashSet::new());
-        });
+    Some(stats)
+}
 
-        for i in 0..cin_gates.len() {
-            let mut direct_collisions = HashSet::new();
+/// Runs up to `regions` local mixing steps on convex subsets that are far apart in the skeleton
+/// graph, searching for their replacement circuits in parallel
+///
+/// Tries `regions` convex subsets and keeps the ones whose closure, the subset with its
+/// predecessors and successors, is disjoint with the closures of the subsets kept before, so that
+/// replacing one doesn't change the others. Replacement circuits of kept subsets are searched in
+/// parallel, each with its own RNG seeded from `rng`. Found replacements are then applied to the
+/// graph one subset at a time, because every replacement rewires the shared graph.
+///
+/// Replacement search dominates the time of a step and wide circuits have many unrelated subsets,
+/// so batches raise the no. of steps per second on wide circuits. Returns stats of every
+/// replacement applied. Nodes of C^in of the replacements are added to the graph in the same
+/// order.
+///
+/// If `cout_gate_ids` is given, ids of the gates of C^out of every replacement applied are pushed
+/// to it in the order of returned stats, each in ascending order. Replacement searches of every
+/// kept subset are tallied in `histograms`. Other arguments are
+/// the same as of [`local_mixing_step`].
+pub fn local_mixing_batch<R: Send + Sync + SeedableRng + RngCore>(
+    state: &mut MixingState,
+    params: &MixingStepParams,
+    regions: usize,
+    rng: &mut R,
+    timings: &mut MixingTimings,
+    histograms: Option<&mut ReplacementHistograms>,
+    mut cout_gate_ids: Option<&mut Vec<Vec<usize>>>,
+) -> Vec<LocalMixingStepStats> {
+    let MixingStepParams {
+        ell_out,
+        ell_in,
+        max_ell_in,
+        max_convex_iterations,
+        max_replacement_iterations,
+        convex_search,
+        replacement_search,
+    } = *params;
+    assert!(ell_out <= ell_in);
+    assert!(ell_in <= max_ell_in);
+    let MixingState {
+        skeleton_graph,
+        direct_connections,
+        direct_incoming_connections,
+        gate_map,
+        gate_id_to_node_index_map,
+        graph_neighbours,
+        removed_nodes,
+        frozen_nodes,
+        active_edges_with_gateids,
+        latest_id,
+        n,
+        level,
+    } = state;
+    let n = *n;
 
-            let gate_i = &cin_gates[i];
-            for j in i + 1..cin_gates.len() {
-                let gate_j = &cin_gates[j];
-                if gate_i.check_collision(gate_j) {
-                    direct_collisions.insert(gate_j.id());
-                }
-            }
+    let convex_search_start = std::time::Instant::now();
+    if level.len() != skeleton_graph.node_count() {
+        *level = graph_level(skeleton_graph, graph_neighbours, removed_nodes);
+    }
 
-            for succ in top_sorted_successors.iter() {
-                let succ_gate = gate_map
-                    .get(skeleton_graph.node_weight(*succ).unwrap())
-                    .unwrap();
+    // Kept convex subsets and union of their closures, a subset with its predecessors and
+    // successors. Closures of kept subsets are disjoint, hence rewiring for one subset never adds
+    // a path between nodes of the closure of another.
+    let mut convex_subsets = vec![];
+    let mut related = HashSet::default();
+    for _ in 0..regions {
+        let Ok((start_node, convex_subset, convex_iterations)) = find_convex_fast(
+            &skeleton_graph,
+            level,
+            ell_out,
+            max_convex_iterations,
+            &convex_search,
+            rng,
+            removed_nodes,
+            frozen_nodes,
+        ) else {
+            continue;
+        };
+        let mut closure = find_all_predecessors(&convex_subset, skeleton_graph, removed_nodes);
+        closure.extend(find_all_successors(
+            &convex_subset,
+            skeleton_graph,
+            removed_nodes,
+        ));
+        closure.extend(convex_subset.iter().copied());
+        if !closure.is_disjoint(&related) {
+            continue;
+        }
+        related.extend(closure);
+        convex_subsets.push((start_node, convex_subset, convex_iterations));
+    }
+    timings.convex_search += convex_search_start.elapsed();
+    log::trace!(
+        "Kept {} of {regions} convex subsets for the batch",
+        convex_subsets.len()
+    );
 
-                if gate_i.check_collision(succ_gate) {
-                    direct_collisions.insert(succ_gate.id());
-                }
-            }
+    let replacement_search_start = std::time::Instant::now();
+    let replacements = convex_subsets
+        .iter()
+        .map(|(start_node, convex_subset, _)| {
+            let (c_out, _, _) = relabelled_c_out(
+                skeleton_graph,
+                *start_node,
+                convex_subset,
+                gate_map,
+                removed_nodes,
+                n,
+            );
+            (c_out, R::from_rng(&mut *rng).unwrap())
+        })
+        .collect_vec()
+        .into_par_iter()
+        .map(|(c_out, mut rng)| {
+            find_replacement_with_retries(
+                &c_out,
+                ell_in,
+                max_ell_in,
+                max_replacement_iterations,
+                &replacement_search,
+                &mut rng,
+            )
+        })
+        .collect::<Vec<_>>();
+    timings.replacement_search += replacement_search_start.elapsed();
+    if let Some(histograms) = histograms {
+        for replacement in &replacements {
+            let (Ok((_, stats)) | Err(stats)) = replacement;
+            histograms.record(
+                stats.active_wires,
+                stats.replacement_iterations,
+                replacement.is_ok(),
+            );
+        }
+    }
 
-            // Make all outsiders succerss
-            for out_succ in top_sorted_outsiders.iter() {
-                let out_succ = gate_map
-                    .get(skeleton_graph.node_weight(*out_succ).unwrap())
-                    .unwrap();
+    izip!(convex_subsets, replacements)
+        .filter_map(
+            |((start_node, convex_subset, convex_iterations), replacement)| {
+                let (c_in_dash, stats) = replacement.ok()?;
 
-                if gate_i.check_collision(out_succ) {
-                    direct_collisions.insert(out_succ.id());
+                // Rewiring for earlier subsets only adds edges from their predecessors and to
+                // their successors and outsiders, none of which is reachable from this subset
+                let convexity_check_start = std::time::Instant::now();
+                assert!(
+                    find_all_successors(&convex_subset, skeleton_graph, removed_nodes)
+                        .is_disjoint(&convex_subset),
+                    "Convex subset is no longer convex after rewiring for earlier subsets of the batch"
+                );
+                timings.convex_search += convexity_check_start.elapsed();
+
+                let cout_successors = convex_subset
+                    .iter()
+                    .flat_map(|node| graph_neighbours[node.index()][1].iter().copied())
+                    .filter(|node| !convex_subset.contains(node))
+                    .collect_vec();
+                let node_count_before = skeleton_graph.node_count();
+                if let Some(cout_gate_ids) = cout_gate_ids.as_deref_mut() {
+                    cout_gate_ids.push(
+                        convex_subset
+                            .iter()
+                            .map(|node| skeleton_graph[*node])
+                            .sorted()
+                            .collect(),
+                    );
                 }
-            }
 
-            // update direct incoming connections for each node in direct_collisions
-            for n in direct_collisions.iter() {
-                direct_incoming_connections
-                    .get_mut(n)
-                    .unwrap()
-                    .insert(gate_i.id());
-            }
+                let stats = replace_convex_set_inner(
+                    skeleton_graph,
+                    start_node,
+                    &convex_subset,
+                    level,
+                    n,
+                    direct_connections,
+                    direct_incoming_connections,
+                    gate_map,
+                    gate_id_to_node_index_map,
+                    graph_neighbours,
+                    removed_nodes,
+                    active_edges_with_gateids,
+                    latest_id,
+                    timings,
+                    |c_out| {
+                        debug_assert!(check_exact_equivalence(c_out, &c_in_dash).0);
+                        Ok::<_, ()>((c_in_dash, stats))
+                    },
+                )
+                .unwrap();
 
-            direct_connections.insert(gate_i.id(), direct_collisions);
-        }
+                let level_update_start = std::time::Instant::now();
+                let cin_nodes =
+                    (node_count_before..skeleton_graph.node_count()).map(NodeIndex::new);
+                update_graph_level(
+                    level,
+                    skeleton_graph,
+                    graph_neighbours,
+                    removed_nodes,
+                    chain!(convex_subset, cout_successors, cin_nodes),
+                );
+                timings.convex_search += level_update_start.elapsed();
 
-        for i in 0..cin_gates.len() {
-            let direct_collisions = direct_connections.get(&cin_gates[i].id()).unwrap();
-            let mut transitive_collisions = direct_collisions.clone();
+                Some(LocalMixingStepStats {
+                    convex_iterations,
+                    ..stats
+                })
+            },
+        )
+        .collect()
+}
 
-            for g_id in direct_collisions.iter() {
-                let dc_other_gate = direct_connections.get(g_id).unwrap();
-                transitive_collisions.retain(|n| !dc_other_gate.contains(n));
+/// Replaces caller supplied convex subset C^out with a functionally equivalent random circuit C^in
+///
+/// Same as [`local_mixing_step`] except that search for convex subset is skipped, hence
+/// `params.ell_out` and `params.max_convex_iterations` are unused. `cout_convex_subset` must be
+/// convex and every node in it must be reachable from `cout_convex_start_node` within the subset.
+///
+/// Returns None if no replacement circuit is found for every \ell^in in `[ell_in, max_ell_in]`.
+/// Returned stats always have `convex_iterations = 0`. Time spent in replacement search and
+/// rewiring is added to `timings`, and time spent updating `state.level` to `convex_search`.
+/// Replacement search is tallied in `histograms` if given.
+pub fn replace_convex_set<R: Send + Sync + SeedableRng + RngCore>(
+    state: &mut MixingState,
+    cout_convex_start_node: NodeIndex,
+    cout_convex_subset: &HashSet<NodeIndex>,
+    params: &MixingStepParams,
+    rng: &mut R,
+    timings: &mut MixingTimings,
+    histograms: Option<&mut ReplacementHistograms>,
+) -> Option<LocalMixingStepStats> {
+    assert!(cout_convex_subset.len() <= params.ell_in);
+    assert!(params.ell_in <= params.max_ell_in);
+    state.refresh_level();
+
+    // Nodes that lose incoming edges from C^out. Together with C^in they are the only nodes whose
+    // incoming edges change other than by removal of transitive edges.
+    let cout_successors = cout_convex_subset
+        .iter()
+        .flat_map(|node| state.graph_neighbours[node.index()][1].iter().copied())
+        .filter(|node| !cout_convex_subset.contains(node))
+        .collect_vec();
+    let node_count_before = state.skeleton_graph.node_count();
+
+    let stats = replace_convex_set_inner(
+        &mut state.skeleton_graph,
+        cout_convex_start_node,
+        cout_convex_subset,
+        &state.level,
+        state.n,
+        &mut state.direct_connections,
+        &mut state.direct_incoming_connections,
+        &mut state.gate_map,
+        &mut state.gate_id_to_node_index_map,
+        &mut state.graph_neighbours,
+        &mut state.removed_nodes,
+        &mut state.active_edges_with_gateids,
+        &mut state.latest_id,
+        timings,
+        |c_out| {
+            let replacement = find_replacement_with_retries(
+                c_out,
+                params.ell_in,
+                params.max_ell_in,
+                params.max_replacement_iterations,
+                &params.replacement_search,
+                rng,
+            );
+            if let Some(histograms) = histograms {
+                let (Ok((_, stats)) | Err(stats)) = &replacement;
+                histograms.record(
+                    stats.active_wires,
+                    stats.replacement_iterations,
+                    replacement.is_ok(),
+                );
             }
+            replacement
+        },
+    )
+    .ok()?;
+
+    let level_update_start = std::time::Instant::now();
+    let cin_nodes = (node_count_before..state.skeleton_graph.node_count()).map(NodeIndex::new);
+    update_graph_level(
+        &mut state.level,
+        &state.skeleton_graph,
+        &state.graph_neighbours,
+        &state.removed_nodes,
+        chain!(
+            cout_convex_subset.iter().copied(),
+            cout_successors,
+            cin_nodes
+        ),
+    );
+    timings.convex_search += level_update_start.elapsed();
 
-            for gate_id in transitive_collisions {
-                new_edges.insert((cin_gates[i].id(), gate_id));
+    Some(stats)
+}
+
+/// Finds replacement circuit C^in' for C^out with \ell^in = `ell_in`. If none is found, retries
+/// with \ell^in + 1 up to `max_ell_in`. Returned stats have `convex_iterations = 0`. If no
+/// replacement is found, returns stats of the search with \ell^in = `max_ell_in` as error.
+fn find_replacement_with_retries<R: Send + Sync + SeedableRng + RngCore>(
+    c_out: &Circuit<BaseGate<2, u8>>,
+    ell_in: usize,
+    max_ell_in: usize,
+    max_replacement_iterations: usize,
+    options: &ReplacementSearchOptions,
+    rng: &mut R,
+) -> Result<(Circuit<BaseGate<2, u8>>, LocalMixingStepStats), LocalMixingStepStats> {
+    let mut replacement_iterations = 0;
+    let mut curr_ell_in = ell_in;
+    loop {
+        let (c_in_dash, iterations) = find_replacement_circuit(
+            c_out,
+            curr_ell_in,
+            c_out.n(),
+            max_replacement_iterations,
+            options,
+            rng,
+        );
+        replacement_iterations += iterations;
+        let stats = LocalMixingStepStats {
+            convex_iterations: 0,
+            replacement_iterations,
+            ell_in: curr_ell_in,
+            active_wires: c_out.n(),
+        };
+        match c_in_dash {
+            Some(c_in_dash) => break Ok((c_in_dash, stats)),
+            None if curr_ell_in < max_ell_in => {
+                log::trace!(
+                    "Find replacement circuit failed with ell_in = {curr_ell_in}. Retrying with ell_in = {}",
+                    curr_ell_in + 1
+                );
+                curr_ell_in += 1;
+            }
+            None => {
+                log::trace!("[returned false] Find replacement circuit");
+                break Err(stats);
             }
         }
-    });
+    }
+}
 
-    // Predecessors
-    // timed!("Process predecessors", {
-    // Handle direction connections from predecessors to gates in C^in
+/// Reason why [`replace_convex_set_with`] rejected replacement circuit C^in'
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidReplacement {
+    /// C^in' does not have the same no. of wires as C^out
+    WireCountMismatch { expected: usize, found: usize },
+    /// C^in' is not functionally equivalent to C^out
+    NotEquivalent,
+    /// Collisions set of C^in' is not weakly connected
+    NotWeaklyConnected,
+}
 
-    // let mut transitive_connections_to_remove = HashSet::new();
-    // let mut transitive_connections_to_add = vec![HashSet::new(); cin_gates.len()];
-    let mut chunk_size = top_sorted_predecessors.len()
-        / (current_num_threads() as f64 / ell_in as f64).ceil() as usize;
-    if chunk_size == 0 {
-        chunk_size += 1;
+/// Replaces caller supplied convex subset C^out with caller supplied replacement circuit C^in'
+///
+/// Same as [`replace_convex_set`] except that search for replacement circuit is skipped. C^out is
+/// relabelled to wires `0..\omega^out` such that i^th smallest active wire of the convex subset is
+/// mapped to wire i. `c_in_dash` must be defined over the relabelled wires.
+///
+/// Returns error, without modifying the graph, if `c_in_dash` is not functionally equivalent to C^out
+/// or its collisions set is not weakly connected.
+pub fn replace_convex_set_with(
+    skeleton_graph: &mut Graph<usize, usize>,
+    cout_convex_start_node: NodeIndex,
+    cout_convex_subset: &HashSet<NodeIndex>,
+    level: &[usize],
+    c_in_dash: &Circuit<BaseGate<2, u8>>,
+    n: u8,
+    direct_connections: &mut HashMap<usize, HashSet<usize>>,
+    direct_incoming_connections: &mut HashMap<usize, HashSet<usize>>,
+    gate_map: &mut HashMap<usize, BaseGate<2, u8>>,
+    gate_id_to_node_index_map: &mut HashMap<usize, NodeIndex>,
+    graph_neighbours: &mut Vec<[HashSet<NodeIndex>; 2]>,
+    removed_nodes: &mut HashSet<NodeIndex>,
+    active_edges_with_gateids: &mut HashSet<(usize, usize)>,
+    latest_id: &mut usize,
+) -> Result<(), InvalidReplacement> {
+    replace_convex_set_inner(
+        skeleton_graph,
+        cout_convex_start_node,
+        cout_convex_subset,
+        level,
+        n,
+        direct_connections,
+        direct_incoming_connections,
+        gate_map,
+        gate_id_to_node_index_map,
+        graph_neighbours,
+        removed_nodes,
+        active_edges_with_gateids,
+        latest_id,
+        &mut MixingTimings::default(),
+        |c_out| {
+            if c_in_dash.n() != c_out.n() {
+                return Err(InvalidReplacement::WireCountMismatch {
+                    expected: c_out.n(),
+                    found: c_in_dash.n(),
+                });
+            }
+            if !check_exact_equivalence(c_out, c_in_dash).0 {
+                return Err(InvalidReplacement::NotEquivalent);
+            }
+            if !is_collisions_set_weakly_connected(&circuit_to_collision_sets(c_in_dash)) {
+                return Err(InvalidReplacement::NotWeaklyConnected);
+            }
+            Ok((c_in_dash.clone(), ()))
+        },
+    )
+}
+
+/// Bitmasks over indices of `gates` by wire. The gates of `gates` a gate collides with are found
+/// with one lookup per wire of the gate instead of [Gate::check_collision] with every gate.
+struct CollisionMasks {
+    /// No. of `u64` words of a bitmask
+    words: usize,
+    /// Bitmask of gates with target `w` at `w * words..(w + 1) * words`
+    targets: Vec<u64>,
+    /// Bitmask of gates with control `w` at `w * words..(w + 1) * words`
+    controls: Vec<u64>,
+}
+
+impl CollisionMasks {
+    fn new(gates: &[BaseGate<2, u8>]) -> Self {
+        let words = gates.len().div_ceil(64).max(1);
+        let mut targets = vec![0; (u8::MAX as usize + 1) * words];
+        let mut controls = vec![0; (u8::MAX as usize + 1) * words];
+        for (index, gate) in gates.iter().enumerate() {
+            let (word, bit) = (index / 64, 1 << (index % 64));
+            targets[gate.target() as usize * words + word] |= bit;
+            for control in gate.controls() {
+                controls[control as usize * words + word] |= bit;
+            }
+        }
+        Self {
+            words,
+            targets,
+            controls,
+        }
     }
 
-    // println!("Chunk size={chunk_size}; threads={}; total_preds={}", current_num_threads(), top_sorted_predecessors.len());
-    // println!("Starting pred processing");
+    fn mask<'a>(&self, masks: &'a [u64], wire: u8) -> &'a [u64] {
+        &masks[wire as usize * self.words..(wire as usize + 1) * self.words]
+    }
 
-    {
-        let (mut tc_to_add, tc_to_remove, direct_outgoing_to_add, direct_incoming_to_add) = timed!(
-            "[Process predecessors] Process C^in gates in parallel",
-            cin_gates
-                .par_iter()
-                .enumerate()
-                .map(|(i, gate_i)| {
-                    // It is possible to chunk topologically sorted predecessors into multiple chunks (ideally `top_sorted_predecessors.len() / (current_num_threads() / 4)`)
-                    // and process each chunk separately. After which remove transtive edge from predecessor A to the gate if there exist an edge from a direct connection of A
-                    // in any of the succeding chunks.
-                    // We're deprioritising this for the moment.
+    /// Bitmask of indices of gates that collide with `gate`. That is gates with a control on the
+    /// target of `gate` or with target on a control of `gate`.
+    fn collisions(&self, gate: &BaseGate<2, u8>) -> Vec<u64> {
+        let mut collisions = self.mask(&self.controls, gate.target()).to_vec();
+        for control in gate.controls() {
+            izip!(&mut collisions, self.mask(&self.targets, control))
+                .for_each(|(collisions, targets)| *collisions |= targets);
+        }
+        collisions
+    }
+}
 
-                    let (tc_remove, tc_add_chunk_map, direct_outgoing_to_insert,direct_incoming_to_insert)=   top_sorted_predecessors
-                        .par_chunks(chunk_size)
-                        .enumerate()
-                        .map(|(chunk_index, top_preds_chunk)| {
-                            // direct outgoing connections to gate_i
-                            let mut direct_outgoing_to_insert_chunk = HashSet::new();
-                            // direct incoming connections to gate_i
-                            let mut direct_incoming_to_insert_chunk = HashSet::new();
+/// Whether `index` is set in bitmask `mask` of [CollisionMasks]
+fn mask_contains(mask: &[u64], index: usize) -> bool {
+    (mask[index / 64] >> (index % 64)) & 1 == 1
+}
 
-                            let mut tc_add_chunk = HashSet::new();
-                            let mut tc_remove_chunk = HashSet::new();
+/// Returns C^out of the convex subset with wires relabelled to `0..\omega^out`, such that i^th
+/// smallest active wire of the subset is mapped to wire i, the map from old to new wires, and the
+/// map from new to old wires.
+fn relabelled_c_out(
+    skeleton_graph: &Graph<usize, usize>,
+    cout_convex_start_node: NodeIndex,
+    cout_convex_subset: &HashSet<NodeIndex>,
+    gate_map: &HashMap<usize, BaseGate<2, u8>>,
+    removed_nodes: &HashSet<NodeIndex>,
+    n: u8,
+) -> (
+    Circuit<BaseGate<2, u8>>,
+    HashMap<usize, usize>,
+    HashMap<usize, usize>,
+) {
+    let mut convex_subset_top_sorted = VecDeque::new();
+    dfs_within_convex_set(
+        cout_convex_start_node,
+        cout_convex_subset,
+        &skeleton_graph,
+        &mut HashSet::default(),
+        &mut convex_subset_top_sorted,
+    );
 
-                            let mut gate_i_pred_collisions_chunk = HashSet::new();
+    // Convex subset sorted in topological order
+    let convex_subgraph_top_sorted_gate_ids = convex_subset_top_sorted
+        .iter()
+        .map(|node_index| skeleton_graph.node_weight(*node_index).unwrap());
 
-                            for pred in top_preds_chunk.iter().rev() {
-                                let pred_gate = gate_map
-                                    .get(skeleton_graph.node_weight(*pred).unwrap())
-                                    .unwrap();
+    for node in convex_subset_top_sorted.iter() {
+        assert!(!removed_nodes.contains(node));
+    }
 
-                                if pred_gate.check_collision(gate_i) {
-                                    let gate_i_dc = direct_connections.get(&gate_i.id()).unwrap();
-                                    let pred_direct_collisions =
-                                        direct_connections.get(&pred_gate.id()).unwrap();
+    #[cfg(feature = "trace")]
+    log::trace!(
+        "Convex subset gate ids: {:?}",
+        &convex_subgraph_top_sorted_gate_ids.clone().collect_vec()
+    );
 
-                                    // Remove all transitive edges to nodes that gate_i has direct connections with.
-                                    for gate_id in pred_direct_collisions.intersection(gate_i_dc) {
-                                        if active_edges_with_gateids
-                                            .contains(&(pred_gate.id(), *gate_id))
-                                        {
-                                            tc_remove_chunk.insert((pred_gate.id(), *gate_id));
-                                        }
-                                    }
+    let convex_subgraph_gates =
+        convex_subgraph_top_sorted_gate_ids.map(|node| gate_map.get(node).unwrap());
 
-                                    // only add transitive edge from pred to gate i if there's no gate beetwen pred and gate i which collides with both.
-                                    if pred_direct_collisions
-                                        .is_disjoint(&gate_i_pred_collisions_chunk)
-                                    {
-                                        tc_add_chunk.insert(pred_gate.id());
-                                    }
+    // Set of active wires in convex subgraph
+    let mut omega_out = HashSet::default();
+    convex_subgraph_gates.clone().for_each(|g| {
+        omega_out.insert(g.target());
+        for wire in g.controls().iter() {
+            omega_out.insert(*wire);
+        }
+    });
+    // Sort active wires to map them to new wires in a fixed order
+    let omega_out = omega_out.into_iter().sorted().collect_vec();
 
-                                    direct_outgoing_to_insert_chunk
-                                        .insert(pred_gate.id());
-                                    direct_incoming_to_insert_chunk
-                                        .insert(pred_gate.id());
+    // return false if omega^out <= 3 because finding a replacement is apparently not possilbe.
+    // if omega_out.len() <= 3 {
+    //     log::trace!("[returned false] <= active wires");
+    //     return false;
+    // }
 
-                                    gate_i_pred_collisions_chunk.insert(pred_gate.id());
-                                }
-                            }
+    // Map from old wires to new wires in C^out
+    let old_to_new_map: HashMap<_, _> = omega_out
+        .iter()
+        .enumerate()
+        .map(|(new_index, old_index)| (*old_index as usize, new_index))
+        .collect();
+    let new_to_old_map: HashMap<_, _> = old_to_new_map
+        .iter()
+        .map(|(old_index, new_index)| (*new_index, *old_index))
+        .collect();
+
+    let c_out = Circuit::new(
+        convex_subgraph_gates
+            .clone()
+            .enumerate()
+            .map(|(index, gate)| {
+                BaseGate::new(index, gate.target(), gate.controls(), gate.control_func())
+            })
+            .collect(),
+        n.into(),
+    )
+    .remap_wires(&old_to_new_map, omega_out.len())
+    .unwrap();
 
-                            let mut tc_add_chunk_map = HashMap::new();
-                            tc_add_chunk_map.insert(chunk_index, tc_add_chunk);
+    (c_out, old_to_new_map, new_to_old_map)
+}
 
-                            return (
-                                tc_remove_chunk,
-                                tc_add_chunk_map,
-                                direct_outgoing_to_insert_chunk,
-                                direct_incoming_to_insert_chunk,
-                            );
-                        })
-                        .reduce(
-                            || {
-                                (
-                                    HashSet::new(),
-                                    HashMap::new(),
-                                    HashSet::new(),
-                                    HashSet::new(),
-                                )
-                            },
-                            |(mut tc_remove0,mut  tc_add0,mut  direct_outgoing0,mut  direct_incoming0), (tc_remove1, tc_add1, direct_outgoing1, direct_incoming1)| {
-                                tc_remove0.extend(tc_remove1);
-                                tc_add0.extend(tc_add1);
-                                direct_outgoing0.extend(direct_outgoing1);
-                                direct_incoming0.extend(direct_incoming1);
+/// Replaces convex subset C^out with replacement circuit C^in' returned by `find_replacement`.
+///
+/// `find_replacement` receives C^out with relabelled wires. If it returns an error, the graph is
+/// not modified and the error is returned. Time spent in `find_replacement` is added to
+/// `timings.replacement_search` and the rest to `timings.rewiring`.
+fn replace_convex_set_inner<T, E>(
+    skeleton_graph: &mut Graph<usize, usize>,
+    cout_convex_start_node: NodeIndex,
+    cout_convex_subset: &HashSet<NodeIndex>,
+    level: &[usize],
+    n: u8,
+    direct_connections: &mut HashMap<usize, HashSet<usize>>,
+    direct_incoming_connections: &mut HashMap<usize, HashSet<usize>>,
+    gate_map: &mut HashMap<usize, BaseGate<2, u8>>,
+    gate_id_to_node_index_map: &mut HashMap<usize, NodeIndex>,
+    graph_neighbours: &mut Vec<[HashSet<NodeIndex>; 2]>,
+    removed_nodes: &mut HashSet<NodeIndex>,
+    active_edges_with_gateids: &mut HashSet<(usize, usize)>,
+    latest_id: &mut usize,
+    timings: &mut MixingTimings,
+    find_replacement: impl FnOnce(&Circuit<BaseGate<2, u8>>) -> Result<(Circuit<BaseGate<2, u8>>, T), E>,
+) -> Result<T, E> {
+    assert!(cout_convex_subset.contains(&cout_convex_start_node));
+    let start = std::time::Instant::now();
 
-                                (tc_remove0, tc_add0, direct_outgoing0, direct_incoming0)
-                            },
-                        );
+    let (c_out, _old_to_new_map, new_to_old_map) = relabelled_c_out(
+        skeleton_graph,
+        cout_convex_start_node,
+        cout_convex_subset,
+        gate_map,
+        removed_nodes,
+        n,
+    );
 
+    let replacement_search_start = std::time::Instant::now();
+    let replacement = timed!("Find replacement circuit", find_replacement(&c_out));
+    let replacement_search_time = replacement_search_start.elapsed();
+    timings.replacement_search += replacement_search_time;
+    let (c_in_dash, out) = match replacement {
+        Ok(replacement) => replacement,
+        Err(e) => {
+            timings.rewiring += start.elapsed() - replacement_search_time;
+            return Err(e);
+        }
+    };
+    debug_assert!(
+        !is_identity(&c_in_dash) || is_identity(&c_out),
+        "C^in' is the identity but C^out is not"
+    );
 
-                    let mut tc_add = HashSet::new();
-                    match tc_add_chunk_map.keys().max() { 
-                        Some(chunk_max_index ) => {
-                            for i in 0..=*chunk_max_index {
-                        let chunk_i_adds = tc_add_chunk_map.get(&i).unwrap();
+    let c_in = Circuit::new(
+        c_in_dash
+            .remap_wires(&new_to_old_map, n.into())
+            .expect("C^in' must only use wires of C^out")
+            .gates()
+            .iter()
+            .map(|g| {
+                *latest_id += 1;
+                BaseGate::<2, _>::new(*latest_id, g.target(), g.controls(), g.control_func())
+            })
+            .collect(),
+        n.into(),
+    );
 
-                        for pred in chunk_i_adds.iter() {
-                            let mut should_remove_pred = false;
-                            let pred_dc = direct_connections.get(pred).unwrap();
-                            for j in i+1..=*chunk_max_index {
-                                let chunk_j_adds = tc_add_chunk_map.get(&j).unwrap();
-                                if !pred_dc.is_disjoint(chunk_j_adds) {
-                                    should_remove_pred = true;
-                                }
-                            }
+    #[cfg(feature = "trace")]
+    {
+        log::trace!("Old to new wires map: {:?}", &_old_to_new_map);
+        log::trace!("New to old wires map: {:?}", &new_to_old_map);
+        log::trace!("@@@@ C^out @@@@ {}", &c_out);
+        log::trace!("@@@@ C^in' @@@@ {}", &c_in_dash);
+        // log::trace!("@@@@ C^in @@@@ {}", &c_in);
+        let collision_sets_c_in = circuit_to_collision_sets(&c_in_dash);
+        log::trace!("C^in collision sets: {:?}", &collision_sets_c_in);
+    }
 
-                            if !should_remove_pred {
-                                tc_add.insert(*pred);
-                            }
-                        }
-                    }
-                        },
-                        None => {}
-                    }
-                    
-                    let mut tc_add_out = HashMap::new();
-                    tc_add_out.insert(i, tc_add);
+    // #### Replace C^out with C^in #### //
 
-                    let mut direct_outgoing_to_insert_out = HashMap::new();
-                    for node in direct_outgoing_to_insert {
-                        let mut set = HashSet::new();
-                        set.insert(gate_i.id());
-                        direct_outgoing_to_insert_out.insert(node, set);
-                    }
-                    let mut direct_incoming_to_insert_out = HashMap::new();
-                    direct_incoming_to_insert_out.insert(gate_i.id(),direct_incoming_to_insert);
+    // Find all predecessors and successors of subgrpah C^out
+    let mut c_out_imm_predecessors = HashSet::default();
+    let mut c_out_imm_successors = HashSet::default();
+    // First find all immediate predecessors and successors
+    for node in cout_convex_subset.iter() {
+        for pred in skeleton_graph
+            .neighbors_directed(node.clone(), Direction::Incoming)
+            .filter(|node| !removed_nodes.contains(node))
+        {
+            if !cout_convex_subset.contains(&pred) {
+                c_out_imm_predecessors.insert(pred);
+            }
+        }
+        for succ in skeleton_graph
+            .neighbors_directed(node.clone(), Direction::Outgoing)
+            .filter(|node| !removed_nodes.contains(node))
+        {
+            if !cout_convex_subset.contains(&succ) {
+                c_out_imm_successors.insert(succ);
+            }
+        }
+    }
 
+    // add C^in gates to the graph
+    let cin_gates = c_in.gates();
+    let cin_nodes = cin_gates
+        .iter()
+        .map(|gate| {
+            let node_index = skeleton_graph.add_node(gate.id());
+            assert!(gate_map.insert(gate.id(), gate.clone()).is_none());
+            assert!(gate_id_to_node_index_map
+                .insert(gate.id(), node_index)
+                .is_none());
+            node_index
+        })
+        .collect_vec();
 
-                    (
-                        tc_add_out,
+    let cout_predecessors = timed!(
+        "Find all predecessors",
+        find_all_predecessors(cout_convex_subset, &skeleton_graph, removed_nodes)
+    );
+    let cout_successors = timed!(
+        "Find all successors",
+        find_all_successors(cout_convex_subset, &skeleton_graph, removed_nodes)
+    );
+
+    assert!(cout_predecessors.is_disjoint(&cout_successors));
+    assert!(cout_predecessors.is_disjoint(&removed_nodes));
+    assert!(cout_successors.is_disjoint(&removed_nodes));
+
+    let top_sorted_predecessors = {
+        let mut top_sorted_predecessors = Vec::from_iter(cout_predecessors.iter().copied());
+        top_sorted_predecessors.par_sort_by(|a, b| level[a.index()].cmp(&level[b.index()]));
+        top_sorted_predecessors
+    };
+    let top_sorted_successors = {
+        let mut top_sorted_successorss = Vec::from_iter(cout_successors.iter().copied());
+        top_sorted_successorss.par_sort_by(|a, b| level[a.index()].cmp(&level[b.index()]));
+        top_sorted_successorss
+    };
+    let top_sorted_outsiders = {
+        let insider = HashSet::<_>::from_iter(
+            chain![
+                &cin_nodes,
+                cout_convex_subset,
+                &cout_successors,
+                &cout_predecessors,
+                removed_nodes.deref()
+            ]
+            .copied(),
+        );
+        let mut top_sorted_outsiders = skeleton_graph
+            .node_indices()
+            .par_bridge()
+            .filter(|n| !insider.contains(n))
+            .collect::<Vec<_>>();
+        top_sorted_outsiders.par_sort_by(|a, b| level[a.index()].cmp(&level[b.index()]));
+        top_sorted_outsiders
+    };
+
+    log::trace!(
+        "@@@ No. of predecessors: {}@@@",
+        top_sorted_predecessors.len()
+    );
+    log::trace!("@@@ No. of successors: {}@@@", top_sorted_successors.len());
+
+    #[cfg(feature = "trace")]
+    {
+        log::trace!(
+            "C^in gate ids: {:?}",
+            cin_gates.iter().map(|g| g.id()).collect_vec()
+        );
+
+        log::trace!(
+            "Top sorted predecessors: {:?}",
+            node_indices_to_gate_ids(top_sorted_predecessors.iter(), skeleton_graph)
+        );
+        log::trace!(
+            "Top sorted successors: {:?}",
+            node_indices_to_gate_ids(top_sorted_successors.iter(), skeleton_graph)
+        );
+        log::trace!(
+            "Top sorted outsiders: {:?}",
+            node_indices_to_gate_ids(top_sorted_outsiders.iter(), skeleton_graph)
+        );
+    }
+
+    // The same nodes are checked for collisions with every C^in gate. Collisions with all C^in
+    // gates are looked up once per node instead.
+    let cin_collision_masks = CollisionMasks::new(cin_gates);
+
+    let mut new_edges = HashSet::default();
+    let mut remove_edges = HashSet::default();
+
+    // Successors
+    timed!("Process successors", {
+        // create blank entries in direct incoming connections for C^in nodes
+        cin_gates.iter().for_each(|g| {
+            direct_incoming_connections.insert(g.id(), HashSet::default());
+        });
+
+        // Successors and outsiders with bitmasks of C^in gates they collide with. All outsiders
+        // are made successors of C^in.
+        let successor_collisions = top_sorted_successors
+            .par_iter()
+            .chain(top_sorted_outsiders.par_iter())
+            .filter_map(|node| {
+                let gate = gate_map
+                    .get(skeleton_graph.node_weight(*node).unwrap())
+                    .unwrap();
+                let collisions = cin_collision_masks.collisions(gate);
+                collisions
+                    .iter()
+                    .any(|word| *word != 0)
+                    .then_some((gate, collisions))
+            })
+            .collect::<Vec<_>>();
+
+        for i in 0..cin_gates.len() {
+            let mut direct_collisions = HashSet::default();
+
+            let gate_i = &cin_gates[i];
+            for j in i + 1..cin_gates.len() {
+                let gate_j = &cin_gates[j];
+                if gate_i.check_collision(gate_j) {
+                    direct_collisions.insert(gate_j.id());
+                }
+            }
+
+            for (succ_gate, collisions) in successor_collisions.iter() {
+                if mask_contains(collisions, i) {
+                    direct_collisions.insert(succ_gate.id());
+                }
+            }
+
+            // update direct incoming connections for each node in direct_collisions
+            for n in direct_collisions.iter() {
+                direct_incoming_connections
+                    .get_mut(n)
+                    .unwrap()
+                    .insert(gate_i.id());
+            }
+
+            direct_connections.insert(gate_i.id(), direct_collisions);
+        }
+
+        for i in 0..cin_gates.len() {
+            let direct_collisions = direct_connections.get(&cin_gates[i].id()).unwrap();
+            let mut transitive_collisions = direct_collisions.clone();
+
+            for g_id in direct_collisions.iter() {
+                let dc_other_gate = direct_connections.get(g_id).unwrap();
+                transitive_collisions.retain(|n| !dc_other_gate.contains(n));
+            }
+
+            for gate_id in transitive_collisions {
+                new_edges.insert((cin_gates[i].id(), gate_id));
+            }
+        }
+    });
+
+    // Predecessors
+    // timed!("Process predecessors", {
+    // Handle direction connections from predecessors to gates in C^in
+
+    // let mut transitive_connections_to_remove = HashSet::default();
+    // let mut transitive_connections_to_add = vec![HashSet::default(); cin_gates.len()];
+    let mut chunk_size = top_sorted_predecessors.len()
+        / (current_num_threads() as f64 / cin_gates.len() as f64).ceil() as usize;
+    if chunk_size == 0 {
+        chunk_size += 1;
+    }
+
+    // println!("Chunk size={chunk_size}; threads={}; total_preds={}", current_num_threads(), top_sorted_predecessors.len());
+    // println!("Starting pred processing");
+
+    {
+        // Bitmasks of C^in gates each predecessor collides with. Aligned with
+        // `top_sorted_predecessors`.
+        let predecessor_collisions = top_sorted_predecessors
+            .par_iter()
+            .map(|pred| {
+                let gate = gate_map
+                    .get(skeleton_graph.node_weight(*pred).unwrap())
+                    .unwrap();
+                (gate, cin_collision_masks.collisions(gate))
+            })
+            .collect::<Vec<_>>();
+
+        let (mut tc_to_add, tc_to_remove, direct_outgoing_to_add, direct_incoming_to_add) = timed!(
+            "[Process predecessors] Process C^in gates in parallel",
+            cin_gates
+                .par_iter()
+                .enumerate()
+                .map(|(i, gate_i)| {
+                    // It is possible to chunk topologically sorted predecessors into multiple chunks (ideally `top_sorted_predecessors.len() / (current_num_threads() / 4)`)
+                    // and process each chunk separately. After which remove transtive edge from predecessor A to the gate if there exist an edge from a direct connection of A
+                    // in any of the succeding chunks.
+                    // We're deprioritising this for the moment.
+
+                    let (tc_remove, tc_add_chunk_map, direct_outgoing_to_insert,direct_incoming_to_insert)=   predecessor_collisions
+                        .par_chunks(chunk_size)
+                        .enumerate()
+                        .map(|(chunk_index, top_preds_chunk)| {
+                            // direct outgoing connections to gate_i
+                            let mut direct_outgoing_to_insert_chunk = HashSet::default();
+                            // direct incoming connections to gate_i
+                            let mut direct_incoming_to_insert_chunk = HashSet::default();
+
+                            let mut tc_add_chunk = HashSet::default();
+                            let mut tc_remove_chunk = HashSet::default();
+
+                            let mut gate_i_pred_collisions_chunk = HashSet::default();
+
+                            for (pred_gate, collisions) in top_preds_chunk.iter().rev() {
+                                if mask_contains(collisions, i) {
+                                    let gate_i_dc = direct_connections.get(&gate_i.id()).unwrap();
+                                    let pred_direct_collisions =
+                                        direct_connections.get(&pred_gate.id()).unwrap();
+
+                                    // Remove all transitive edges to nodes that gate_i has direct connections with.
+                                    for gate_id in pred_direct_collisions.intersection(gate_i_dc) {
+                                        if active_edges_with_gateids
+                                            .contains(&(pred_gate.id(), *gate_id))
+                                        {
+                                            tc_remove_chunk.insert((pred_gate.id(), *gate_id));
+                                        }
+                                    }
+
+                                    // only add transitive edge from pred to gate i if there's no gate beetwen pred and gate i which collides with both.
+                                    if pred_direct_collisions
+                                        .is_disjoint(&gate_i_pred_collisions_chunk)
+                                    {
+                                        tc_add_chunk.insert(pred_gate.id());
+                                    }
+
+                                    direct_outgoing_to_insert_chunk
+                                        .insert(pred_gate.id());
+                                    direct_incoming_to_insert_chunk
+                                        .insert(pred_gate.id());
+
+                                    gate_i_pred_collisions_chunk.insert(pred_gate.id());
+                                }
+                            }
+
+                            let mut tc_add_chunk_map = HashMap::default();
+                            tc_add_chunk_map.insert(chunk_index, tc_add_chunk);
+
+                            return (
+                                tc_remove_chunk,
+                                tc_add_chunk_map,
+                                direct_outgoing_to_insert_chunk,
+                                direct_incoming_to_insert_chunk,
+                            );
+                        })
+                        .reduce(
+                            || {
+                                (
+                                    HashSet::default(),
+                                    HashMap::default(),
+                                    HashSet::default(),
+                                    HashSet::default(),
+                                )
+                            },
+                            |(mut tc_remove0,mut  tc_add0,mut  direct_outgoing0,mut  direct_incoming0), (tc_remove1, tc_add1, direct_outgoing1, direct_incoming1)| {
+                                tc_remove0.extend(tc_remove1);
+                                tc_add0.extend(tc_add1);
+                                direct_outgoing0.extend(direct_outgoing1);
+                                direct_incoming0.extend(direct_incoming1);
+
+                                (tc_remove0, tc_add0, direct_outgoing0, direct_incoming0)
+                            },
+                        );
+
+
+                    let mut tc_add = HashSet::default();
+                    match tc_add_chunk_map.keys().max() { 
+                        Some(chunk_max_index ) => {
+                            for i in 0..=*chunk_max_index {
+                        let chunk_i_adds = tc_add_chunk_map.get(&i).unwrap();
+
+                        for pred in chunk_i_adds.iter() {
+                            let mut should_remove_pred = false;
+                            let pred_dc = direct_connections.get(pred).unwrap();
+                            for j in i+1..=*chunk_max_index {
+                                let chunk_j_adds = tc_add_chunk_map.get(&j).unwrap();
+                                if !pred_dc.is_disjoint(chunk_j_adds) {
+                                    should_remove_pred = true;
+                                }
+                            }
+
+                            if !should_remove_pred {
+                                tc_add.insert(*pred);
+                            }
+                        }
+                    }
+                        },
+                        None => {}
+                    }
+                    
+                    let mut tc_add_out = HashMap::default();
+                    tc_add_out.insert(i, tc_add);
+
+                    let mut direct_outgoing_to_insert_out = HashMap::default();
+                    for node in direct_outgoing_to_insert {
+                        let mut set = HashSet::default();
+                        set.insert(gate_i.id());
+                        direct_outgoing_to_insert_out.insert(node, set);
+                    }
+                    let mut direct_incoming_to_insert_out = HashMap::default();
+                    direct_incoming_to_insert_out.insert(gate_i.id(),direct_incoming_to_insert);
+
+
+                    (
+                        tc_add_out,
                         tc_remove,
                         direct_outgoing_to_insert_out,
                         direct_incoming_to_insert_out,
@@ -1745,10 +3638,10 @@ pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
                 .reduce(
                     || {
                         (
-                            HashMap::new(),
-                            HashSet::new(),
-                            HashMap::new(),
-                            HashMap::new(),
+                            HashMap::default(),
+                            HashSet::default(),
+                            HashMap::default(),
+                            HashMap::default(),
                         )
                     },
                     |(
@@ -1775,14 +3668,14 @@ pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
                         for (k, v) in direct_outgoing_to_insert1 {
                             direct_outgoing_to_insert0
                                 .entry(k)
-                                .or_insert(HashSet::new())
+                                .or_insert(HashSet::default())
                                 .extend(v);
                         }
 
                         for (k, v) in direct_incoming_to_insert1 {
                             direct_incoming_to_insert0
                                 .entry(k)
-                                .or_insert(HashSet::new())
+                                .or_insert(HashSet::default())
                                 .extend(v);
                         }
 
@@ -1807,7 +3700,7 @@ pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
                     let tc_to_add_i = tc_to_add.get(&i).unwrap();
                     let tc_to_add_j = tc_to_add.get(&j).unwrap();
 
-                    let mut to_remove = HashSet::new();
+                    let mut to_remove = HashSet::default();
                     for node in tc_to_add_i.intersection(tc_to_add_j) {
                         to_remove.insert(*node);
                     }
@@ -1851,8 +3744,8 @@ pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
         .map(|node| *skeleton_graph.node_weight(*node).unwrap())
         .collect_vec();
 
-    let mut union_dir_conns = HashSet::new();
-    let mut union_dir_inc_conns = HashSet::new();
+    let mut union_dir_conns = HashSet::default();
+    let mut union_dir_inc_conns = HashSet::default();
     cout_ids.iter().for_each(|id| {
         let mut conns = direct_connections.get(id).unwrap().clone();
         conns.retain(|v| !cout_ids.contains(v));
@@ -1904,7 +3797,7 @@ pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
                 let missing_new_edges = union_dir_inc_conns
                     .par_iter()
                     .map(|pred| {
-                        let mut edges = HashSet::new();
+                        let mut edges = HashSet::default();
                         let pred_dcs = direct_connections.get(pred).unwrap();
                         for succ in union_dir_conns.iter() {
                             if pred_dcs.contains(succ) {
@@ -1917,7 +3810,7 @@ pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
                         }
                         edges
                     })
-                    .reduce(HashSet::new, |mut acc, item| {
+                    .reduce(HashSet::default, |mut acc, item| {
                         acc.extend(item);
                         acc
                     });
@@ -1925,7 +3818,7 @@ pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
                 #[cfg(feature = "trace")]
                 {
                     let missing_new_edges_old = {
-                        let mut new_edges_old = HashSet::new();
+                        let mut new_edges_old = HashSet::default();
                         for pred in &union_dir_inc_conns {
                             let pred_dcs = direct_connections.get_mut(pred).unwrap();
                             for succ in union_dir_conns.iter() {
@@ -2060,7 +3953,7 @@ pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
         update_graph_neighbors(
             skeleton_graph,
             graph_neighbours,
-            &cout_convex_subset,
+            cout_convex_subset,
             chain![
                 // cout_convex_subset.iter().copied(),
                 cin_nodes.iter().copied(),
@@ -2123,687 +4016,3508 @@ pub fn local_mixing_step<R: Send + Sync + SeedableRng + RngCore>(
     //     panic!("");
     // }
 
-    // Checks whether active edge list updates are correct
-    // {
-    //     let mut expected_active_edges = HashSet::new();
-    //     for edge in skeleton_graph.edge_references() {
-    //         let source_id = *skeleton_graph.node_weight(edge.source()).unwrap();
-    //         let target_id = *skeleton_graph.node_weight(edge.target()).unwrap();
-    //         expected_active_edges.insert((source_id, target_id));
-    //     }
+    // Checks whether active edge list updates are correct
+    // {
+    //     let mut expected_active_edges = HashSet::default();
+    //     for edge in skeleton_graph.edge_references() {
+    //         let source_id = *skeleton_graph.node_weight(edge.source()).unwrap();
+    //         let target_id = *skeleton_graph.node_weight(edge.target()).unwrap();
+    //         expected_active_edges.insert((source_id, target_id));
+    //     }
+
+    //     let diff = expected_active_edges
+    //         .difference(active_edges_with_gateids)
+    //         .collect_vec();
+
+    //     if diff.len() != 0 {
+    //         log::trace!("@@ Active edge set did not update correctly @@");
+    //         for d in diff {
+    //             if expected_active_edges.contains(d) {
+    //                 log::trace!("   Active edge set does not contains expected edge {:?}", d);
+    //             } else {
+    //                 log::trace!("   Active edge set contains not-expected edge {:?}", d);
+    //             }
+    //         }
+    //         panic!();
+    //     }
+    // }
+
+    // update gate_id to node_index map
+    // timed!(
+    //     "Update gate_id_to_node_index_map",
+    //     for node in skeleton_graph.node_indices() {
+    //         if !removed_nodes.contains(&node) {
+    //             assert!(
+    //                 *gate_id_to_node_index_map
+    //                     .get(skeleton_graph.node_weight(node).unwrap())
+    //                     .unwrap()
+    //                     == node
+    //             );
+    //         }
+    //     }
+    // );
+
+    timings.rewiring += start.elapsed() - replacement_search_time;
+
+    return Ok(out);
+}
+
+/// Writes skeleton graph, gate map, and removed nodes to `path`. Use [`load_skeleton`] to read
+/// them back.
+pub fn dump_skeleton(
+    path: impl AsRef<Path>,
+    skeleton_graph: &Graph<usize, usize>,
+    gate_map: &HashMap<usize, BaseGate<2, u8>>,
+    removed_nodes: &HashSet<NodeIndex>,
+) -> std::io::Result<()> {
+    std::fs::write(
+        path,
+        bincode::serialize(&(skeleton_graph, gate_map, removed_nodes)).unwrap(),
+    )
+}
+
+/// Skeleton graph, gate map, and removed nodes
+pub type Skeleton = (
+    Graph<usize, usize>,
+    HashMap<usize, BaseGate<2, u8>>,
+    HashSet<NodeIndex>,
+);
+
+/// Reads skeleton graph, gate map, and removed nodes written by [`dump_skeleton`]
+pub fn load_skeleton(path: impl AsRef<Path>) -> Skeleton {
+    bincode::deserialize(&std::fs::read(path).unwrap()).unwrap()
+}
+
+/// Rebuilds gate map and map from gate id to node index, as returned by [`prepare_circuit`], from
+/// a skeleton graph and the circuit of its live nodes. For example, from a skeleton reloaded with
+/// [`load_skeleton`] and the circuit at the time of the dump. Removed nodes are skipped.
+///
+/// Returns an error if a live node has a gate id that is not in the circuit, or a gate of the
+/// circuit is not the weight of any live node.
+pub fn rebuild_maps<G: Gate + Clone>(
+    skeleton_graph: &Graph<usize, usize>,
+    removed_nodes: &HashSet<NodeIndex>,
+    circuit: &Circuit<G>,
+) -> Result<(HashMap<usize, G>, HashMap<usize, NodeIndex>), GateMapInconsistency> {
+    let mut gate_map = HashMap::default();
+    let mut gate_id_to_node_index_map = HashMap::default();
+    for node in skeleton_graph
+        .node_indices()
+        .filter(|node| !removed_nodes.contains(node))
+    {
+        let id = skeleton_graph[node];
+        let gate = circuit
+            .gate_by_id(id)
+            .ok_or(GateMapInconsistency::MissingGate { id })?;
+        gate_map.insert(id, gate.clone());
+        gate_id_to_node_index_map.insert(id, node);
+    }
+
+    if let Some(gate) = circuit
+        .iter_gates()
+        .find(|g| !gate_map.contains_key(&g.id()))
+    {
+        return Err(GateMapInconsistency::StaleGate { id: gate.id() });
+    }
+
+    Ok((gate_map, gate_id_to_node_index_map))
+}
+
+/// Peak resident set size, in bytes, over the checkpoints so far
+#[cfg(feature = "memory-stats")]
+static PEAK_RESIDENT_SET_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Logs resident set size of the process and its peak over the checkpoints so far. Dense
+/// structures, like the collision matrix of convex subcircuit search, grow quickly with the no. of
+/// gates.
+#[cfg(feature = "memory-stats")]
+fn log_memory_usage(tag: &str) {
+    match memory_stats::memory_stats() {
+        Some(usage) => {
+            let rss = usage.physical_mem;
+            let peak = PEAK_RESIDENT_SET_SIZE.fetch_max(rss, Relaxed).max(rss);
+            log::info!(
+                "[Memory] {tag} Resident set size: {} MiB, peak: {} MiB",
+                rss >> 20,
+                peak >> 20
+            );
+        }
+        None => {
+            log::error!("[Memory] {tag} Failed to query resident set size");
+        }
+    }
+}
+
+/// Schedule of the max. no. of iterations of replacement circuit search over the mixing steps of
+/// a run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplacementIterationsSchedule {
+    /// Same max. no. of iterations at every step
+    Constant,
+    /// Changes linearly from the initial max. no. of iterations at the first step to
+    /// `final_iterations` at the last step
+    Linear { final_iterations: usize },
+    /// Changes by a constant factor per step from the initial max. no. of iterations at the
+    /// first step to `final_iterations` at the last step
+    Exponential { final_iterations: usize },
+}
+
+impl ReplacementIterationsSchedule {
+    /// Returns max. no. of iterations at step `step` of a run of `total_steps` steps, starting from
+    /// `initial_iterations`. Never returns less than 1.
+    pub fn iterations(&self, initial_iterations: usize, step: usize, total_steps: usize) -> usize {
+        let step_fraction = (step as f64 / total_steps.max(1) as f64).min(1.0);
+        let initial = initial_iterations.max(1) as f64;
+        let iterations = match *self {
+            ReplacementIterationsSchedule::Constant => return initial_iterations.max(1),
+            ReplacementIterationsSchedule::Linear { final_iterations } => {
+                initial + (final_iterations.max(1) as f64 - initial) * step_fraction
+            }
+            ReplacementIterationsSchedule::Exponential { final_iterations } => {
+                initial * (final_iterations.max(1) as f64 / initial).powf(step_fraction)
+            }
+        };
+        (iterations.round() as usize).max(1)
+    }
+}
+
+/// State of the mixing before a local mixing step, including the RNG state.
+///
+/// Written by [`run_local_mixing`] when a step fails so that the step can be re-run with
+/// [`MixingStepSnapshot::replay`]. Replay follows the same path as the failed step only if the crate
+/// is built with feature `deterministic` and rayon runs a single thread (`RAYON_NUM_THREADS=1`).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MixingStepSnapshot<R> {
+    pub rng: R,
+    pub state: MixingState,
+    pub params: MixingStepParams,
+    /// [`MixingOptions::batch_regions`] of the step
+    pub batch_regions: usize,
+}
+
+impl<R: Send + Sync + SeedableRng + RngCore + Serialize + DeserializeOwned> MixingStepSnapshot<R> {
+    /// Writes snapshot to `path`. Use [`MixingStepSnapshot::load`] to read it back.
+    pub fn dump(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, bincode::serialize(self).unwrap())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        bincode::deserialize(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    /// Circuit before the local mixing step
+    pub fn circuit(&self) -> Circuit<BaseGate<2, u8>> {
+        self.state.circuit()
+    }
+
+    /// Re-runs the local mixing step, or batch of steps, from the snapshot. Returns stats of every
+    /// step applied and the circuit after the steps.
+    pub fn replay(mut self) -> (Vec<LocalMixingStepStats>, Circuit<BaseGate<2, u8>>) {
+        let stats = if self.batch_regions > 1 {
+            local_mixing_batch(
+                &mut self.state,
+                &self.params,
+                self.batch_regions,
+                &mut self.rng,
+                &mut MixingTimings::default(),
+                None,
+                None,
+            )
+        } else {
+            local_mixing_step(
+                &mut self.state,
+                &self.params,
+                &mut self.rng,
+                &mut MixingTimings::default(),
+                None,
+                None,
+            )
+            .into_iter()
+            .collect()
+        };
+        (stats, self.state.circuit())
+    }
+}
+
+/// Max. no. of wires for which the equivalence checks of [run_local_mixing] every `verify_every`
+/// steps run on all `2^n` inputs instead of random inputs
+pub const DEBUG_EXACT_EQ_CHECK_MAX_N: usize = 16;
+
+/// Runs a local mixing step, or a batch of steps if `options.batch_regions` > 1, on `state` and
+/// checks the mixed circuit against `original_circuit` at checkpoints, every
+/// `options.verify_every` steps, and after every batch. Mixed circuit and `rng` are passed to `cb`
+/// at checkpoints, so that mixing can be continued from the checkpoint. Returns the no. of steps applied, which is 0 if the step failed.
+///
+/// Steps of a batch are numbered from `options.step` in the metrics CSV and the mixing trace.
+pub fn run_local_mixing<
+    R: Send + Sync + SeedableRng + RngCore + Clone + Serialize + DeserializeOwned,
+>(
+    tag: &str,
+    original_circuit: Option<&Circuit<BaseGate<2, u8>>>,
+    state: &mut MixingState,
+    rng: &mut R,
+    options: &MixingOptions,
+    observers: MixingObservers<'_>,
+    mut cb: impl FnMut(Circuit<BaseGate<2, u8>>, &R),
+) -> usize {
+    let MixingOptions {
+        step_params,
+        replacement_iterations_schedule,
+        step,
+        total_steps,
+        to_checkpoint,
+        probabilitic_eq_check_iterations,
+        verify_every,
+        batch_regions,
+        // Only read by the gate count check of debug builds
+        preserve_gate_count: _preserve_gate_count,
+    } = *options;
+    let MixingObservers {
+        skeleton_dump_path,
+        step_snapshot_path,
+        metrics_csv_path,
+        checkpoint_csv_path,
+        mixing_trace_path,
+        mut replacement_histograms,
+        timings,
+        progress,
+    } = observers;
+    let mut default_timings = MixingTimings::default();
+    let timings = timings.unwrap_or(&mut default_timings);
+
+    // Regions of a batch are replaced without a check in between, so every batch is checked even
+    // in release builds
+    let is_batch = batch_regions > 1;
+    if verify_every != 0 || is_batch {
+        assert!(original_circuit.is_some());
+    }
+    let to_verify = is_batch || (verify_every != 0 && step % verify_every == 0);
+
+    log::info!("############################## [run_local_mixing START] {tag} ##############################");
+
+    let step_params = MixingStepParams {
+        max_replacement_iterations: replacement_iterations_schedule.iterations(
+            step_params.max_replacement_iterations,
+            step,
+            total_steps,
+        ),
+        ..step_params
+    };
+    log::info!(
+        "Max. replacement iterations: {}",
+        step_params.max_replacement_iterations
+    );
+
+    // Snapshot of the state before the step. Dumped if the step panics or fails the equivalence check.
+    let snapshot = step_snapshot_path.map(|_| MixingStepSnapshot {
+        rng: rng.clone(),
+        state: state.clone(),
+        params: step_params,
+        batch_regions,
+    });
+    let dump_snapshot = |snapshot: &Option<MixingStepSnapshot<R>>| {
+        if let (Some(path), Some(snapshot)) = (step_snapshot_path, snapshot) {
+            match snapshot.dump(path) {
+                Ok(_) => {
+                    log::error!("Dumped step snapshot to {:?}", path);
+                }
+                Err(e) => {
+                    log::error!("Failed to dump step snapshot with {:?}", e);
+                }
+            }
+        }
+    };
+    let dump_skeleton_graph = |state: &MixingState| {
+        let Some(path) = skeleton_dump_path else {
+            return;
+        };
+        match dump_skeleton(
+            path,
+            &state.skeleton_graph,
+            &state.gate_map,
+            &state.removed_nodes,
+        ) {
+            Ok(_) => {
+                log::error!("Dumped skeleton graph to {:?}", path);
+            }
+            Err(e) => {
+                log::error!("Failed to dump skeleton graph with {:?}", e);
+            }
+        }
+    };
+
+    #[cfg(debug_assertions)]
+    let gate_count_before = state.gate_count();
+
+    // Nodes of C^in are added after the existing nodes, in the order of the steps
+    let node_count_before = state.skeleton_graph.node_count();
+    let mut cout_gate_ids = mixing_trace_path.map(|_| vec![]);
+
+    let now = std::time::Instant::now();
+    let stats = match panic::catch_unwind(AssertUnwindSafe(|| {
+        if is_batch {
+            local_mixing_batch::<_>(
+                state,
+                &step_params,
+                batch_regions,
+                rng,
+                timings,
+                replacement_histograms.as_deref_mut(),
+                cout_gate_ids.as_mut(),
+            )
+        } else {
+            let mut step_cout_gate_ids = vec![];
+            let stats = local_mixing_step::<_>(
+                state,
+                &step_params,
+                rng,
+                timings,
+                replacement_histograms.as_deref_mut(),
+                cout_gate_ids.is_some().then_some(&mut step_cout_gate_ids),
+            );
+            if let (Some(cout_gate_ids), Some(_)) = (cout_gate_ids.as_mut(), stats) {
+                cout_gate_ids.push(step_cout_gate_ids);
+            }
+            stats.into_iter().collect_vec()
+        }
+    })) {
+        Ok(stats) => stats,
+        Err(e) => {
+            log::error!("[Error] (Local mixing step panicked at) {tag}");
+            dump_snapshot(&snapshot);
+            panic::resume_unwind(e);
+        }
+    };
+    let elapsed = now.elapsed();
+    let success = !stats.is_empty();
+
+    log::info!(
+        "local mixing step applied {} steps in {:?}",
+        stats.len(),
+        elapsed
+    );
+
+    #[cfg(debug_assertions)]
+    if _preserve_gate_count {
+        debug_assert_eq!(
+            state.gate_count(),
+            gate_count_before,
+            "Gate count changed in a step that preserves the gate count"
+        );
+    }
+
+    if let Some(histograms) = &replacement_histograms {
+        log::trace!("Replacement histograms: {:?}", histograms);
+    }
+
+    if let (Some(metrics_csv_path), true) = (metrics_csv_path, success) {
+        let gate_count = state.gate_count();
+        let depth = if gate_count == 0 {
+            0
+        } else {
+            let level = graph_level(
+                &state.skeleton_graph,
+                &state.graph_neighbours,
+                &state.removed_nodes,
+            );
+            level.into_iter().max().unwrap() + 1
+        };
+        // Steps of a batch share its time, gate count, and depth
+        for (i, step_stats) in stats.iter().enumerate() {
+            let row = StepMetrics {
+                step: step + i,
+                ell_out: step_params.ell_out,
+                ell_in: step_stats.ell_in,
+                convex_iterations: step_stats.convex_iterations,
+                replacement_iterations: step_stats.replacement_iterations,
+                step_time: elapsed / stats.len() as u32,
+                gate_count,
+                depth,
+            };
+            if let Err(e) = row.append_to_csv(metrics_csv_path) {
+                log::error!(
+                    "Failed to append step metrics to {:?}: {:?}",
+                    metrics_csv_path,
+                    e
+                );
+            }
+        }
+    }
+
+    if let (Some(mixing_trace_path), Some(cout_gate_ids)) = (mixing_trace_path, cout_gate_ids) {
+        debug_assert_eq!(
+            node_count_before
+                + stats
+                    .iter()
+                    .map(|step_stats| step_stats.ell_in)
+                    .sum::<usize>(),
+            state.skeleton_graph.node_count()
+        );
+        let mut cin_start = node_count_before;
+        for (i, (step_stats, removed_gates)) in izip!(&stats, cout_gate_ids).enumerate() {
+            let cin_end = cin_start + step_stats.ell_in;
+            let cin_nodes = (cin_start..cin_end).map(NodeIndex::new);
+            cin_start = cin_end;
+            let record = MixingTraceRecord {
+                step: step + i,
+                removed_gates,
+                added_gates: cin_nodes
+                    .clone()
+                    .map(|node| state.gate_map[&state.skeleton_graph[node]].clone())
+                    .collect(),
+                active_wires: step_stats.active_wires,
+                new_edges: cin_nodes
+                    .flat_map(|node| {
+                        let [incoming, outgoing] = &state.graph_neighbours[node.index()];
+                        chain!(
+                            incoming.iter().map(move |from| (*from, node)),
+                            outgoing.iter().map(move |to| (node, *to))
+                        )
+                    })
+                    // Nodes of later steps of a batch are added after this step's
+                    .filter(|(from, to)| from.index() < cin_end && to.index() < cin_end)
+                    .map(|(from, to)| (state.skeleton_graph[from], state.skeleton_graph[to]))
+                    .sorted()
+                    .dedup()
+                    .collect(),
+            };
+            if let Err(e) = record.append_to_jsonl(mixing_trace_path) {
+                log::error!(
+                    "Failed to append mixing trace record to {:?}: {:?}",
+                    mixing_trace_path,
+                    e
+                );
+            }
+        }
+    }
+
+    if success && (to_verify || to_checkpoint) {
+        let original_circuit = original_circuit.unwrap();
+        let verification_start = std::time::Instant::now();
+
+        #[cfg(debug_assertions)]
+        if let Err(e) = check_gate_map_consistency(
+            &state.skeleton_graph,
+            &state.gate_map,
+            &state.removed_nodes,
+            state.latest_id,
+        ) {
+            log::error!("[Error] (Gate map inconsistent with skeleton graph at) {tag}: {e:?}");
+            dump_skeleton_graph(state);
+            dump_snapshot(&snapshot);
+            panic!("Gate map inconsistent with skeleton graph: {e:?}");
+        }
+
+        let top_sorted_nodes = timed!("Topological sort after local mixing", {
+            toposort_with_cached_graph_neighbours(
+                &state.skeleton_graph,
+                &state.graph_neighbours,
+                &state.removed_nodes,
+            )
+        });
+
+        #[cfg(feature = "trace")]
+        log::trace!(
+            "Top sort after local mixing: {:?}",
+            // node_indices_to_gate_ids(top_sorted_nodes.iter(), &skeleton_graph)
+            &top_sorted_nodes
+        );
+
+        let mixed_circuit = Circuit::from_top_sorted_nodes(
+            &top_sorted_nodes,
+            &state.skeleton_graph,
+            &state.gate_map,
+            original_circuit.n(),
+        );
+
+        // Intermediate checks of small circuits run on all inputs, which is certain and doesn't
+        // consume the RNG
+        let (is_correct, diff_indices) =
+            if to_verify && original_circuit.n() <= DEBUG_EXACT_EQ_CHECK_MAX_N {
+                check_exact_equivalence(&original_circuit, &mixed_circuit)
+            } else {
+                check_probabilisitic_equivalence(
+                    &original_circuit,
+                    &mixed_circuit,
+                    probabilitic_eq_check_iterations,
+                    rng,
+                )
+            };
+        timings.verification += verification_start.elapsed();
+        if !is_correct {
+            log::error!(
+                "[Error] (Failed equivalence check at) {tag}. Different at indices {:?}",
+                diff_indices
+            );
+
+            match toposort(&state.skeleton_graph, None) {
+                Ok(_) => {
+                    log::error!("Top sort did not fail");
+                }
+                Err(e) => {
+                    log::error!("Top sort also fails with {:?}", e);
+                }
+            }
+
+            dump_skeleton_graph(state);
+            dump_snapshot(&snapshot);
+            assert!(false);
+        }
+
+        if let (Some(checkpoint_csv_path), true) = (checkpoint_csv_path, to_checkpoint) {
+            let row = CheckpointMetrics::new(step, &mixed_circuit);
+            if let Err(e) = row.append_to_csv(checkpoint_csv_path) {
+                log::error!(
+                    "Failed to append checkpoint metrics to {:?}: {:?}",
+                    checkpoint_csv_path,
+                    e
+                );
+            }
+        }
+
+        cb(mixed_circuit, rng);
+
+        #[cfg(feature = "memory-stats")]
+        if to_checkpoint {
+            log_memory_usage(tag);
+        }
+    }
+
+    if let Some(progress) = progress {
+        progress(StepProgress {
+            step,
+            success,
+            gate_count: state.gate_count(),
+            elapsed,
+        });
+    }
+
+    log::info!("############################## [run_local_mixing FINISH] {tag} ##############################");
+    stats.len()
+}
+
+pub fn check_probabilisitic_equivalence<G, R: RngCore>(
+    circuit0: &Circuit<G>,
+    circuit1: &Circuit<G>,
+    iterations: usize,
+    rng: &mut R,
+) -> (bool, Vec<usize>)
+where
+    G: Gate<Input = [bool]>,
+{
+    assert_eq!(circuit0.n(), circuit1.n());
+    let n = circuit0.n();
+
+    // Wires are sampled bit by bit so that any n is supported
+    let mut value = vec![0u8; n.div_ceil(8)];
+    for _ in 0..iterations {
+        rng.fill_bytes(&mut value);
+        let inputs = (0..n)
+            .map(|i| (value[i / 8] >> (i % 8)) & 1 == 1)
+            .collect_vec();
+
+        let mut inputs0 = inputs.clone();
+        circuit0.run(&mut inputs0);
+
+        let mut inputs1 = inputs.clone();
+        circuit1.run(&mut inputs1);
+
+        let mut diff_indices = vec![];
+        if inputs0 != inputs1 {
+            izip!(inputs0.iter(), inputs1.iter())
+                .enumerate()
+                .for_each(|(index, (v0, v1))| {
+                    if v0 != v1 {
+                        diff_indices.push(index);
+                    }
+                });
+
+            return (false, diff_indices);
+        }
+
+        // assert_eq!(inputs0, inputs1, "Different at indices {:?}", diff_indices);
+    }
+
+    return (true, vec![]);
+}
+
+/// Checks whether both circuits compute the same permutation by evaluating them on all `2^n`
+/// inputs.
+///
+/// Returns indices of output wires that differ for the smallest input on which circuits differ.
+pub fn check_exact_equivalence<G>(
+    circuit0: &Circuit<G>,
+    circuit1: &Circuit<G>,
+) -> (bool, Vec<usize>)
+where
+    G: Gate<Input = [bool]> + Sync,
+{
+    assert_eq!(circuit0.n(), circuit1.n());
+    let n = circuit0.n();
+    assert!(n <= 20, "{n} > 20; Too big!");
+
+    let diff_indices = (0..1usize << n).into_par_iter().find_map_first(|value| {
+        let inputs = (0..n).map(|i| (value >> i) & 1 == 1).collect_vec();
+
+        let mut inputs0 = inputs.clone();
+        circuit0.run(&mut inputs0);
+
+        let mut inputs1 = inputs;
+        circuit1.run(&mut inputs1);
+
+        (inputs0 != inputs1).then(|| {
+            izip!(0.., inputs0, inputs1)
+                .filter_map(|(index, v0, v1)| (v0 != v1).then_some(index))
+                .collect_vec()
+        })
+    });
+
+    match diff_indices {
+        Some(diff_indices) => (false, diff_indices),
+        None => (true, vec![]),
+    }
+}
+
+/// Finds where executions of two circuits on `input` diverge. Meant for debugging circuits that
+/// are expected to be equivalent but differ on `input`.
+///
+/// Gate orders of the circuits may differ (eg. after mixing), so executions are aligned by wire
+/// states instead of gate indices. Returns `(i, j)` such that wire states before gate `i` of
+/// `circuit0` and before gate `j` of `circuit1` are equal, with `j` as large as possible (and `i`
+/// as large as possible for that `j`). Remaining gates of both circuits compute different outputs
+/// from that state, so the first of them are where the executions break apart.
+///
+/// A circuit without gates only has the state `input`, before its gate 0. Hence if `circuit1` has
+/// no gates, `(i, 0)` is returned with the last `i` before which `circuit0` is in state `input`,
+/// and the same for `circuit0`.
+///
+/// Returns `None` only if both circuits produce the same output on `input`.
+pub fn first_divergent_gate<G>(
+    circuit0: &Circuit<G>,
+    circuit1: &Circuit<G>,
+    input: &[bool],
+) -> Option<(usize, usize)>
+where
+    G: Gate<Input = [bool]>,
+{
+    assert_eq!(circuit0.n(), circuit1.n());
+    assert_eq!(input.len(), circuit0.n());
+
+    let mut outputs0 = input.to_vec();
+    let trace0 = circuit0.run_with_trace(&mut outputs0);
+    let mut outputs1 = input.to_vec();
+    let trace1 = circuit1.run_with_trace(&mut outputs1);
+    if outputs0 == outputs1 {
+        return None;
+    }
+
+    // `i`-th state is the state before gate `i`. Circuit without gates has the input state only.
+    fn states<'a>(input: &'a [bool], trace: &'a [Vec<bool>]) -> Vec<&'a [bool]> {
+        iter::once(input)
+            .chain(trace.iter().map(Vec::as_slice))
+            .take(trace.len().max(1))
+            .collect()
+    }
+
+    // Last gate index of `circuit0` before which each state is seen
+    let mut state_to_gate0 = HashMap::default();
+    for (i, state) in states(input, &trace0).into_iter().enumerate() {
+        state_to_gate0.insert(state, i);
+    }
+
+    states(input, &trace1)
+        .into_iter()
+        .enumerate()
+        .rev()
+        .find_map(|(j, state)| state_to_gate0.get(state).map(|i| (*i, j)))
+}
+
+/// Estimates avalanche score of the circuit
+///
+/// For `iterations` random inputs, flips each input bit one at a time and measures the fraction
+/// of output bits that change. A circuit that mixes well has avalanche score close to 0.5.
+pub fn avalanche_score<G, R: RngCore>(circuit: &Circuit<G>, iterations: usize, rng: &mut R) -> f64
+where
+    G: Gate<Input = [bool]>,
+{
+    let n = circuit.n();
+    if n == 0 || iterations == 0 {
+        return 0.0;
+    }
+
+    let mut flipped_bits = 0usize;
+    for _ in 0..iterations {
+        let inputs = (0..n).map(|_| rng.gen_bool(0.5)).collect_vec();
+        let mut outputs = inputs.clone();
+        circuit.run(&mut outputs);
+
+        for i in 0..n {
+            let mut flipped_outputs = inputs.clone();
+            flipped_outputs[i] = !flipped_outputs[i];
+            circuit.run(&mut flipped_outputs);
+            flipped_bits += izip!(&outputs, &flipped_outputs)
+                .filter(|(v0, v1)| v0 != v1)
+                .count();
+        }
+    }
+
+    flipped_bits as f64 / (iterations * n * n) as f64
+}
+
+/// Max. no. of wires for which [`lat`] computes the `2^n x 2^n` linear approximation table
+pub const MAX_LAT_N: usize = 12;
+
+/// Returns linear approximation table (LAT) of the circuit
+///
+/// Entry `[a][b]` is the no. of inputs `x` for which parity of `x & a` equals parity of
+/// `output & b`, minus `2^(n-1)`. Bit `j` of masks, inputs and outputs is the value of wire `j`.
+/// Column `b` is half of the Walsh-Hadamard transform of `(-1)^(parity of output & b)`, hence the
+/// table is computed in `O(n 4^n)`.
+///
+/// Returns error if `n > MAX_LAT_N`.
+pub fn lat(circuit: &Circuit<BaseGate<2, u8>>) -> Result<Vec<Vec<i32>>, TooManyWires> {
+    let n = circuit.n();
+    if n > MAX_LAT_N {
+        return Err(TooManyWires {
+            n,
+            max_n: MAX_LAT_N,
+        });
+    }
+
+    let permutation = permutation_map(circuit);
+    let size = 1usize << n;
+    let columns = (0..size)
+        .into_par_iter()
+        .map(|output_mask| {
+            let mut walsh = permutation
+                .iter()
+                .map(|output| 1 - 2 * ((output & output_mask).count_ones() & 1) as i32)
+                .collect_vec();
+            let mut half = 1;
+            while half < size {
+                for block in walsh.chunks_mut(2 * half) {
+                    let (low, high) = block.split_at_mut(half);
+                    for (u, v) in izip!(low, high) {
+                        (*u, *v) = (*u + *v, *u - *v);
+                    }
+                }
+                half *= 2;
+            }
+            walsh.into_iter().map(|w| w / 2).collect_vec()
+        })
+        .collect::<Vec<_>>();
+
+    Ok((0..size)
+        .map(|input_mask| columns.iter().map(|column| column[input_mask]).collect())
+        .collect())
+}
+
+/// Max. no. of wires for which [`differential_uniformity`] runs the circuit on all inputs
+pub const MAX_DIFFERENTIAL_UNIFORMITY_N: usize = 12;
+
+/// Returns differential uniformity of the circuit
+///
+/// It is the max., over non-zero input differences `a` and output differences `b`, of no. of
+/// inputs `x` for which `f(x) ^ f(x ^ a) = b`. Inputs come in pairs `x` and `x ^ a`, hence it is
+/// even, and it is `2^n` for a circuit that computes an affine map. Lower is better.
+///
+/// Returns error if `n > MAX_DIFFERENTIAL_UNIFORMITY_N`.
+pub fn differential_uniformity(circuit: &Circuit<BaseGate<2, u8>>) -> Result<u32, TooManyWires> {
+    let n = circuit.n();
+    if n > MAX_DIFFERENTIAL_UNIFORMITY_N {
+        return Err(TooManyWires {
+            n,
+            max_n: MAX_DIFFERENTIAL_UNIFORMITY_N,
+        });
+    }
+
+    let permutation = permutation_map(circuit);
+    let size = 1usize << n;
+    Ok((1..size)
+        .into_par_iter()
+        .map(|input_difference| {
+            let mut counts = vec![0u32; size];
+            for (x, output) in permutation.iter().enumerate() {
+                counts[output ^ permutation[x ^ input_difference]] += 1;
+            }
+            counts.into_iter().max().unwrap()
+        })
+        .max()
+        .unwrap_or(0))
+}
+
+/// Returns no. of gates in the longest chain of gates in which every gate collides with the next
+///
+/// Gate `j` collides with an earlier gate `i` iff target of one is a control of the other. Hence,
+/// longest chain ending at gate `j` is found with a DP over gates that tracks, for every wire, the
+/// longest chain ending at a gate with the wire as target and as control. Runs in time linear in
+/// no. of gates, unlike [`prepare_circuit`] that checks every pair of gates.
+pub fn longest_chain<const N: usize, D>(circuit: &Circuit<BaseGate<N, D>>) -> usize
+where
+    D: Into<usize> + Copy,
+{
+    // Longest chain ending at a gate with the wire as target and as one of the controls
+    let mut longest_with_target = vec![0; circuit.n()];
+    let mut longest_with_control = vec![0; circuit.n()];
+
+    circuit
+        .gates()
+        .iter()
+        .map(|gate| {
+            let target = gate.target().into();
+            let controls = gate.controls().map(Into::into);
+            let longest = 1 + controls
+                .iter()
+                .map(|control| longest_with_target[*control])
+                .chain([longest_with_control[target]])
+                .max()
+                .unwrap();
+
+            longest_with_target[target] = longest_with_target[target].max(longest);
+            for control in controls {
+                longest_with_control[control] = longest_with_control[control].max(longest);
+            }
+            longest
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns no. of gates that don't collide with any earlier gate, i.e. gates with no incoming
+/// edges in the skeleton graph
+pub fn source_gate_count<const N: usize, D>(circuit: &Circuit<BaseGate<N, D>>) -> usize
+where
+    D: Into<usize> + Copy,
+{
+    non_colliding_gate_count(circuit.gates().iter(), circuit.n())
+}
+
+/// Returns no. of gates that don't collide with any later gate, i.e. gates with no outgoing edges
+/// in the skeleton graph
+pub fn sink_gate_count<const N: usize, D>(circuit: &Circuit<BaseGate<N, D>>) -> usize
+where
+    D: Into<usize> + Copy,
+{
+    non_colliding_gate_count(circuit.gates().iter().rev(), circuit.n())
+}
+
+/// Returns no. of `gates` that don't collide with any gate before them in the iteration order
+fn non_colliding_gate_count<'a, const N: usize, D>(
+    gates: impl Iterator<Item = &'a BaseGate<N, D>>,
+    n: usize,
+) -> usize
+where
+    D: Into<usize> + Copy + 'a,
+{
+    // Whether any gate seen so far has the wire as target and as one of the controls
+    let mut is_target = vec![false; n];
+    let mut is_control = vec![false; n];
+
+    gates
+        .filter(|gate| {
+            let target = gate.target().into();
+            let controls = gate.controls().map(Into::into);
+            let collides = is_control[target] || controls.iter().any(|control| is_target[*control]);
+
+            is_target[target] = true;
+            controls
+                .iter()
+                .for_each(|control| is_control[*control] = true);
+            !collides
+        })
+        .count()
+}
+
+/// Returns ids of pairs of identical gates that cancel each other. Gate `i` cancels a later
+/// identical gate `j` if there is no path from `i` to `j` in the skeleton graph, because then the
+/// circuit can be reordered to make them adjacent and every gate is an involution. Identical gates
+/// collide with the same gates, so that's the case iff no gate between them collides with them,
+/// no matter how many gates there are in between.
+///
+/// Pairs may share a gate, eg. when three identical gates don't collide with anything in between.
+/// Removing a pair only removes paths, so pairs that share no gate can be removed together.
+pub fn find_cancelling_pairs<const N: usize, D>(
+    circuit: &Circuit<BaseGate<N, D>>,
+) -> Vec<(usize, usize)>
+where
+    D: Into<usize> + Copy + Eq + Hash,
+{
+    let gates = circuit.gates();
+    let key = |gate: &BaseGate<N, D>| (gate.target(), gate.controls(), gate.control_func());
+
+    // Index of the last of identical gates
+    let last_identical_gate: HashMap<_, usize> = gates
+        .iter()
+        .enumerate()
+        .map(|(j, gate)| (key(gate), j))
+        .collect();
+
+    let mut pairs = vec![];
+    for (i, gate_i) in gates.iter().enumerate() {
+        let last = last_identical_gate[&key(gate_i)];
+        for gate_j in &gates[i + 1..=last] {
+            if gate_i.check_collision(gate_j) {
+                break;
+            }
+            if key(gate_j) == key(gate_i) {
+                pairs.push((gate_i.id(), gate_j.id()));
+            }
+        }
+    }
+    pairs
+}
+
+/// Structural statistics of a circuit returned by [`Circuit::stats`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitStats {
+    pub wire_count: usize,
+    pub gate_count: usize,
+    /// No. of levels of the skeleton graph, that is no. of gates in the longest chain of gates in
+    /// which every gate collides with the next. See [`longest_chain`].
+    pub depth: usize,
+    pub source_gates: usize,
+    pub sink_gates: usize,
+    /// No. of wires used by at least one gate, as target or as control
+    pub active_wires: usize,
+    /// See [`find_cancelling_pairs`]
+    pub cancelling_pairs: usize,
+    /// No. of gates per control function code
+    pub control_func_histogram: BTreeMap<u8, usize>,
+}
+
+impl Circuit<BaseGate<2, u8>> {
+    /// Returns structural statistics of the circuit. Doesn't build the skeleton graph.
+    pub fn stats(&self) -> CircuitStats {
+        CircuitStats {
+            wire_count: self.n(),
+            gate_count: self.num_gates(),
+            depth: longest_chain(self),
+            source_gates: source_gate_count(self),
+            sink_gates: sink_gate_count(self),
+            active_wires: self
+                .wire_usage()
+                .iter()
+                .filter(|(as_target, as_control)| as_target + as_control > 0)
+                .count(),
+            cancelling_pairs: find_cancelling_pairs(self).len(),
+            control_func_histogram: self.control_func_histogram().into_iter().collect(),
+        }
+    }
+}
+
+/// JSON format of a circuit with 2-control base gates. Each gate is `[control0, control1, target,
+/// control_func]`.
+#[derive(Serialize, Deserialize)]
+pub struct PrettyCircuit {
+    pub wire_count: usize,
+    pub gate_count: usize,
+    pub gates: Vec<[u8; 4]>,
+    /// Annotations of gates keyed by index of the gate in `gates`. Omitted if there are none.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<usize, String>,
+}
+
+impl From<&Circuit<BaseGate<2, u8>>> for PrettyCircuit {
+    fn from(circuit: &Circuit<BaseGate<2, u8>>) -> Self {
+        PrettyCircuit {
+            wire_count: circuit.n(),
+            gate_count: circuit.num_gates(),
+            gates: circuit
+                .gates()
+                .iter()
+                .map(|gate| {
+                    [
+                        gate.controls()[0],
+                        gate.controls()[1],
+                        gate.target(),
+                        gate.control_func(),
+                    ]
+                })
+                .collect_vec(),
+            annotations: circuit
+                .gates()
+                .iter()
+                .enumerate()
+                .filter_map(|(index, gate)| {
+                    circuit
+                        .annotation(gate.id())
+                        .map(|annotation| (index, annotation.to_string()))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&PrettyCircuit> for Circuit<BaseGate<2, u8>> {
+    /// Does not validate the circuit. Use [`parse_circuit_json`] for circuits from untrusted
+    /// sources. Annotations of gates that are not in the circuit are dropped.
+    fn from(circuit: &PrettyCircuit) -> Self {
+        let mut converted = Circuit::new(
+            circuit
+                .gates
+                .iter()
+                .enumerate()
+                .map(|(id, [control0, control1, target, control_func])| {
+                    BaseGate::<2, u8>::new(id, *target, [*control0, *control1], *control_func)
+                })
+                .collect(),
+            circuit.wire_count,
+        );
+        for (index, annotation) in &circuit.annotations {
+            if *index < circuit.gates.len() {
+                converted.annotate(*index, annotation.clone());
+            }
+        }
+        converted
+    }
+}
+
+/// Reason why [`parse_circuit_json`] rejected a circuit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// Input is not JSON of a [`PrettyCircuit`]
+    Json(String),
+    /// `gate_count` does not match no. of gates
+    GateCountMismatch { expected: usize, found: usize },
+    /// Gate at index `gate` has a wire that is not less than `wire_count`
+    WireOutOfBounds { gate: usize, wire: u8 },
+    /// Gate at index `gate` has its target wire as one of its control wires
+    TargetIsControl { gate: usize },
+    /// Gate at index `gate` has a control function larger than 15
+    InvalidControlFunc { gate: usize, control_func: u8 },
+    /// Annotation is of gate at index `gate`, which is not less than `gate_count`
+    AnnotationOutOfBounds { gate: usize },
+}
+
+/// Parses circuit from JSON of a [`PrettyCircuit`]. Gate ids are set to gate indices.
+///
+/// Unlike converting a deserialized [`PrettyCircuit`], checks that every wire of every gate is
+/// less than `wire_count`, that target wire is not a control wire, that control function is a
+/// valid [`circuit::Base2GateControlFunc`], and that annotated gates are in the circuit. Never
+/// panics, hence is safe to call on untrusted input.
+pub fn parse_circuit_json(s: &str) -> Result<Circuit<BaseGate<2, u8>>, ParseError> {
+    let circuit: PrettyCircuit =
+        serde_json::from_str(s).map_err(|e| ParseError::Json(e.to_string()))?;
+
+    if circuit.gate_count != circuit.gates.len() {
+        return Err(ParseError::GateCountMismatch {
+            expected: circuit.gate_count,
+            found: circuit.gates.len(),
+        });
+    }
+
+    for (gate, [control0, control1, target, control_func]) in circuit.gates.iter().enumerate() {
+        if let Some(wire) = [control0, control1, target]
+            .into_iter()
+            .find(|wire| **wire as usize >= circuit.wire_count)
+        {
+            return Err(ParseError::WireOutOfBounds { gate, wire: *wire });
+        }
+        if target == control0 || target == control1 {
+            return Err(ParseError::TargetIsControl { gate });
+        }
+        if *control_func > 15 {
+            return Err(ParseError::InvalidControlFunc {
+                gate,
+                control_func: *control_func,
+            });
+        }
+    }
+
+    if let Some(gate) = circuit
+        .annotations
+        .keys()
+        .find(|gate| **gate >= circuit.gate_count)
+    {
+        return Err(ParseError::AnnotationOutOfBounds { gate: *gate });
+    }
+
+    Ok((&circuit).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use petgraph::{
+        algo::{all_simple_paths, connected_components, has_path_connecting, toposort},
+        visit::{Dfs, Reversed, Visitable, Walker},
+    };
+    use rand::{thread_rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    #[test]
+    fn test_local_mixing() {
+        env_logger::init();
+
+        let gates = 100;
+        let n = 6;
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (original_circuit, _) =
+            sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
+
+        let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&original_circuit);
+        let mut top_sorted_nodes = toposort(&skeleton_graph, None).unwrap();
+        let mut latest_id = 0;
+        let mut gate_map = HashMap::default();
+        original_circuit.gates().iter().for_each(|g| {
+            latest_id = std::cmp::max(latest_id, g.id());
+            gate_map.insert(g.id(), g.clone());
+        });
+
+        let ell_out = 2;
+        let ell_in = 4;
+        let max_convex_iterations = 1000usize;
+        let max_replacement_iterations = 1000000usize;
+
+        let mut state = MixingState::new(&original_circuit);
+
+        let mut mixing_steps = 0;
+        let total_mixing_steps = 1000;
+
+        while mixing_steps < total_mixing_steps {
+            log::info!("#### Mixing step {mixing_steps} ####");
+
+            log::info!(
+                "Topological order before local mixing iteration: {:?}",
+                &node_indices_to_gate_ids(top_sorted_nodes.iter(), &skeleton_graph)
+            );
+
+            let success = local_mixing_step::<_>(
+                &mut state,
+                &MixingStepParams {
+                    ell_out,
+                    ell_in,
+                    max_ell_in: ell_in,
+                    max_convex_iterations,
+                    max_replacement_iterations,
+                    convex_search: ConvexSearchOptions::default(),
+                    replacement_search: ReplacementSearchOptions::default(),
+                },
+                &mut rng,
+                &mut MixingTimings::default(),
+                None,
+                None,
+            )
+            .is_some();
+
+            log::info!("local mixing step {mixing_steps} returned {success}");
+
+            if success {
+                // let original_graph = circuit_to_skeleton_graph(&original_circuit);
+                // let original_circuit_graphviz = Dot::with_config(
+                //     &original_graph,
+                //     &[Config::EdgeNoLabel, Config::NodeIndexLabel],
+                // );
+                // let mixed_circuit_graphviz = Dot::with_config(
+                //     &skeleton_graph,
+                //     &[Config::EdgeNoLabel, Config::NodeIndexLabel],
+                // );
+
+                // println!("Original circuit: {:?}", original_circuit_graphviz);
+                // println!("Mixed circuit: {:?}", mixed_circuit_graphviz);
+
+                let top_sorted_nodes = toposort_with_cached_graph_neighbours(
+                    &state.skeleton_graph,
+                    &state.graph_neighbours,
+                    &state.removed_nodes,
+                );
+
+                log::info!(
+                    "Topological order after local mixing iteration: {:?}",
+                    &node_indices_to_gate_ids(top_sorted_nodes.iter(), &state.skeleton_graph)
+                );
+
+                let mixed_circuit = state.circuit();
+                // n = 6, so all inputs are checked
+                let (is_correct, diff_indices) =
+                    check_exact_equivalence(&original_circuit, &mixed_circuit);
+                if !is_correct {
+                    println!("[Error] Different at indices {:?}", diff_indices);
+                    assert!(false);
+                }
+
+                mixing_steps += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_dfs() {
+        let gates = 50;
+        let n = 10;
+        let mut rng = thread_rng();
+        for _ in 0..10 {
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
+            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+
+            let mut visited_with_path = HashSet::default();
+            let mut visited = HashSet::default();
+            let mut path = vec![];
+            let node_a = thread_rng().gen_range(0..n) as u32;
+            let node_b = thread_rng().gen_range(0..n) as u32;
+            let source = NodeIndex::from(std::cmp::min(node_a, node_b));
+            let target = NodeIndex::from(std::cmp::max(node_a, node_b));
+            visited_with_path.insert(target);
+            dfs(
+                source,
+                &mut visited_with_path,
+                &mut visited,
+                &mut path,
+                &skeleton_graph,
+                Direction::Outgoing,
+                &mut HashSet::default(),
+            );
+
+            // visited path will always contain `target` even if no path exists from source to target. Here we remove it.
+            if visited_with_path.len() == 1 {
+                assert!(visited_with_path.remove(&target));
+            }
+
+            // println!(
+            //     "Source: {:?}, Target: {:?}, Visited nodes: {:?}",
+            //     source, target, &visited_with_path
+            // );
+
+            // visited nodes must equal all nodes on all paths from source to target
+            let mut expected_visited_nodes = HashSet::default();
+            all_simple_paths::<Vec<_>, _>(&skeleton_graph, source, target, 0, None)
+                .into_iter()
+                .for_each(|path| {
+                    path.into_iter().for_each(|node| {
+                        expected_visited_nodes.insert(node);
+                    });
+                });
+
+            assert_eq!(visited_with_path, expected_visited_nodes);
+        }
+    }
+
+    #[test]
+    fn test_find_convex_subcircuit() {
+        let gates = 100;
+        let n = 64;
+        let ell_out = 4;
+        let max_iterations = 10000;
+        let mut rng = ChaCha8Rng::from_entropy();
+
+        let mut iter = 0;
+        while iter < 10 {
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
+            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+            let graph_neighbors = graph_neighbors(&skeleton_graph, &mut HashSet::default());
+            let levels = graph_level(&skeleton_graph, &graph_neighbors, &HashSet::default());
+            let convex_subgraph = find_convex_fast(
+                &skeleton_graph,
+                &levels,
+                ell_out,
+                max_iterations,
+                &ConvexSearchOptions::default(),
+                &mut rng,
+                &mut HashSet::default(),
+                &HashSet::default(),
+            );
+
+            match convex_subgraph {
+                Ok((_, convex_subgraph, _)) => {
+                    // check that the subgraph is convex
+
+                    let values = convex_subgraph.iter().map(|v| *v).collect_vec();
+                    // If path exists from i to j then find all nodes in any path from i to j and check that the nodes are in the convex subgraph set
+                    for i in 0..values.len() {
+                        for j in 0..values.len() {
+                            if i != j {
+                                if has_path_connecting(&skeleton_graph, values[i], values[j], None)
+                                {
+                                    all_simple_paths::<Vec<_>, _>(
+                                        &skeleton_graph,
+                                        values[i],
+                                        values[j],
+                                        0,
+                                        None,
+                                    )
+                                    .into_iter()
+                                    .for_each(|path| {
+                                        path.iter().for_each(|node| {
+                                            assert!(convex_subgraph.contains(node));
+                                        });
+                                    });
+                                }
+                            }
+                        }
+                    }
+
+                    iter += 1;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_blah_level_bounded() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(200, 16, 1.0, &mut rng);
+        let (_, _, skeleton_graph, _, _, graph_neighbours, _, _) = prepare_circuit(&circuit);
+        let level = graph_level(&skeleton_graph, &graph_neighbours, &HashSet::default());
+
+        for ell_out in 2..=5 {
+            let mut successes = 0;
+            for _ in 0..50 {
+                let start_node = NodeIndex::new(rng.gen_range(0..200));
+                let mut convex_set = HashSet::from_iter([start_node]);
+                if !blah(
+                    ell_out,
+                    &mut convex_set,
+                    &skeleton_graph,
+                    &level,
+                    &HashSet::default(),
+                    &HashSet::default(),
+                    &ConvexSearchOptions {
+                        level_bounded: true,
+                        ..Default::default()
+                    },
+                ) {
+                    continue;
+                }
+                successes += 1;
+
+                assert_eq!(convex_set.len(), ell_out);
+                assert!(convex_set.contains(&start_node));
+                // No node outside the set is on a path between nodes of the set
+                for node in skeleton_graph.node_indices() {
+                    if convex_set.contains(&node) {
+                        continue;
+                    }
+                    let from_set = convex_set
+                        .iter()
+                        .any(|v| has_path_connecting(&skeleton_graph, *v, node, None));
+                    let to_set = convex_set
+                        .iter()
+                        .any(|v| has_path_connecting(&skeleton_graph, node, *v, None));
+                    assert!(!(from_set && to_set), "{node:?} breaks convexity");
+                }
+            }
+            assert!(successes > 0, "No convex set of size {ell_out} found");
+        }
+    }
+
+    /// Level bounded search finds a convex set from at least as many random start nodes as the
+    /// default search
+    #[test]
+    fn test_convex_search_success_rate() {
+        let gates = 2000;
+        let samples = 200;
+        for n in [64, 128] {
+            let mut rng = ChaCha8Rng::seed_from_u64(n as u64);
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
+            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+            for ell_out in 2..=5 {
+                let [default, level_bounded] = [false, true].map(|level_bounded| {
+                    let histogram = convex_size_histogram(
+                        &skeleton_graph,
+                        ell_out,
+                        samples,
+                        &ConvexSearchOptions {
+                            level_bounded,
+                            ..Default::default()
+                        },
+                        &mut ChaCha8Rng::seed_from_u64(ell_out as u64),
+                    );
+                    histogram.get(&ell_out).copied().unwrap_or(0)
+                });
+                assert!(
+                    level_bounded >= default,
+                    "n: {n}, ell_out: {ell_out}, successes: {level_bounded} level bounded, {default} default"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_convex_subcircuit_failure() {
+        let mut rng = ChaCha8Rng::from_entropy();
+
+        // Gates act on disjoint wires. Hence, the skeleton graph has no edges.
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+                BaseGate::<2, u8>::new(1, 3, [4, 5], 1),
+            ],
+            6,
+        );
+        let (_, _, skeleton_graph, _, _, graph_neighbors, _, _) = prepare_circuit(&circuit);
+        let levels = graph_level(&skeleton_graph, &graph_neighbors, &HashSet::default());
+
+        assert_eq!(
+            find_convex_fast(
+                &skeleton_graph,
+                &levels,
+                2,
+                100,
+                &ConvexSearchOptions::default(),
+                &mut rng,
+                &HashSet::default(),
+                &HashSet::default()
+            ),
+            Err(ConvexSearchFailure::ExhaustedStartNodes {
+                attempts: 2,
+                abandoned: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_find_convex_fast_prioritize_start_nodes() {
+        let mut rng = ChaCha8Rng::from_entropy();
+
+        // Only node 0 has a successor, node 1. Other nodes have none, hence can't start a convex
+        // set of 2 nodes.
+        let mut graph = Graph::<usize, usize>::new();
+        for id in 0..1000 {
+            graph.add_node(id);
+        }
+        graph.add_edge(NodeIndex::new(0), NodeIndex::new(1), 1);
+        let level = graph_level(
+            &graph,
+            &graph_neighbors(&graph, &mut HashSet::default()),
+            &HashSet::default(),
+        );
+
+        assert!(has_successors(
+            &graph,
+            NodeIndex::new(0),
+            1,
+            &HashSet::default(),
+            &HashSet::default()
+        ));
+        assert!(!has_successors(
+            &graph,
+            NodeIndex::new(0),
+            2,
+            &HashSet::default(),
+            &HashSet::default()
+        ));
+        assert!(!has_successors(
+            &graph,
+            NodeIndex::new(0),
+            1,
+            &HashSet::default(),
+            &HashSet::from_iter([NodeIndex::new(1)])
+        ));
+
+        let options = ConvexSearchOptions {
+            prioritize_start_nodes: true,
+            ..Default::default()
+        };
+        let (start_node, convex_set, attempts) = find_convex_fast(
+            &graph,
+            &level,
+            2,
+            1000,
+            &options,
+            &mut rng,
+            &HashSet::default(),
+            &HashSet::default(),
+        )
+        .unwrap();
+        assert_eq!(start_node, NodeIndex::new(0));
+        assert_eq!(
+            convex_set,
+            HashSet::from_iter([NodeIndex::new(0), NodeIndex::new(1)])
+        );
+        assert!(attempts >= 1);
+
+        // Start nodes without enough successors are still tried, just later
+        assert_eq!(
+            find_convex_fast(
+                &graph,
+                &level,
+                3,
+                1000,
+                &options,
+                &mut rng,
+                &HashSet::default(),
+                &HashSet::default(),
+            ),
+            Err(ConvexSearchFailure::ExhaustedStartNodes {
+                attempts: 1000,
+                abandoned: 1
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "at least 1 node")]
+    fn test_find_convex_fast_empty_subset() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(10, 4, 1.0, &mut rng);
+        let (_, _, skeleton_graph, _, _, graph_neighbours, _, _) = prepare_circuit(&circuit);
+        let level = graph_level(&skeleton_graph, &graph_neighbours, &HashSet::default());
+        let _ = find_convex_fast(
+            &skeleton_graph,
+            &level,
+            0,
+            1000,
+            &ConvexSearchOptions {
+                prioritize_start_nodes: true,
+                ..Default::default()
+            },
+            &mut rng,
+            &HashSet::default(),
+            &HashSet::default(),
+        );
+    }
+
+    #[test]
+    fn test_local_mixing_step_degenerate_circuits() {
+        let mut rng = ChaCha8Rng::from_entropy();
+
+        // (circuit, ell_out)
+        let cases = [
+            // No gates
+            (Circuit::new(vec![], 4), 2),
+            // Fewer gates than ell_out
+            (
+                Circuit::new(
+                    vec![
+                        BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+                        BaseGate::<2, u8>::new(1, 1, [0, 3], 6),
+                    ],
+                    4,
+                ),
+                3,
+            ),
+            // Single wire can't have a gate
+            (Circuit::new(vec![], 1), 2),
+        ];
+
+        for (circuit, ell_out) in cases {
+            let mut state = MixingState::new(&circuit);
+
+            let levels = graph_level(
+                &state.skeleton_graph,
+                &state.graph_neighbours,
+                &state.removed_nodes,
+            );
+            assert_eq!(
+                find_convex_fast(
+                    &state.skeleton_graph,
+                    &levels,
+                    ell_out,
+                    100,
+                    &ConvexSearchOptions::default(),
+                    &mut rng,
+                    &state.removed_nodes,
+                    &HashSet::default()
+                ),
+                Err(ConvexSearchFailure::TooFewGates {
+                    gates: circuit.gates().len()
+                })
+            );
+
+            let stats = local_mixing_step(
+                &mut state,
+                &MixingStepParams {
+                    ell_out,
+                    ell_in: 4,
+                    max_ell_in: 4,
+                    max_convex_iterations: 100,
+                    max_replacement_iterations: 1000,
+                    convex_search: ConvexSearchOptions::default(),
+                    replacement_search: ReplacementSearchOptions::default(),
+                },
+                &mut rng,
+                &mut MixingTimings::default(),
+                None,
+                None,
+            );
+            assert!(stats.is_none());
+            assert_eq!(state.circuit(), circuit);
+        }
+    }
+
+    fn find_all_predecessors_of_node(
+        node: NodeIndex,
+        graph: &Graph<usize, usize>,
+    ) -> HashSet<NodeIndex> {
+        let mut all_preds = HashSet::default();
+        for curr_node in graph.neighbors_directed(node, Direction::Incoming) {
+            dfs(
+                curr_node,
+                &mut HashSet::default(),
+                &mut all_preds,
+                &mut vec![],
+                graph,
+                Direction::Incoming,
+                &mut HashSet::default(),
+            );
+        }
+        return all_preds;
+    }
+
+    #[test]
+    fn test_topological_order_of_convex_subset() {
+        let gates = 2000;
+        let n = 64;
+        // TODO: Fails for >= 2 because top sort is random
+        let ell_out = 4;
+        let max_iterations = 10000;
+        let mut rng = ChaCha8Rng::from_entropy();
+
+        let mut iter = 0;
+        while iter < 100 {
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
+            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+            let graph_neighbors = graph_neighbors(&skeleton_graph, &mut HashSet::default());
+            let levels = graph_level(&skeleton_graph, &graph_neighbors, &HashSet::default());
+
+            let convex_subgraph = find_convex_fast(
+                &skeleton_graph,
+                &levels,
+                ell_out,
+                max_iterations,
+                &ConvexSearchOptions::default(),
+                &mut rng,
+                &mut HashSet::default(),
+                &HashSet::default(),
+            );
+
+            match convex_subgraph {
+                Ok((start_node, convex_subgraph, _)) => {
+                    // use DFS within convex set to topologically sort nodes in convex subgraph
+                    let mut convex_set_sorted = VecDeque::new();
+                    dfs_within_convex_set(
+                        start_node,
+                        &convex_subgraph,
+                        &skeleton_graph,
+                        &mut HashSet::default(),
+                        &mut convex_set_sorted,
+                    );
+
+                    // If node j is after node i, then j cannot be predecessor of i
+                    for i in 0..convex_set_sorted.len() {
+                        // find predecessors of i
+                        let all_preds =
+                            find_all_predecessors_of_node(convex_set_sorted[i], &skeleton_graph);
+                        for j in i + 1..convex_set_sorted.len() {
+                            assert!(!all_preds.contains(&convex_set_sorted[j]));
+                        }
+                    }
+
+                    iter += 1;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_weakly_connected() {
+        let n = 5;
+        let gates = 5;
+        let mut rng = thread_rng();
+        for _ in 0..10000 {
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
+            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+
+            let collisions_sets = circuit_to_collision_sets(&circuit);
+            let is_wc = is_collisions_set_weakly_connected(&collisions_sets);
+            let expected_wc = connected_components(&skeleton_graph) == 1;
+            assert_eq!(
+                is_wc, expected_wc,
+                "Expected {expected_wc} but got {is_wc} for collisions sets {:?}",
+                collisions_sets
+            );
+        }
+    }
+
+    #[test]
+    fn test_sample_circuit_with_active_wires() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        for _ in 0..200 {
+            let total_n: usize = rng.gen_range(3..64);
+            let active = rng.gen_range(3..=total_n);
+            let gates = rng.gen_range(active.div_ceil(3)..40);
+            let circuit = sample_circuit_with_active_wires(active, gates, total_n, &mut rng);
+            assert_eq!(circuit.gates().len(), gates);
+            assert_eq!(circuit.n(), total_n);
+
+            let active_wires: HashSet<_> = circuit
+                .gates()
+                .iter()
+                .flat_map(|gate| chain![[gate.target()], gate.controls()])
+                .collect();
+            assert_eq!(active_wires.len(), active);
+            assert!(active_wires.iter().all(|wire| (*wire as usize) < total_n));
+        }
+
+        let seed = rng.next_u64();
+        assert_eq!(
+            sample_circuit_with_active_wires(7, 4, 20, &mut ChaCha8Rng::seed_from_u64(seed)),
+            sample_circuit_with_active_wires(7, 4, 20, &mut ChaCha8Rng::seed_from_u64(seed)),
+        );
+    }
 
-    //     let diff = expected_active_edges
-    //         .difference(active_edges_with_gateids)
-    //         .collect_vec();
+    #[test]
+    fn test_sample_connected_circuit() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        for _ in 0..200 {
+            let gates = rng.gen_range(1..30);
+            let n = rng.gen_range(3..20);
+            let circuit = sample_connected_circuit(gates, n, &mut rng);
+            assert_eq!(circuit.gates().len(), gates);
+            assert_eq!(circuit.n(), n as usize);
 
-    //     if diff.len() != 0 {
-    //         log::trace!("@@ Active edge set did not update correctly @@");
-    //         for d in diff {
-    //             if expected_active_edges.contains(d) {
-    //                 log::trace!("   Active edge set does not contains expected edge {:?}", d);
-    //             } else {
-    //                 log::trace!("   Active edge set contains not-expected edge {:?}", d);
-    //             }
-    //         }
-    //         panic!();
-    //     }
-    // }
+            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+            assert_eq!(connected_components(&skeleton_graph), 1);
+            assert!(is_collisions_set_weakly_connected(
+                &circuit_to_collision_sets(&circuit)
+            ));
+        }
 
-    // update gate_id to node_index map
-    // timed!(
-    //     "Update gate_id_to_node_index_map",
-    //     for node in skeleton_graph.node_indices() {
-    //         if !removed_nodes.contains(&node) {
-    //             assert!(
-    //                 *gate_id_to_node_index_map
-    //                     .get(skeleton_graph.node_weight(node).unwrap())
-    //                     .unwrap()
-    //                     == node
-    //             );
-    //         }
-    //     }
-    // );
+        assert!(sample_connected_circuit(0, 2, &mut rng).gates().is_empty());
+    }
 
-    return true;
-}
+    #[test]
+    fn test_find_replacement_circuit() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 3;
+        let ell_in = 4;
+        let options = ReplacementSearchOptions::default();
 
-pub fn run_local_mixing<R: Send + Sync + SeedableRng + RngCore>(
-    tag: &str,
-    original_circuit: Option<&Circuit<BaseGate<2, u8>>>,
-    skeleton_graph: &mut Graph<usize, usize>,
-    direct_connections: &mut HashMap<usize, HashSet<usize>>,
-    direct_incoming_connections: &mut HashMap<usize, HashSet<usize>>,
-    gate_map: &mut HashMap<usize, BaseGate<2, u8>>,
-    gate_id_to_node_index_map: &mut HashMap<usize, NodeIndex>,
-    graph_neighbors: &mut Vec<[HashSet<NodeIndex>; 2]>,
-    removed_nodes: &mut HashSet<NodeIndex>,
-    active_edges_with_gateids: &mut HashSet<(usize, usize)>,
-    latest_id: &mut usize,
-    n: u8,
-    rng: &mut R,
-    ell_out: usize,
-    ell_in: usize,
-    max_convex_iterations: usize,
-    max_replacement_iterations: usize,
-    to_checkpoint: bool,
-    probabilitic_eq_check_iterations: usize,
-    mut cb: impl FnMut(Circuit<BaseGate<2, u8>>),
-    debug: bool,
-) -> bool {
-    if debug {
-        assert!(original_circuit.is_some());
+        for _ in 0..10 {
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(2, n as u8, 1.0, &mut rng);
+
+            // Specialized search and generic search must have identical acceptance criteria, with
+            // or without the prefilter
+            let no_prefilter = ReplacementSearchOptions {
+                prefilter_inputs: 0,
+                ..options
+            };
+            let partial_prefilter = ReplacementSearchOptions {
+                prefilter_inputs: 4,
+                ..options
+            };
+            let replacements = [
+                find_replacement_circuit(&circuit, ell_in, n, 1_000_000, &options, &mut rng),
+                find_replacement_circuit(&circuit, ell_in, n, 1_000_000, &no_prefilter, &mut rng),
+                find_replacement_circuit(
+                    &circuit,
+                    ell_in,
+                    n,
+                    1_000_000,
+                    &partial_prefilter,
+                    &mut rng,
+                ),
+                find_replacement_circuit_generic::<2, u8, _>(
+                    &circuit, ell_in, n as u8, 1.0, 1_000_000, &options, &mut rng,
+                ),
+            ];
+            for (replacement, iterations) in replacements {
+                let replacement = replacement.unwrap();
+                assert!(iterations >= 1);
+                assert_eq!(replacement.gates().len(), ell_in);
+                assert!(check_exact_equivalence(&circuit, &replacement).0);
+                assert!(replacement != circuit);
+                assert!(is_collisions_set_weakly_connected(
+                    &circuit_to_collision_sets(&replacement)
+                ));
+            }
+        }
     }
 
-    log::info!("############################## [run_local_mixing START] {tag} ##############################");
+    #[test]
+    #[should_panic(expected = "supports at most 19 wires")]
+    fn test_find_replacement_circuit_too_many_wires() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = MAX_REPLACEMENT_N + 1;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(4, n as u8, 1.0, &mut rng);
+        find_replacement_circuit(
+            &circuit,
+            4,
+            n,
+            100,
+            &ReplacementSearchOptions::default(),
+            &mut rng,
+        );
+    }
 
-    let now = std::time::Instant::now();
-    let success = local_mixing_step::<_>(
-        skeleton_graph,
-        ell_in,
-        ell_out,
-        n,
-        direct_connections,
-        direct_incoming_connections,
-        gate_map,
-        gate_id_to_node_index_map,
-        graph_neighbors,
-        removed_nodes,
-        active_edges_with_gateids,
-        latest_id,
-        max_replacement_iterations,
-        max_convex_iterations,
-        rng,
-    );
-    let elapsed = now.elapsed();
+    #[test]
+    fn test_replace_convex_set() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 6;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(30, n, 1.0, &mut rng);
+
+        // First two gates collide and nothing precedes the second gate. Hence they form a convex subset.
+        let mut gates = circuit.gates().to_vec();
+        gates[0] = BaseGate::new(gates[0].id(), 0, [1, 2], 1);
+        gates[1] = BaseGate::new(gates[1].id(), 1, [0, 3], 6);
+        let original_circuit = Circuit::new(gates, n as usize);
+
+        let mut state = MixingState::new(&original_circuit);
+
+        let start_node = state.gate_id_to_node_index_map[&original_circuit.gates()[0].id()];
+        let convex_subset = HashSet::from_iter([
+            start_node,
+            state.gate_id_to_node_index_map[&original_circuit.gates()[1].id()],
+        ]);
+
+        // Search that samples a single candidate fails without changing the graph, but is tallied
+        let mut histograms = ReplacementHistograms::default();
+        let gate_map = state.gate_map.clone();
+        assert!(replace_convex_set(
+            &mut state,
+            start_node,
+            &convex_subset,
+            &test_step_params(1),
+            &mut ChaCha8Rng::seed_from_u64(0),
+            &mut MixingTimings::default(),
+            Some(&mut histograms),
+        )
+        .is_none());
+        assert_eq!(state.gate_map, gate_map);
+
+        let mut timings = MixingTimings::default();
+        let stats = replace_convex_set(
+            &mut state,
+            start_node,
+            &convex_subset,
+            &test_step_params(10000000),
+            &mut rng,
+            &mut timings,
+            Some(&mut histograms),
+        )
+        .unwrap();
+        assert_eq!(stats.convex_iterations, 0);
+        assert_eq!(stats.ell_in, 4);
+        assert_eq!(stats.active_wires, 4);
+        assert_eq!(histograms.active_wires, BTreeMap::from([(4, 2)]));
+        assert_eq!(histograms.failed_iterations, BTreeMap::from([(1, 1)]));
+        assert_eq!(
+            histograms.found_iterations,
+            BTreeMap::from([(stats.replacement_iterations.next_power_of_two(), 1)])
+        );
+        // Convex subset is supplied by the caller, only levels are updated for the next search
+        assert!(timings.convex_search < timings.replacement_search);
+        assert!(timings.replacement_search > Duration::ZERO);
+        assert!(timings.rewiring > Duration::ZERO);
+
+        assert!(convex_subset.is_subset(&state.removed_nodes));
+        assert_eq!(
+            state.level,
+            graph_level(
+                &state.skeleton_graph,
+                &state.graph_neighbours,
+                &state.removed_nodes
+            )
+        );
+        let mixed_circuit = state.circuit();
+        assert_eq!(mixed_circuit.gates().len(), 30 - 2 + 4);
+        assert!(check_exact_equivalence(&original_circuit, &mixed_circuit).0);
+    }
 
-    log::info!("local mixing step returned {success} in {:?}", elapsed);
+    /// Params of 4 -> 4 replacements with `max_replacement_iterations`
+    fn test_step_params(max_replacement_iterations: usize) -> MixingStepParams {
+        MixingStepParams {
+            ell_out: 4,
+            ell_in: 4,
+            max_ell_in: 4,
+            max_convex_iterations: 0,
+            max_replacement_iterations,
+            convex_search: ConvexSearchOptions::default(),
+            replacement_search: ReplacementSearchOptions::default(),
+        }
+    }
 
-    if success {
-        if debug || to_checkpoint {
-            let original_circuit = original_circuit.unwrap();
+    #[test]
+    fn test_replace_identity_convex_set() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 6;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(30, n, 1.0, &mut rng);
+
+        // First four gates are A, B, B, A where A and B collide. Nothing precedes them, hence they
+        // form a convex subset that computes the identity.
+        let mut gates = circuit.gates().to_vec();
+        for (index, (target, controls, control_func)) in [
+            (0, [1, 2], 1),
+            (1, [0, 3], 6),
+            (1, [0, 3], 6),
+            (0, [1, 2], 1),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            gates[index] = BaseGate::new(gates[index].id(), target, controls, control_func);
+        }
+        let original_circuit = Circuit::new(gates, n as usize);
 
-            let top_sorted_nodes = timed!("Topological sort after local mixing", {
-                toposort_with_cached_graph_neighbours(
-                    skeleton_graph,
-                    graph_neighbors,
-                    removed_nodes,
-                )
-            });
+        let mut state = MixingState::new(&original_circuit);
 
-            #[cfg(feature = "trace")]
-            log::trace!(
-                "Top sort after local mixing: {:?}",
-                // node_indices_to_gate_ids(top_sorted_nodes.iter(), &skeleton_graph)
-                &top_sorted_nodes
-            );
+        let start_node = state.gate_id_to_node_index_map[&original_circuit.gates()[0].id()];
+        let convex_subset = HashSet::from_iter(
+            original_circuit.gates()[..4]
+                .iter()
+                .map(|gate| state.gate_id_to_node_index_map[&gate.id()]),
+        );
 
-            let mixed_circuit = Circuit::from_top_sorted_nodes(
-                &top_sorted_nodes,
-                &skeleton_graph,
-                &gate_map,
-                original_circuit.n(),
-            );
+        let stats = replace_convex_set(
+            &mut state,
+            start_node,
+            &convex_subset,
+            &test_step_params(1000),
+            &mut rng,
+            &mut MixingTimings::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(stats.ell_in, 4);
+
+        assert!(convex_subset.is_subset(&state.removed_nodes));
+        assert_eq!(
+            state.level,
+            graph_level(
+                &state.skeleton_graph,
+                &state.graph_neighbours,
+                &state.removed_nodes
+            )
+        );
+        let mixed_circuit = state.circuit();
+        assert_eq!(mixed_circuit.gates().len(), 30);
+        assert!(check_exact_equivalence(&original_circuit, &mixed_circuit).0);
+    }
 
-            let (is_correct, diff_indices) = check_probabilisitic_equivalence(
-                &original_circuit,
-                &mixed_circuit,
-                probabilitic_eq_check_iterations,
-                rng,
-            );
-            if !is_correct {
-                log::error!(
-                    "[Error] (Failed equivalence check at) {tag}. Different at indices {:?}",
-                    diff_indices
-                );
+    #[test]
+    fn test_find_identity_replacement_circuit() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let n = 6;
+        let identity = Circuit::new(
+            vec![
+                BaseGate::new(0, 0, [1, 2], 1),
+                BaseGate::new(1, 0, [1, 2], 1),
+            ],
+            n,
+        );
+        assert!(is_identity(&identity));
 
-                match toposort(skeleton_graph.deref(), None) {
-                    Ok(_) => {
-                        log::error!("Top sort did not fail");
-                    }
-                    Err(e) => {
-                        log::error!("Top sort also fails with {:?}", e);
-                    }
+        for weak_connectivity_check in [true, false] {
+            let options = ReplacementSearchOptions {
+                weak_connectivity_check,
+                ..Default::default()
+            };
+            let min_ell_in = if weak_connectivity_check { 4 } else { 2 };
+            for ell_in in 1..=7 {
+                let (replacement, _) = find_identity_replacement_circuit(
+                    &identity, ell_in, n, 1000, &options, &mut rng,
+                );
+                if ell_in < min_ell_in {
+                    assert_eq!(replacement, None);
+                    continue;
                 }
-                assert!(false);
+                let replacement = replacement.unwrap();
+                assert_eq!(replacement.gates().len(), ell_in);
+                assert!(is_identity(&replacement));
+                assert!(is_acceptable_replacement(&replacement, &identity, &options));
             }
-
-            cb(mixed_circuit);
         }
     }
 
-    log::info!("############################## [run_local_mixing FINISH] {tag} ##############################");
-    success
-}
+    #[test]
+    fn test_replace_convex_set_with() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 6;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(30, n, 1.0, &mut rng);
 
-pub fn check_probabilisitic_equivalence<G, R: RngCore>(
-    circuit0: &Circuit<G>,
-    circuit1: &Circuit<G>,
-    iterations: usize,
-    rng: &mut R,
-) -> (bool, Vec<usize>)
-where
-    G: Gate<Input = [bool]>,
-{
-    assert_eq!(circuit0.n(), circuit1.n());
-    let n = circuit0.n();
+        let mut gates = circuit.gates().to_vec();
+        gates[0] = BaseGate::new(gates[0].id(), 0, [1, 2], 1);
+        gates[1] = BaseGate::new(gates[1].id(), 1, [0, 3], 6);
+        let original_circuit = Circuit::new(gates, n as usize);
 
-    for value in rng
-        .sample_iter(Uniform::new(0, 1u128 << n))
-        .take(iterations)
-    {
-        // for value in 0..1u128 << 16 {
-        let mut inputs = vec![];
-        for i in 0..n {
-            inputs.push((value >> i) & 1u128 == 1);
+        let (
+            mut direct_connections,
+            mut direct_incoming_connections,
+            mut skeleton_graph,
+            mut gate_id_to_node_index_map,
+            mut gate_map,
+            mut graph_neighbors,
+            mut active_edges_with_gateids,
+            mut latest_id,
+        ) = prepare_circuit(&original_circuit);
+        let mut removed_nodes = HashSet::default();
+
+        let start_node = gate_id_to_node_index_map[&original_circuit.gates()[0].id()];
+        let convex_subset = HashSet::from_iter([
+            start_node,
+            gate_id_to_node_index_map[&original_circuit.gates()[1].id()],
+        ]);
+        let level = graph_level(&skeleton_graph, &graph_neighbors, &removed_nodes);
+
+        // Active wires {0, 1, 2, 3} are mapped to themselves. Appending a gate twice does not change the function.
+        let g0 = BaseGate::new(0, 0, [1, 2], 1);
+        let g1 = BaseGate::new(1, 1, [0, 3], 6);
+        let h = BaseGate::new(2, 2, [0, 1], 7);
+        let invalid_replacements = [
+            (
+                Circuit::new(vec![g0.clone(), g1.clone(), h.clone(), h.clone()], 5),
+                InvalidReplacement::WireCountMismatch {
+                    expected: 4,
+                    found: 5,
+                },
+            ),
+            (
+                Circuit::new(vec![g0.clone(), h.clone(), g1.clone(), h.clone()], 4),
+                InvalidReplacement::NotEquivalent,
+            ),
+        ];
+        for (c_in_dash, expected_err) in invalid_replacements {
+            let node_count = skeleton_graph.node_count();
+            let res = replace_convex_set_with(
+                &mut skeleton_graph,
+                start_node,
+                &convex_subset,
+                &level,
+                &c_in_dash,
+                n,
+                &mut direct_connections,
+                &mut direct_incoming_connections,
+                &mut gate_map,
+                &mut gate_id_to_node_index_map,
+                &mut graph_neighbors,
+                &mut removed_nodes,
+                &mut active_edges_with_gateids,
+                &mut latest_id,
+            );
+            assert_eq!(res, Err(expected_err));
+            assert_eq!(skeleton_graph.node_count(), node_count);
+            assert!(removed_nodes.is_empty());
         }
 
-        let mut inputs0 = inputs.clone();
-        circuit0.run(&mut inputs0);
+        replace_convex_set_with(
+            &mut skeleton_graph,
+            start_node,
+            &convex_subset,
+            &level,
+            &Circuit::new(vec![g0.clone(), g1.clone(), h.clone(), h.clone()], 4),
+            n,
+            &mut direct_connections,
+            &mut direct_incoming_connections,
+            &mut gate_map,
+            &mut gate_id_to_node_index_map,
+            &mut graph_neighbors,
+            &mut removed_nodes,
+            &mut active_edges_with_gateids,
+            &mut latest_id,
+        )
+        .unwrap();
 
-        let mut inputs1 = inputs.clone();
-        circuit1.run(&mut inputs1);
+        assert!(convex_subset.is_subset(&removed_nodes));
+        let top_sorted_nodes = toposort_with_cached_graph_neighbours(
+            &skeleton_graph,
+            &graph_neighbors,
+            &removed_nodes,
+        );
+        let mixed_circuit = Circuit::from_top_sorted_nodes(
+            &top_sorted_nodes,
+            &skeleton_graph,
+            &gate_map,
+            original_circuit.n(),
+        );
+        assert_eq!(mixed_circuit.gates().len(), 30 - 2 + 4);
+        assert!(check_exact_equivalence(&original_circuit, &mixed_circuit).0);
+    }
 
-        let mut diff_indices = vec![];
-        if inputs0 != inputs1 {
-            izip!(inputs0.iter(), inputs1.iter())
-                .enumerate()
-                .for_each(|(index, (v0, v1))| {
-                    if v0 != v1 {
-                        diff_indices.push(index);
-                    }
-                });
+    #[test]
+    fn test_replay_mixing_step_snapshot() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let n = 6;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(50, n, 1.0, &mut rng);
+        let snapshot = MixingStepSnapshot {
+            rng,
+            state: MixingState::new(&circuit),
+            params: MixingStepParams {
+                ell_out: 2,
+                ell_in: 4,
+                max_ell_in: 4,
+                max_convex_iterations: 1000,
+                max_replacement_iterations: 1000000,
+                convex_search: ConvexSearchOptions::default(),
+                replacement_search: ReplacementSearchOptions::default(),
+            },
+            batch_regions: 1,
+        };
 
-            return (false, diff_indices);
-        }
+        let path = std::env::temp_dir().join(format!("step_{}.bin", snapshot.rng.get_seed()[0]));
+        snapshot.dump(&path).unwrap();
+        let loaded = MixingStepSnapshot::<ChaCha8Rng>::load(&path);
+        std::fs::remove_file(&path).unwrap();
 
-        // assert_eq!(inputs0, inputs1, "Different at indices {:?}", diff_indices);
+        assert_eq!(loaded.rng, snapshot.rng);
+        assert!(check_exact_equivalence(&loaded.circuit(), &circuit).0);
+
+        let (stats, mixed_circuit) = loaded.replay();
+        assert_eq!(stats.len(), 1);
+        assert!(check_exact_equivalence(&circuit, &mixed_circuit).0);
     }
 
-    return (true, vec![]);
-}
+    #[cfg(feature = "no-rayon")]
+    #[test]
+    fn test_no_rayon_reproducible() {
+        let mix = |seed: u64| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(50, 8, 1.0, &mut rng);
+            let mut state = MixingState::new(&circuit);
+
+            for _ in 0..5 {
+                local_mixing_step(
+                    &mut state,
+                    &MixingStepParams {
+                        ell_out: 2,
+                        ell_in: 4,
+                        max_ell_in: 4,
+                        max_convex_iterations: 1000,
+                        max_replacement_iterations: 1000000,
+                        convex_search: ConvexSearchOptions::default(),
+                        replacement_search: ReplacementSearchOptions::default(),
+                    },
+                    &mut rng,
+                    &mut MixingTimings::default(),
+                    None,
+                    None,
+                );
+            }
 
-#[cfg(test)]
-mod tests {
-    use petgraph::{
-        algo::{all_simple_paths, connected_components, has_path_connecting, toposort},
-        visit::{Dfs, Reversed, Visitable, Walker},
-    };
-    use rand::{thread_rng, SeedableRng};
-    use rand_chacha::ChaCha8Rng;
+            state.circuit().canonical_hash()
+        };
 
-    use super::*;
+        // Sections that still use rayon must run on a single thread
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let seed = thread_rng().next_u64();
+        assert_eq!(pool.install(|| mix(seed)), pool.install(|| mix(seed)));
+    }
 
     #[test]
-    fn test_local_mixing() {
-        env_logger::init();
-
-        let gates = 100;
-        let n = 6;
+    #[should_panic(expected = "Too few wires")]
+    fn test_sample_circuit_with_too_few_wires() {
         let mut rng = ChaCha8Rng::from_entropy();
-        let (original_circuit, _) =
-            sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
 
-        let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&original_circuit);
-        let mut top_sorted_nodes = toposort(&skeleton_graph, None).unwrap();
-        let mut latest_id = 0;
-        let mut gate_map = HashMap::new();
-        original_circuit.gates().iter().for_each(|g| {
-            latest_id = std::cmp::max(latest_id, g.id());
-            gate_map.insert(g.id(), g.clone());
-        });
+        // No gates need no wires
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(0, 2, 1.0, &mut rng);
+        assert!(circuit.gates().is_empty());
+
+        sample_circuit_with_base_gate::<2, u8, _>(10, 2, 1.0, &mut rng);
+    }
+
+    #[test]
+    fn test_is_acceptable_replacement() {
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+                BaseGate::<2, u8>::new(1, 1, [0, 3], 6),
+            ],
+            4,
+        );
 
-        let ell_out = 2;
-        let ell_in = 4;
-        let max_convex_iterations = 1000usize;
-        let max_replacement_iterations = 1000000usize;
+        // Same gates with different ids is the same circuit
+        let relabelled = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(10, 0, [1, 2], 1),
+                BaseGate::<2, u8>::new(11, 1, [0, 3], 6),
+            ],
+            4,
+        );
+        let options = ReplacementSearchOptions::default();
+        assert!(!is_acceptable_replacement(&relabelled, &circuit, &options));
 
-        let (
-            mut direct_connections,
-            mut direct_incoming_connections,
-            mut skeleton_graph,
-            mut gate_id_to_node_index_map,
-            mut gate_map,
-            mut graph_neighbors,
-            mut active_edges_with_gateids,
-            mut latest_id,
-        ) = prepare_circuit(&original_circuit);
+        let h = BaseGate::<2, u8>::new(2, 2, [0, 1], 7);
+        let inflated = Circuit::new(
+            chain![circuit.gates().iter().cloned(), [h.clone(), h]].collect(),
+            4,
+        );
+        assert!(is_acceptable_replacement(&inflated, &circuit, &options));
+
+        // Neither gate controls on target of the other
+        let disconnected = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [2, 3], 1),
+                BaseGate::<2, u8>::new(1, 1, [2, 3], 6),
+            ],
+            4,
+        );
+        assert!(!is_acceptable_replacement(
+            &disconnected,
+            &circuit,
+            &options
+        ));
+        let options = ReplacementSearchOptions {
+            weak_connectivity_check: false,
+            ..Default::default()
+        };
+        assert!(is_acceptable_replacement(&disconnected, &circuit, &options));
+        // Same circuit is never acceptable
+        assert!(!is_acceptable_replacement(&relabelled, &circuit, &options));
+    }
 
-        let mut removed_nodes = HashSet::new();
+    #[test]
+    fn test_dump_and_load_skeleton() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, 10, 1.0, &mut rng);
+        let (_, _, skeleton_graph, _, gate_map, _, _, _) = prepare_circuit(&circuit);
+        let removed_nodes = skeleton_graph
+            .node_indices()
+            .step_by(7)
+            .collect::<HashSet<_>>();
 
-        let mut mixing_steps = 0;
-        let total_mixing_steps = 1000;
+        let path = std::env::temp_dir().join(format!("skeleton_{}.bin", rng.next_u64()));
+        dump_skeleton(&path, &skeleton_graph, &gate_map, &removed_nodes).unwrap();
+        let (loaded_graph, loaded_gate_map, loaded_removed_nodes) = load_skeleton(&path);
+        std::fs::remove_file(&path).unwrap();
 
-        while mixing_steps < total_mixing_steps {
-            log::info!("#### Mixing step {mixing_steps} ####");
+        assert_eq!(
+            loaded_graph.node_weights().collect_vec(),
+            skeleton_graph.node_weights().collect_vec()
+        );
+        assert_eq!(
+            loaded_graph
+                .edge_references()
+                .map(|e| (e.source(), e.target()))
+                .collect_vec(),
+            skeleton_graph
+                .edge_references()
+                .map(|e| (e.source(), e.target()))
+                .collect_vec()
+        );
+        assert_eq!(loaded_gate_map, gate_map);
+        assert_eq!(loaded_removed_nodes, removed_nodes);
+    }
 
-            log::info!(
-                "Topological order before local mixing iteration: {:?}",
-                &node_indices_to_gate_ids(top_sorted_nodes.iter(), &skeleton_graph)
-            );
+    #[test]
+    fn test_rebuild_maps() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, 10, 1.0, &mut rng);
+        let (_, _, skeleton_graph, gate_id_to_node_index_map, gate_map, _, _, _) =
+            prepare_circuit(&circuit);
+
+        let path = std::env::temp_dir().join(format!("skeleton_{}.bin", rng.next_u64()));
+        dump_skeleton(&path, &skeleton_graph, &gate_map, &HashSet::default()).unwrap();
+        let (loaded_graph, _, loaded_removed_nodes) = load_skeleton(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            rebuild_maps(&loaded_graph, &loaded_removed_nodes, &circuit),
+            Ok((gate_map.clone(), gate_id_to_node_index_map.clone()))
+        );
 
-            let success = local_mixing_step::<_>(
-                &mut skeleton_graph,
-                ell_in,
-                ell_out,
-                n,
-                &mut direct_connections,
-                &mut direct_incoming_connections,
-                &mut gate_map,
-                &mut gate_id_to_node_index_map,
-                &mut graph_neighbors,
-                &mut removed_nodes,
-                &mut active_edges_with_gateids,
-                &mut latest_id,
-                max_replacement_iterations,
-                max_convex_iterations,
-                &mut rng,
+        // Removed nodes are skipped, so the circuit only has gates of live nodes
+        let removed_nodes = skeleton_graph
+            .node_indices()
+            .step_by(7)
+            .collect::<HashSet<_>>();
+        let removed_ids = removed_nodes
+            .iter()
+            .map(|node| skeleton_graph[*node])
+            .collect::<HashSet<_>>();
+        let live_circuit = Circuit::new(
+            circuit
+                .iter_gates()
+                .filter(|g| !removed_ids.contains(&g.id()))
+                .cloned()
+                .collect(),
+            circuit.n(),
+        );
+        let (live_gate_map, live_gate_id_to_node_index_map) =
+            rebuild_maps(&skeleton_graph, &removed_nodes, &live_circuit).unwrap();
+        assert_eq!(live_gate_map.len(), 100 - removed_nodes.len());
+        for (id, gate) in &live_gate_map {
+            assert_eq!(gate, &gate_map[id]);
+            assert_eq!(
+                live_gate_id_to_node_index_map[id],
+                gate_id_to_node_index_map[id]
             );
+        }
 
-            log::info!("local mixing step {mixing_steps} returned {success}");
+        // Gates of removed nodes are not expected in the circuit
+        assert!(matches!(
+            rebuild_maps(&skeleton_graph, &removed_nodes, &circuit),
+            Err(GateMapInconsistency::StaleGate { id }) if removed_ids.contains(&id)
+        ));
+
+        // Every live node needs a gate in the circuit
+        let missing_id = live_circuit.gates()[0].id();
+        let missing_circuit = Circuit::new(live_circuit.gates()[1..].to_vec(), circuit.n());
+        assert_eq!(
+            rebuild_maps(&skeleton_graph, &removed_nodes, &missing_circuit),
+            Err(GateMapInconsistency::MissingGate { id: missing_id })
+        );
+    }
 
-            if success {
-                // let original_graph = circuit_to_skeleton_graph(&original_circuit);
-                // let original_circuit_graphviz = Dot::with_config(
-                //     &original_graph,
-                //     &[Config::EdgeNoLabel, Config::NodeIndexLabel],
-                // );
-                // let mixed_circuit_graphviz = Dot::with_config(
-                //     &skeleton_graph,
-                //     &[Config::EdgeNoLabel, Config::NodeIndexLabel],
-                // );
+    #[test]
+    fn test_find_replacement_circuit_deterministic() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 3;
+        let ell_in = 4;
 
-                // println!("Original circuit: {:?}", original_circuit_graphviz);
-                // println!("Mixed circuit: {:?}", mixed_circuit_graphviz);
+        for _ in 0..10 {
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(2, n as u8, 1.0, &mut rng);
+            let seed = rng.next_u64();
+
+            let replacements = (0..2)
+                .map(|_| {
+                    find_replacement_circuit_deterministic(
+                        &circuit,
+                        ell_in,
+                        n,
+                        1_000_000,
+                        &ReplacementSearchOptions::default(),
+                        &mut ChaCha8Rng::seed_from_u64(seed),
+                    )
+                })
+                .collect_vec();
+            assert!(replacements[0] == replacements[1]);
+
+            let replacement = replacements[0].0.as_ref().unwrap();
+            assert!(check_exact_equivalence(&circuit, replacement).0);
+            assert!(is_acceptable_replacement(
+                replacement,
+                &circuit,
+                &ReplacementSearchOptions::default()
+            ));
+        }
+    }
 
-                let top_sorted_nodes = toposort_with_cached_graph_neighbours(
-                    &skeleton_graph,
-                    &graph_neighbors,
-                    &removed_nodes,
-                );
+    #[test]
+    fn test_permutation_map() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 10;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, n as u8, 1.0, &mut rng);
+
+        let permutation = permutation_map(&circuit);
+        assert_eq!(permutation.len(), 1 << n);
+        for (value, output) in permutation.iter().enumerate() {
+            let mut inputs = value_to_bitstring(value, n);
+            assert_eq!(bitstring_to_value(&inputs), value);
+            circuit.run(&mut inputs);
+            assert_eq!(bitstring_to_value(&inputs), *output);
+        }
+        assert!(permutation.iter().all_unique());
+    }
 
-                log::info!(
-                    "Topological order after local mixing iteration: {:?}",
-                    &node_indices_to_gate_ids(top_sorted_nodes.iter(), &skeleton_graph)
-                );
+    #[test]
+    fn test_permutation_table() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, 11, 1.0, &mut rng);
 
-                let mixed_circuit = Circuit::from_top_sorted_nodes(
-                    &top_sorted_nodes,
-                    &skeleton_graph,
-                    &gate_map,
-                    original_circuit.n(),
-                );
-                let (is_correct, diff_indices) = check_probabilisitic_equivalence(
-                    &original_circuit,
-                    &mixed_circuit,
-                    1000,
-                    &mut rng,
-                );
-                if !is_correct {
-                    println!("[Error] Different at indices {:?}", diff_indices);
-                    assert!(false);
-                }
+        // Must match the serial construction exactly, including order of entries
+        let mut serial = vec![];
+        for i in 0..1usize << 11 {
+            let inputs = from_fn::<_, 11, _>(|j| (i >> j) & 1 == 1);
+            let mut outputs = inputs;
+            circuit.run(&mut outputs);
+            serial.push((inputs, outputs));
+        }
+        assert_eq!(
+            permutation_table::<11, { 1 << 11 }>(&circuit).to_vec(),
+            serial
+        );
+    }
 
-                mixing_steps += 1;
+    #[test]
+    fn test_step_metrics_csv() {
+        let path = std::env::temp_dir().join(format!("metrics_{}.csv", thread_rng().next_u64()));
+        for step in 0..3 {
+            StepMetrics {
+                step,
+                ell_out: 2,
+                ell_in: 4,
+                convex_iterations: 1,
+                replacement_iterations: 100,
+                step_time: Duration::from_millis(5),
+                gate_count: 50,
+                depth: 10,
             }
+            .append_to_csv(&path)
+            .unwrap();
         }
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let rows = csv.lines().collect_vec();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], StepMetrics::CSV_HEADER);
+        assert_eq!(rows[1], "0,2,4,1,100,5.000,50,10");
+        assert_eq!(rows[3], "2,2,4,1,100,5.000,50,10");
     }
 
     #[test]
-    fn test_dfs() {
-        let gates = 50;
-        let n = 10;
-        let mut rng = thread_rng();
-        for _ in 0..10 {
-            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
-            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+    fn test_checkpoint_metrics_csv() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(50, 8, 1.0, &mut rng);
+        let path = std::env::temp_dir().join(format!("checkpoints_{}.csv", rng.next_u64()));
+        for step in [0, 1000] {
+            CheckpointMetrics::new(step, &circuit)
+                .append_to_csv(&path)
+                .unwrap();
+        }
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let rows = csv.lines().collect_vec();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], CheckpointMetrics::CSV_HEADER);
+        let columns = rows[2].split(',').collect_vec();
+        assert_eq!(columns[0], "1000");
+        assert_eq!(columns[1], "50");
+        assert_eq!(columns[2], longest_chain(&circuit).to_string());
+        let avalanche_score: f64 = columns[3].parse().unwrap();
+        assert!((0.0..=1.0).contains(&avalanche_score));
+    }
 
-            let mut visited_with_path = HashSet::new();
-            let mut visited = HashSet::new();
-            let mut path = vec![];
-            let node_a = thread_rng().gen_range(0..n) as u32;
-            let node_b = thread_rng().gen_range(0..n) as u32;
-            let source = NodeIndex::from(std::cmp::min(node_a, node_b));
-            let target = NodeIndex::from(std::cmp::max(node_a, node_b));
-            visited_with_path.insert(target);
-            dfs(
-                source,
-                &mut visited_with_path,
-                &mut visited,
-                &mut path,
-                &skeleton_graph,
-                Direction::Outgoing,
-                &mut HashSet::new(),
+    /// Options of a step of [`run_local_mixing`] with 2 -> 4 replacements, verified after every
+    /// step
+    fn test_mixing_options(step: usize, total_steps: usize) -> MixingOptions {
+        MixingOptions {
+            step_params: MixingStepParams {
+                ell_out: 2,
+                ell_in: 4,
+                max_ell_in: 4,
+                max_convex_iterations: 1000,
+                max_replacement_iterations: 100_000,
+                convex_search: ConvexSearchOptions::default(),
+                replacement_search: ReplacementSearchOptions::default(),
+            },
+            replacement_iterations_schedule: ReplacementIterationsSchedule::Constant,
+            step,
+            total_steps,
+            to_checkpoint: false,
+            probabilitic_eq_check_iterations: 100,
+            verify_every: 1,
+            batch_regions: 1,
+            preserve_gate_count: false,
+        }
+    }
+
+    /// Runs 20 verified steps of [`run_local_mixing`] on `state` of `original_circuit` with
+    /// `batch_regions`, tracing to `mixing_trace_path` if set. Returns the successful steps.
+    fn run_test_mixing(
+        original_circuit: &Circuit<BaseGate<2, u8>>,
+        state: &mut MixingState,
+        rng: &mut ChaCha8Rng,
+        batch_regions: usize,
+        mixing_trace_path: Option<&Path>,
+    ) -> Vec<usize> {
+        let skeleton_dump_path =
+            std::env::temp_dir().join(format!("skeleton_{}.bin", rng.next_u64()));
+        let total_steps = 20;
+        let mut successful_steps = vec![];
+        let mut step = 0;
+        for _ in 0..total_steps {
+            let applied = run_local_mixing(
+                &format!("Step {step}"),
+                Some(original_circuit),
+                state,
+                rng,
+                &MixingOptions {
+                    batch_regions,
+                    ..test_mixing_options(step, total_steps)
+                },
+                MixingObservers {
+                    skeleton_dump_path: Some(&skeleton_dump_path),
+                    mixing_trace_path,
+                    ..Default::default()
+                },
+                |_, _| {},
             );
+            successful_steps.extend(step..step + applied);
+            step += applied;
+        }
+        successful_steps
+    }
 
-            // visited path will always contain `target` even if no path exists from source to target. Here we remove it.
-            if visited_with_path.len() == 1 {
-                assert!(visited_with_path.remove(&target));
+    #[test]
+    fn test_run_local_mixing_progress() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 8;
+        let (original_circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(40, n, 1.0, &mut rng);
+
+        for verify_every in [1, 3] {
+            let mut state = MixingState::new(&original_circuit);
+            let skeleton_dump_path =
+                std::env::temp_dir().join(format!("skeleton_{}.bin", rng.next_u64()));
+
+            let total_steps = 10;
+            let mut progress = vec![];
+            let mut checkpoints = 0;
+            let mut replacement_histograms = ReplacementHistograms::default();
+            for step in 0..total_steps {
+                let applied = run_local_mixing(
+                    &format!("Step {step}"),
+                    Some(&original_circuit),
+                    &mut state,
+                    &mut rng,
+                    &MixingOptions {
+                        verify_every,
+                        ..test_mixing_options(step, total_steps)
+                    },
+                    MixingObservers {
+                        skeleton_dump_path: Some(&skeleton_dump_path),
+                        replacement_histograms: Some(&mut replacement_histograms),
+                        progress: Some(&mut |step_progress| progress.push(step_progress)),
+                        ..Default::default()
+                    },
+                    |_, _| checkpoints += 1,
+                );
+                assert_eq!(progress.len(), step + 1);
+                assert_eq!(progress[step].step, step);
+                assert_eq!(progress[step].success, applied > 0);
+                assert_eq!(progress[step].gate_count, state.gate_count());
             }
 
-            // println!(
-            //     "Source: {:?}, Target: {:?}, Visited nodes: {:?}",
-            //     source, target, &visited_with_path
-            // );
+            // Checkpoint callback still runs after every successful step that is verified. With
+            // `verify_every = 1` that's every successful step.
+            let successes = progress.iter().filter(|p| p.success).count();
+            let verified = progress
+                .iter()
+                .filter(|p| p.success && p.step % verify_every == 0)
+                .count();
+            assert_eq!(checkpoints, verified);
+            // Every search is tallied, found or not. A step finds C^in iff it succeeds.
+            let ReplacementHistograms {
+                active_wires,
+                found_iterations,
+                failed_iterations,
+            } = &replacement_histograms;
+            assert_eq!(found_iterations.values().sum::<usize>(), successes);
+            assert!(failed_iterations.values().sum::<usize>() <= total_steps - successes);
+            assert_eq!(
+                active_wires.values().sum::<usize>(),
+                successes + failed_iterations.values().sum::<usize>()
+            );
+            assert!(chain!(found_iterations.keys(), failed_iterations.keys())
+                .all(|iterations| iterations.is_power_of_two()));
+        }
+    }
 
-            // visited nodes must equal all nodes on all paths from source to target
-            let mut expected_visited_nodes = HashSet::new();
-            all_simple_paths::<Vec<_>, _>(&skeleton_graph, source, target, 0, None)
-                .into_iter()
-                .for_each(|path| {
-                    path.into_iter().for_each(|node| {
-                        expected_visited_nodes.insert(node);
-                    });
-                });
+    #[test]
+    fn test_run_local_mixing_frozen_gates() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let n = 8;
+        let (original_circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(40, n, 1.0, &mut rng);
+        let mut state = MixingState::new(&original_circuit);
+
+        // Every 4th gate is frozen
+        let frozen_gates: HashSet<usize> = (0..40).step_by(4).collect();
+        state.freeze_gates(&frozen_gates).unwrap();
+        let level = graph_level(
+            &state.skeleton_graph,
+            &state.graph_neighbours,
+            &state.removed_nodes,
+        );
+        for _ in 0..100 {
+            if let Ok((_, convex_set, _)) = find_convex_fast(
+                &state.skeleton_graph,
+                &level,
+                4,
+                1000,
+                &ConvexSearchOptions::default(),
+                &mut rng,
+                &state.removed_nodes,
+                &state.frozen_nodes,
+            ) {
+                assert!(convex_set.is_disjoint(&state.frozen_nodes));
+            }
+        }
 
-            assert_eq!(visited_with_path, expected_visited_nodes);
+        assert!(!run_test_mixing(&original_circuit, &mut state, &mut rng, 1, None).is_empty());
+
+        let mixed_circuit = state.circuit();
+        assert!(check_exact_equivalence(&original_circuit, &mixed_circuit).0);
+        for id in frozen_gates {
+            assert_eq!(
+                mixed_circuit.gate_by_id(id),
+                original_circuit.gate_by_id(id),
+                "Frozen gate {id} is removed"
+            );
         }
     }
 
     #[test]
-    fn test_find_convex_subcircuit() {
-        let gates = 100;
-        let n = 64;
-        let ell_out = 4;
-        let max_iterations = 10000;
-        let mut rng = ChaCha8Rng::from_entropy();
+    fn test_shuffled_circuit() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let n = 8;
+        let (original_circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(40, n, 1.0, &mut rng);
+        let mut state = MixingState::new(&original_circuit);
+        assert!(!run_test_mixing(&original_circuit, &mut state, &mut rng, 1, None).is_empty());
+
+        let circuit = state.circuit();
+        let shuffled = state.shuffled_circuit(&mut rng);
+        assert!(check_exact_equivalence(&circuit, &shuffled).0);
+        assert_eq!(
+            shuffled
+                .gates()
+                .iter()
+                .map(|gate| gate.id())
+                .sorted()
+                .collect_vec(),
+            circuit
+                .gates()
+                .iter()
+                .map(|gate| gate.id())
+                .sorted()
+                .collect_vec()
+        );
+        assert_ne!(shuffled.gates(), circuit.gates());
+
+        // Graph built from it has the same collisions without the removed nodes
+        let prepared = prepare_circuit(&shuffled);
+        assert_eq!(validate_prepared(&prepared), Ok(()));
+        let rebuilt = MixingState::from_prepared(prepared, n);
+        assert_eq!(rebuilt.gate_count(), state.gate_count());
+        assert!(rebuilt.removed_nodes.is_empty());
+        assert!(check_exact_equivalence(&circuit, &rebuilt.circuit()).0);
+    }
 
-        let mut iter = 0;
-        while iter < 10 {
-            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
-            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
-            let graph_neighbors = graph_neighbors(&skeleton_graph, &mut HashSet::new());
-            let levels = graph_level(&skeleton_graph, &graph_neighbors, &HashSet::new());
-            let convex_subgraph = find_convex_fast(
-                &skeleton_graph,
-                &levels,
-                ell_out,
-                max_iterations,
+    #[test]
+    fn test_annotations_survive_mixing() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let n = 8;
+        let (mut original_circuit, _) =
+            sample_circuit_with_base_gate::<2, u8, _>(40, n, 1.0, &mut rng);
+        let digest = original_circuit.digest();
+        for id in 0..40 {
+            original_circuit.annotate(id, format!("gate {id}"));
+        }
+        // Annotations are not serialized with the circuit
+        assert_eq!(original_circuit.digest(), digest);
+
+        let mut state = MixingState::new(&original_circuit);
+        assert!(!run_test_mixing(&original_circuit, &mut state, &mut rng, 1, None).is_empty());
+
+        let mixed_circuit = state.circuit().with_annotations_from(&original_circuit);
+        assert!(check_exact_equivalence(&original_circuit, &mixed_circuit).0);
+        // Surviving gates keep their annotations, replacement gates have none
+        for gate in mixed_circuit.iter_gates() {
+            let expected = (gate.id() < 40).then(|| format!("gate {}", gate.id()));
+            assert_eq!(mixed_circuit.annotation(gate.id()), expected.as_deref());
+        }
+        // Annotations of removed gates are dropped
+        assert!(mixed_circuit.annotations().len() < 40);
+        for id in mixed_circuit.annotations().keys() {
+            assert!(mixed_circuit.gate_by_id(*id).is_some());
+        }
+    }
+
+    #[test]
+    fn test_mixing_trace() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let n = 8;
+        let (original_circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(40, n, 1.0, &mut rng);
+
+        for batch_regions in [1, 4] {
+            let mut state = MixingState::new(&original_circuit);
+            let trace_path = std::env::temp_dir().join(format!("trace_{}.jsonl", rng.next_u64()));
+
+            let successful_steps = run_test_mixing(
+                &original_circuit,
+                &mut state,
                 &mut rng,
-                &mut HashSet::new(),
+                batch_regions,
+                Some(&trace_path),
             );
+            assert!(!successful_steps.is_empty());
 
-            match convex_subgraph {
-                Some((start_node, convex_subgraph)) => {
-                    // check that the subgraph is convex
+            let records = std::fs::read_to_string(&trace_path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<MixingTraceRecord>(line).unwrap())
+                .collect_vec();
+            std::fs::remove_file(&trace_path).unwrap();
+            assert_eq!(
+                records.iter().map(|record| record.step).collect_vec(),
+                successful_steps
+            );
 
-                    let values = convex_subgraph.iter().map(|v| *v).collect_vec();
-                    // If path exists from i to j then find all nodes in any path from i to j and check that the nodes are in the convex subgraph set
-                    for i in 0..values.len() {
-                        for j in 0..values.len() {
-                            if i != j {
-                                if has_path_connecting(&skeleton_graph, values[i], values[j], None)
-                                {
-                                    all_simple_paths::<Vec<_>, _>(
-                                        &skeleton_graph,
-                                        values[i],
-                                        values[j],
-                                        0,
-                                        None,
-                                    )
-                                    .into_iter()
-                                    .for_each(|path| {
-                                        path.iter().for_each(|node| {
-                                            assert!(convex_subgraph.contains(node));
-                                        });
-                                    });
-                                }
-                            }
-                        }
-                    }
+            // Replaying the trace on the original circuit gives the gates of the mixed circuit
+            let mut gates: HashMap<usize, BaseGate<2, u8>> = original_circuit
+                .iter_gates()
+                .map(|gate| (gate.id(), gate.clone()))
+                .collect();
+            for record in &records {
+                assert!(record.active_wires >= 3);
+                for id in &record.removed_gates {
+                    assert!(gates.remove(id).is_some(), "Removed gate {id} is not live");
+                }
+                for gate in &record.added_gates {
+                    assert!(gates.insert(gate.id(), gate.clone()).is_none());
+                }
+                let added_ids = record
+                    .added_gates
+                    .iter()
+                    .map(|gate| gate.id())
+                    .collect::<HashSet<_>>();
+                for (from, to) in &record.new_edges {
+                    assert!(added_ids.contains(from) || added_ids.contains(to));
+                    assert!(gates[from].check_collision(&gates[to]));
+                }
+            }
+            assert_eq!(gates, state.gate_map);
+        }
+    }
+
+    #[test]
+    fn test_replacement_iterations_schedule() {
+        let constant = ReplacementIterationsSchedule::Constant;
+        assert_eq!(constant.iterations(1000, 0, 100), 1000);
+        assert_eq!(constant.iterations(1000, 100, 100), 1000);
+
+        let linear = ReplacementIterationsSchedule::Linear {
+            final_iterations: 100,
+        };
+        assert_eq!(linear.iterations(1000, 0, 100), 1000);
+        assert_eq!(linear.iterations(1000, 50, 100), 550);
+        assert_eq!(linear.iterations(1000, 100, 100), 100);
+        // Steps past the end of the run stay at the final value
+        assert_eq!(linear.iterations(1000, 200, 100), 100);
+
+        let exponential = ReplacementIterationsSchedule::Exponential {
+            final_iterations: 10,
+        };
+        assert_eq!(exponential.iterations(1000, 0, 100), 1000);
+        assert_eq!(exponential.iterations(1000, 50, 100), 100);
+        assert_eq!(exponential.iterations(1000, 100, 100), 10);
+
+        // Never drops to 0 iterations
+        let to_zero = ReplacementIterationsSchedule::Linear {
+            final_iterations: 0,
+        };
+        assert_eq!(to_zero.iterations(1000, 100, 100), 1);
+        assert_eq!(constant.iterations(0, 0, 0), 1);
+    }
+
+    #[test]
+    fn test_lat() {
+        let parity = |value: usize| (value.count_ones() & 1) as i32;
 
-                    iter += 1;
-                }
-                None => {}
+        // Matches the definition on a random circuit
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(30, 5, 1.0, &mut rng);
+        let permutation = permutation_map(&circuit);
+        let table = lat(&circuit).unwrap();
+        for (input_mask, row) in table.iter().enumerate() {
+            for (output_mask, entry) in row.iter().enumerate() {
+                let matches = izip!(0.., &permutation)
+                    .filter(|(x, output)| parity(x & input_mask) == parity(*output & output_mask))
+                    .count() as i32;
+                assert_eq!(*entry, matches - 16);
             }
         }
-    }
 
-    fn find_all_predecessors_of_node(
-        node: NodeIndex,
-        graph: &Graph<usize, usize>,
-    ) -> HashSet<NodeIndex> {
-        let mut all_preds = HashSet::new();
-        for curr_node in graph.neighbors_directed(node, Direction::Incoming) {
-            dfs(
-                curr_node,
-                &mut HashSet::new(),
-                &mut all_preds,
-                &mut vec![],
-                graph,
-                Direction::Incoming,
-                &mut HashSet::new(),
+        // XOR gates compute a linear map M. Parity of output & b equals parity of x & M^T b for
+        // every x, hence every column has a single maximal entry and 0s elsewhere.
+        let n = 6;
+        let gates = (0..40)
+            .map(|id| {
+                let wires = rand::seq::index::sample(&mut rng, n, 3);
+                BaseGate::<2, u8>::new(
+                    id,
+                    wires.index(0) as u8,
+                    [wires.index(1) as u8, wires.index(2) as u8],
+                    circuit::Base2GateControlFunc::XOR as u8,
+                )
+            })
+            .collect_vec();
+        let table = lat(&Circuit::new(gates, n)).unwrap();
+        for output_mask in 0..1 << n {
+            let column = table.iter().map(|row| row[output_mask]).collect_vec();
+            assert_eq!(
+                column
+                    .iter()
+                    .filter(|entry| **entry == 1 << (n - 1))
+                    .count(),
+                1
+            );
+            assert_eq!(
+                column.iter().filter(|entry| **entry == 0).count(),
+                (1 << n) - 1
             );
         }
-        return all_preds;
+
+        let circuit = Circuit::<BaseGate<2, u8>>::new(vec![], 13);
+        assert_eq!(lat(&circuit), Err(TooManyWires { n: 13, max_n: 12 }));
     }
 
     #[test]
-    fn test_topological_order_of_convex_subset() {
-        let gates = 2000;
-        let n = 64;
-        // TODO: Fails for >= 2 because top sort is random
-        let ell_out = 4;
-        let max_iterations = 10000;
+    fn test_differential_uniformity() {
+        // Matches the definition on a random circuit
         let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(30, 5, 1.0, &mut rng);
+        let permutation = permutation_map(&circuit);
+        let expected = (1..32usize)
+            .flat_map(|a| (0..32usize).map(move |b| (a, b)))
+            .map(|(a, b)| {
+                (0..32usize)
+                    .filter(|x| permutation[*x] ^ permutation[x ^ a] == b)
+                    .count() as u32
+            })
+            .max()
+            .unwrap();
+        let uniformity = differential_uniformity(&circuit).unwrap();
+        assert_eq!(uniformity, expected);
+        assert_eq!(uniformity % 2, 0);
+
+        // Output difference of an affine map only depends on input difference
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], circuit::Base2GateControlFunc::XOR as u8),
+                BaseGate::<2, u8>::new(1, 3, [0, 0], circuit::Base2GateControlFunc::NA as u8),
+            ],
+            4,
+        );
+        assert_eq!(differential_uniformity(&circuit), Ok(16));
+
+        // wire 0 ^= wire 1 & wire 2. Input difference on wire 0 always gives the same output
+        // difference.
+        let circuit = Circuit::new(vec![BaseGate::<2, u8>::new(0, 0, [1, 2], 1)], 3);
+        assert_eq!(differential_uniformity(&circuit), Ok(8));
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+                BaseGate::<2, u8>::new(1, 1, [0, 2], 1),
+                BaseGate::<2, u8>::new(2, 2, [0, 1], 1),
+            ],
+            3,
+        );
+        // Almost perfect nonlinear: every (a, b) holds for 0 or 2 inputs
+        assert_eq!(differential_uniformity(&circuit), Ok(2));
 
-        let mut iter = 0;
-        while iter < 100 {
-            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
-            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
-            let graph_neighbors = graph_neighbors(&skeleton_graph, &mut HashSet::new());
-            let levels = graph_level(&skeleton_graph, &graph_neighbors, &HashSet::new());
+        let circuit = Circuit::<BaseGate<2, u8>>::new(vec![], 13);
+        assert_eq!(
+            differential_uniformity(&circuit),
+            Err(TooManyWires { n: 13, max_n: 12 })
+        );
+    }
 
-            let convex_subgraph = find_convex_fast(
-                &skeleton_graph,
-                &levels,
-                ell_out,
-                max_iterations,
-                &mut rng,
-                &mut HashSet::new(),
-            );
+    #[test]
+    fn test_longest_chain() {
+        assert_eq!(
+            longest_chain(&Circuit::<BaseGate<2, u8>>::new(vec![], 4)),
+            0
+        );
 
-            match convex_subgraph {
-                Some((start_node, convex_subgraph)) => {
-                    // use DFS within convex set to topologically sort nodes in convex subgraph
-                    let mut convex_set_sorted = VecDeque::new();
-                    dfs_within_convex_set(
-                        start_node,
-                        &convex_subgraph,
-                        &skeleton_graph,
-                        &mut HashSet::new(),
-                        &mut convex_set_sorted,
-                    );
+        // Gate 1 and gate 2 collide with gate 0 but not with each other
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+                BaseGate::<2, u8>::new(1, 3, [0, 4], 1),
+                BaseGate::<2, u8>::new(2, 1, [4, 5], 1),
+                BaseGate::<2, u8>::new(3, 5, [3, 6], 1),
+            ],
+            7,
+        );
+        assert_eq!(longest_chain(&circuit), 3);
 
-                    // If node j is after node i, then j cannot be predecessor of i
-                    for i in 0..convex_set_sorted.len() {
-                        // find predecessors of i
-                        let all_preds =
-                            find_all_predecessors_of_node(convex_set_sorted[i], &skeleton_graph);
-                        for j in i + 1..convex_set_sorted.len() {
-                            assert!(!all_preds.contains(&convex_set_sorted[j]));
-                        }
-                    }
+        // Longest chain is one more than the deepest level of the skeleton graph
+        let mut rng = ChaCha8Rng::from_entropy();
+        for _ in 0..10 {
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(200, 10, 1.0, &mut rng);
+            let (_, _, skeleton_graph, _, _, graph_neighbours, _, _) = prepare_circuit(&circuit);
+            let levels = graph_level(&skeleton_graph, &graph_neighbours, &HashSet::default());
+            assert_eq!(longest_chain(&circuit), levels.iter().max().unwrap() + 1);
+        }
+    }
 
-                    iter += 1;
-                }
-                None => {}
-            }
+    #[test]
+    fn test_graph_level_reverse() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(200, 10, 1.0, &mut rng);
+        let (_, _, skeleton_graph, _, _, graph_neighbours, _, _) = prepare_circuit(&circuit);
+        let levels = graph_level(&skeleton_graph, &graph_neighbours, &HashSet::default());
+        let reverse_levels =
+            graph_level_reverse(&skeleton_graph, &graph_neighbours, &HashSet::default());
+
+        for edge in skeleton_graph.edge_references() {
+            let (source, target) = (edge.source().index(), edge.target().index());
+            assert!(levels[source] < levels[target]);
+            assert!(reverse_levels[source] > reverse_levels[target]);
         }
+        for (node, [incoming, outgoing]) in graph_neighbours.iter().enumerate() {
+            assert_eq!(levels[node] == 0, incoming.is_empty());
+            assert_eq!(reverse_levels[node] == 0, outgoing.is_empty());
+        }
+
+        // Longest of longest paths through nodes is the longest chain
+        let longest_path = izip!(&levels, &reverse_levels)
+            .map(|(level, reverse_level)| level + reverse_level)
+            .max()
+            .unwrap();
+        assert_eq!(longest_path + 1, longest_chain(&circuit));
     }
 
     #[test]
-    fn test_is_weakly_connected() {
-        let n = 5;
-        let gates = 5;
-        let mut rng = thread_rng();
-        for _ in 0..10000 {
-            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
-            let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+    fn test_update_graph_level() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(200, 10, 1.0, &mut rng);
+        let mut state = MixingState::new(&circuit);
+
+        let mut successful_steps = 0;
+        for _ in 0..20 {
+            let stats = local_mixing_step(
+                &mut state,
+                &MixingStepParams {
+                    ell_out: 2,
+                    ell_in: 4,
+                    max_ell_in: 4,
+                    max_convex_iterations: 1000,
+                    max_replacement_iterations: 100_000,
+                    convex_search: ConvexSearchOptions::default(),
+                    replacement_search: ReplacementSearchOptions::default(),
+                },
+                &mut rng,
+                &mut MixingTimings::default(),
+                None,
+                None,
+            );
+            successful_steps += stats.is_some() as usize;
 
-            let collisions_sets = circuit_to_collision_sets(&circuit);
-            let is_wc = is_collisions_set_weakly_connected(&collisions_sets);
-            let expected_wc = connected_components(&skeleton_graph) == 1;
+            let expected = graph_level(
+                &state.skeleton_graph,
+                &state.graph_neighbours,
+                &state.removed_nodes,
+            );
+            assert_eq!(state.level, expected);
+        }
+        assert!(successful_steps > 0);
+    }
+
+    #[test]
+    fn test_local_mixing_batch() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let n = 128;
+        let (original_circuit, _) =
+            sample_circuit_with_base_gate::<2, u8, _>(100, n, 1.0, &mut rng);
+        let mut state = MixingState::new(&original_circuit);
+
+        // Most regions of a batch fail to find a replacement circuit or have closures that overlap
+        // with earlier regions. Batches run until one replaces more than 1 region.
+        let mut max_batch_size = 0;
+        for batch in 0..50 {
+            if batch >= 10 && max_batch_size > 1 {
+                break;
+            }
+            let stats = local_mixing_batch(
+                &mut state,
+                &MixingStepParams {
+                    ell_out: 2,
+                    ell_in: 4,
+                    max_ell_in: 4,
+                    max_convex_iterations: 1000,
+                    max_replacement_iterations: 100000,
+                    convex_search: ConvexSearchOptions::default(),
+                    replacement_search: ReplacementSearchOptions::default(),
+                },
+                8,
+                &mut rng,
+                &mut MixingTimings::default(),
+                None,
+                None,
+            );
+            max_batch_size = max_batch_size.max(stats.len());
+
+            check_gate_map_consistency(
+                &state.skeleton_graph,
+                &state.gate_map,
+                &state.removed_nodes,
+                state.latest_id,
+            )
+            .unwrap();
             assert_eq!(
-                is_wc, expected_wc,
-                "Expected {expected_wc} but got {is_wc} for collisions sets {:?}",
-                collisions_sets
+                state.level,
+                graph_level(
+                    &state.skeleton_graph,
+                    &state.graph_neighbours,
+                    &state.removed_nodes
+                )
             );
+
+            let mixed_circuit = state.circuit();
+            let (is_correct, diff_indices) =
+                check_probabilisitic_equivalence(&original_circuit, &mixed_circuit, 1000, &mut rng);
+            assert!(is_correct, "Different at indices {diff_indices:?}");
         }
+        assert!(max_batch_size > 1);
     }
 
-    struct Stats<T> {
-        samples: Vec<T>,
+    #[test]
+    fn test_convex_size_histogram() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(200, 10, 1.0, &mut rng);
+        let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+
+        let histogram = convex_size_histogram(
+            &skeleton_graph,
+            4,
+            500,
+            &ConvexSearchOptions::default(),
+            &mut rng,
+        );
+        assert_eq!(histogram.values().sum::<usize>(), 500);
+        assert!(histogram.keys().all(|size| (1..=4).contains(size)));
+        assert!(histogram.contains_key(&4));
+
+        let histogram = convex_size_histogram(
+            &skeleton_graph,
+            1,
+            100,
+            &ConvexSearchOptions::default(),
+            &mut rng,
+        );
+        assert_eq!(histogram, BTreeMap::from([(1, 100)]));
+
+        let empty = Graph::<usize, usize>::new();
+        assert!(
+            convex_size_histogram(&empty, 4, 100, &ConvexSearchOptions::default(), &mut rng)
+                .is_empty()
+        );
     }
 
-    impl<T> Stats<T>
-    where
-        T: for<'a> std::iter::Sum<&'a T> + TryInto<f64>,
-        <T as TryInto<f64>>::Error: Debug,
-    {
-        fn new() -> Stats<T> {
-            Self {
-                samples: Default::default(),
+    #[test]
+    fn test_collision_masks() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        // More than 64 gates so that bitmasks span words
+        for cin_gates in [4, 100] {
+            let (cin, _) = sample_circuit_with_base_gate::<2, u8, _>(cin_gates, 10, 1.0, &mut rng);
+            let cin_collision_masks = CollisionMasks::new(cin.gates());
+            let (others, _) = sample_circuit_with_base_gate::<2, u8, _>(500, 10, 1.0, &mut rng);
+            for other in others.gates() {
+                let collisions = cin_collision_masks.collisions(other);
+                for (index, gate) in cin.gates().iter().enumerate() {
+                    assert_eq!(
+                        mask_contains(&collisions, index),
+                        gate.check_collision(other)
+                    );
+                }
             }
         }
+    }
 
-        fn add_sample(&mut self, sample: T) {
-            self.samples.push(sample);
-        }
+    #[test]
+    fn test_rewired_edges_match_rebuild() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let n = 8;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(100, n, 1.0, &mut rng);
+        let mut state = MixingState::new(&circuit);
+
+        // Edges of the skeleton graph as gate ids, without removed nodes
+        let gate_id_edges = |state: &MixingState| {
+            state
+                .skeleton_graph
+                .edge_references()
+                .filter(|e| {
+                    !state.removed_nodes.contains(&e.source())
+                        && !state.removed_nodes.contains(&e.target())
+                })
+                .map(|e| {
+                    (
+                        state.skeleton_graph[e.source()],
+                        state.skeleton_graph[e.target()],
+                    )
+                })
+                .collect::<HashSet<_>>()
+        };
 
-        fn average(&self) -> f64 {
-            let s: T = self.samples.iter().sum();
-            let s: f64 = s.try_into().unwrap();
-            s / self.samples.len() as f64
+        let mut successful_steps = 0;
+        for step in 0..20 {
+            let params = MixingStepParams {
+                ell_out: 2 + step % 3,
+                ..test_mixing_options(step, 20).step_params
+            };
+            let applied = if step % 4 == 3 {
+                local_mixing_batch(
+                    &mut state,
+                    &params,
+                    4,
+                    &mut rng,
+                    &mut MixingTimings::default(),
+                    None,
+                    None,
+                )
+                .len()
+            } else {
+                local_mixing_step(
+                    &mut state,
+                    &params,
+                    &mut rng,
+                    &mut MixingTimings::default(),
+                    None,
+                    None,
+                )
+                .is_some() as usize
+            };
+            successful_steps += applied;
+
+            // Rewiring only updates edges of C^in, its neighbours, and outsiders. It must keep
+            // every edge that building the graph of the mixed circuit from scratch does. It may
+            // keep edges the rebuild drops as implied by a path through another gate, but no edge
+            // between gates the rebuilt graph doesn't order.
+            let rebuilt = MixingState::new(&state.circuit());
+            let (rewired_edges, rebuilt_edges) = (gate_id_edges(&state), gate_id_edges(&rebuilt));
+            assert!(rebuilt_edges.is_subset(&rewired_edges));
+            assert!(rebuilt
+                .active_edges_with_gateids
+                .is_subset(&state.active_edges_with_gateids));
+            for (from, to) in rewired_edges.difference(&rebuilt_edges) {
+                assert!(has_path_connecting(
+                    &rebuilt.skeleton_graph,
+                    rebuilt.gate_id_to_node_index_map[from],
+                    rebuilt.gate_id_to_node_index_map[to],
+                    None
+                ));
+            }
+            for (id, connections) in &rebuilt.direct_connections {
+                assert_eq!(&state.direct_connections[id], connections);
+            }
         }
+        assert!(successful_steps > 0);
     }
 
     #[test]
-    fn time_convex_subcircuit() {
-        env_logger::init();
-        let gates = 53000;
-        let n = 128;
-        let ell_out = 2;
-        let max_iterations = 10000;
+    fn test_validate_prepared() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(200, 10, 1.0, &mut rng);
+        let prepared = prepare_circuit(&circuit);
+        assert_eq!(validate_prepared(&prepared), Ok(()));
+
+        // Cycle
+        let mut cyclic = prepared.clone();
+        let edge = cyclic.2.edge_references().next().unwrap();
+        let (source, target) = (edge.source(), edge.target());
+        cyclic.2.add_edge(target, source, Default::default());
+        assert!(validate_prepared(&cyclic).is_err());
+
+        // Gate missing in gate map
+        let mut missing_gate = prepared.clone();
+        missing_gate.4.remove(&circuit.gates()[0].id());
+        assert!(validate_prepared(&missing_gate).is_err());
+
+        // Gate id mapped to another node
+        let mut wrong_node = prepared.clone();
+        wrong_node
+            .3
+            .insert(circuit.gates()[0].id(), NodeIndex::new(1));
+        assert!(validate_prepared(&wrong_node).is_err());
+
+        // Stale graph neighbours
+        let mut stale_neighbours = prepared.clone();
+        stale_neighbours.5[source.index()][1].remove(&target);
+        assert!(validate_prepared(&stale_neighbours).is_err());
+
+        // Edge between gates that don't collide. Nodes are added in the order of the gates, hence
+        // an edge to a later gate keeps the graph acyclic.
+        let (i, j) = (0..200)
+            .flat_map(|j| (0..j).map(move |i| (i, j)))
+            .find(|(i, j)| !circuit.gates()[*i].check_collision(&circuit.gates()[*j]))
+            .unwrap();
+        let mut commuting_edge = prepared.clone();
+        let (from, to) = (NodeIndex::new(i), NodeIndex::new(j));
+        commuting_edge.2.add_edge(from, to, Default::default());
+        commuting_edge.5[i][1].insert(to);
+        commuting_edge.5[j][0].insert(from);
+        assert!(validate_prepared(&commuting_edge)
+            .unwrap_err()
+            .contains("don't collide"));
+
+        // Gate replaced by one on wires its neighbours don't touch
+        let mut moved_gate = prepared.clone();
+        let (node, free_wires) = moved_gate
+            .2
+            .node_indices()
+            .filter(|node| moved_gate.5[node.index()].iter().any(|set| !set.is_empty()))
+            .find_map(|node| {
+                let neighbours_wires = moved_gate.5[node.index()]
+                    .iter()
+                    .flatten()
+                    .flat_map(|neighbour| {
+                        let gate = &moved_gate.4[&moved_gate.2[*neighbour]];
+                        let [control0, control1] = gate.controls();
+                        [gate.target(), control0, control1]
+                    })
+                    .collect::<HashSet<_>>();
+                let free_wires = (0..10u8)
+                    .filter(|wire| !neighbours_wires.contains(wire))
+                    .collect_vec();
+                (free_wires.len() >= 3).then_some((node, free_wires))
+            })
+            .unwrap();
+        let id = moved_gate.2[node];
+        moved_gate.4.insert(
+            id,
+            BaseGate::new(id, free_wires[0], [free_wires[1], free_wires[2]], 1),
+        );
+        assert!(validate_prepared(&moved_gate)
+            .unwrap_err()
+            .contains("collide"));
+    }
+
+    #[test]
+    fn test_check_gate_map_consistency() {
         let mut rng = ChaCha8Rng::from_entropy();
-        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
-        let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
-        let graph_neighbors = graph_neighbors(&skeleton_graph, &mut HashSet::new());
-        let levels = graph_level(&skeleton_graph, &graph_neighbors, &HashSet::new());
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(50, 10, 1.0, &mut rng);
+        let (_, _, skeleton_graph, gate_id_to_node_index_map, gate_map, _, _, latest_id) =
+            prepare_circuit(&circuit);
+        let removed_nodes = HashSet::default();
+        assert_eq!(
+            check_gate_map_consistency(&skeleton_graph, &gate_map, &removed_nodes, latest_id),
+            Ok(())
+        );
 
-        let mut stats = Stats::new();
+        let id = circuit.gates()[0].id();
 
-        for _ in 0..10 {
-            let now = std::time::Instant::now();
-            let _ = find_convex_fast(
+        // Gate of a live node removed from gate map
+        let mut missing_gate_map = gate_map.clone();
+        missing_gate_map.remove(&id);
+        assert_eq!(
+            check_gate_map_consistency(
                 &skeleton_graph,
-                &levels,
-                ell_out,
-                max_iterations,
-                &mut rng,
-                &mut HashSet::new(),
-            )
-            .unwrap();
-            stats.add_sample(now.elapsed().as_secs_f64());
-        }
+                &missing_gate_map,
+                &removed_nodes,
+                latest_id
+            ),
+            Err(GateMapInconsistency::MissingGate { id })
+        );
+
+        // Node removed but its gate left in gate map
+        let stale_removed_nodes = HashSet::from_iter([gate_id_to_node_index_map[&id]]);
+        assert_eq!(
+            check_gate_map_consistency(&skeleton_graph, &gate_map, &stale_removed_nodes, latest_id),
+            Err(GateMapInconsistency::StaleGate { id })
+        );
 
-        println!("Average find convex runtime: {}", stats.average());
+        // Gate id after the latest id
+        assert_eq!(
+            check_gate_map_consistency(&skeleton_graph, &gate_map, &removed_nodes, latest_id - 1),
+            Err(GateMapInconsistency::IdAfterLatest {
+                id: latest_id,
+                latest_id: latest_id - 1
+            })
+        );
     }
 
     #[test]
-    fn time_blah() {
-        env_logger::init();
-        let gates = 50000;
-        let n = 64;
-        let ell_out = 4;
-        let mut rng = thread_rng();
-        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
-        let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
-        let level = graph_level(
-            &skeleton_graph,
-            &graph_neighbors(&skeleton_graph, &mut HashSet::new()),
-            &HashSet::new(),
+    fn test_source_and_sink_gate_count() {
+        // Gate 1 and gate 2 collide with gate 0, and gate 3 collides with gate 1 and gate 2. Gate 4
+        // collides with no gate.
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+                BaseGate::<2, u8>::new(1, 3, [0, 4], 1),
+                BaseGate::<2, u8>::new(2, 1, [4, 5], 1),
+                BaseGate::<2, u8>::new(3, 5, [3, 6], 1),
+                BaseGate::<2, u8>::new(4, 7, [8, 9], 1),
+            ],
+            10,
         );
+        assert_eq!(source_gate_count(&circuit), 2);
+        assert_eq!(sink_gate_count(&circuit), 2);
 
-        let mut stats = Stats::new();
-
-        for _ in 0..10000 {
-            let start_node = NodeIndex::from(rng.gen_range(0..skeleton_graph.node_count()) as u32);
-            let mut convex_set = HashSet::new();
-            convex_set.insert(start_node);
-            let now = std::time::Instant::now();
-            // let _ = trialll(&skeleton_graph, ell_out, max_iterations, &mut rng).unwrap();
-            let _ = blah(
-                ell_out,
-                &mut convex_set,
-                &skeleton_graph,
-                &level,
-                &mut HashSet::new(),
+        // Same as no. of nodes without incoming and outgoing edges in the skeleton graph
+        let mut rng = ChaCha8Rng::from_entropy();
+        for _ in 0..10 {
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(200, 10, 1.0, &mut rng);
+            let (_, _, _, _, _, graph_neighbours, _, _) = prepare_circuit(&circuit);
+            assert_eq!(
+                source_gate_count(&circuit),
+                graph_neighbours
+                    .iter()
+                    .filter(|[incoming, _]| incoming.is_empty())
+                    .count()
+            );
+            assert_eq!(
+                sink_gate_count(&circuit),
+                graph_neighbours
+                    .iter()
+                    .filter(|[_, outgoing]| outgoing.is_empty())
+                    .count()
             );
-            stats.add_sample(now.elapsed().as_secs_f64());
         }
+    }
+
+    #[test]
+    fn test_circuit_stats() {
+        // Same as in `test_source_and_sink_gate_count`, with wires 10 and 11 unused
+        let circuit = Circuit::new(
+            vec![
+                BaseGate::<2, u8>::new(0, 0, [1, 2], 1),
+                BaseGate::<2, u8>::new(1, 3, [0, 4], 1),
+                BaseGate::<2, u8>::new(2, 1, [4, 5], 1),
+                BaseGate::<2, u8>::new(3, 5, [3, 6], 1),
+                BaseGate::<2, u8>::new(4, 7, [8, 9], 6),
+            ],
+            12,
+        );
+        let stats = circuit.stats();
+        assert_eq!(
+            stats,
+            CircuitStats {
+                wire_count: 12,
+                gate_count: 5,
+                depth: 3,
+                source_gates: 2,
+                sink_gates: 2,
+                active_wires: 10,
+                cancelling_pairs: 0,
+                control_func_histogram: BTreeMap::from([(1, 4), (6, 1)]),
+            }
+        );
 
-        println!("Average blah runtime: {}", stats.average());
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(serde_json::from_str::<CircuitStats>(&json).unwrap(), stats);
     }
 
     #[test]
-    fn time_graph_level() {
-        let gates = 50000;
-        let n = 128;
+    fn test_find_cancelling_pairs() {
+        let gate = |id, target, controls| BaseGate::<2, u8>::new(id, target, controls, 1);
+        // Gate 0 and gate 2 are separated by gate 1, which collides with neither. Gate 3 collides
+        // with gate 2 and gate 5, but gate 1 and gate 4 only have gate 2 and gate 3 in between.
+        let circuit = Circuit::new(
+            vec![
+                gate(0, 0, [1, 2]),
+                gate(1, 3, [4, 5]),
+                gate(2, 0, [1, 2]),
+                gate(3, 1, [6, 7]),
+                gate(4, 3, [4, 5]),
+                gate(5, 0, [1, 2]),
+            ],
+            8,
+        );
+        assert_eq!(find_cancelling_pairs(&circuit), vec![(0, 2), (1, 4)]);
+
+        // Few wires so that identical gates are common. Pair is reported iff there's no path
+        // between the gates in the skeleton graph, and removing it preserves the permutation.
+        let mut rng = ChaCha8Rng::from_entropy();
+        for _ in 0..10 {
+            let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(200, 4, 1.0, &mut rng);
+            let pairs = find_cancelling_pairs(&circuit);
+            let (_, _, skeleton_graph, gate_id_to_node_index_map, _, _, _, _) =
+                prepare_circuit(&circuit);
+
+            for (i, gate_i) in circuit.gates().iter().enumerate() {
+                for gate_j in &circuit.gates()[i + 1..] {
+                    let is_identical = gate_i.target() == gate_j.target()
+                        && gate_i.controls() == gate_j.controls()
+                        && gate_i.control_func() == gate_j.control_func();
+                    let has_path = has_path_connecting(
+                        &skeleton_graph,
+                        gate_id_to_node_index_map[&gate_i.id()],
+                        gate_id_to_node_index_map[&gate_j.id()],
+                        None,
+                    );
+                    assert_eq!(
+                        pairs.contains(&(gate_i.id(), gate_j.id())),
+                        is_identical && !has_path
+                    );
+                }
+            }
 
-        let mut rng = rand_chacha::ChaCha8Rng::from_entropy();
+            for (id0, id1) in pairs {
+                let reduced = Circuit::new(
+                    circuit
+                        .gates()
+                        .iter()
+                        .filter(|g| g.id() != id0 && g.id() != id1)
+                        .cloned()
+                        .collect(),
+                    circuit.n(),
+                );
+                assert!(check_exact_equivalence(&circuit, &reduced).0);
+            }
+        }
+    }
 
-        let (original_circuit, _) =
-            sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
-        let (_, _, graph, _, _, _, _, _) = prepare_circuit(&original_circuit);
-        let graph_neighbors = graph_neighbors(&graph, &mut HashSet::new());
+    #[test]
+    fn test_check_probabilisitic_equivalence_large_n() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let n = 130;
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(500, n, 1.0, &mut rng);
+        assert!(check_probabilisitic_equivalence(&circuit, &circuit, 100, &mut rng).0);
+
+        // Flipping the last wire is caught by any input
+        let mut gates = circuit.gates().to_vec();
+        gates.push(BaseGate::new(
+            gates.len(),
+            n - 1,
+            [0, 1],
+            circuit::Base2GateControlFunc::T as u8,
+        ));
+        let flipped = Circuit::new(gates, n as usize);
+        let (is_equivalent, diff_indices) =
+            check_probabilisitic_equivalence(&circuit, &flipped, 100, &mut rng);
+        assert!(!is_equivalent);
+        assert_eq!(diff_indices, vec![n as usize - 1]);
+    }
 
+    #[test]
+    fn test_first_divergent_gate() {
+        // Gate `i` flips wire `i` unconditionally, so every gate changes the state and all states
+        // of an execution are distinct. Controls are never targets, so no gates collide.
         let n = 10;
-        let start = std::time::Instant::now();
-        for _ in 0..n {
-            graph_level(&graph, &graph_neighbors, &HashSet::new());
-        }
-        dbg!(start.elapsed() / n);
+        let gate = |id, target| BaseGate::<2, u8>::new(id, target, [8, 9], 15);
+        let circuit = Circuit::new((0..8).map(|i| gate(i, i as u8)).collect(), n);
+        let input = vec![false; n];
+
+        // Swapping non-colliding gates keeps the circuit equivalent
+        let mut gates = circuit.gates().to_vec();
+        gates.swap(0, 1);
+        let reordered = Circuit::new(gates.clone(), n);
+        assert_eq!(first_divergent_gate(&circuit, &reordered, &input), None);
+
+        // Wrong replacement of gate 5. Execution of `wrong` never returns to a state of `circuit`
+        // after it.
+        gates[5] = gate(5, 7);
+        let wrong = Circuit::new(gates, n);
+        assert_eq!(first_divergent_gate(&circuit, &wrong, &input), Some((5, 5)));
+
+        // Executions agree on no state but the input
+        assert_eq!(
+            first_divergent_gate(&circuit, &wrong.inverse(), &input),
+            Some((0, 0))
+        );
+
+        // Circuit without gates diverges from the input state
+        let empty = Circuit::new(vec![], n);
+        assert_eq!(first_divergent_gate(&circuit, &empty, &input), Some((0, 0)));
+        assert_eq!(first_divergent_gate(&empty, &wrong, &input), Some((0, 0)));
+        assert_eq!(first_divergent_gate(&empty, &empty, &input), None);
+        // Gates 0 and 1 of `circuit` cancel out on the input, hence it is back in the input state
+        // before gate 2
+        let mut gates = circuit.gates().to_vec();
+        gates[1] = gate(1, 0);
+        let cancelling = Circuit::new(gates, n);
+        assert_eq!(
+            first_divergent_gate(&cancelling, &empty, &input),
+            Some((2, 0))
+        );
     }
 
     #[test]
-    fn time_dfs_fast() {
-        let gates = 50000;
-        let n = 128;
+    fn test_avalanche_score() {
+        let mut rng = ChaCha8Rng::from_entropy();
 
-        let mut rng = rand_chacha::ChaCha8Rng::from_entropy();
+        // Flipping input bit i of identity circuit only flips output bit i
+        let identity = Circuit::<BaseGate<2, u8>>::new(vec![], 8);
+        assert_eq!(avalanche_score(&identity, 100, &mut rng), 1.0 / 8.0);
 
-        let (original_circuit, _) =
-            sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
-        let (_, _, graph, _, _, _, _, _) = prepare_circuit(&original_circuit);
-
-        let n = 30;
-        let mut t = Duration::default();
-        for _ in 0..n {
-            let source = NodeIndex::from(rng.gen_range(0..gates as _));
-            let start = std::time::Instant::now();
-            dfs_fast(
-                &graph,
-                vec![source],
-                Direction::Incoming,
-                &mut HashSet::new(),
-            );
-            t += start.elapsed();
-        }
-        println!("Find predecessors: {:?}", t / n);
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(1000, 8, 1.0, &mut rng);
+        let score = avalanche_score(&circuit, 100, &mut rng);
+        assert!(score > 0.0 && score <= 1.0);
+    }
 
-        let mut t = Duration::default();
-        for _ in 0..n {
-            let source = NodeIndex::from(rng.gen_range(0..gates as _));
-            let start = std::time::Instant::now();
-            dfs_fast(
-                &graph,
-                vec![source],
-                Direction::Outgoing,
-                &mut HashSet::new(),
-            );
-            t += start.elapsed();
-        }
-        println!("Find successors: {:?}", t / n);
+    #[test]
+    fn test_dfs_fast() {
+        let gates = 500;
+        let n = 32;
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let (circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(gates, n, 1.0, &mut rng);
+        let (_, _, graph, _, _, _, _, _) = prepare_circuit(&circuit);
 
         for _ in 0..10 {
             let start = NodeIndex::from(rng.gen_range(0..gates as _));
@@ -2812,7 +7526,7 @@ mod tests {
                     &graph,
                     vec![start],
                     Direction::Outgoing,
-                    &mut HashSet::new()
+                    &mut HashSet::default()
                 ),
                 Dfs::from_parts(vec![start], graph.visit_map())
                     .iter(&graph)
@@ -2823,7 +7537,7 @@ mod tests {
                     &graph,
                     vec![start],
                     Direction::Incoming,
-                    &mut HashSet::new()
+                    &mut HashSet::default()
                 ),
                 Dfs::from_parts(vec![start], graph.visit_map())
                     .iter(Reversed(&graph))
@@ -2851,4 +7565,71 @@ mod tests {
     //         assert_eq!(&mapp, &mapp2);
     //     }
     // }
+
+    #[test]
+    fn test_parse_circuit_json() {
+        let mut rng = ChaCha8Rng::from_entropy();
+        let (mut circuit, _) = sample_circuit_with_base_gate::<2, u8, _>(50, 8, 1.0, &mut rng);
+        let json = serde_json::to_string(&PrettyCircuit::from(&circuit)).unwrap();
+        assert!(!json.contains("annotations"));
+        assert_eq!(parse_circuit_json(&json).unwrap(), circuit);
+
+        // Annotations are kept
+        circuit.annotate(3, "watermark");
+        circuit.annotate(49, "last");
+        let json = serde_json::to_string(&PrettyCircuit::from(&circuit)).unwrap();
+        let parsed = parse_circuit_json(&json).unwrap();
+        assert_eq!(parsed, circuit);
+        assert_eq!(parsed.annotations(), circuit.annotations());
+
+        let parse = |gates: &str| {
+            parse_circuit_json(&format!(
+                r#"{{"wire_count": 4, "gate_count": 1, "gates": {gates}}}"#
+            ))
+        };
+        assert_eq!(parse("[[0, 1, 2, 1]]").unwrap().gates().len(), 1);
+        assert_eq!(
+            parse("[[0, 1, 2, 1], [1, 2, 3, 6]]"),
+            Err(ParseError::GateCountMismatch {
+                expected: 1,
+                found: 2
+            })
+        );
+        assert_eq!(
+            parse("[[0, 4, 2, 1]]"),
+            Err(ParseError::WireOutOfBounds { gate: 0, wire: 4 })
+        );
+        assert_eq!(
+            parse("[[0, 1, 1, 1]]"),
+            Err(ParseError::TargetIsControl { gate: 0 })
+        );
+        assert_eq!(
+            parse("[[0, 1, 2, 16]]"),
+            Err(ParseError::InvalidControlFunc {
+                gate: 0,
+                control_func: 16
+            })
+        );
+        assert!(matches!(
+            parse("[[0, 1, 256, 1]]"),
+            Err(ParseError::Json(_))
+        ));
+        assert!(matches!(parse("[[0, 1, 2]]"), Err(ParseError::Json(_))));
+        assert_eq!(
+            parse_circuit_json(
+                r#"{"wire_count": 4, "gate_count": 1, "gates": [[0, 1, 2, 1]], "annotations": {"1": "x"}}"#
+            ),
+            Err(ParseError::AnnotationOutOfBounds { gate: 1 })
+        );
+
+        // Truncated or corrupted JSON must be rejected without panicking
+        for _ in 0..1000 {
+            let mut bytes = json.clone().into_bytes();
+            bytes.truncate(rng.gen_range(0..=bytes.len()));
+            if let Some(byte) = bytes.choose_mut(&mut rng) {
+                *byte = rng.gen_range(b' '..=b'~');
+            }
+            let _ = parse_circuit_json(&String::from_utf8(bytes).unwrap());
+        }
+    }
 }
@@ -1,32 +1,58 @@
-use hashbrown::HashSet;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use itertools::Itertools;
 use rand::{thread_rng, Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 use rust::{
-    check_probabilisitic_equivalence,
-    circuit::{BaseGate, Circuit},
-    prepare_circuit, run_local_mixing, toposort_with_cached_graph_neighbours,
+    avalanche_score, check_exact_equivalence, check_probabilisitic_equivalence,
+    circuit::{Base2GateControlFunc, BaseGate, Circuit},
+    convex_size_histogram, local_mixing_batch, local_mixing_step, parse_circuit_json,
+    prepare_circuit, run_local_mixing, validate_prepared, CircuitStats, ConvexSearchOptions,
+    MixingObservers, MixingOptions, MixingState, MixingStepParams, MixingStepSnapshot,
+    MixingTimings, PrettyCircuit, ReplacementHistograms, ReplacementIterationsSchedule,
+    ReplacementSearchOptions, REPLACEMENT_PREFILTER_INPUTS,
 };
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    env::{self, args},
+    collections::{BTreeMap, BTreeSet},
+    env,
     error::Error,
-    io::Read,
+    fs::File,
+    io::{BufWriter, Write},
     path::Path,
+    time::Duration,
 };
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 enum Strategy {
     Strategy1,
     Strategy2,
+    /// Strategy 1 that rebuilds the skeleton graph with gates in a random order after
+    /// `convex_search_restart_steps` consecutive failed steps
+    Strategy3,
+}
+
+/// How the no. of iterations of probabilistic equivalence checks scales with no. of wires
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, ValueEnum)]
+enum EqCheckScaling {
+    /// `probabilitic_eq_check_iterations` iterations
+    Flat,
+    /// `probabilitic_eq_check_iterations * n` iterations
+    Linear,
+}
+
+/// [Strategy 2] Stages to run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum StageSelection {
+    Inflationary,
+    Kneading,
+    Both,
 }
 
 #[derive(Serialize, Deserialize)]
 struct ObfuscationConfig {
     /// Number of wires
     n: usize,
-    /// Total steps in strategy 1
+    /// Total steps in strategy 1 and 3
     total_steps: usize,
     /// Number of inflationary steps in strategy 2
     inflationary_stage_steps: usize,
@@ -36,14 +62,128 @@ struct ObfuscationConfig {
     max_convex_iterations: usize,
     /// Maximum number of iterations for each replacement circuit searching
     max_replacement_iterations: usize,
+    /// Schedule of `max_replacement_iterations` over the mixing steps of the job
+    replacement_iterations_schedule: ReplacementIterationsSchedule,
     /// Strategy used
     starategy: Strategy,
     /// Checkpoint steps. Checkpoints obfuscated circuit after `checkpoint` number of iterations
     checkpoint_steps: usize,
+    /// Also checkpoints the obfuscated circuit once this many seconds have passed since the last
+    /// checkpoint, whichever of the two triggers first. Disabled if `None`.
+    checkpoint_secs: Option<u64>,
     /// No. of iterations for probabilitic equivalance check.
     probabilitic_eq_check_iterations: usize,
+    /// Scales `probabilitic_eq_check_iterations` with `n`
+    eq_check_scaling: EqCheckScaling,
+    /// End of stage equivalence checks run on all `2^n` inputs if `n` is at most this. Can be at
+    /// most `MAX_EXHAUSTIVE_N`. 0 disables exhaustive checks.
+    exhaustive_eq_check_max_n: usize,
+    /// [Strategy 1 and 3] Schedule for `ell_out`. Each entry `(step_fraction, (min, max))` sets
+    /// the range `ell_out` is sampled from once `step_fraction` of total steps are done. Entries
+    /// must be sorted by `step_fraction`. Empty schedule samples `ell_out` from `[2, 4]`.
+    ell_out_schedule: Vec<(f64, (usize, usize))>,
+    /// [Strategy 1, 3, and inflationary stage of strategy 2] Max. no. of times replacement circuit
+    /// search is retried with \ell^in + 1 after a failure. Must be <= `MAX_ELL_IN_RETRIES`.
+    ell_in_retries: usize,
+    /// [Strategy 1 and 3] Sets \ell^in = \ell^out at every step so that the no. of gates of the
+    /// obfuscated circuit stays the same as of the original circuit. Replacement circuit search is
+    /// never retried with larger \ell^in. Each step mixes less because C^out can only be replaced
+    /// by a circuit of the same size, so more steps are needed for the same mixing.
+    preserve_gate_count: bool,
+    /// No. of stages of multi-stage cipher sampled as the original circuit
+    cipher_stages: usize,
+    /// No. of layers in each stage of multi-stage cipher sampled as the original circuit
+    cipher_layers_per_stage: usize,
+    /// [Strategy 2] Whether to run inflationary stage
+    run_inflationary_stage: bool,
+    /// [Strategy 2] Whether to run kneading stage
+    run_kneading_stage: bool,
+    /// Path of CSV file to append metrics of every successful mixing step to. Disabled if `None`.
+    metrics_csv_path: Option<String>,
+    /// Path of CSV file to append the gate count, depth, and avalanche score of the obfuscated
+    /// circuit to at every checkpoint. Disabled if `None`.
+    checkpoint_csv_path: Option<String>,
+    /// Path of JSON lines file to append a record of the gates removed and added by every
+    /// successful mixing step to. Disabled if `None`.
+    mixing_trace_path: Option<String>,
+    /// Seed of RNGs used to sample the original circuit and to mix it. RNGs are seeded from
+    /// entropy if `None`.
+    seed: Option<u64>,
+    /// Directory to write a step-numbered copy of the job to at every checkpoint. Disabled if
+    /// `None`.
+    history_dir: Option<String>,
+    /// No. of most recent copies kept in `history_dir`. Older copies are deleted.
+    history_keep: usize,
+    /// [Strategy 2] Kneading stage stops early once the avalanche score of the obfuscated circuit
+    /// is at least this at `kneading_early_stop_checkpoints` consecutive checkpoints. Disabled if
+    /// `None`.
+    kneading_early_stop_avalanche: Option<f64>,
+    /// [Strategy 2] No. of consecutive checkpoints the avalanche score must reach
+    /// `kneading_early_stop_avalanche` at to stop the kneading stage early
+    kneading_early_stop_checkpoints: usize,
+    /// [Strategy 3] No. of consecutive failed mixing steps after which the skeleton graph is
+    /// rebuilt from the mixed circuit with gates of the same level in random order. A rebuild
+    /// drops removed nodes and shuffles the order of edges, which convex subcircuit search grows
+    /// sets along.
+    convex_search_restart_steps: usize,
+    /// Asserts at the end of the last stage that every output wire of the obfuscated circuit
+    /// structurally depends on every input wire
+    assert_full_diffusion: bool,
+    /// Whether replacement circuits must have a weakly connected collisions set. Refer to
+    /// [ReplacementSearchOptions::weak_connectivity_check].
+    #[serde(default = "default_true")]
+    weak_connectivity_check: bool,
+    /// No. of inputs replacement candidates are checked on with bit-sliced evaluation first. Refer
+    /// to [ReplacementSearchOptions::prefilter_inputs].
+    #[serde(default = "default_replacement_prefilter_inputs")]
+    replacement_prefilter_inputs: usize,
+    /// Whether convex subcircuits are grown by the candidate gate of lowest level. Refer to
+    /// [ConvexSearchOptions::level_bounded].
+    #[serde(default)]
+    level_bounded_convex_search: bool,
+    /// Whether convex subcircuit search tries start gates with enough successors first. Refer to
+    /// [ConvexSearchOptions::prioritize_start_nodes].
+    #[serde(default)]
+    prioritize_convex_start_nodes: bool,
+    /// Ids of gates of the obfuscated circuit that mixing never removes
+    #[serde(default)]
+    frozen_gates: BTreeSet<usize>,
+    /// No. of convex subcircuits tried at once by a mixing step. More than 1 replaces the ones
+    /// that are far apart in the skeleton graph in parallel, see [rust::local_mixing_batch]
+    #[serde(default = "default_batch_regions")]
+    batch_regions: usize,
+}
+
+// Serde defaults of settings added to [ObfuscationConfig] after job version 1
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_replacement_prefilter_inputs() -> usize {
+    REPLACEMENT_PREFILTER_INPUTS
+}
+
+fn default_batch_regions() -> usize {
+    1
 }
 
+/// Default no. of copies of the job kept in the history directory
+const DEFAULT_HISTORY_KEEP: usize = 10;
+
+/// Default no. of consecutive checkpoints for early stop of the kneading stage
+const DEFAULT_KNEADING_EARLY_STOP_CHECKPOINTS: usize = 3;
+
+/// No. of random inputs used to estimate the avalanche score for early stop of the kneading stage
+const KNEADING_EARLY_STOP_AVALANCHE_ITERATIONS: usize = 100;
+
+/// Default no. of consecutive failed mixing steps after which strategy 3 rebuilds the skeleton
+/// graph
+const DEFAULT_CONVEX_SEARCH_RESTART_STEPS: usize = 10;
+
+/// Caps `ell_in_retries` so that a single mixing step can't inflate the circuit by too much
+const MAX_ELL_IN_RETRIES: usize = 2;
+
 impl ObfuscationConfig {
     fn new_with_strategy1(
         n: usize,
@@ -60,9 +200,36 @@ impl ObfuscationConfig {
             kneading_stage_steps: 0,
             max_convex_iterations,
             max_replacement_iterations,
+            replacement_iterations_schedule: ReplacementIterationsSchedule::Constant,
             starategy: Strategy::Strategy1,
             checkpoint_steps,
+            checkpoint_secs: None,
             probabilitic_eq_check_iterations,
+            eq_check_scaling: EqCheckScaling::Flat,
+            exhaustive_eq_check_max_n: 0,
+            ell_out_schedule: vec![],
+            ell_in_retries: 0,
+            preserve_gate_count: false,
+            cipher_stages: Circuit::DEFAULT_CIPHER_STAGES,
+            cipher_layers_per_stage: Circuit::default_cipher_layers_per_stage(n),
+            run_inflationary_stage: true,
+            run_kneading_stage: true,
+            metrics_csv_path: None,
+            checkpoint_csv_path: None,
+            mixing_trace_path: None,
+            seed: None,
+            history_dir: None,
+            history_keep: DEFAULT_HISTORY_KEEP,
+            kneading_early_stop_avalanche: None,
+            kneading_early_stop_checkpoints: DEFAULT_KNEADING_EARLY_STOP_CHECKPOINTS,
+            convex_search_restart_steps: DEFAULT_CONVEX_SEARCH_RESTART_STEPS,
+            assert_full_diffusion: false,
+            weak_connectivity_check: true,
+            replacement_prefilter_inputs: REPLACEMENT_PREFILTER_INPUTS,
+            level_bounded_convex_search: false,
+            prioritize_convex_start_nodes: false,
+            frozen_gates: BTreeSet::new(),
+            batch_regions: 1,
         }
     }
 
@@ -81,10 +248,37 @@ impl ObfuscationConfig {
             kneading_stage_steps,
             max_convex_iterations,
             max_replacement_iterations,
+            replacement_iterations_schedule: ReplacementIterationsSchedule::Constant,
             starategy: Strategy::Strategy2,
             total_steps: 0,
             checkpoint_steps,
+            checkpoint_secs: None,
             probabilitic_eq_check_iterations,
+            eq_check_scaling: EqCheckScaling::Flat,
+            exhaustive_eq_check_max_n: 0,
+            ell_out_schedule: vec![],
+            ell_in_retries: 0,
+            preserve_gate_count: false,
+            cipher_stages: Circuit::DEFAULT_CIPHER_STAGES,
+            cipher_layers_per_stage: Circuit::default_cipher_layers_per_stage(n),
+            run_inflationary_stage: true,
+            run_kneading_stage: true,
+            metrics_csv_path: None,
+            checkpoint_csv_path: None,
+            mixing_trace_path: None,
+            seed: None,
+            history_dir: None,
+            history_keep: DEFAULT_HISTORY_KEEP,
+            kneading_early_stop_avalanche: None,
+            kneading_early_stop_checkpoints: DEFAULT_KNEADING_EARLY_STOP_CHECKPOINTS,
+            convex_search_restart_steps: DEFAULT_CONVEX_SEARCH_RESTART_STEPS,
+            assert_full_diffusion: false,
+            weak_connectivity_check: true,
+            replacement_prefilter_inputs: REPLACEMENT_PREFILTER_INPUTS,
+            level_bounded_convex_search: false,
+            prioritize_convex_start_nodes: false,
+            frozen_gates: BTreeSet::new(),
+            batch_regions: 1,
         }
     }
 
@@ -95,251 +289,914 @@ impl ObfuscationConfig {
     fn default_strategy2() -> Self {
         ObfuscationConfig::new_with_strategy2(64, 100_000, 100_000, 10000, 1000000, 1000, 1000)
     }
+
+    fn default_strategy3() -> Self {
+        Self {
+            starategy: Strategy::Strategy3,
+            ..ObfuscationConfig::default_strategy1()
+        }
+    }
+
+    /// RNG to sample the original circuit with
+    fn cipher_rng(&self) -> ChaCha8Rng {
+        self.seed
+            .map_or_else(ChaCha8Rng::from_entropy, ChaCha8Rng::seed_from_u64)
+    }
+
+    /// RNG to mix a new job with. With a seed it is a different stream than
+    /// [`Self::cipher_rng`].
+    fn mixing_rng(&self) -> ChaCha8Rng {
+        self.seed.map_or_else(ChaCha8Rng::from_entropy, |seed| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            rng.set_stream(1);
+            rng
+        })
+    }
+
+    /// Settings of convex subcircuit search of every mixing step
+    fn convex_search(&self) -> ConvexSearchOptions {
+        ConvexSearchOptions {
+            level_bounded: self.level_bounded_convex_search,
+            prioritize_start_nodes: self.prioritize_convex_start_nodes,
+        }
+    }
+
+    /// Settings of replacement circuit search of every mixing step
+    fn replacement_search(&self) -> ReplacementSearchOptions {
+        ReplacementSearchOptions {
+            weak_connectivity_check: self.weak_connectivity_check,
+            prefilter_inputs: self.replacement_prefilter_inputs,
+        }
+    }
+
+    /// No. of iterations of probabilistic equivalence checks after scaling with `n`
+    fn eq_check_iterations(&self) -> usize {
+        match self.eq_check_scaling {
+            EqCheckScaling::Flat => self.probabilitic_eq_check_iterations,
+            EqCheckScaling::Linear => self.probabilitic_eq_check_iterations * self.n,
+        }
+    }
+
+    /// Whether to checkpoint at mixing step `stage_step` of a stage given the time of the last
+    /// checkpoint
+    fn is_checkpoint_due(&self, stage_step: usize, last_checkpoint: std::time::Instant) -> bool {
+        stage_step % self.checkpoint_steps == 0
+            || self
+                .checkpoint_secs
+                .is_some_and(|secs| last_checkpoint.elapsed().as_secs() >= secs)
+    }
+
+    /// No. of convex subcircuits tried at once by a mixing step at `stage_step` of a stage of
+    /// `stage_steps` steps. A batch doesn't run past the end of the stage or the next multiple of
+    /// `checkpoint_steps`, so that a batch starts at every step [Self::is_checkpoint_due] is due
+    /// at.
+    fn batch_regions(&self, stage_step: usize, stage_steps: usize) -> usize {
+        self.batch_regions
+            .min(self.checkpoint_steps - stage_step % self.checkpoint_steps)
+            .min(stage_steps.saturating_sub(stage_step))
+            .max(1)
+    }
+
+    /// Whether equivalence checks run on all `2^n` inputs
+    fn is_eq_check_exhaustive(&self) -> bool {
+        self.n <= self.exhaustive_eq_check_max_n.min(MAX_EXHAUSTIVE_N)
+    }
+
+    /// Checks that `circuit0` and `circuit1` are functionally equivalent, exhaustively if
+    /// [`Self::is_eq_check_exhaustive`] and with [`Self::eq_check_iterations`] random inputs
+    /// otherwise
+    fn check_equivalence(
+        &self,
+        circuit0: &Circuit<BaseGate<2, u8>>,
+        circuit1: &Circuit<BaseGate<2, u8>>,
+        rng: &mut impl Rng,
+    ) -> (bool, Vec<usize>) {
+        if self.is_eq_check_exhaustive() {
+            check_exact_equivalence(circuit0, circuit1)
+        } else {
+            check_probabilisitic_equivalence(circuit0, circuit1, self.eq_check_iterations(), rng)
+        }
+    }
+
+    /// Default adaptive `ell_out` schedule. Uses larger `ell_out` early in the run for faster
+    /// coarse mixing and shrinks it towards the end.
+    fn default_ell_out_schedule() -> Vec<(f64, (usize, usize))> {
+        vec![(0.0, (3, 4)), (0.5, (2, 4)), (0.8, (2, 3))]
+    }
+
+    /// Total no. of mixing steps of the job, over both stages with strategy 2
+    fn mixing_steps(&self) -> usize {
+        self.total_steps + self.inflationary_stage_steps + self.kneading_stage_steps
+    }
+
+    /// Params of a mixing step with the convex and replacement search settings of the job. Max.
+    /// replacement iterations are not scheduled yet, see [rust::run_local_mixing].
+    fn step_params(&self, ell_out: usize, ell_in: usize, max_ell_in: usize) -> MixingStepParams {
+        MixingStepParams {
+            ell_out,
+            ell_in,
+            max_ell_in,
+            max_convex_iterations: self.max_convex_iterations,
+            max_replacement_iterations: self.max_replacement_iterations,
+            convex_search: self.convex_search(),
+            replacement_search: self.replacement_search(),
+        }
+    }
+
+    /// Params of a step of strategy 1 and 3 with `ell_out` sampled from [Self::ell_out_range].
+    /// \ell^in is [MIXING_STAGE_ELL_IN], or `ell_out` if `preserve_gate_count` is set.
+    fn mixing_stage_step_params(&self, ell_out: usize) -> MixingStepParams {
+        if self.preserve_gate_count {
+            self.step_params(ell_out, ell_out, ell_out)
+        } else {
+            self.step_params(
+                ell_out,
+                MIXING_STAGE_ELL_IN,
+                MIXING_STAGE_ELL_IN + self.ell_in_retries,
+            )
+        }
+    }
+
+    /// Params of a step of the inflationary stage of strategy 2, 2 -> 4 replacements
+    fn inflationary_stage_step_params(&self) -> MixingStepParams {
+        self.step_params(2, 4, 4 + self.ell_in_retries)
+    }
+
+    /// Params of a step of the kneading stage of strategy 2, 4 -> 4 replacements
+    fn kneading_stage_step_params(&self) -> MixingStepParams {
+        self.step_params(4, 4, 4)
+    }
+
+    /// Returns range to sample `ell_out` from at step `curr_step` of strategy 1 and 3
+    fn ell_out_range(&self, curr_step: usize, ell_in: usize) -> std::ops::RangeInclusive<usize> {
+        let step_fraction = curr_step as f64 / self.total_steps.max(1) as f64;
+        let (min, max) = self
+            .ell_out_schedule
+            .iter()
+            .rev()
+            .find(|(from_fraction, _)| *from_fraction <= step_fraction)
+            .map_or((2, 4), |(_, range)| *range);
+        assert!(
+            2 <= min && min <= max && max <= ell_in,
+            "Invalid ell_out range [{min}, {max}] for ell_in = {ell_in}"
+        );
+        min..=max
+    }
+}
+
+/// \ell^in of steps of strategy 1 and 3 that don't preserve the gate count
+const MIXING_STAGE_ELL_IN: usize = 4;
+
+/// Prefix of versioned job files. Unversioned job files start with the config's `n` instead, which
+/// would have to be about 5 * 10^18 to be mistaken for it.
+const OBFUSCATION_JOB_MAGIC: [u8; 8] = *b"OBFSJOB\0";
+
+/// Serialization version of [ObfuscationJob], stored after [OBFUSCATION_JOB_MAGIC]. Bump it
+/// whenever the layout of the serialized job changes and add a migration from the previous
+/// version to [ObfuscationJob::migrate]. Settings added to [ObfuscationConfig] don't change the
+/// layout since the config is stored as JSON. They take a serde default, the value older jobs ran
+/// with, instead.
+///
+/// - 0: Unversioned job without the magic prefix, see [ObfuscationJobV0]
+/// - 1: Config is stored as JSON
+const OBFUSCATION_JOB_VERSION: u32 = 1;
+
+/// Error loading a job file
+#[derive(Debug)]
+enum JobLoadError {
+    /// Job file has a serialization version that can't be loaded by this build
+    UnsupportedVersion(u32),
+    /// Job file can't be read or doesn't decode with the layout of its version
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for JobLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobLoadError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported checkpoint version {version}, this build loads versions up to {OBFUSCATION_JOB_VERSION}"
+            ),
+            JobLoadError::Io(error) => write!(f, "failed to load job: {error}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for JobLoadError {
+    fn from(error: std::io::Error) -> Self {
+        JobLoadError::Io(error)
+    }
+}
+
+/// Decodes `bytes` with the layout `T` of job version `version`
+fn decode_job_layout<T: DeserializeOwned>(version: u32, bytes: &[u8]) -> Result<T, JobLoadError> {
+    bincode::deserialize(bytes).map_err(|error| {
+        JobLoadError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("job file doesn't decode as version {version}: {error}"),
+        ))
+    })
+}
+
+/// Provenance of an obfuscation job. Fields are `None` for jobs created before metadata was
+/// added or when the value is unavailable.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct JobMetadata {
+    /// Creation time in seconds since the Unix epoch
+    created_at: Option<u64>,
+    /// Host that created the job
+    hostname: Option<String>,
+    /// Version of the crate that created the job
+    crate_version: Option<String>,
+    /// Seed of the job's RNGs, if the job is seeded
+    seed: Option<u64>,
+}
+
+impl JobMetadata {
+    /// Metadata of a job created now on this host
+    fn new(seed: Option<u64>) -> Self {
+        JobMetadata {
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs()),
+            hostname: env::var("HOSTNAME")
+                .ok()
+                .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+                .map(|hostname| hostname.trim().to_string())
+                .filter(|hostname| !hostname.is_empty()),
+            crate_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            seed,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct ObfuscationJob {
+    /// Serialization version. Is the first field so that it can be read without knowing the
+    /// layout of the rest of the job.
+    version: u32,
+    #[serde(with = "json_encoded")]
     config: ObfuscationConfig,
-    /// [Strategy 1] Curr no. of total steps
+    /// [Strategy 1 and 3] Curr no. of total steps
     curr_total_steps: usize,
     /// [Strategy 2] Curr no. of steps in inflationary stage
     curr_inflationary_stage_steps: usize,
     /// [Strategy 2] Curr no. of steps in kneading stage
     curr_kneading_stage_steps: usize,
+    /// [Strategy 2] No. of consecutive checkpoints, up to the last one, at which the avalanche
+    /// score reached `kneading_early_stop_avalanche`
+    curr_kneading_early_stop_checkpoints: usize,
+    /// Length in bytes of the mixing trace at the last checkpoint. The trace is truncated to it
+    /// when the job is continued, so that it has no records of steps after the checkpoint. `None`
+    /// if the job has no trace or was stored before the length was.
+    mixing_trace_len: Option<u64>,
+    /// State of the mixing RNG at the last checkpoint, so that a continued job draws new random
+    /// values instead of those the job started with. `None` if no mixing step was checkpointed
+    /// yet.
+    mixing_rng: Option<ChaCha8Rng>,
+    /// Stored with its gate annotations
+    #[serde(with = "annotated_circuit")]
+    curr_circuit: Circuit<BaseGate<2, u8>>,
+    original_circuit: Circuit<BaseGate<2, u8>>,
+    metadata: JobMetadata,
+}
+
+/// (De)serializes a value as a JSON string within the job, so that fields added to it with a serde
+/// default don't change the layout of the job
+mod json_encoded {
+    use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serde_json::to_string(value)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        serde_json::from_str(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// (De)serializes a circuit followed by its gate annotations, which [Circuit] doesn't serialize
+mod annotated_circuit {
+    use rust::circuit::{BaseGate, Circuit};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(
+        circuit: &Circuit<BaseGate<2, u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        (circuit, circuit.annotations()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Circuit<BaseGate<2, u8>>, D::Error> {
+        let (mut circuit, annotations): (Circuit<BaseGate<2, u8>>, BTreeMap<usize, String>) =
+            Deserialize::deserialize(deserializer)?;
+        for (id, annotation) in annotations {
+            if circuit.gate_by_id(id).is_none() {
+                return Err(D::Error::custom(format!(
+                    "annotated gate {id} is not in the circuit"
+                )));
+            }
+            circuit.annotate(id, annotation);
+        }
+        Ok(circuit)
+    }
+}
+
+/// [ObfuscationConfig] of unversioned jobs
+#[derive(Deserialize)]
+struct ObfuscationConfigV0 {
+    n: usize,
+    total_steps: usize,
+    inflationary_stage_steps: usize,
+    kneading_stage_steps: usize,
+    max_convex_iterations: usize,
+    max_replacement_iterations: usize,
+    starategy: Strategy,
+    checkpoint_steps: usize,
+    probabilitic_eq_check_iterations: usize,
+}
+
+impl From<ObfuscationConfigV0> for ObfuscationConfig {
+    fn from(config: ObfuscationConfigV0) -> Self {
+        // Settings added since default to how unversioned jobs ran
+        ObfuscationConfig {
+            inflationary_stage_steps: config.inflationary_stage_steps,
+            kneading_stage_steps: config.kneading_stage_steps,
+            starategy: config.starategy,
+            ..ObfuscationConfig::new_with_strategy1(
+                config.n,
+                config.total_steps,
+                config.max_convex_iterations,
+                config.max_replacement_iterations,
+                config.checkpoint_steps,
+                config.probabilitic_eq_check_iterations,
+            )
+        }
+    }
+}
+
+/// [ObfuscationJob] stored before the job had a serialization version
+#[derive(Deserialize)]
+struct ObfuscationJobV0 {
+    config: ObfuscationConfigV0,
+    curr_total_steps: usize,
+    curr_inflationary_stage_steps: usize,
+    curr_kneading_stage_steps: usize,
     curr_circuit: Circuit<BaseGate<2, u8>>,
     original_circuit: Circuit<BaseGate<2, u8>>,
 }
 
+impl From<ObfuscationJobV0> for ObfuscationJob {
+    fn from(job: ObfuscationJobV0) -> Self {
+        ObfuscationJob {
+            version: OBFUSCATION_JOB_VERSION,
+            config: job.config.into(),
+            curr_total_steps: job.curr_total_steps,
+            curr_inflationary_stage_steps: job.curr_inflationary_stage_steps,
+            curr_kneading_stage_steps: job.curr_kneading_stage_steps,
+            curr_kneading_early_stop_checkpoints: 0,
+            mixing_trace_len: None,
+            mixing_rng: None,
+            curr_circuit: job.curr_circuit,
+            original_circuit: job.original_circuit,
+            metadata: JobMetadata::default(),
+        }
+    }
+}
+
 impl ObfuscationJob {
-    fn load(path: impl AsRef<Path>) -> Self {
-        let job: ObfuscationJob = bincode::deserialize(&std::fs::read(path).unwrap()).unwrap();
+    fn load(path: impl AsRef<Path>) -> Result<Self, JobLoadError> {
+        let job = Self::decode(&std::fs::read(path)?)?;
 
         #[allow(dead_code)]
         #[derive(Debug)]
         struct Status {
+            version: u32,
             n: usize,
             total_steps: usize,
             inflationary_stage_steps: usize,
             kneading_stage_steps: usize,
             max_convex_iterations: usize,
             max_replacement_iterations: usize,
+            replacement_iterations_schedule: ReplacementIterationsSchedule,
             starategy: Strategy,
             checkpoint_steps: usize,
+            checkpoint_secs: Option<u64>,
+            eq_check_scaling: EqCheckScaling,
+            exhaustive_eq_check_max_n: usize,
+            ell_out_schedule: Vec<(f64, (usize, usize))>,
+            ell_in_retries: usize,
+            preserve_gate_count: bool,
+            cipher_stages: usize,
+            cipher_layers_per_stage: usize,
+            run_inflationary_stage: bool,
+            run_kneading_stage: bool,
+            metrics_csv_path: Option<String>,
+            checkpoint_csv_path: Option<String>,
+            mixing_trace_path: Option<String>,
+            seed: Option<u64>,
+            history_dir: Option<String>,
+            history_keep: usize,
+            kneading_early_stop_avalanche: Option<f64>,
+            kneading_early_stop_checkpoints: usize,
+            convex_search_restart_steps: usize,
+            assert_full_diffusion: bool,
+            weak_connectivity_check: bool,
+            replacement_prefilter_inputs: usize,
+            level_bounded_convex_search: bool,
+            prioritize_convex_start_nodes: bool,
+            frozen_gates: BTreeSet<usize>,
+            batch_regions: usize,
             curr_total_steps: usize,
             curr_inflationary_stage_steps: usize,
             curr_kneading_stage_steps: usize,
+            curr_kneading_early_stop_checkpoints: usize,
+            mixing_trace_len: Option<u64>,
+            mixing_rng_stored: bool,
             curr_circuit_digest: String,
+            curr_circuit_annotations: usize,
             original_circuit_digest: String,
+            metadata: JobMetadata,
         }
 
         log::info!(
             "loaded job: {:#?}",
             Status {
+                version: job.version,
                 n: job.config.n,
                 total_steps: job.config.total_steps,
                 inflationary_stage_steps: job.config.inflationary_stage_steps,
                 kneading_stage_steps: job.config.kneading_stage_steps,
                 max_convex_iterations: job.config.max_convex_iterations,
                 max_replacement_iterations: job.config.max_replacement_iterations,
+                replacement_iterations_schedule: job.config.replacement_iterations_schedule,
                 starategy: job.config.starategy,
                 checkpoint_steps: job.config.checkpoint_steps,
+                checkpoint_secs: job.config.checkpoint_secs,
+                eq_check_scaling: job.config.eq_check_scaling,
+                exhaustive_eq_check_max_n: job.config.exhaustive_eq_check_max_n,
+                ell_out_schedule: job.config.ell_out_schedule.clone(),
+                ell_in_retries: job.config.ell_in_retries,
+                preserve_gate_count: job.config.preserve_gate_count,
+                cipher_stages: job.config.cipher_stages,
+                cipher_layers_per_stage: job.config.cipher_layers_per_stage,
+                run_inflationary_stage: job.config.run_inflationary_stage,
+                run_kneading_stage: job.config.run_kneading_stage,
+                metrics_csv_path: job.config.metrics_csv_path.clone(),
+                checkpoint_csv_path: job.config.checkpoint_csv_path.clone(),
+                mixing_trace_path: job.config.mixing_trace_path.clone(),
+                seed: job.config.seed,
+                history_dir: job.config.history_dir.clone(),
+                history_keep: job.config.history_keep,
+                kneading_early_stop_avalanche: job.config.kneading_early_stop_avalanche,
+                kneading_early_stop_checkpoints: job.config.kneading_early_stop_checkpoints,
+                convex_search_restart_steps: job.config.convex_search_restart_steps,
+                assert_full_diffusion: job.config.assert_full_diffusion,
+                weak_connectivity_check: job.config.weak_connectivity_check,
+                replacement_prefilter_inputs: job.config.replacement_prefilter_inputs,
+                level_bounded_convex_search: job.config.level_bounded_convex_search,
+                prioritize_convex_start_nodes: job.config.prioritize_convex_start_nodes,
+                frozen_gates: job.config.frozen_gates.clone(),
+                batch_regions: job.config.batch_regions,
                 curr_total_steps: job.curr_total_steps,
                 curr_inflationary_stage_steps: job.curr_inflationary_stage_steps,
                 curr_kneading_stage_steps: job.curr_kneading_stage_steps,
-                curr_circuit_digest: hex::encode(Sha256::digest(
-                    bincode::serialize(&job.curr_circuit).unwrap()
-                )),
-                original_circuit_digest: hex::encode(Sha256::digest(
-                    bincode::serialize(&job.original_circuit).unwrap()
-                )),
+                curr_kneading_early_stop_checkpoints: job.curr_kneading_early_stop_checkpoints,
+                mixing_trace_len: job.mixing_trace_len,
+                mixing_rng_stored: job.mixing_rng.is_some(),
+                curr_circuit_digest: job.curr_circuit.digest_hex(),
+                curr_circuit_annotations: job.curr_circuit.annotations().len(),
+                original_circuit_digest: job.original_circuit.digest_hex(),
+                metadata: job.metadata.clone(),
             }
         );
 
-        job
+        Ok(job)
+    }
+
+    /// Decodes a job stored with any version this build loads
+    fn decode(bytes: &[u8]) -> Result<Self, JobLoadError> {
+        let Some(bytes) = bytes.strip_prefix(&OBFUSCATION_JOB_MAGIC) else {
+            return Self::migrate(0, bytes);
+        };
+        let version = decode_job_layout(0, bytes)?;
+        if version == OBFUSCATION_JOB_VERSION {
+            decode_job_layout(version, bytes)
+        } else {
+            Self::migrate(version, bytes)
+        }
+    }
+
+    /// Migrates a job stored with an older serialization version `version` to the current version.
+    /// `bytes` don't include the magic prefix.
+    fn migrate(version: u32, bytes: &[u8]) -> Result<Self, JobLoadError> {
+        let job = match version {
+            0 => decode_job_layout::<ObfuscationJobV0>(version, bytes)?.into(),
+            _ => return Err(JobLoadError::UnsupportedVersion(version)),
+        };
+        log::info!("Migrating job from version {version} to version {OBFUSCATION_JOB_VERSION}");
+        Ok(job)
     }
 
+    /// Encodes the job with the current version
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = OBFUSCATION_JOB_MAGIC.to_vec();
+        bincode::serialize_into(&mut bytes, self).unwrap();
+        bytes
+    }
+
+    /// Stores the job at `path`. Job is written to `path.tmp` first and then renamed over `path`,
+    /// so that a crash mid-write leaves either the old or the new job at `path`.
     fn store(&self, path: impl AsRef<Path>) {
-        std::fs::write(&path, bincode::serialize(self).unwrap()).unwrap();
+        let mut tmp_path = path.as_ref().as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path).unwrap();
+        file.write_all(&self.encode()).unwrap();
+        // Data must be on disk before the rename is, otherwise a crash may still leave a partial
+        // job at `path`
+        file.sync_all().unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
 
         log::info!(
             "stored job, curr_inflationary_stage_steps: {}, curr_kneading_stage_steps: {}, curr_circuit digest: 0x{}, original_circuit digest: 0x{}",
             self.curr_inflationary_stage_steps,
             self.curr_kneading_stage_steps,
-            hex::encode(Sha256::digest(bincode::serialize(&self.curr_circuit).unwrap())),
-            hex::encode(Sha256::digest(bincode::serialize(&self.original_circuit).unwrap())),
+            self.curr_circuit.digest_hex(),
+            self.original_circuit.digest_hex(),
+        );
+    }
+
+    /// Stores the job at `path` and, if `history_dir` is set, a copy named after the no. of
+    /// steps done in `history_dir`. Only the last `history_keep` copies are kept.
+    fn checkpoint(&mut self, path: impl AsRef<Path>) {
+        // Records of every step up to the checkpoint have been appended to the trace
+        if let Some(mixing_trace_path) = &self.config.mixing_trace_path {
+            self.mixing_trace_len =
+                Some(std::fs::metadata(mixing_trace_path).map_or(0, |m| m.len()));
+        }
+        self.store(path);
+
+        let Some(history_dir) = &self.config.history_dir else {
+            return;
+        };
+        let steps = self.curr_total_steps
+            + self.curr_inflationary_stage_steps
+            + self.curr_kneading_stage_steps;
+        std::fs::create_dir_all(history_dir).unwrap();
+        // Zero padded so that copies sort by step
+        self.store(Path::new(history_dir).join(format!("step_{steps:012}.bin")));
+
+        let copies = std::fs::read_dir(history_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("step_") && name.ends_with(".bin"))
+            })
+            .sorted()
+            .collect_vec();
+        for path in &copies[..copies.len().saturating_sub(self.config.history_keep)] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    /// RNG to continue mixing with, from the last checkpoint if there is one
+    fn mixing_rng(&self) -> ChaCha8Rng {
+        self.mixing_rng
+            .clone()
+            .unwrap_or_else(|| self.config.mixing_rng())
+    }
+
+    /// Truncates the mixing trace to its length at the last checkpoint, which drops records of
+    /// steps run after the checkpoint by an interrupted run
+    fn truncate_mixing_trace(&self) {
+        let Some(mixing_trace_path) = &self.config.mixing_trace_path else {
+            return;
+        };
+        let Some(len) = self.mixing_trace_len else {
+            log::warn!(
+                "Job doesn't store the length of mixing trace {mixing_trace_path}. It may have records of steps after the last checkpoint"
+            );
+            return;
+        };
+        let file = match std::fs::OpenOptions::new()
+            .write(true)
+            .open(mixing_trace_path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && len == 0 => return,
+            Err(e) => panic!("Failed to open mixing trace {mixing_trace_path}: {e}"),
+        };
+        let trace_len = file.metadata().unwrap().len();
+        assert!(
+            trace_len >= len,
+            "Mixing trace {mixing_trace_path} is shorter than at the last checkpoint"
+        );
+        if trace_len > len {
+            log::info!(
+                "Dropping {} bytes of mixing trace records of steps after the last checkpoint",
+                trace_len - len
+            );
+            file.set_len(len).unwrap();
+        }
+    }
+}
+
+/// Asserts that every output wire of `circuit` structurally depends on every input wire. Logs
+/// `(output, input)` pairs without a dependency otherwise.
+fn assert_full_diffusion(circuit: &Circuit<BaseGate<2, u8>>, tag: &str) {
+    let missing_dependencies = circuit
+        .dependency_matrix()
+        .into_iter()
+        .enumerate()
+        .flat_map(|(output, dependencies)| {
+            dependencies
+                .into_iter()
+                .enumerate()
+                .filter(|(_, depends)| !depends)
+                .map(move |(input, _)| (output, input))
+        })
+        .collect_vec();
+    if !missing_dependencies.is_empty() {
+        log::error!(
+            "[Error] [{tag}] Obfuscated circuit is not fully diffused. (output, input) wire pairs without a dependency: {:?}",
+            missing_dependencies
         );
+        panic!("Obfuscated circuit is not fully diffused");
+    }
+    log::info!("[{tag}] Obfuscated circuit is fully diffused");
+}
+
+/// Mixing state of `circuit` with the frozen gates of `config`. Panics if the skeleton graph of
+/// `circuit` is invalid or a frozen gate is not in `circuit`.
+fn prepare_mixing_state(
+    circuit: &Circuit<BaseGate<2, u8>>,
+    config: &ObfuscationConfig,
+) -> MixingState {
+    let prepared = prepare_circuit(circuit);
+    if let Err(e) = validate_prepared(&prepared) {
+        log::error!("Invalid skeleton graph of job's circuit: {e}");
+        panic!("Invalid skeleton graph of job's circuit: {e}");
     }
+    let mut state = MixingState::from_prepared(prepared, config.n as u8);
+    if let Err(id) = state.freeze_gates(&config.frozen_gates) {
+        panic!("Frozen gate {id} is not in the circuit");
+    }
+    state
 }
 
-fn run_strategy1(job: &mut ObfuscationJob, job_path: String, debug: bool) {
+fn run_strategy1(
+    job: &mut ObfuscationJob,
+    job_path: String,
+    verify_every: usize,
+    snapshot_steps: bool,
+) {
     let original_circuit = job.original_circuit.clone();
-    let mut rng = ChaCha8Rng::from_entropy();
-
-    let (
-        mut direct_connections,
-        mut direct_incoming_connections,
-        mut skeleton_graph,
-        mut gate_id_to_node_index_map,
-        mut gate_map,
-        mut graph_neighbours,
-        mut active_edges_with_gateids,
-        mut latest_id,
-    ) = prepare_circuit(&original_circuit);
+    let metrics_csv_path = job.config.metrics_csv_path.clone();
+    let checkpoint_csv_path = job.config.checkpoint_csv_path.clone();
+    let mixing_trace_path = job.config.mixing_trace_path.clone();
+    let step_snapshot_path = snapshot_steps.then(|| format!("{job_path}.step"));
+    let skeleton_dump_path = format!("{job_path}.skeleton");
+    // Tallied only with feature `trace` to see how often replacement search runs on many wires
+    // and how costly it is
+    let mut replacement_histograms = cfg!(feature = "trace").then(ReplacementHistograms::default);
+    let mut rng = job.mixing_rng();
+
+    // Continue mixing from the last checkpoint so that a job can be resumed or extended
+    let mut state = prepare_mixing_state(&job.curr_circuit, &job.config);
 
     // For total no. of steps do the following:
-    //  -> Sample a random no. betwee [2, 4]. Set that as ell_out
-    //  -> Run local mixing step with ell_out and ell_in = 4
-
-    let mut removed_nodes = HashSet::new();
+    //  -> Sample a random no. from `ell_out_schedule` range (default [2, 4]). Set that as ell_out
+    //  -> Run local mixing step with ell_out and ell_in = 4, or ell_in = ell_out if
+    //     `preserve_gate_count` is set
+
+    // Strategy 3 runs strategy 1 with rebuilds of the skeleton graph when steps keep failing.
+    // Failed steps are counted from the start of this run, since the graph is built anew when a
+    // job is continued as well.
+    let (strategy_tag, restart_steps) = match job.config.starategy {
+        Strategy::Strategy3 => ("Strategy 3", Some(job.config.convex_search_restart_steps)),
+        _ => ("Strategy 1", None),
+    };
+    let mut failed_steps = 0;
+    let mut restarts = 0;
+    let mut timings = MixingTimings::default();
+    let mut last_checkpoint = std::time::Instant::now();
 
     while job.curr_total_steps < job.config.total_steps {
-        let ell_out = rng.gen_range(2..=4);
-        let to_checkpoint = job.curr_total_steps % job.config.checkpoint_steps == 0;
+        if restart_steps == Some(failed_steps) {
+            restarts += 1;
+            state = prepare_mixing_state(&state.shuffled_circuit(&mut rng), &job.config);
+            log::info!(
+                "[{strategy_tag}] Rebuilt skeleton graph with shuffled gates after {failed_steps} consecutive failed steps (restart {restarts})"
+            );
+            failed_steps = 0;
+        }
 
-        let success = run_local_mixing(
+        let ell_out = rng.gen_range(
+            job.config
+                .ell_out_range(job.curr_total_steps, MIXING_STAGE_ELL_IN),
+        );
+        let to_checkpoint = job
+            .config
+            .is_checkpoint_due(job.curr_total_steps, last_checkpoint);
+
+        let applied = run_local_mixing(
             &format!(
-                "[Strategy 1] [ell^out = {}] Mixing stage step {}",
-                ell_out, job.curr_total_steps
+                "[{}] [ell^out = {}] Mixing stage step {}",
+                strategy_tag, ell_out, job.curr_total_steps
             ),
             Some(&original_circuit),
-            &mut skeleton_graph,
-            &mut direct_connections,
-            &mut direct_incoming_connections,
-            &mut gate_map,
-            &mut gate_id_to_node_index_map,
-            &mut graph_neighbours,
-            &mut removed_nodes,
-            &mut active_edges_with_gateids,
-            &mut latest_id,
-            job.config.n as u8,
+            &mut state,
             &mut rng,
-            ell_out,
-            4,
-            job.config.max_convex_iterations,
-            job.config.max_replacement_iterations,
-            to_checkpoint,
-            job.config.probabilitic_eq_check_iterations,
-            |mixed_circuit| {
-                job.curr_circuit = mixed_circuit;
-                job.store(&job_path);
+            &MixingOptions {
+                step_params: job.config.mixing_stage_step_params(ell_out),
+                replacement_iterations_schedule: job.config.replacement_iterations_schedule,
+                step: job.curr_total_steps,
+                total_steps: job.config.mixing_steps(),
+                to_checkpoint,
+                probabilitic_eq_check_iterations: job.config.eq_check_iterations(),
+                verify_every,
+                batch_regions: job
+                    .config
+                    .batch_regions(job.curr_total_steps, job.config.total_steps),
+                preserve_gate_count: job.config.preserve_gate_count,
+            },
+            MixingObservers {
+                skeleton_dump_path: Some(Path::new(&skeleton_dump_path)),
+                step_snapshot_path: step_snapshot_path.as_deref().map(Path::new),
+                metrics_csv_path: metrics_csv_path.as_deref().map(Path::new),
+                checkpoint_csv_path: checkpoint_csv_path.as_deref().map(Path::new),
+                mixing_trace_path: mixing_trace_path.as_deref().map(Path::new),
+                replacement_histograms: replacement_histograms.as_mut(),
+                timings: Some(&mut timings),
+                progress: None,
+            },
+            |mixed_circuit, rng| {
+                job.curr_circuit = mixed_circuit.with_annotations_from(&job.curr_circuit);
+                job.mixing_rng = Some(rng.clone());
+                job.checkpoint(&job_path);
+                last_checkpoint = std::time::Instant::now();
             },
-            debug,
         );
-        if success {
-            job.curr_total_steps += 1;
-        }
+        job.curr_total_steps += applied;
+        failed_steps = if applied == 0 { failed_steps + 1 } else { 0 };
     }
 
     {
-        let top_sorted_nodes = toposort_with_cached_graph_neighbours(
-            &skeleton_graph,
-            &graph_neighbours,
-            &removed_nodes,
-        );
         job.curr_total_steps = job.config.total_steps;
-        job.curr_circuit = Circuit::from_top_sorted_nodes(
-            &top_sorted_nodes,
-            &skeleton_graph,
-            &gate_map,
-            job.config.n as _,
-        );
-
-        let (is_correct, diff_indices) = check_probabilisitic_equivalence(
-            &job.curr_circuit,
-            &original_circuit,
-            job.config.probabilitic_eq_check_iterations,
-            &mut rng,
-        );
+        job.curr_circuit = state.circuit().with_annotations_from(&job.curr_circuit);
+
+        let verification_start = std::time::Instant::now();
+        let (is_correct, diff_indices) =
+            job.config
+                .check_equivalence(&job.curr_circuit, &original_circuit, &mut rng);
+        timings.verification += verification_start.elapsed();
+        log::info!("[{strategy_tag}] Time spent in Mixing stage: {:?}", timings);
+        if let Some(histograms) = &replacement_histograms {
+            log::info!(
+                "[{strategy_tag}] Replacement histograms of Mixing stage: {:?}",
+                histograms
+            );
+        }
         if !is_correct {
             log::error!(
-                "[Error] [Strategy 1] Failed at end of Mixing stage. Different at indices {:?}",
+                "[Error] [{}] Failed at end of Mixing stage. Different at indices {:?}",
+                strategy_tag,
                 diff_indices
             );
             assert!(false);
         }
 
-        job.store(&job_path);
+        job.mixing_rng = Some(rng.clone());
+        job.checkpoint(&job_path);
+        if job.config.assert_full_diffusion {
+            assert_full_diffusion(&job.curr_circuit, strategy_tag);
+        }
     }
 }
 
-fn run_strategy2(job: &mut ObfuscationJob, job_path: String, debug: bool) {
+fn run_strategy2(
+    job: &mut ObfuscationJob,
+    job_path: String,
+    verify_every: usize,
+    snapshot_steps: bool,
+) {
     let original_circuit = job.original_circuit.clone();
-    let mut rng = ChaCha8Rng::from_entropy();
+    let metrics_csv_path = job.config.metrics_csv_path.clone();
+    let checkpoint_csv_path = job.config.checkpoint_csv_path.clone();
+    let mixing_trace_path = job.config.mixing_trace_path.clone();
+    let step_snapshot_path = snapshot_steps.then(|| format!("{job_path}.step"));
+    let skeleton_dump_path = format!("{job_path}.skeleton");
+    let mut rng = job.mixing_rng();
+    if job.config.preserve_gate_count {
+        log::warn!(
+            "[Strategy 2] Ignoring preserve_gate_count, it only applies to strategies 1 and 3"
+        );
+    }
 
-    let (
-        mut direct_connections,
-        mut direct_incoming_connections,
-        mut skeleton_graph,
-        mut gate_id_to_node_index_map,
-        mut gate_map,
-        mut graph_neighbours,
-        mut active_edges_with_gateids,
-        mut latest_id,
-    ) = prepare_circuit(&original_circuit);
+    // Continue mixing from the last checkpoint so that a stage can be resumed or skipped
+    let mut state = prepare_mixing_state(&job.curr_circuit, &job.config);
 
-    let mut removed_nodes = HashSet::new();
+    let mut last_checkpoint = std::time::Instant::now();
 
     // Inflationary stage
     {
-        while job.curr_inflationary_stage_steps < job.config.inflationary_stage_steps {
-            let to_checkpoint =
-                job.curr_inflationary_stage_steps % job.config.checkpoint_steps == 0;
+        let mut timings = MixingTimings::default();
+        // Tallied only with feature `trace` to see how often replacement search runs on many wires
+        // and how costly it is
+        let mut replacement_histograms =
+            cfg!(feature = "trace").then(ReplacementHistograms::default);
+        if !job.config.run_inflationary_stage {
+            log::info!(
+                "[Strategy 2] Skipping inflationary stage at step {}",
+                job.curr_inflationary_stage_steps
+            );
+        }
+
+        while job.config.run_inflationary_stage
+            && job.curr_inflationary_stage_steps < job.config.inflationary_stage_steps
+        {
+            let to_checkpoint = job
+                .config
+                .is_checkpoint_due(job.curr_inflationary_stage_steps, last_checkpoint);
 
             // Inflationary stage
-            let success = run_local_mixing(
+            let applied = run_local_mixing(
                 &format!(
                     "[Strategy 2] Inflationary stage step {}",
                     job.curr_inflationary_stage_steps
                 ),
                 Some(&original_circuit),
-                &mut skeleton_graph,
-                &mut direct_connections,
-                &mut direct_incoming_connections,
-                &mut gate_map,
-                &mut gate_id_to_node_index_map,
-                &mut graph_neighbours,
-                &mut removed_nodes,
-                &mut active_edges_with_gateids,
-                &mut latest_id,
-                job.config.n as u8,
+                &mut state,
                 &mut rng,
-                2,
-                4,
-                job.config.max_convex_iterations,
-                job.config.max_replacement_iterations,
-                to_checkpoint,
-                job.config.probabilitic_eq_check_iterations,
-                |mixed_circuit| {
-                    job.curr_circuit = mixed_circuit;
-                    job.store(&job_path);
+                &MixingOptions {
+                    step_params: job.config.inflationary_stage_step_params(),
+                    replacement_iterations_schedule: job.config.replacement_iterations_schedule,
+                    step: job.curr_inflationary_stage_steps,
+                    total_steps: job.config.mixing_steps(),
+                    to_checkpoint,
+                    probabilitic_eq_check_iterations: job.config.eq_check_iterations(),
+                    verify_every,
+                    batch_regions: job.config.batch_regions(
+                        job.curr_inflationary_stage_steps,
+                        job.config.inflationary_stage_steps,
+                    ),
+                    preserve_gate_count: false,
+                },
+                MixingObservers {
+                    skeleton_dump_path: Some(Path::new(&skeleton_dump_path)),
+                    step_snapshot_path: step_snapshot_path.as_deref().map(Path::new),
+                    metrics_csv_path: metrics_csv_path.as_deref().map(Path::new),
+                    checkpoint_csv_path: checkpoint_csv_path.as_deref().map(Path::new),
+                    mixing_trace_path: mixing_trace_path.as_deref().map(Path::new),
+                    replacement_histograms: replacement_histograms.as_mut(),
+                    timings: Some(&mut timings),
+                    progress: None,
+                },
+                |mixed_circuit, rng| {
+                    job.curr_circuit = mixed_circuit.with_annotations_from(&job.curr_circuit);
+                    job.mixing_rng = Some(rng.clone());
+                    job.checkpoint(&job_path);
+                    last_checkpoint = std::time::Instant::now();
                 },
-                debug,
             );
-            if success {
-                job.curr_inflationary_stage_steps += 1;
-            }
+            job.curr_inflationary_stage_steps += applied;
         }
 
         {
-            let top_sorted_nodes = toposort_with_cached_graph_neighbours(
-                &skeleton_graph,
-                &graph_neighbours,
-                &removed_nodes,
-            );
-            job.curr_inflationary_stage_steps = job.config.inflationary_stage_steps;
-            job.curr_circuit = Circuit::from_top_sorted_nodes(
-                &top_sorted_nodes,
-                &skeleton_graph,
-                &gate_map,
-                job.config.n as _,
-            );
-
-            let (is_correct, diff_indices) = check_probabilisitic_equivalence(
-                &job.curr_circuit,
-                &original_circuit,
-                job.config.probabilitic_eq_check_iterations,
-                &mut rng,
+            // End of stage check runs even if the stage is skipped
+            if job.config.run_inflationary_stage {
+                job.curr_inflationary_stage_steps = job.config.inflationary_stage_steps;
+            }
+            job.curr_circuit = state.circuit().with_annotations_from(&job.curr_circuit);
+
+            let verification_start = std::time::Instant::now();
+            let (is_correct, diff_indices) =
+                job.config
+                    .check_equivalence(&job.curr_circuit, &original_circuit, &mut rng);
+            timings.verification += verification_start.elapsed();
+            log::info!(
+                "[Strategy 2] Time spent in Inflationary stage: {:?}",
+                timings
             );
+            if let Some(histograms) = &replacement_histograms {
+                log::info!(
+                    "[Strategy 2] Replacement histograms of Inflationary stage: {:?}",
+                    histograms
+                );
+            }
             if !is_correct {
                 log::error!(
                     "[Error] [Strategy 2] Failed at end of Inflationary stage. Different at indices {:?}",
@@ -348,70 +1205,133 @@ fn run_strategy2(job: &mut ObfuscationJob, job_path: String, debug: bool) {
                 assert!(false);
             }
 
-            job.store(&job_path);
+            job.mixing_rng = Some(rng.clone());
+            job.checkpoint(&job_path);
         }
     }
 
     // Kneading stage
     {
-        while job.curr_kneading_stage_steps < job.config.kneading_stage_steps {
-            let to_checkpoint = job.curr_kneading_stage_steps % job.config.checkpoint_steps == 0;
+        let mut timings = MixingTimings::default();
+        let mut replacement_histograms =
+            cfg!(feature = "trace").then(ReplacementHistograms::default);
+        if !job.config.run_kneading_stage {
+            log::info!(
+                "[Strategy 2] Skipping kneading stage at step {}",
+                job.curr_kneading_stage_steps
+            );
+        }
+
+        // Avalanche scores are estimated with a separate RNG since the mixing RNG is borrowed by
+        // the mixing step. It's derived from the mixing RNG so that a seeded run stays
+        // reproducible. Consecutive checkpoints are stored with the job, so that they carry over
+        // when the job is continued.
+        let mut avalanche_rng = job
+            .config
+            .kneading_early_stop_avalanche
+            .map(|_| ChaCha8Rng::from_rng(&mut rng).unwrap());
+
+        while job.config.run_kneading_stage
+            && job.curr_kneading_stage_steps < job.config.kneading_stage_steps
+        {
+            let to_checkpoint = job
+                .config
+                .is_checkpoint_due(job.curr_kneading_stage_steps, last_checkpoint);
 
-            let success = run_local_mixing(
+            let applied = run_local_mixing(
                 &format!(
                     "[Strategy 2] Kneading stage step {}",
                     job.curr_kneading_stage_steps
                 ),
                 Some(&original_circuit),
-                &mut skeleton_graph,
-                &mut direct_connections,
-                &mut direct_incoming_connections,
-                &mut gate_map,
-                &mut gate_id_to_node_index_map,
-                &mut graph_neighbours,
-                &mut removed_nodes,
-                &mut active_edges_with_gateids,
-                &mut latest_id,
-                job.config.n as u8,
+                &mut state,
                 &mut rng,
-                4,
-                4,
-                job.config.max_convex_iterations,
-                job.config.max_replacement_iterations,
-                to_checkpoint,
-                job.config.probabilitic_eq_check_iterations,
-                |mixed_circuit| {
-                    job.curr_circuit = mixed_circuit;
-                    job.store(&job_path);
+                &MixingOptions {
+                    step_params: job.config.kneading_stage_step_params(),
+                    replacement_iterations_schedule: job.config.replacement_iterations_schedule,
+                    step: job.curr_inflationary_stage_steps + job.curr_kneading_stage_steps,
+                    total_steps: job.config.mixing_steps(),
+                    to_checkpoint,
+                    probabilitic_eq_check_iterations: job.config.eq_check_iterations(),
+                    verify_every,
+                    batch_regions: job.config.batch_regions(
+                        job.curr_kneading_stage_steps,
+                        job.config.kneading_stage_steps,
+                    ),
+                    preserve_gate_count: false,
+                },
+                MixingObservers {
+                    skeleton_dump_path: Some(Path::new(&skeleton_dump_path)),
+                    step_snapshot_path: step_snapshot_path.as_deref().map(Path::new),
+                    metrics_csv_path: metrics_csv_path.as_deref().map(Path::new),
+                    checkpoint_csv_path: checkpoint_csv_path.as_deref().map(Path::new),
+                    mixing_trace_path: mixing_trace_path.as_deref().map(Path::new),
+                    replacement_histograms: replacement_histograms.as_mut(),
+                    timings: Some(&mut timings),
+                    progress: None,
+                },
+                |mixed_circuit, rng| {
+                    if let (Some(threshold), Some(avalanche_rng), true) = (
+                        job.config.kneading_early_stop_avalanche,
+                        avalanche_rng.as_mut(),
+                        to_checkpoint,
+                    ) {
+                        let score = avalanche_score(
+                            &mixed_circuit,
+                            KNEADING_EARLY_STOP_AVALANCHE_ITERATIONS,
+                            avalanche_rng,
+                        );
+                        if score >= threshold {
+                            job.curr_kneading_early_stop_checkpoints += 1;
+                        } else {
+                            job.curr_kneading_early_stop_checkpoints = 0;
+                        }
+                        log::info!(
+                            "[Strategy 2] Avalanche score at kneading stage step {}: {score:.4} ({} consecutive checkpoints >= {threshold})",
+                            job.curr_kneading_stage_steps,
+                            job.curr_kneading_early_stop_checkpoints
+                        );
+                    }
+                    job.curr_circuit = mixed_circuit.with_annotations_from(&job.curr_circuit);
+                    job.mixing_rng = Some(rng.clone());
+                    job.checkpoint(&job_path);
+                    last_checkpoint = std::time::Instant::now();
                 },
-                debug,
             );
 
-            if success {
-                job.curr_kneading_stage_steps += 1
+            job.curr_kneading_stage_steps += applied;
+
+            if job.curr_kneading_early_stop_checkpoints
+                >= job.config.kneading_early_stop_checkpoints
+            {
+                log::info!(
+                    "[Strategy 2] Stopping kneading stage early at step {} of {}",
+                    job.curr_kneading_stage_steps,
+                    job.config.kneading_stage_steps
+                );
+                break;
             }
         }
 
         {
-            let top_sorted_nodes = toposort_with_cached_graph_neighbours(
-                &skeleton_graph,
-                &graph_neighbours,
-                &removed_nodes,
-            );
-            job.curr_kneading_stage_steps = job.config.kneading_stage_steps;
-            job.curr_circuit = Circuit::from_top_sorted_nodes(
-                &top_sorted_nodes,
-                &skeleton_graph,
-                &gate_map,
-                job.config.n as _,
-            );
-
-            let (is_correct, diff_indices) = check_probabilisitic_equivalence(
-                &job.curr_circuit,
-                &original_circuit,
-                job.config.probabilitic_eq_check_iterations,
-                &mut rng,
-            );
+            // End of stage check runs even if the stage is skipped
+            if job.config.run_kneading_stage {
+                job.curr_kneading_stage_steps = job.config.kneading_stage_steps;
+            }
+            job.curr_circuit = state.circuit().with_annotations_from(&job.curr_circuit);
+
+            let verification_start = std::time::Instant::now();
+            let (is_correct, diff_indices) =
+                job.config
+                    .check_equivalence(&job.curr_circuit, &original_circuit, &mut rng);
+            timings.verification += verification_start.elapsed();
+            log::info!("[Strategy 2] Time spent in Kneading stage: {:?}", timings);
+            if let Some(histograms) = &replacement_histograms {
+                log::info!(
+                    "[Strategy 2] Replacement histograms of Kneading stage: {:?}",
+                    histograms
+                );
+            }
             if !is_correct {
                 log::error!(
                     "[Error] [Strategy 2] Failed at end of kneading stage. Different at indices {:?}",
@@ -420,125 +1340,674 @@ fn run_strategy2(job: &mut ObfuscationJob, job_path: String, debug: bool) {
                 assert!(false);
             }
 
-            job.store(&job_path);
+            job.mixing_rng = Some(rng.clone());
+            job.checkpoint(&job_path);
+            if job.config.assert_full_diffusion {
+                assert_full_diffusion(&job.curr_circuit, "Strategy 2");
+            }
         }
     }
 }
 
-fn create_log4rs_config(log_path: &str) -> Result<log4rs::Config, Box<dyn Error>> {
-    // Define the file appender with the specified path and pattern
-    let file_appender = log4rs::append::file::FileAppender::builder()
-        .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new(
-            "{d} - {l} - {m}{n}",
-        )))
-        .build(log_path)?;
+/// Creates log4rs config that logs records up to `level` to file at `log_path`, if any, and to
+/// stdout if `log_stdout` is set.
+fn create_log4rs_config(
+    log_path: Option<&str>,
+    log_stdout: bool,
+    level: log::LevelFilter,
+) -> Result<log4rs::Config, Box<dyn Error>> {
+    assert!(
+        log_path.is_some() || log_stdout,
+        "Logs must be written to a file or stdout"
+    );
+
+    let mut config = log4rs::Config::builder();
+    let mut root = log4rs::config::Root::builder();
+
+    if let Some(log_path) = log_path {
+        // Define the file appender with the specified path and pattern
+        let file_appender = log4rs::append::file::FileAppender::builder()
+            .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new(
+                "{d} - {l} - {m}{n}",
+            )))
+            .build(log_path)?;
+        config = config
+            .appender(log4rs::config::Appender::builder().build("file", Box::new(file_appender)));
+        root = root.appender("file");
+    }
+
+    if log_stdout {
+        let console_appender = log4rs::append::console::ConsoleAppender::builder()
+            .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new(
+                "{d} - {l} - {m}{n}",
+            )))
+            .build();
+        config = config.appender(
+            log4rs::config::Appender::builder().build("stdout", Box::new(console_appender)),
+        );
+        root = root.appender("stdout");
+    }
 
     // Build the configuration
-    let config = log4rs::Config::builder()
-        .appender(log4rs::config::Appender::builder().build("file", Box::new(file_appender)))
-        .build(
-            log4rs::config::Root::builder()
-                .appender("file")
-                .build(log::LevelFilter::Trace),
-        )?;
+    let config = config.build(root.build(level))?;
 
     Ok(config)
 }
 
 /// Start a new obfuscation job OR continue an existing obfuscation job
-fn run_obfuscation() {
+fn run_obfuscation(args: ObfuscateArgs) {
     let debug = env::var("DEBUG") // only support `DEBUG=true` or `DEBUG=false`
         .ok()
         .and_then(|var| var.parse().ok())
         .unwrap_or(true);
 
-    // Setup logs
-    let log_path = args().nth(2).expect("Missing log path");
-    let log_confg = create_log4rs_config(&log_path).unwrap();
+    // Setup logs. Log path `-` disables file logging.
+    let log_confg = create_log4rs_config(
+        Some(args.log_path.as_str()).filter(|path| *path != "-"),
+        args.log_stdout,
+        args.log_level,
+    )
+    .unwrap();
     log4rs::init_config(log_confg).unwrap();
 
-    let job_path = args().nth(3).expect("Missing obfuscated circuit path");
+    // Sections that are not serial with feature `no-rayon` must run on a single thread for the
+    // obfuscated circuit to only depend on the seed
+    #[cfg(feature = "no-rayon")]
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build_global()
+        .unwrap();
+
+    let new_job_flags = args.new_job_flags();
+    let job_path = args.job_path;
     let mut job = if std::fs::exists(&job_path).unwrap() {
         log::info!("Found obfuscation job at path. Continuing the pending job.");
+        assert!(
+            new_job_flags.is_empty(),
+            "{} only apply when starting a new job, but there is a job at {job_path}",
+            new_job_flags.join(", ")
+        );
 
-        ObfuscationJob::load(&job_path)
+        let mut job = ObfuscationJob::load(&job_path).unwrap_or_else(|e| panic!("{e}"));
+        // Dry run doesn't write anything
+        if !args.dry_run {
+            job.truncate_mixing_trace();
+        }
+        if let Some(steps) = args.continue_for {
+            // Steps are added to the last stage so that a finished job mixes further from its
+            // obfuscated circuit
+            match job.config.starategy {
+                Strategy::Strategy1 | Strategy::Strategy3 => job.config.total_steps += steps,
+                Strategy::Strategy2 => job.config.kneading_stage_steps += steps,
+            }
+            log::info!(
+                "Extended job by {steps} steps. total_steps: {}, kneading_stage_steps: {}",
+                job.config.total_steps,
+                job.config.kneading_stage_steps
+            );
+        }
+        job
     } else {
         log::info!("Starting new obfuscation job at path");
-        let orignal_circuit_path = args().nth(4).expect("Missing original circuit path");
-
-        let strategy = args().nth(5).map_or_else(
-            || Strategy::Strategy1,
-            |sid| match sid.parse::<u8>() {
-                Ok(sid) => {
-                    if sid == 1 {
-                        return Strategy::Strategy1;
-                    } else if sid == 2 {
-                        return Strategy::Strategy2;
-                    } else {
-                        assert!(false, "Strategy can either be 1 or 2, not {sid}");
-                        return Strategy::Strategy1; // Just to calm the compiler
-                    }
-                }
-                Err(e) => {
-                    assert!(false, "Strategy can either be 1 or 2, not {sid}");
-                    return Strategy::Strategy1; // Just to calm the compiler
-                }
-            },
+        assert!(
+            args.continue_for.is_none(),
+            "--continue-for extends an existing job, but there is no job at {job_path}"
         );
+        let orignal_circuit_path = args
+            .original_circuit_path
+            .expect("Missing original circuit path");
+
+        let strategy = match args.strategy {
+            1 => Strategy::Strategy1,
+            2 => Strategy::Strategy2,
+            _ => Strategy::Strategy3,
+        };
 
-        let config = match strategy {
+        let mut config = match strategy {
             Strategy::Strategy1 => ObfuscationConfig::default_strategy1(),
             Strategy::Strategy2 => ObfuscationConfig::default_strategy2(),
+            Strategy::Strategy3 => ObfuscationConfig::default_strategy3(),
         };
-
-        // let (original_circuit, _) =
-        // sample_circuit_with_base_gate::<2, u8, _>(300, config.n as u8, 1.0, &mut thread_rng());
-        // Circuit::sample_mutli_stage_cipher(config.n, thread_rng());
-        let original_circuit = Circuit::sample_multi_stage_cipher(config.n, thread_rng());
-
-        std::fs::write(
-            &orignal_circuit_path,
-            bincode::serialize(&original_circuit).unwrap(),
-        )
-        .unwrap();
-
-        ObfuscationJob {
-            config,
-            curr_total_steps: 0,
-            curr_inflationary_stage_steps: 0,
-            curr_kneading_stage_steps: 0,
-            curr_circuit: original_circuit.clone(),
-            original_circuit,
+        if args.adaptive_ell_out {
+            config.ell_out_schedule = ObfuscationConfig::default_ell_out_schedule();
         }
-    };
-
-    match job.config.starategy {
-        Strategy::Strategy1 => {
-            run_strategy1(&mut job, job_path, debug);
+        if let Some(retries) = args.ell_in_retries {
+            assert!(
+                retries <= MAX_ELL_IN_RETRIES,
+                "--ell-in-retries can be at most {MAX_ELL_IN_RETRIES}, not {retries}"
+            );
+            config.ell_in_retries = retries;
         }
-        Strategy::Strategy2 => {
-            run_strategy2(&mut job, job_path, debug);
+        if args.preserve_gate_count {
+            assert!(
+                !matches!(strategy, Strategy::Strategy2),
+                "--preserve-gate-count only applies to strategies 1 and 3"
+            );
+            assert!(
+                config.ell_in_retries == 0,
+                "--preserve-gate-count can't be used with --ell-in-retries"
+            );
+            config.preserve_gate_count = true;
+        }
+        config.checkpoint_secs = args.checkpoint_secs;
+        if let Some(stages) = args.cipher_stages {
+            config.cipher_stages = stages;
+        }
+        config.metrics_csv_path = args.metrics_csv;
+        config.checkpoint_csv_path = args.checkpoint_csv;
+        config.mixing_trace_path = args.mixing_trace;
+        config.seed = args.seed;
+        if let Some(scaling) = args.eq_check_scaling {
+            config.eq_check_scaling = scaling;
+        }
+        if let Some(max_n) = args.exhaustive_eq_check_max_n {
+            assert!(
+                max_n <= MAX_EXHAUSTIVE_N,
+                "--exhaustive-eq-check-max-n can be at most {MAX_EXHAUSTIVE_N}, not {max_n}"
+            );
+            config.exhaustive_eq_check_max_n = max_n;
+        }
+        if let Some(schedule) = args.replacement_iterations_schedule {
+            config.replacement_iterations_schedule = match schedule.split_once(':') {
+                None if schedule == "constant" => ReplacementIterationsSchedule::Constant,
+                Some(("linear", final_iterations)) => ReplacementIterationsSchedule::Linear {
+                    final_iterations: final_iterations
+                        .parse()
+                        .expect("final iterations of --replacement-iterations-schedule must be a number"),
+                },
+                Some(("exponential", final_iterations)) => {
+                    ReplacementIterationsSchedule::Exponential {
+                        final_iterations: final_iterations
+                            .parse()
+                            .expect("final iterations of --replacement-iterations-schedule must be a number"),
+                    }
+                }
+                _ => panic!(
+                    "--replacement-iterations-schedule must be one of constant, linear:[final_iterations], exponential:[final_iterations]"
+                ),
+            };
+        }
+        if let Some(threshold) = args.kneading_early_stop_avalanche {
+            assert!(
+                0.0 < threshold && threshold <= 1.0,
+                "--kneading-early-stop-avalanche must be in (0, 1], not {threshold}"
+            );
+            config.kneading_early_stop_avalanche = Some(threshold);
+        }
+        if let Some(checkpoints) = args.kneading_early_stop_checkpoints {
+            config.kneading_early_stop_checkpoints = checkpoints;
+            assert!(
+                config.kneading_early_stop_checkpoints > 0,
+                "--kneading-early-stop-checkpoints must be at least 1"
+            );
+        }
+        if let Some(restart_steps) = args.convex_search_restart_steps {
+            config.convex_search_restart_steps = restart_steps;
+            assert!(
+                config.convex_search_restart_steps > 0,
+                "--convex-search-restart-steps must be at least 1"
+            );
+        }
+        if args.assert_full_diffusion {
+            config.assert_full_diffusion = true;
+        }
+        if args.no_weak_connectivity_check {
+            config.weak_connectivity_check = false;
+        }
+        if let Some(inputs) = args.replacement_prefilter_inputs {
+            assert!(
+                inputs <= 64,
+                "--replacement-prefilter-inputs can be at most 64, not {inputs}"
+            );
+            config.replacement_prefilter_inputs = inputs;
+        }
+        if args.level_bounded_convex_search {
+            config.level_bounded_convex_search = true;
+        }
+        if args.prioritize_convex_start_nodes {
+            config.prioritize_convex_start_nodes = true;
+        }
+        if let Some(regions) = args.batch_regions {
+            assert!(regions > 0, "--batch-regions must be at least 1");
+            config.batch_regions = regions;
+        }
+        config.history_dir = args.history_dir;
+        if let Some(keep) = args.history_keep {
+            config.history_keep = keep;
+            assert!(config.history_keep > 0, "--history-keep must be at least 1");
+        }
+        if let Some(layers) = args.cipher_layers {
+            config.cipher_layers_per_stage = layers;
+        }
+
+        // Obfuscate the circuit at path if there is one. Otherwise sample a cipher and store it there.
+        let original_circuit = if std::fs::exists(&orignal_circuit_path).unwrap() {
+            let original_circuit = load_circuit(&orignal_circuit_path);
+            config.n = original_circuit.n();
+            log::info!(
+                "Loaded original circuit with {} wires and {} gates, digest: 0x{}",
+                original_circuit.n(),
+                original_circuit.num_gates(),
+                original_circuit.digest_hex()
+            );
+            original_circuit
+        } else {
+            // let (original_circuit, _) =
+            // sample_circuit_with_base_gate::<2, u8, _>(300, config.n as u8, 1.0, &mut thread_rng());
+            // Circuit::sample_mutli_stage_cipher(config.n, thread_rng());
+            let original_circuit = Circuit::sample_multi_stage_cipher(
+                config.n,
+                config.cipher_stages,
+                config.cipher_layers_per_stage,
+                config.cipher_rng(),
+            );
+
+            // Dry run doesn't write anything
+            if !args.dry_run {
+                std::fs::write(
+                    &orignal_circuit_path,
+                    bincode::serialize(&original_circuit).unwrap(),
+                )
+                .unwrap();
+            }
+            original_circuit
+        };
+
+        ObfuscationJob {
+            version: OBFUSCATION_JOB_VERSION,
+            metadata: JobMetadata::new(config.seed),
+            config,
+            curr_total_steps: 0,
+            curr_inflationary_stage_steps: 0,
+            curr_kneading_stage_steps: 0,
+            curr_kneading_early_stop_checkpoints: 0,
+            mixing_trace_len: None,
+            mixing_rng: None,
+            curr_circuit: original_circuit.clone(),
+            original_circuit,
         }
+    };
+
+    // Stages to run are set on every run so that a resumed job can skip a stage
+    job.config.run_inflationary_stage = args.stage != StageSelection::Kneading;
+    job.config.run_kneading_stage = args.stage != StageSelection::Inflationary;
+
+    if !job.config.weak_connectivity_check {
+        log::warn!("Weak connectivity check of replacement circuits is disabled");
+    }
+    if job.config.level_bounded_convex_search {
+        log::info!("Convex subcircuit search grows sets by the candidate of lowest level");
+    }
+    if job.config.prioritize_convex_start_nodes {
+        log::info!("Convex subcircuit search tries start gates with enough successors first");
+    }
+
+    // `DEBUG=true` checks after every step and `DEBUG=false` only at checkpoints
+    let verify_every = args.verify_every.unwrap_or(debug as usize);
+    log::info!("Checking equivalence every {verify_every} steps (0 is only at checkpoints)");
+
+    // Gates frozen by earlier runs stay frozen
+    for id in args.freeze_gates {
+        assert!(
+            job.curr_circuit.gate_by_id(id).is_some(),
+            "Frozen gate {id} is not in the obfuscated circuit"
+        );
+        job.config.frozen_gates.insert(id);
+    }
+    if !job.config.frozen_gates.is_empty() {
+        log::info!("Frozen gates: {:?}", job.config.frozen_gates);
+    }
+
+    for (id, annotation) in args.annotate_gate {
+        assert!(
+            job.curr_circuit.gate_by_id(id).is_some(),
+            "Annotated gate {id} is not in the obfuscated circuit"
+        );
+        job.curr_circuit.annotate(id, annotation);
+    }
+    if !job.curr_circuit.annotations().is_empty() {
+        log::info!("Gate annotations: {:?}", job.curr_circuit.annotations());
+    }
+
+    if args.dry_run {
+        assert!(args.dry_run_steps > 0, "--dry-run-steps must be at least 1");
+        run_dry_run(&job, args.dry_run_steps);
+        return;
+    }
+
+    let snapshot_steps = args.snapshot_steps;
+
+    match job.config.starategy {
+        Strategy::Strategy1 | Strategy::Strategy3 => {
+            run_strategy1(&mut job, job_path, verify_every, snapshot_steps);
+        }
+        Strategy::Strategy2 => {
+            run_strategy2(&mut job, job_path, verify_every, snapshot_steps);
+        }
+    }
+
+    // Stats scan the whole circuit, so they are only computed if they are logged
+    if log::log_enabled!(log::Level::Debug) {
+        log::debug!(
+            "Obfuscated circuit stats: {}",
+            serde_json::to_string(&job.curr_circuit.stats()).unwrap()
+        );
     }
 }
 
+/// Default no. of mixing steps of every stage run by a dry run
+const DEFAULT_DRY_RUN_STEPS: usize = 20;
+
+/// Runs `dry_run_steps` mixing steps of every remaining stage of the job and prints the estimated
+/// time to finish the job. Nothing is written to the job and equivalence checks are skipped.
+fn run_dry_run(job: &ObfuscationJob, dry_run_steps: usize) {
+    let config = &job.config;
+    let mut rng = job.mixing_rng();
+    let mut state = MixingState::new(&job.curr_circuit);
+    if let Err(id) = state.freeze_gates(&job.config.frozen_gates) {
+        panic!("Frozen gate {id} is not in the circuit");
+    }
+
+    // Remaining stages as (name, steps done, steps of the stage, steps of the job done before the
+    // stage, params of a step of the stage), with the params the strategies use
+    type StageParams<'a> = Box<dyn Fn(&mut ChaCha8Rng, usize) -> MixingStepParams + 'a>;
+    let stages: Vec<(&str, usize, usize, usize, StageParams)> = match config.starategy {
+        Strategy::Strategy1 | Strategy::Strategy3 => vec![(
+            "Mixing stage",
+            job.curr_total_steps,
+            config.total_steps,
+            0,
+            Box::new(|rng, step| {
+                let ell_out = rng.gen_range(config.ell_out_range(step, MIXING_STAGE_ELL_IN));
+                config.mixing_stage_step_params(ell_out)
+            }),
+        )],
+        Strategy::Strategy2 => {
+            let mut stages: Vec<(&str, usize, usize, usize, StageParams)> = vec![];
+            if config.run_inflationary_stage {
+                stages.push((
+                    "Inflationary stage",
+                    job.curr_inflationary_stage_steps,
+                    config.inflationary_stage_steps,
+                    0,
+                    Box::new(|_, _| config.inflationary_stage_step_params()),
+                ));
+            }
+            if config.run_kneading_stage {
+                stages.push((
+                    "Kneading stage",
+                    job.curr_kneading_stage_steps,
+                    config.kneading_stage_steps,
+                    config.inflationary_stage_steps,
+                    Box::new(|_, _| config.kneading_stage_step_params()),
+                ));
+            }
+            stages
+        }
+    };
+
+    let mut total_estimate = Duration::ZERO;
+    for (stage, steps_done, stage_steps, job_steps_before, params) in stages {
+        let remaining_steps = stage_steps.saturating_sub(steps_done);
+        if remaining_steps == 0 {
+            continue;
+        }
+
+        let mut timings = MixingTimings::default();
+        let mut successful_steps = 0;
+        let mut convex_iterations = 0;
+        let mut replacement_iterations = 0;
+        let start = std::time::Instant::now();
+        for _ in 0..dry_run_steps {
+            let step = steps_done + successful_steps;
+            let params = params(&mut rng, step);
+            let params = MixingStepParams {
+                max_replacement_iterations: config.replacement_iterations_schedule.iterations(
+                    params.max_replacement_iterations,
+                    job_steps_before + step,
+                    config.mixing_steps(),
+                ),
+                ..params
+            };
+            let regions = config.batch_regions(step, stage_steps);
+            let stats = if regions > 1 {
+                local_mixing_batch(
+                    &mut state,
+                    &params,
+                    regions,
+                    &mut rng,
+                    &mut timings,
+                    None,
+                    None,
+                )
+            } else {
+                local_mixing_step(&mut state, &params, &mut rng, &mut timings, None, None)
+                    .into_iter()
+                    .collect()
+            };
+            for stats in stats {
+                successful_steps += 1;
+                convex_iterations += stats.convex_iterations;
+                replacement_iterations += stats.replacement_iterations;
+            }
+        }
+        let elapsed = start.elapsed();
+
+        if config.batch_regions > 1 {
+            println!(
+                "{stage}: {successful_steps} steps succeeded in {dry_run_steps} batches in {elapsed:?}"
+            );
+            println!(
+                "    Average batch time: {:?}",
+                elapsed / dry_run_steps as u32
+            );
+        } else {
+            println!(
+                "{stage}: {successful_steps} of {dry_run_steps} steps succeeded in {elapsed:?}"
+            );
+            println!(
+                "    Average step time: {:?}",
+                elapsed / dry_run_steps as u32
+            );
+        }
+        println!("    Time spent: {timings:?}");
+        if successful_steps == 0 {
+            println!(
+                "    No step succeeded. Can't estimate time of {remaining_steps} remaining steps"
+            );
+            continue;
+        }
+        println!(
+            "    Average convex iterations per successful step: {:.1}",
+            convex_iterations as f64 / successful_steps as f64
+        );
+        println!(
+            "    Average replacement iterations per successful step: {:.1}",
+            replacement_iterations as f64 / successful_steps as f64
+        );
+        // Failed steps also take time, so the estimate is per successful step
+        let estimate = elapsed.mul_f64(remaining_steps as f64 / successful_steps as f64);
+        println!(
+            "    Estimated time of {remaining_steps} remaining steps: {:.1} hours",
+            estimate.as_secs_f64() / 3600.0
+        );
+        total_estimate += estimate;
+    }
+
+    println!(
+        "Estimated time to finish the job: {:.1} hours, excluding equivalence checks",
+        total_estimate.as_secs_f64() / 3600.0
+    );
+}
+
 /// Verifies that a obfuscation job is correct by checking whether it is obfuscated circuit
 /// is functionally equivalent to the original circuit
-fn run_job_verification() {
-    let job_path = args().nth(2).expect("Missing obfuscated circuit path");
+fn run_job_verification(args: VerifyArgs) {
+    let job_path = args.job_path;
     std::fs::exists(&job_path).expect("Missing obfuscated circuit at path");
-    let job = ObfuscationJob::load(&job_path);
-
-    let iterations = args().nth(3).map_or_else(
-        || 1000,
-        |id| id.parse::<usize>().map_or_else(|_| 1000, |x| x),
-    );
+    let job = ObfuscationJob::load(&job_path).unwrap_or_else(|e| panic!("{e}"));
+
+    // Defaults to iterations of the job's equivalence checks
+    let iterations = args
+        .iterations
+        .unwrap_or_else(|| job.config.eq_check_iterations());
+
+    let exhaustive = args.exhaustive || job.config.is_eq_check_exhaustive();
+    if exhaustive && job.config.n > MAX_EXHAUSTIVE_N {
+        eprintln!(
+            "Exhaustive verification requires n <= {MAX_EXHAUSTIVE_N} but job has n = {}",
+            job.config.n
+        );
+        std::process::exit(2);
+    }
 
     let original_circuit = &job.original_circuit;
     let obfuscated_circuit = &job.curr_circuit;
-    run_verification(original_circuit, obfuscated_circuit, iterations);
+    let success = if args.resumable && !exhaustive {
+        let (success, diff_indices) = check_job_equivalence_resumable(
+            &job,
+            iterations,
+            format!("{job_path}.verify"),
+            args.output.seed,
+        );
+        print_verification_result(success, &diff_indices, args.output.json);
+        success
+    } else {
+        run_verification(
+            original_circuit,
+            obfuscated_circuit,
+            iterations,
+            exhaustive,
+            &args.output,
+        )
+    };
+    if success && !args.output.json {
+        if exhaustive {
+            println!(
+                "Obfsucated job verification with all 2^{} inputs is success",
+                job.config.n
+            );
+        } else {
+            println!("Obfsucated job verification with {iterations} iterations is success");
+        }
+    }
 
-    println!("Obfsucated job verification with {iterations} iterations is success");
+    std::process::exit(if success { 0 } else { 1 });
+}
+
+/// Verifies that a obfuscation job is correct by checking whether its obfuscated circuit composed
+/// with inverse of the original circuit is the identity
+fn run_job_identity_verification(args: VerifyIdentityArgs) {
+    let job_path = args.job_path;
+    std::fs::exists(&job_path).expect("Missing obfuscated circuit at path");
+    let job = ObfuscationJob::load(&job_path).unwrap_or_else(|e| panic!("{e}"));
+
+    // Defaults to iterations of the job's equivalence checks
+    let iterations = args
+        .iterations
+        .unwrap_or_else(|| job.config.eq_check_iterations());
+
+    let exhaustive = args.exhaustive || job.config.is_eq_check_exhaustive();
+    if exhaustive && job.config.n > MAX_EXHAUSTIVE_N {
+        eprintln!(
+            "Exhaustive verification requires n <= {MAX_EXHAUSTIVE_N} but job has n = {}",
+            job.config.n
+        );
+        std::process::exit(2);
+    }
+
+    let composed = job.curr_circuit.compose(&job.original_circuit.inverse());
+    let identity = Circuit::new(vec![], job.config.n);
+    let success = run_verification(&composed, &identity, iterations, exhaustive, &args.output);
+    if success && !args.output.json {
+        if exhaustive {
+            println!(
+                "Obfuscated circuit composed with inverse of original circuit is the identity on all 2^{} inputs",
+                job.config.n
+            );
+        } else {
+            println!(
+                "Obfuscated circuit composed with inverse of original circuit is the identity on {iterations} random inputs"
+            );
+        }
+    }
+
+    std::process::exit(if success { 0 } else { 1 });
+}
+
+/// Progress of a resumable job verification
+#[derive(Serialize, Deserialize)]
+struct VerificationProgress {
+    /// RNG state after `samples_done` samples
+    rng: ChaCha8Rng,
+    samples_done: usize,
+    iterations: usize,
+    /// Canonical hash of the obfuscated circuit being verified
+    circuit_digest: [u8; 32],
+}
+
+/// No. of samples checked between two stores of verification progress
+const VERIFICATION_PROGRESS_SAMPLES: usize = 100_000;
+
+/// Probabilistically checks that job's obfuscated circuit is equivalent to the original circuit
+/// with `iterations` samples. Progress is stored at `progress_path` every
+/// `VERIFICATION_PROGRESS_SAMPLES` samples and, if `progress_path` exists, the check resumes
+/// from it. Progress is deleted once the check is done.
+///
+/// Samples are drawn from a single RNG, seeded with `seed` if set, whose state is stored with the
+/// progress, so the result is the same as of an uninterrupted check.
+fn check_job_equivalence_resumable(
+    job: &ObfuscationJob,
+    iterations: usize,
+    progress_path: impl AsRef<Path>,
+    seed: Option<u64>,
+) -> (bool, Vec<usize>) {
+    let progress_path = progress_path.as_ref();
+    let circuit_digest = job.curr_circuit.canonical_hash();
+
+    let mut progress = if std::fs::exists(progress_path).unwrap() {
+        let progress: VerificationProgress =
+            bincode::deserialize(&std::fs::read(progress_path).unwrap()).unwrap();
+        assert!(
+            progress.iterations == iterations && progress.circuit_digest == circuit_digest,
+            "Verification progress at {} is of a different job or no. of iterations",
+            progress_path.display()
+        );
+        progress
+    } else {
+        VerificationProgress {
+            rng: verification_rng(seed),
+            samples_done: 0,
+            iterations,
+            circuit_digest,
+        }
+    };
+
+    while progress.samples_done < iterations {
+        let samples = VERIFICATION_PROGRESS_SAMPLES.min(iterations - progress.samples_done);
+        let (success, diff_indices) = check_probabilisitic_equivalence(
+            &job.original_circuit,
+            &job.curr_circuit,
+            samples,
+            &mut progress.rng,
+        );
+        if !success {
+            std::fs::remove_file(progress_path).ok();
+            return (false, diff_indices);
+        }
+
+        progress.samples_done += samples;
+        std::fs::write(progress_path, bincode::serialize(&progress).unwrap()).unwrap();
+    }
+
+    std::fs::remove_file(progress_path).ok();
+    (true, vec![])
+}
+
+/// RNG to sample inputs of probabilistic equivalence checks with. Seeded with `seed` if set,
+/// otherwise from entropy.
+fn verification_rng(seed: Option<u64>) -> ChaCha8Rng {
+    seed.map_or_else(ChaCha8Rng::from_entropy, ChaCha8Rng::seed_from_u64)
 }
 
 /// Checks whether file at `file_path` is `json`
@@ -549,47 +2018,90 @@ fn is_json_file(file_path: &str) -> bool {
         .map_or(false, |ext| ext.eq_ignore_ascii_case("json"))
 }
 
-/// Takes Json path to two circuit files ands check whether they are funtionally equivalent
-fn run_circuits_json_equivalence_check() {
-    let c0: Circuit<BaseGate<2, u8>> = {
-        let c0_path = args().nth(2).expect("Missing circuit 1 json file at path");
-        assert!(is_json_file(&c0_path), "{c0_path} is not circuit JSON file");
-        let mut c0_file = std::fs::File::open(c0_path).unwrap();
-        let mut c0_contents = String::new();
-        c0_file.read_to_string(&mut c0_contents).unwrap();
-        let c0: PrettyCircuit = serde_json::from_str(&c0_contents).unwrap();
-        (&c0).into()
-    };
-    let c1: Circuit<BaseGate<2, u8>> = {
-        let c1_path = args().nth(3).expect("Missing circuit 2 json file at path");
-        assert!(is_json_file(&c1_path), "{c1_path} is not circuit JSON file");
-        let mut c1_file = std::fs::File::open(c1_path).unwrap();
-        let mut c1_contents = String::new();
-        c1_file.read_to_string(&mut c1_contents).unwrap();
-        let c0: PrettyCircuit = serde_json::from_str(&c1_contents).unwrap();
-        (&c0).into()
-    };
+/// Takes paths to two circuit files and checks whether they are funtionally equivalent. Each
+/// circuit is loaded with [`load_circuit`], hence can be in JSON or in bincode format.
+fn run_circuits_equivalence_check(args: EquivalenceArgs) {
+    let c0 = load_circuit(&args.circuit0_path);
+    let c1 = load_circuit(&args.circuit1_path);
+    if c0.n() != c1.n() {
+        eprintln!(
+            "Circuits have different no. of wires: circuit 0 has n = {}, circuit 1 has n = {}",
+            c0.n(),
+            c1.n()
+        );
+        std::process::exit(2);
+    }
 
-    let iterations = args().nth(4).map_or_else(
-        || 1000,
-        |id| id.parse::<usize>().map_or_else(|_| 1000, |x| x),
-    );
+    if args.exhaustive && c0.n() > MAX_EXHAUSTIVE_N {
+        eprintln!(
+            "Exhaustive equivalence check requires n <= {MAX_EXHAUSTIVE_N} but circuits have n = {}",
+            c0.n()
+        );
+        std::process::exit(2);
+    }
 
-    run_verification(&c0, &c1, iterations);
+    let iterations = args.iterations;
+    let success = run_verification(&c0, &c1, iterations, args.exhaustive, &args.output);
+    if success && !args.output.json {
+        if args.exhaustive {
+            println!(
+                "circuit 0, circuit 1 equivalance check with all 2^{} inputs is success",
+                c0.n()
+            );
+        } else {
+            println!(
+                "circuit 0, circuit 1 equivalance check with {iterations} iterations is success"
+            );
+        }
+    }
 
-    println!("circuit 0, circuit 1 equivalance check with {iterations} iterations is success");
+    std::process::exit(if success { 0 } else { 1 });
 }
 
-/// Verifies whether 2 circuits are equivalent
+/// Verifies whether 2 circuits are equivalent. Returns true if they are.
+///
+/// Prints result as JSON if `--json` flag is passed.
 fn run_verification(
     c0: &Circuit<BaseGate<2, u8>>,
     c1: &Circuit<BaseGate<2, u8>>,
     iterations: usize,
-) {
-    let (success, diff_indices) =
-        check_probabilisitic_equivalence(c0, c1, iterations, &mut thread_rng());
+    exhaustive: bool,
+    output: &VerificationOutputArgs,
+) -> bool {
+    let (success, diff_indices) = check_equivalence(c0, c1, iterations, exhaustive, output.seed);
+
+    print_verification_result(success, &diff_indices, output.json);
 
-    if !success {
+    success
+}
+
+/// Checks whether 2 circuits are equivalent on all inputs if `exhaustive` is set, otherwise on
+/// `iterations` random inputs. Returns the result and the indices of output wires that differ.
+fn check_equivalence(
+    c0: &Circuit<BaseGate<2, u8>>,
+    c1: &Circuit<BaseGate<2, u8>>,
+    iterations: usize,
+    exhaustive: bool,
+    seed: Option<u64>,
+) -> (bool, Vec<usize>) {
+    if exhaustive {
+        check_exact_equivalence(c0, c1)
+    } else {
+        check_probabilisitic_equivalence(c0, c1, iterations, &mut verification_rng(seed))
+    }
+}
+
+/// Prints result of an equivalence check. Prints it as JSON if `json` is set.
+fn print_verification_result(success: bool, diff_indices: &[usize], json: bool) {
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "equivalent": success,
+                "diff_indices": diff_indices,
+            })
+        );
+    } else if !success {
         println!(
             "Equivalance check failed with following different indices: {:?}",
             diff_indices
@@ -597,83 +2109,82 @@ fn run_verification(
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct PrettyCircuit {
-    wire_count: usize,
-    gate_count: usize,
-    gates: Vec<[u8; 4]>,
-}
-
-impl From<&Circuit<BaseGate<2, u8>>> for PrettyCircuit {
-    fn from(circuit: &Circuit<BaseGate<2, u8>>) -> Self {
-        PrettyCircuit {
-            wire_count: circuit.n(),
-            gate_count: circuit.gates().len(),
-            gates: circuit
-                .gates()
-                .iter()
-                .map(|gate| {
-                    [
-                        gate.controls()[0],
-                        gate.controls()[1],
-                        gate.target(),
-                        gate.control_func(),
-                    ]
-                })
-                .collect_vec(),
-        }
-    }
-}
-
-impl From<&PrettyCircuit> for Circuit<BaseGate<2, u8>> {
-    fn from(circuit: &PrettyCircuit) -> Self {
-        Circuit::new(
-            circuit
-                .gates
-                .iter()
-                .enumerate()
-                .map(|(id, [control0, control1, target, control_func])| {
-                    BaseGate::<2, u8>::new(id, *target, [*control0, *control1], *control_func)
-                })
-                .collect(),
-            circuit.wire_count,
-        )
-    }
-}
+/// Max. no. of wires for which exhaustive verification over all `2^n` inputs is allowed
+const MAX_EXHAUSTIVE_N: usize = 20;
 
-fn run_convert_circuit_to_json() {
-    let input_path = args().nth(2).expect("Missing binary circuit input path");
-    let output_path = args().nth(3).expect("[2] Missing json circuit output path");
+fn run_convert_circuit_to_json(args: ConvertArgs) {
+    let ConvertArgs {
+        input_path,
+        output_path,
+    } = args;
 
     let circuit: Circuit<BaseGate<2, u8>> =
         bincode::deserialize(&std::fs::read(input_path).unwrap()).unwrap();
 
-    std::fs::write(
-        output_path,
-        serde_json::to_string_pretty(&PrettyCircuit::from(&circuit)).unwrap(),
-    )
-    .unwrap();
+    write_json_pretty(output_path, &PrettyCircuit::from(&circuit));
 }
 
-fn run_convert_job_to_json() {
-    let input_path = args().nth(2).expect("[1] Missing job input path");
-    let output_path = args().nth(3).expect("[2] Missing json circuit output path");
+/// Writes `value` as pretty printed JSON to `path`. JSON is streamed to the file, so large
+/// circuits are not held in memory as a string.
+fn write_json_pretty(path: impl AsRef<Path>, value: &impl Serialize) {
+    let mut writer = BufWriter::new(File::create(path).unwrap());
+    serde_json::to_writer_pretty(&mut writer, value).unwrap();
+    writer.flush().unwrap();
+}
 
-    let job = ObfuscationJob::load(input_path);
+/// Companion JSON of the obfuscated circuit written by `job-to-json --metrics`
+#[derive(Serialize)]
+struct JobMetrics<'a> {
+    stats: CircuitStats,
+    strategy: Strategy,
+    total_steps: usize,
+    inflationary_stage_steps: usize,
+    kneading_stage_steps: usize,
+    curr_total_steps: usize,
+    curr_inflationary_stage_steps: usize,
+    curr_kneading_stage_steps: usize,
+    curr_circuit_digest: String,
+    original_circuit_digest: String,
+    /// Gate annotations of the obfuscated circuit keyed by gate id
+    annotations: &'a BTreeMap<usize, String>,
+    metadata: &'a JobMetadata,
+}
 
-    std::fs::write(
+fn run_convert_job_to_json(args: JobToJsonArgs) {
+    let JobToJsonArgs {
+        input_path,
         output_path,
-        serde_json::to_string_pretty(&PrettyCircuit::from(&job.curr_circuit)).unwrap(),
-    )
-    .unwrap();
+        metrics,
+    } = args;
+
+    let job = ObfuscationJob::load(input_path).unwrap_or_else(|e| panic!("{e}"));
+
+    write_json_pretty(output_path, &PrettyCircuit::from(&job.curr_circuit));
+
+    if let Some(metrics_path) = metrics {
+        let metrics = JobMetrics {
+            stats: job.curr_circuit.stats(),
+            strategy: job.config.starategy,
+            total_steps: job.config.total_steps,
+            inflationary_stage_steps: job.config.inflationary_stage_steps,
+            kneading_stage_steps: job.config.kneading_stage_steps,
+            curr_total_steps: job.curr_total_steps,
+            curr_inflationary_stage_steps: job.curr_inflationary_stage_steps,
+            curr_kneading_stage_steps: job.curr_kneading_stage_steps,
+            curr_circuit_digest: job.curr_circuit.digest_hex(),
+            original_circuit_digest: job.original_circuit.digest_hex(),
+            annotations: job.curr_circuit.annotations(),
+            metadata: &job.metadata,
+        };
+        write_json_pretty(metrics_path, &metrics);
+    }
 }
 
-fn run_evaluate_circuit() {
-    let circuit_path = args().nth(2).expect("Missing json circuit input path");
+fn run_evaluate_circuit(args: EvaluateArgs) {
+    let circuit_path = args.circuit_json_path;
     assert!(is_json_file(&circuit_path));
-    let inputs = args()
-        .nth(3)
-        .expect("Missing circuit inputs")
+    let inputs = args
+        .binary_input
         .split(",")
         .map(|bit| {
             bit.parse::<u8>()
@@ -683,9 +2194,8 @@ fn run_evaluate_circuit() {
         })
         .collect_vec();
 
-    let circuit: &PrettyCircuit =
-        &serde_json::from_reader(std::fs::File::open(circuit_path).unwrap()).unwrap();
-    let circuit: Circuit<BaseGate<2, u8>> = circuit.into();
+    let circuit = parse_circuit_json(&std::fs::read_to_string(circuit_path).unwrap())
+        .unwrap_or_else(|e| panic!("Invalid circuit JSON: {e:?}"));
 
     if inputs.len() != circuit.n() {
         panic!(
@@ -700,39 +2210,695 @@ fn run_evaluate_circuit() {
     println!("{}", inputs.into_iter().map(|bit| bit as u8).join(","))
 }
 
-fn main() {
-    let action = args()
-        .nth(1)
-        .map_or_else(|| 100, |id| id.parse::<u8>().map_or_else(|_| 100, |x| x));
-    match action {
-        1 => {
-            run_obfuscation();
-        }
-        2 => {
-            run_job_verification();
-        }
-        3 => {
-            run_convert_circuit_to_json();
-        }
-        4 => {
-            run_convert_job_to_json();
-        }
-        5 => {
-            run_circuits_json_equivalence_check();
+/// Loads circuit at `circuit_path`. Circuit is expected to be in JSON format if file has `json`
+/// extension. Otherwise circuit is expected to be bincode serialized.
+fn load_circuit(circuit_path: &str) -> Circuit<BaseGate<2, u8>> {
+    if is_json_file(circuit_path) {
+        parse_circuit_json(&std::fs::read_to_string(circuit_path).unwrap())
+            .unwrap_or_else(|e| panic!("Invalid circuit JSON: {e:?}"))
+    } else {
+        bincode::deserialize(&std::fs::read(circuit_path).unwrap()).unwrap()
+    }
+}
+
+/// Re-runs the local mixing step stored in step snapshot at path and checks that the circuit after
+/// the step is functionally equivalent to the circuit before the step
+fn run_replay_step(args: ReplayArgs) {
+    let snapshot_path = args.snapshot_path;
+    let iterations = args.iterations;
+
+    let snapshot = MixingStepSnapshot::<ChaCha8Rng>::load(&snapshot_path);
+    let circuit_before = snapshot.circuit();
+    let (stats, circuit_after) = snapshot.replay();
+
+    let exhaustive = circuit_before.n() <= MAX_EXHAUSTIVE_N;
+    let (success, diff_indices) = check_equivalence(
+        &circuit_before,
+        &circuit_after,
+        iterations,
+        exhaustive,
+        args.output.seed,
+    );
+    if args.output.json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "steps": stats,
+                "equivalent": success,
+                "diff_indices": diff_indices,
+            })
+        );
+    } else {
+        println!("Local mixing step returned {:?}", stats);
+        print_verification_result(success, &diff_indices, false);
+        if success {
+            println!("Circuit after the replayed step is equivalent to circuit before the step");
         }
-        6 => {
-            run_evaluate_circuit();
+    }
+
+    std::process::exit(if success { 0 } else { 1 });
+}
+
+/// Prints structural statistics of circuit stored at path
+fn run_circuit_stats(args: StatsArgs) {
+    let circuit_path = args.circuit_path;
+    let avalanche_iterations = args.avalanche_iterations;
+
+    let circuit = load_circuit(&circuit_path);
+
+    let stats = circuit.stats();
+    let avalanche_score = avalanche_score(&circuit, avalanche_iterations, &mut thread_rng());
+
+    println!("Wire count: {}", stats.wire_count);
+    println!("Gate count: {}", stats.gate_count);
+    println!("Depth: {}", stats.depth);
+    println!("Source gates: {}", stats.source_gates);
+    println!("Sink gates: {}", stats.sink_gates);
+    println!("Active wires: {}", stats.active_wires);
+    println!("Cancelling gate pairs: {}", stats.cancelling_pairs);
+    println!("Control function histogram:");
+    for (control_func, count) in stats.control_func_histogram {
+        println!(
+            "    {:>2} ({:?}): {}",
+            control_func,
+            Base2GateControlFunc::from_u8(control_func),
+            count
+        );
+    }
+    println!("Wire usage (as target, as control):");
+    for (wire, (as_target, as_control)) in circuit.wire_usage().into_iter().enumerate() {
+        println!("    {:>3}: {}, {}", wire, as_target, as_control);
+    }
+    println!(
+        "Avalanche score ({} iterations): {:.4}",
+        avalanche_iterations, avalanche_score
+    );
+}
+
+/// Prints histogram of sizes of convex sets found from random start nodes in circuit stored at path
+fn run_convex_size_histogram(args: ConvexSizesArgs) {
+    let ConvexSizesArgs {
+        circuit_path,
+        max_size,
+        samples,
+        seed,
+        level_bounded,
+    } = args;
+    let mut rng = seed.map_or_else(ChaCha8Rng::from_entropy, ChaCha8Rng::seed_from_u64);
+
+    let circuit = load_circuit(&circuit_path);
+    let (_, _, skeleton_graph, _, _, _, _, _) = prepare_circuit(&circuit);
+    let histogram = convex_size_histogram(
+        &skeleton_graph,
+        max_size,
+        samples,
+        &ConvexSearchOptions {
+            level_bounded,
+            ..Default::default()
+        },
+        &mut rng,
+    );
+
+    println!("Convex set sizes from {samples} start nodes, growing up to {max_size} nodes:");
+    for (size, count) in histogram {
+        println!(
+            "    {:>2}: {} ({:.2}%)",
+            size,
+            count,
+            100.0 * count as f64 / samples as f64
+        );
+    }
+}
+
+/// Obfuscates reversible circuits with local mixing. Every command also accepts its number as
+/// its name, for example `1` for `obfuscate`.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+// Parsed once per run, so the size of `ObfuscateArgs` doesn't matter
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Start a new obfuscation job or continue an existing obfuscation job
+    #[command(alias = "1")]
+    Obfuscate(ObfuscateArgs),
+    /// Verify that the obfuscated circuit of a job is functionally equivalent to its original
+    /// circuit
+    #[command(alias = "2")]
+    Verify(VerifyArgs),
+    /// Convert a binary circuit to JSON
+    #[command(alias = "3")]
+    ToJson(ConvertArgs),
+    /// Convert the obfuscated circuit of a job to JSON
+    #[command(alias = "4")]
+    JobToJson(JobToJsonArgs),
+    /// Check whether 2 circuits are functionally equivalent
+    #[command(alias = "5")]
+    Equivalence(EquivalenceArgs),
+    /// Evaluate a circuit on an input
+    #[command(alias = "6")]
+    Evaluate(EvaluateArgs),
+    /// Print structural statistics of a circuit
+    #[command(alias = "7")]
+    Stats(StatsArgs),
+    /// Re-run a failed mixing step from its snapshot
+    #[command(alias = "8")]
+    Replay(ReplayArgs),
+    /// Print histogram of sizes of convex sets found from random start nodes of a circuit
+    #[command(alias = "9")]
+    ConvexSizes(ConvexSizesArgs),
+    /// Verify that the obfuscated circuit of a job composed with inverse of its original circuit
+    /// is the identity
+    #[command(alias = "10")]
+    VerifyIdentity(VerifyIdentityArgs),
+}
+
+/// Parses `ID=TEXT` of `--annotate-gate`
+fn parse_gate_annotation(value: &str) -> Result<(usize, String), String> {
+    let (id, annotation) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected ID=TEXT, got {value:?}"))?;
+    let id = id
+        .parse()
+        .map_err(|e| format!("invalid gate id {id:?}: {e}"))?;
+    Ok((id, annotation.to_string()))
+}
+
+#[derive(Args)]
+struct ObfuscateArgs {
+    /// Path of the log file. `-` disables file logging
+    log_path: String,
+    /// Path of the obfuscation job. The job is continued if it exists
+    job_path: String,
+    /// Path of the circuit to obfuscate. A cipher is sampled and stored at the path if there is no
+    /// circuit. Required for new jobs
+    original_circuit_path: Option<String>,
+    /// Strategy of a new job
+    #[arg(default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=3))]
+    strategy: u8,
+    /// [Strategy 1 and 3] Sample \ell^out from a range that narrows as the job progresses
+    #[arg(long)]
+    adaptive_ell_out: bool,
+    /// Retries of replacement search with \ell^in + 1 when no replacement circuit is found
+    #[arg(long, value_name = "RETRIES")]
+    ell_in_retries: Option<usize>,
+    /// [Strategy 1 and 3] Replace convex subcircuits with circuits of the same no. of gates
+    #[arg(long)]
+    preserve_gate_count: bool,
+    /// Also checkpoint once SECS seconds have passed since the last checkpoint
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    checkpoint_secs: Option<u64>,
+    /// No. of stages of the sampled cipher
+    #[arg(long, value_name = "STAGES")]
+    cipher_stages: Option<usize>,
+    /// No. of layers in each stage of the sampled cipher
+    #[arg(long, value_name = "LAYERS")]
+    cipher_layers: Option<usize>,
+    /// [Strategy 2] Stages to run. A skipped stage still runs its end of stage equivalence check
+    #[arg(long, value_enum, default_value_t = StageSelection::Both)]
+    stage: StageSelection,
+    /// Append a row for every successful mixing step to the CSV file
+    #[arg(long, value_name = "CSV_PATH")]
+    metrics_csv: Option<String>,
+    /// Append a row for every checkpoint to the CSV file
+    #[arg(long, value_name = "CSV_PATH")]
+    checkpoint_csv: Option<String>,
+    /// Append a JSON line with the gates removed and added by every successful mixing step to the
+    /// file
+    #[arg(long, value_name = "JSONL_PATH")]
+    mixing_trace: Option<String>,
+    /// Also log to stdout
+    #[arg(long)]
+    log_stdout: bool,
+    /// One of off, error, warn, info, debug, trace
+    #[arg(long, value_name = "LEVEL", default_value_t = log::LevelFilter::Info)]
+    log_level: log::LevelFilter,
+    /// Snapshot the mixing state before every step to replay a failed step
+    #[arg(long)]
+    snapshot_steps: bool,
+    /// Accept replacement circuits whose collisions set is not weakly connected
+    #[arg(long)]
+    no_weak_connectivity_check: bool,
+    /// No. of inputs, at most 64, replacement candidates are checked on with bit-sliced evaluation
+    /// before all inputs. 0 disables the prefilter. Default is 64. Only used when starting a new
+    /// job
+    #[arg(long, value_name = "N")]
+    replacement_prefilter_inputs: Option<usize>,
+    /// Grow convex subcircuits by the candidate gate of lowest level, which pulls in fewer gates.
+    /// Only used when starting a new job
+    #[arg(long)]
+    level_bounded_convex_search: bool,
+    /// Try start gates of convex subcircuit search that have enough successors to grow a convex
+    /// subcircuit first. Only used when starting a new job
+    #[arg(long)]
+    prioritize_convex_start_nodes: bool,
+    /// Try N convex subcircuits at once per mixing step and replace the ones far apart in the
+    /// skeleton graph in parallel. The mixed circuit is checked after every batch. Only used when
+    /// starting a new job
+    #[arg(long, value_name = "N")]
+    batch_regions: Option<usize>,
+    /// Check equivalence of the mixed circuit every N successful mixing steps, besides at
+    /// checkpoints. 0 only checks at checkpoints. Defaults to 1, or 0 with `DEBUG=false`
+    #[arg(long, value_name = "N")]
+    verify_every: Option<usize>,
+    /// Comma separated ids of gates of the obfuscated circuit that mixing never removes. Added to
+    /// the gates frozen by earlier runs of the job
+    #[arg(long, value_name = "IDS", value_delimiter = ',')]
+    freeze_gates: Vec<usize>,
+    /// Annotate gate ID of the obfuscated circuit with TEXT. Annotations are stored with the job
+    /// and kept while mixing keeps the gate. Can be repeated
+    #[arg(long, value_name = "ID=TEXT", value_parser = parse_gate_annotation)]
+    annotate_gate: Vec<(usize, String)>,
+    /// Seed of the RNGs used to sample the original circuit and to mix it
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Write a copy of the job to the directory at every checkpoint
+    #[arg(long, value_name = "DIR")]
+    history_dir: Option<String>,
+    /// No. of most recent copies kept in the history directory
+    #[arg(long, value_name = "COPIES")]
+    history_keep: Option<usize>,
+    /// How the no. of inputs of equivalence checks scales with n
+    #[arg(long, value_name = "SCALING")]
+    eq_check_scaling: Option<EqCheckScaling>,
+    /// Check equivalence on all inputs at the end of each stage if n is at most MAX_N
+    #[arg(long, value_name = "MAX_N")]
+    exhaustive_eq_check_max_n: Option<usize>,
+    /// One of constant, linear:[final_iterations], exponential:[final_iterations]
+    #[arg(long, value_name = "SCHEDULE")]
+    replacement_iterations_schedule: Option<String>,
+    /// [Strategy 3] No. of consecutive failed mixing steps after which the skeleton graph is
+    /// rebuilt with gates in random order
+    #[arg(long, value_name = "STEPS")]
+    convex_search_restart_steps: Option<usize>,
+    /// [Strategy 2] Stop the kneading stage early once the avalanche score is at least THRESHOLD
+    #[arg(long, value_name = "THRESHOLD")]
+    kneading_early_stop_avalanche: Option<f64>,
+    /// [Strategy 2] No. of consecutive checkpoints for --kneading-early-stop-avalanche
+    #[arg(long, value_name = "CHECKPOINTS")]
+    kneading_early_stop_checkpoints: Option<usize>,
+    /// Assert that every output wire depends on every input wire at the end of the last stage
+    #[arg(long)]
+    assert_full_diffusion: bool,
+    /// Add STEPS mixing steps to an existing job. Starting a new job with it is an error
+    #[arg(long, value_name = "STEPS")]
+    continue_for: Option<usize>,
+    /// Run a few mixing steps of every remaining stage and print the estimated time to finish
+    #[arg(long)]
+    dry_run: bool,
+    /// No. of mixing steps of every stage run by --dry-run
+    #[arg(long, value_name = "STEPS", default_value_t = DEFAULT_DRY_RUN_STEPS)]
+    dry_run_steps: usize,
+}
+
+impl ObfuscateArgs {
+    /// Flags passed that only apply when starting a new job
+    fn new_job_flags(&self) -> Vec<&'static str> {
+        [
+            ("--adaptive-ell-out", self.adaptive_ell_out),
+            ("--ell-in-retries", self.ell_in_retries.is_some()),
+            ("--preserve-gate-count", self.preserve_gate_count),
+            ("--checkpoint-secs", self.checkpoint_secs.is_some()),
+            ("--cipher-stages", self.cipher_stages.is_some()),
+            ("--cipher-layers", self.cipher_layers.is_some()),
+            ("--metrics-csv", self.metrics_csv.is_some()),
+            ("--checkpoint-csv", self.checkpoint_csv.is_some()),
+            ("--mixing-trace", self.mixing_trace.is_some()),
+            (
+                "--no-weak-connectivity-check",
+                self.no_weak_connectivity_check,
+            ),
+            (
+                "--replacement-prefilter-inputs",
+                self.replacement_prefilter_inputs.is_some(),
+            ),
+            (
+                "--level-bounded-convex-search",
+                self.level_bounded_convex_search,
+            ),
+            (
+                "--prioritize-convex-start-nodes",
+                self.prioritize_convex_start_nodes,
+            ),
+            ("--batch-regions", self.batch_regions.is_some()),
+            ("--seed", self.seed.is_some()),
+            ("--history-dir", self.history_dir.is_some()),
+            ("--history-keep", self.history_keep.is_some()),
+            ("--eq-check-scaling", self.eq_check_scaling.is_some()),
+            (
+                "--exhaustive-eq-check-max-n",
+                self.exhaustive_eq_check_max_n.is_some(),
+            ),
+            (
+                "--replacement-iterations-schedule",
+                self.replacement_iterations_schedule.is_some(),
+            ),
+            (
+                "--convex-search-restart-steps",
+                self.convex_search_restart_steps.is_some(),
+            ),
+            (
+                "--kneading-early-stop-avalanche",
+                self.kneading_early_stop_avalanche.is_some(),
+            ),
+            (
+                "--kneading-early-stop-checkpoints",
+                self.kneading_early_stop_checkpoints.is_some(),
+            ),
+            ("--assert-full-diffusion", self.assert_full_diffusion),
+        ]
+        .into_iter()
+        .filter_map(|(flag, passed)| passed.then_some(flag))
+        .collect()
+    }
+}
+
+/// Options of commands that check equivalence of circuits
+#[derive(Args)]
+struct VerificationOutputArgs {
+    /// Print the result as JSON
+    #[arg(long)]
+    json: bool,
+    /// Seed of the RNG that samples inputs of probabilistic checks
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Path of the obfuscation job
+    job_path: String,
+    /// No. of random inputs. Defaults to the no. of inputs of the job's equivalence checks
+    iterations: Option<usize>,
+    /// Check all 2^n inputs
+    #[arg(long)]
+    exhaustive: bool,
+    /// Store progress of the check to resume it if interrupted
+    #[arg(long)]
+    resumable: bool,
+    #[command(flatten)]
+    output: VerificationOutputArgs,
+}
+
+#[derive(Args)]
+struct VerifyIdentityArgs {
+    /// Path of the obfuscation job
+    job_path: String,
+    /// No. of random inputs. Defaults to the no. of inputs of the job's equivalence checks
+    iterations: Option<usize>,
+    /// Check all 2^n inputs
+    #[arg(long)]
+    exhaustive: bool,
+    #[command(flatten)]
+    output: VerificationOutputArgs,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    /// Path of the input file
+    input_path: String,
+    /// Path of the JSON output file
+    output_path: String,
+}
+
+#[derive(Args)]
+struct JobToJsonArgs {
+    /// Path of the obfuscation job
+    input_path: String,
+    /// Path of the JSON output file
+    output_path: String,
+    /// Also write statistics of the obfuscated circuit and progress of the job as JSON to the path
+    #[arg(long, value_name = "JSON_PATH")]
+    metrics: Option<String>,
+}
+
+#[derive(Args)]
+struct EquivalenceArgs {
+    /// Path of circuit 0, JSON if it has `.json` extension, binary otherwise
+    circuit0_path: String,
+    /// Path of circuit 1, JSON if it has `.json` extension, binary otherwise
+    circuit1_path: String,
+    /// No. of random inputs
+    #[arg(default_value_t = 1000)]
+    iterations: usize,
+    /// Check all 2^n inputs
+    #[arg(long)]
+    exhaustive: bool,
+    #[command(flatten)]
+    output: VerificationOutputArgs,
+}
+
+#[derive(Args)]
+struct EvaluateArgs {
+    /// Path of the JSON circuit
+    circuit_json_path: String,
+    /// Comma separated bits of the input, one per wire, for example 0,1,0,1
+    binary_input: String,
+}
+
+#[derive(Args)]
+struct StatsArgs {
+    /// Path of the circuit, JSON if it has `.json` extension, binary otherwise
+    circuit_path: String,
+    /// No. of random inputs used to estimate the avalanche score
+    #[arg(default_value_t = 100)]
+    avalanche_iterations: usize,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// Path of the step snapshot
+    snapshot_path: String,
+    /// No. of random inputs of the equivalence check. Ignored if n <= 20
+    #[arg(default_value_t = 1000)]
+    iterations: usize,
+    #[command(flatten)]
+    output: VerificationOutputArgs,
+}
+
+#[derive(Args)]
+struct ConvexSizesArgs {
+    /// Path of the circuit, JSON if it has `.json` extension, binary otherwise
+    circuit_path: String,
+    /// No. of gates convex sets are grown to
+    #[arg(default_value_t = 4)]
+    max_size: usize,
+    /// No. of random start nodes
+    #[arg(default_value_t = 1000)]
+    samples: usize,
+    /// Seed of the RNG that samples start nodes
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Grow convex sets by the candidate gate of lowest level
+    #[arg(long)]
+    level_bounded: bool,
+}
+
+fn main() {
+    match Cli::parse().command {
+        Command::Obfuscate(args) => run_obfuscation(args),
+        Command::Verify(args) => run_job_verification(args),
+        Command::ToJson(args) => run_convert_circuit_to_json(args),
+        Command::JobToJson(args) => run_convert_job_to_json(args),
+        Command::Equivalence(args) => run_circuits_equivalence_check(args),
+        Command::Evaluate(args) => run_evaluate_circuit(args),
+        Command::Stats(args) => run_circuit_stats(args),
+        Command::Replay(args) => run_replay_step(args),
+        Command::ConvexSizes(args) => run_convex_size_histogram(args),
+        Command::VerifyIdentity(args) => run_job_identity_verification(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust::sample_circuit_with_base_gate;
+
+    /// Job stored by the unversioned build with strategy 1 on 4 wires after all 20 steps
+    const JOB_V0: &[u8] = include_bytes!("../fixtures/job_v0.bin");
+
+    fn sample_job() -> ObfuscationJob {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let config = ObfuscationConfig::new_with_strategy1(8, 10, 100, 1000, 5, 100);
+        let original_circuit = sample_circuit_with_base_gate::<2, u8, _>(20, 8, 1.0, &mut rng).0;
+        let mut curr_circuit = original_circuit.clone();
+        curr_circuit.annotate(curr_circuit.gates()[0].id(), "watermark");
+        ObfuscationJob {
+            version: OBFUSCATION_JOB_VERSION,
+            metadata: JobMetadata::new(config.seed),
+            config,
+            curr_total_steps: 3,
+            curr_inflationary_stage_steps: 0,
+            curr_kneading_stage_steps: 0,
+            curr_kneading_early_stop_checkpoints: 0,
+            mixing_trace_len: None,
+            mixing_rng: None,
+            curr_circuit,
+            original_circuit,
         }
-        _ => {
-            // Help
-            println!(
-                r#"
-            Welcome to Obfustopia
+    }
 
-Please refer to README for instructions on how to use.
-            
-                "#
-            );
+    #[test]
+    fn test_stage_step_params() {
+        let mut config = sample_job().config;
+        config.ell_in_retries = 2;
+        let ells = |params: MixingStepParams| (params.ell_out, params.ell_in, params.max_ell_in);
+        assert_eq!(ells(config.mixing_stage_step_params(3)), (3, 4, 6));
+        assert_eq!(ells(config.inflationary_stage_step_params()), (2, 4, 6));
+        assert_eq!(ells(config.kneading_stage_step_params()), (4, 4, 4));
+
+        config.preserve_gate_count = true;
+        assert_eq!(ells(config.mixing_stage_step_params(3)), (3, 3, 3));
+
+        let params = config.mixing_stage_step_params(3);
+        assert_eq!(params.max_convex_iterations, config.max_convex_iterations);
+        assert_eq!(
+            params.max_replacement_iterations,
+            config.max_replacement_iterations
+        );
+        assert_eq!(params.convex_search, config.convex_search());
+        assert_eq!(params.replacement_search, config.replacement_search());
+    }
+
+    #[test]
+    fn test_load_unversioned_job() {
+        let job = ObfuscationJob::decode(JOB_V0).unwrap();
+        assert_eq!(job.version, OBFUSCATION_JOB_VERSION);
+        assert_eq!(job.config.n, 4);
+        assert!(matches!(job.config.starategy, Strategy::Strategy1));
+        assert_eq!(job.config.total_steps, 20);
+        assert_eq!(job.config.checkpoint_steps, 5);
+        assert_eq!(job.curr_total_steps, 20);
+        assert_eq!(
+            job.original_circuit.digest_hex(),
+            "7725f6149e605bfc95d73f33c2fd2996591bfc3fd25e346553a3e137bb254ab9"
+        );
+        assert_eq!(
+            job.curr_circuit.digest_hex(),
+            "971de8c544029360b954e6f3d33591502263e6608c3ce62481ffab91ed9aea56"
+        );
+        assert!(check_exact_equivalence(&job.curr_circuit, &job.original_circuit).0);
+
+        // Migrated job is stored with the current version
+        let bytes = job.encode();
+        assert!(bytes.starts_with(&OBFUSCATION_JOB_MAGIC));
+        assert_eq!(ObfuscationJob::decode(&bytes).unwrap().encode(), bytes);
+    }
+
+    #[test]
+    fn test_job_round_trip() {
+        let job = sample_job();
+        let bytes = job.encode();
+        let decoded = ObfuscationJob::decode(&bytes).unwrap();
+        assert_eq!(decoded.encode(), bytes);
+        assert_eq!(decoded.curr_circuit, job.curr_circuit);
+        assert_eq!(
+            decoded.curr_circuit.annotations(),
+            job.curr_circuit.annotations()
+        );
+        assert_eq!(decoded.metadata.crate_version, job.metadata.crate_version);
+    }
+
+    #[test]
+    fn test_continue_mixing_rng() {
+        let mut job = sample_job();
+        job.config.seed = Some(1);
+        let mut rng = job.mixing_rng();
+        assert_eq!(rng, job.config.mixing_rng());
+        let first = rng.gen::<u64>();
+
+        // Continued job draws from where the checkpoint left off, not what the job started with
+        job.mixing_rng = Some(rng.clone());
+        let mut continued = ObfuscationJob::decode(&job.encode()).unwrap().mixing_rng();
+        assert_eq!(continued, rng);
+        assert_ne!(continued.gen::<u64>(), first);
+    }
+
+    #[test]
+    fn test_annotated_circuit_round_trip() {
+        #[derive(Serialize, Deserialize)]
+        struct Annotated(#[serde(with = "annotated_circuit")] Circuit<BaseGate<2, u8>>);
+
+        let circuit = sample_job().curr_circuit;
+        let bytes = bincode::serialize(&Annotated(circuit.clone())).unwrap();
+        let decoded: Annotated = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.0, circuit);
+        assert_eq!(decoded.0.annotations(), circuit.annotations());
+
+        // Annotation of a gate that is not in the circuit is rejected
+        let mut annotations = circuit.annotations().clone();
+        annotations.insert(usize::MAX, "missing".to_string());
+        let bytes = bincode::serialize(&(&circuit, &annotations)).unwrap();
+        assert!(bincode::deserialize::<Annotated>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_truncate_mixing_trace() {
+        let dir = std::env::temp_dir().join(format!("trace_{}", thread_rng().gen::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let trace_path = dir.join("trace.jsonl");
+        let mut job = sample_job();
+        job.config.mixing_trace_path = Some(trace_path.to_str().unwrap().to_string());
+
+        // Nothing is traced before the first checkpoint
+        job.checkpoint(dir.join("job.bin"));
+        assert_eq!(job.mixing_trace_len, Some(0));
+        job.truncate_mixing_trace();
+
+        std::fs::write(&trace_path, "{\"step\":0}\n").unwrap();
+        job.checkpoint(dir.join("job.bin"));
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&trace_path)
+            .unwrap();
+        writeln!(file, "{{\"step\":1}}").unwrap();
+
+        // Record of the step after the checkpoint is dropped when the job is continued
+        let job = ObfuscationJob::load(dir.join("job.bin")).unwrap();
+        job.truncate_mixing_trace();
+        assert_eq!(
+            std::fs::read_to_string(&trace_path).unwrap(),
+            "{\"step\":0}\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_new_job_flags() {
+        let parse = |args: &[&str]| match Cli::parse_from(
+            ["rust", "obfuscate", "log", "job"].iter().chain(args),
+        )
+        .command
+        {
+            Command::Obfuscate(args) => args.new_job_flags(),
+            _ => unreachable!(),
+        };
+        assert!(parse(&["--continue-for", "10", "--stage", "kneading"]).is_empty());
+        assert_eq!(
+            parse(&["--seed", "1", "--verify-every", "5", "--batch-regions", "4"]),
+            ["--batch-regions", "--seed"]
+        );
+    }
+
+    #[test]
+    fn test_load_malformed_job() {
+        let bytes = sample_job().encode();
+        for truncated in [&bytes[..bytes.len() / 2], &JOB_V0[..JOB_V0.len() / 2], &[]] {
+            assert!(matches!(
+                ObfuscationJob::decode(truncated),
+                Err(JobLoadError::Io(_))
+            ));
         }
+
+        let mut bytes = OBFUSCATION_JOB_MAGIC.to_vec();
+        bytes.extend(bincode::serialize(&(OBFUSCATION_JOB_VERSION + 1)).unwrap());
+        assert!(matches!(
+            ObfuscationJob::decode(&bytes),
+            Err(JobLoadError::UnsupportedVersion(version)) if version == OBFUSCATION_JOB_VERSION + 1
+        ));
     }
 }